@@ -0,0 +1,225 @@
+//! Rough throughput comparison of the lock types.
+//!
+//! Run with `cargo run --release --example bench [ops] [threads]`. Every case performs `ops` lock
+//! operations in total, first on one thread (uncontended) and then split across `threads` threads
+//! (contended). Waiters yield to the OS scheduler, so the numbers stay meaningful when there are more
+//! threads than CPUs. Only the `SpinMutex` backoff comparison spins without yielding, on at most one thread
+//! per CPU.
+//!
+//! Afterwards it measures how `RcuLock` reads scale from one to four reader threads and asserts that four
+//! readers get past twice the single reader throughput when there are at least four CPUs.
+use kernel_sync::{
+    rculock::RcuLock,
+    rwlock::RwLock,
+    spin::SpinMutex,
+    ticket::{RelaxedTicketMutex, TicketMutex},
+    EmptyLockAction, LockAction, LockActionSendMarker,
+};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+struct YieldAction;
+impl LockAction for YieldAction {
+    type Guard = ();
+    fn relax() {
+        std::thread::yield_now();
+    }
+}
+impl LockActionSendMarker for YieldAction {}
+
+/// Run `op` `ops` times split across `threads` threads and return the elapsed time.
+fn run<S: Send + Sync + 'static>(
+    shared: &Arc<S>,
+    threads: usize,
+    ops: usize,
+    op: fn(&S, usize),
+) -> Duration {
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                for i in 0..ops / threads {
+                    op(&shared, t + i);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn report<S: Send + Sync + 'static>(
+    name: &str,
+    shared: S,
+    threads: usize,
+    ops: usize,
+    op: fn(&S, usize),
+) {
+    let shared = Arc::new(shared);
+    let ns = |elapsed: Duration| elapsed.as_nanos() as f64 / ops as f64;
+    let uncontended = ns(run(&shared, 1, ops, op));
+    let contended = ns(run(&shared, threads, ops, op));
+    println!("{name:<16} {uncontended:>14.1} {contended:>14.1}");
+}
+
+/// Hammer `RcuLock::read` from `threads` threads for `duration` and return the total reads per second.
+fn rcu_reads_per_sec(threads: usize, duration: Duration) -> f64 {
+    let lock = RcuLock::<[usize; 8], YieldAction>::new([0; 8]);
+    let stop = Arc::new(AtomicBool::new(false));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let lock = lock.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut reads = 0usize;
+                while !stop.load(Ordering::Relaxed) {
+                    for _ in 0..1000 {
+                        black_box(lock.read()[7]);
+                    }
+                    reads += 1000;
+                }
+                reads
+            })
+        })
+        .collect();
+    let start = Instant::now();
+    std::thread::sleep(duration);
+    stop.store(true, Ordering::Relaxed);
+    let reads: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    reads as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let ops = args.next().map_or(1_000_000, |s| s.parse().expect("ops"));
+    let threads = args.next().map_or_else(
+        || std::thread::available_parallelism().map_or(4, |n| n.get()),
+        |s| s.parse().expect("threads"),
+    );
+
+    println!("{ops} ops, {threads} threads, ns/op");
+    println!("{:<16} {:>14} {:>14}", "lock", "uncontended", "contended");
+    report(
+        "SpinMutex",
+        SpinMutex::<usize, YieldAction>::new(0),
+        threads,
+        ops,
+        |lock, _| *lock.lock() += 1,
+    );
+    // Backoff only helps waiters that keep polling the lock from other CPUs, so compare it without yielding
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    for max_backoff in [0, 16, 64, 256] {
+        report(
+            &format!("Spin backoff {max_backoff}"),
+            SpinMutex::<usize, EmptyLockAction>::with_backoff(0, max_backoff),
+            threads.min(cpus),
+            ops,
+            |lock, _| *lock.lock() += 1,
+        );
+    }
+    report(
+        "TicketMutex",
+        TicketMutex::<usize, YieldAction>::new(0),
+        threads,
+        ops,
+        |lock, _| *lock.lock() += 1,
+    );
+    report(
+        "RelaxedTicket",
+        RelaxedTicketMutex::<usize, YieldAction>::new(0),
+        threads,
+        ops,
+        |lock, _| *lock.lock() += 1,
+    );
+    report(
+        "RwLock read",
+        RwLock::<usize, YieldAction>::new(0),
+        threads,
+        ops,
+        |lock, _| {
+            black_box(*lock.read());
+        },
+    );
+    report(
+        "RwLock write",
+        RwLock::<usize, YieldAction>::new(0),
+        threads,
+        ops,
+        |lock, _| *lock.write() += 1,
+    );
+    report(
+        "RwLock 1/16 w",
+        RwLock::<usize, YieldAction>::new(0),
+        threads,
+        ops,
+        |lock, i| {
+            if i % 16 == 0 {
+                *lock.write() += 1;
+            } else {
+                black_box(*lock.read());
+            }
+        },
+    );
+    report(
+        "RcuLock read",
+        RcuLock::<usize, YieldAction>::new(0),
+        threads,
+        ops,
+        |lock, _| {
+            black_box(*lock.read());
+        },
+    );
+    report(
+        "RcuLock write",
+        RcuLock::<usize, YieldAction>::new(0),
+        threads,
+        ops,
+        |lock, _| *lock.write() += 1,
+    );
+    report(
+        "RcuLock 1/16 w",
+        RcuLock::<usize, YieldAction>::new(0),
+        threads,
+        ops,
+        |lock, i| {
+            if i % 16 == 0 {
+                *lock.write() += 1;
+            } else {
+                black_box(*lock.read());
+            }
+        },
+    );
+
+    // Reads of a vector after a write has published a second version, against the same reads on an RwLock
+    let rcu = RcuLock::<Vec<usize>, YieldAction>::new(vec![1, 2, 3]);
+    rcu.write().push(4);
+    report("RcuLock Vec read", rcu, threads, ops, |lock, _| {
+        black_box(lock.read()[3]);
+    });
+    report(
+        "RwLock Vec read",
+        RwLock::<Vec<usize>, YieldAction>::new(vec![1, 2, 3, 4]),
+        threads,
+        ops,
+        |lock, _| {
+            black_box(lock.read()[3]);
+        },
+    );
+
+    // Readers register on per-CPU counters, so RcuLock reads should scale with the reader count
+    let duration = Duration::from_millis(300);
+    let single = rcu_reads_per_sec(1, duration);
+    let multi = rcu_reads_per_sec(4, duration);
+    println!("RcuLock reads/sec: 1 thread {single:.0}, 4 threads {multi:.0}");
+    if cpus >= 4 {
+        assert!(
+            multi > 2.0 * single,
+            "4 reader threads should scale past 2x a single reader"
+        );
+    }
+}
@@ -0,0 +1,33 @@
+//! Run with `cargo run --example critical_section --features critical-section`.
+//!
+//! With the `critical-section` feature the top-level aliases hold their locks inside a critical section of the
+//! `critical-section` crate. On a host the `std` implementation of that crate is used; on a microcontroller the
+//! platform's implementation, e.g. one that disables interrupts, takes its place without any change here.
+use kernel_sync::{RwLock, SpinMutex, TicketMutex};
+
+static COUNTER: SpinMutex<usize> = SpinMutex::new(0);
+
+fn main() {
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            std::thread::spawn(|| {
+                for _ in 0..1000 {
+                    *COUNTER.lock() += 1;
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*COUNTER.lock(), 4000);
+
+    // Nested locks share one critical section
+    let table = TicketMutex::new(0);
+    let config = RwLock::new(1);
+    {
+        let mut entry = table.lock();
+        *entry += *config.read();
+    }
+    assert_eq!(*table.lock(), 1);
+}
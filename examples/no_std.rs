@@ -0,0 +1,99 @@
+//! Uses every lock from a `#![no_std]` binary, to check that the crate builds and links without std.
+//!
+//! Build it for a bare-metal target with
+//! `cargo +nightly build -Zbuild-std=core,alloc --target x86_64-unknown-none --example no_std`.
+//! On a hosted target it is an ordinary program, so `cargo run --example no_std` runs the same code.
+#![cfg_attr(target_os = "none", no_std, no_main)]
+
+extern crate alloc;
+
+use alloc::vec;
+use kernel_sync::{
+    epochrcu::EpochRcuLock, rculock::RcuLock, rwlock::RwLock, spin::SpinMutex, ticket::TicketMutex,
+    EmptyLockAction,
+};
+
+fn run() {
+    let x = SpinMutex::<_, EmptyLockAction>::new(0);
+    *x.lock() = 19;
+    assert_eq!(*x.lock(), 19);
+    let y = TicketMutex::<_, EmptyLockAction>::new(0);
+    *y.lock() = 19;
+    assert_eq!(*y.lock(), 19);
+    let z = RwLock::<_, EmptyLockAction>::new(0);
+    *z.write() = 19;
+    assert_eq!(*z.read(), 19);
+    // Writing publishes a new version and reclaims the old one
+    let rcu = RcuLock::<_, EmptyLockAction>::new(vec![1, 2, 3]);
+    rcu.write().push(4);
+    assert_eq!(rcu.read().len(), 4);
+    assert_eq!(rcu.pending_reclaims(), 0);
+    let epoch = EpochRcuLock::<_, EmptyLockAction>::new(vec![1]);
+    epoch.write().push(2);
+    assert_eq!(epoch.read().len(), 2);
+}
+
+#[cfg(not(target_os = "none"))]
+fn main() {
+    run();
+}
+
+#[cfg(target_os = "none")]
+mod bare_metal {
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    const HEAP_SIZE: usize = 64 * 1024;
+
+    /// Hands out memory from a static array and never frees it, which is enough for one run.
+    struct BumpAllocator {
+        heap: UnsafeCell<[u8; HEAP_SIZE]>,
+        next: AtomicUsize,
+    }
+
+    unsafe impl Sync for BumpAllocator {}
+
+    unsafe impl GlobalAlloc for BumpAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let base = self.heap.get() as usize;
+            let mut next = self.next.load(Ordering::Relaxed);
+            loop {
+                let start = (base + next).next_multiple_of(layout.align()) - base;
+                let end = start + layout.size();
+                if end > HEAP_SIZE {
+                    return core::ptr::null_mut();
+                }
+                match self.next.compare_exchange_weak(next, end, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => return (base + start) as *mut u8,
+                    Err(actual) => next = actual,
+                }
+            }
+        }
+
+        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: BumpAllocator = BumpAllocator {
+        heap: UnsafeCell::new([0; HEAP_SIZE]),
+        next: AtomicUsize::new(0),
+    };
+
+    #[panic_handler]
+    fn panic(_info: &core::panic::PanicInfo) -> ! {
+        halt()
+    }
+
+    #[no_mangle]
+    extern "C" fn _start() -> ! {
+        super::run();
+        halt()
+    }
+
+    fn halt() -> ! {
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+}
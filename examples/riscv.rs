@@ -1,11 +1,86 @@
+//! Locks for a RISC-V kernel that keep interrupts disabled while held, like `push_off`/`pop_off` of xv6.
+//!
+//! `before_lock` disables interrupts and returns whether `sstatus.SIE` was set before. The lock keeps that token
+//! in its guard and hands it to `after_lock` on release, which restores it. When nested guards are released in
+//! reverse order, as scoped guards are, only the outermost one enables interrupts again, and releasing a lock inside a trap handler, where
+//! interrupts are already off, does not enable them. The guards are not `Send`, so the token is always restored
+//! on the hart that saved it.
 use kernel_sync::{LockAction, rwlock::RwLock, spin::SpinMutex, ticket::TicketMutex};
+
+#[cfg(target_arch = "riscv64")]
+mod arch {
+    use core::arch::asm;
+
+    const SSTATUS_SIE: usize = 1 << 1;
+
+    pub fn cpuid() -> usize {
+        let id;
+        unsafe { asm!("mv {}, tp", out(reg) id) };
+        id
+    }
+    pub fn intr_get() -> bool {
+        let x: usize;
+        unsafe { asm!("csrr {}, sstatus", out(reg) x) };
+        x & SSTATUS_SIE != 0
+    }
+    pub fn intr_on() {
+        unsafe { asm!("csrs sstatus, {}", in(reg) SSTATUS_SIE) };
+    }
+    pub fn intr_off() {
+        unsafe { asm!("csrc sstatus, {}", in(reg) SSTATUS_SIE) };
+    }
+}
+
+/// Simulates `sstatus.SIE` of a single hart, so that the example also runs on the host.
+#[cfg(not(target_arch = "riscv64"))]
+mod arch {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static SIE: AtomicBool = AtomicBool::new(false);
+
+    pub fn cpuid() -> usize {
+        0
+    }
+    pub fn intr_get() -> bool {
+        SIE.load(Ordering::Relaxed)
+    }
+    pub fn intr_on() {
+        SIE.store(true, Ordering::Relaxed);
+    }
+    pub fn intr_off() {
+        SIE.store(false, Ordering::Relaxed);
+    }
+}
+
+/// The interrupt state saved by `before_lock`. The default, used for a lost token, leaves interrupts disabled.
+#[derive(Default)]
+pub struct SavedSie(bool);
+
 pub struct KernelLockAction;
 impl LockAction for KernelLockAction {
-    fn before_lock() {
-        // push_off(); //disable interrupt
+    type Guard = SavedSie;
+    // Interrupts stay off while a lock is held, so the holder can't be preempted or moved
+    const PINS_HOLDER: bool = true;
+    fn before_lock() -> SavedSie {
+        let enabled = arch::intr_get();
+        arch::intr_off();
+        SavedSie(enabled)
     }
-    fn after_lock() {
-        // pop_off(); //enable interrupt
+    fn after_lock(saved: SavedSie) {
+        assert!(!arch::intr_get(), "after_lock: interruptible");
+        if saved.0 {
+            arch::intr_on();
+        }
+    }
+    // Read and write holds save the same state, so a downgrade keeps interrupts off
+    fn convert_to_read(saved: SavedSie) -> SavedSie {
+        saved
+    }
+    fn convert_to_write(saved: SavedSie) -> SavedSie {
+        saved
+    }
+    fn current_cpu() -> Option<usize> {
+        Some(arch::cpuid())
     }
 }
 
@@ -19,4 +94,29 @@ fn main() {
     let z = RwLock::<_,KernelLockAction>::new(0);
     *z.write() = 19;
     assert_eq!(*z.read(), 19);
+
+    // Nested locks keep interrupts off until the last one is released
+    arch::intr_on();
+    let a = x.lock();
+    let b = y.lock();
+    assert!(!arch::intr_get());
+    drop(b);
+    assert!(!arch::intr_get());
+    drop(a);
+    assert!(arch::intr_get());
+    // A failed attempt restores the state too
+    let a = x.lock();
+    assert!(x.try_lock().is_none());
+    drop(a);
+    assert!(arch::intr_get());
+    // A downgraded guard restores the state of the write hold
+    let r = z.write().downgrade();
+    assert!(!arch::intr_get());
+    drop(r);
+    assert!(arch::intr_get());
+
+    // In a trap handler interrupts are already off and stay off
+    arch::intr_off();
+    drop(z.read());
+    assert!(!arch::intr_get());
 }
@@ -0,0 +1,27 @@
+//! Run with `cargo run --example yield_action --features action-yield`.
+use kernel_sync::{register_yield_now, SpinMutex};
+use std::sync::Arc;
+
+fn yield_now() {
+    std::thread::yield_now();
+}
+
+fn main() {
+    // The top-level aliases now wait through `YieldLockAction` instead of pure spinning.
+    register_yield_now(yield_now);
+    let x = Arc::new(SpinMutex::new(0));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let x = x.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    *x.lock() += 1;
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*x.lock(), 4000);
+}
@@ -1,11 +1,15 @@
-use core::cell::UnsafeCell;
 use core::fmt::Debug;
+use core::mem::MaybeUninit;
 use core::ptr::null_mut;
-use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
-use core::{borrow, ops, ptr};
-// use std::fmt::Debug;
+use core::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use core::{borrow, ops};
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::atomic::AtomicCounter;
+use crate::spin::{SpinMutex, SpinMutexGuard};
+use crate::EmptyLockAction;
 
 /// Based on [droundy/rcu-clean/arcrcu.rs](https://github.com/droundy/rcu-clean/blob/master/src/arcrcu.rs) on Github.
 ///
@@ -13,12 +17,10 @@ use alloc::sync::Arc;
 ///
 /// The [ArcRcu] is functionally roughly equivalent to
 /// `Arc<RwLock<T>>`, except that reads (of the old value) may happen
-/// while a write is taking place.  Reads on an [ArcRcu] are much
-/// faster (by a factor of 2 or 3) than reads on either a
-/// `Arc<RwLock<T>>` or a `Arc<Mutex<T>>`.  So in this case you gain
-/// both ergonomics and read speed.  Writes are slow, so only use this
+/// while a write is taking place.  Reads on an [ArcRcu] never wait
+/// for a writer and never allocate.  Writes are slow, so only use this
 /// type if writes are rare (or their speed doesn't matter).
-
+///
 /// ```ignore
 /// let x = ArcRcu::new(3);
 /// let y: &usize = &(*x);
@@ -29,15 +31,16 @@ use alloc::sync::Arc;
 /// assert_eq!(*z, 7); // but the cloned pointer also points to the new value.
 /// ```
 ///
-/// Todo：改一下borrow_count机制，现在只要有读者或写者在占用这个锁，就无法释放旧版本的数据。需要改成Grace Period那样的。
-
-#[derive(Debug)]
+/// 当前版本通过`current`指针发布，被替换下来的旧版本挂在`retired`链表上，由写者在宽限期结束后释放。
+/// 读者持有的引用始终指向一个完整的堆上版本，不会在读者仍在使用时被移动或释放。
+/// 每个版本的节点自带链表指针，退休时不需要分配内存；回收的节点留作下一个版本使用，因此稳定状态下写者不会分配内存。
 pub struct ArcRcu<T> {
     pub inner: Arc<Inner<T>>,
 }
+
 unsafe impl<T: Send + Sync> Send for ArcRcu<T> {}
 unsafe impl<T: Send + Sync> Sync for ArcRcu<T> {}
-impl<T: Clone> Clone for ArcRcu<T> {
+impl<T> Clone for ArcRcu<T> {
     fn clone(&self) -> Self {
         ArcRcu {
             inner: self.inner.clone(),
@@ -45,40 +48,319 @@ impl<T: Clone> Clone for ArcRcu<T> {
     }
 }
 
-// impl<T: Clone + Debug> Debug for ArcRcu<T> {
-//     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-//         f.debug_struct("ArcRcu").field("inner", &self.inner).finish()
-//     }
-// }
+/// 读者计数的分片数。读者按照各自栈地址散列到不同分片，避免所有CPU争用同一个缓存行。
+pub const BORROW_COUNT_SHARDS: usize = 8;
+
+/// 独占一个缓存行的计数器
+#[derive(Debug)]
+#[repr(align(64))]
+pub struct ShardCount(AtomicCounter);
+
+/// 一个CPU的静止状态，独占一个缓存行，通常只有这个CPU访问
+#[derive(Debug)]
+#[repr(align(64))]
+pub struct QuiescentCpu {
+    /// 最近一次报告静止状态时看到的宽限期编号
+    reported: AtomicUsize,
+    /// 在这个CPU上拿到、还没有释放的静止状态模式的读者守卫数。不为0时这个CPU不能报告静止状态
+    readers: AtomicUsize,
+}
 
 #[derive(Debug)]
 pub struct Inner<T> {
-    pub borrow_count: [AtomicUsize; 2],
+    pub borrow_count: [[ShardCount; BORROW_COUNT_SHARDS]; 2],
     pub current_borrow_count_index: AtomicUsize,
+    /// 每个引用计数位置上持有读者守卫的CPU，第`cpu`位对应CPU `cpu`。写者据此识别自己持有的读者，见[`Inner::only_reader_cpu`]
+    pub reader_cpus: [AtomicUsize; 2],
+    /// 静止状态模式下每个CPU的报告，不使用这个模式时为空，见[`Inner::quiescent_state`]
+    pub quiescent: Box<[QuiescentCpu]>,
+    /// 静止状态模式下最近开始的宽限期编号，每个写者发布之后加一
+    pub grace_period: AtomicUsize,
     pub am_writing: AtomicBool,
-    list: List<T>,
+    /// 每发布一个新版本加一
+    pub version: AtomicCounter,
+    /// 持有写者锁的CPU，没有时为`usize::MAX`。只在debug模式下用于检测递归写。
+    #[cfg(debug_assertions)]
+    pub writer_cpu: AtomicUsize,
+    /// 当前版本，读者从这里读取
+    current: AtomicPtr<Node<T>>,
+    /// 已经被替换下来、等待宽限期结束后释放的旧版本
+    retired: RetiredStack<T>,
+    /// `retired`中旧版本的个数。先于压栈增加、晚于释放减少，因此不会小于实际个数
+    pending: AtomicUsize,
+    /// `retired`中最旧的版本被发布时`version`的值，没有旧版本时为`usize::MAX`。和清理并发时可能偏旧，但不会偏新
+    oldest_retired: AtomicUsize,
+    /// 一个值已经被释放、可以用来存放下一个版本的节点，没有时为空
+    spare: AtomicPtr<Node<T>>,
+    /// 写者发布新版本之后调用的观察者
+    pub observers: Observers<T>,
+}
+
+/// 发布新版本之后调用的观察者，见[`crate::rculock::RcuLock::on_update`]
+pub struct Observers<T>(SpinMutex<Vec<Observer<T>>, EmptyLockAction>);
+
+/// 一个观察者，参数是刚发布的版本
+pub type Observer<T> = Box<dyn Fn(&T) + Send + Sync>;
+
+impl<T> Observers<T> {
+    const fn new() -> Self {
+        Observers(SpinMutex::new(Vec::new()))
+    }
+
+    /// 锁住观察者列表。持有期间不能再登记观察者
+    pub fn lock(&self) -> SpinMutexGuard<'_, Vec<Observer<T>>, EmptyLockAction> {
+        self.0.lock()
+    }
 }
 
+impl<T> Debug for Observers<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Observers").finish_non_exhaustive()
+    }
+}
+
+/// 存放一个版本的节点，由`Box::into_raw`得到。宽限期结束前读者仍可能引用它，因此不能提前转回`Box`
+struct Node<T> {
+    /// 除了`spare`中的节点，都是已初始化的
+    value: MaybeUninit<T>,
+    /// 退休后在`retired`链表中的下一个节点
+    next: *mut Node<T>,
+}
+
+/// 退休版本组成的无锁栈（Treiber栈），压栈和取出都不需要写者锁。
+///
+/// 只支持一次取出整个链表，不支持逐个弹出：逐个弹出需要先读栈顶的`next`再CAS，
+/// 在这之间另一个线程可能弹出并释放栈顶、又压入一个复用了同一地址的节点，CAS仍然成功但`next`已经失效（ABA问题）。
+/// 整个取出只有一次`swap`，取出之后链表只属于调用者，不存在这个问题。
 #[derive(Debug)]
-pub struct List<T> {
-    value: UnsafeCell<T>,
-    next: AtomicPtr<List<T>>,
+struct RetiredStack<T> {
+    head: AtomicPtr<Node<T>>,
+}
+
+impl<T> RetiredStack<T> {
+    const fn new() -> Self {
+        RetiredStack {
+            head: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    /// 压入一个节点。节点不能在任何链表中，因此压入成功之前只有调用者会访问它的`next`
+    fn push(&self, node: *mut Node<T>) {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*node).next = head };
+            // Release：取出链表的线程能看到next和节点中的值
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// 取出整个链表，返回链表头，栈为空时返回空指针
+    fn take_all(&self) -> *mut Node<T> {
+        self.head.swap(null_mut(), Ordering::Acquire)
+    }
+}
+
+impl<T> Inner<T> {
+    /// 在当前的引用计数位置上登记一个读者（或写者），返回登记的位置和分片
+    ///
+    /// 登记之后读到的版本，在撤销登记之前都不会被释放。
+    pub fn enter(&self) -> (usize, usize) {
+        let shard = current_shard();
+        loop {
+            let index = self.current_borrow_count_index.load(Ordering::SeqCst);
+            self.borrow_count[index][shard].0.inc();
+            // 和Guard::publish中的fence配对：要么写者等待时能看到这次登记，要么之后读到的是新版本
+            fence(Ordering::SeqCst);
+            // 登记期间写者可能已经切换了位置，之后的写者不会再等待旧位置上的读者，因此需要重新登记
+            if self.current_borrow_count_index.load(Ordering::SeqCst) == index {
+                return (index, shard);
+            }
+            self.borrow_count[index][shard].0.dec();
+        }
+    }
+
+    /// 撤销[`Inner::enter`]的登记
+    ///
+    /// 每次登记只能撤销一次。多撤销的一次会让计数下溢成一个极大的值，之后的写者会永远等待宽限期结束。
+    /// debug模式下会检测到这种情况：恢复计数并panic。release模式下不检查。
+    pub fn exit(&self, index: usize, shard: usize) {
+        let count = &self.borrow_count[index][shard].0;
+        let _prev = count.dec();
+        #[cfg(debug_assertions)]
+        if _prev == 0 {
+            count.inc();
+            panic!("RCU borrow count underflow: a guard was released more often than it was taken");
+        }
+    }
+
+    /// 记录`cpu`在`index`位置上持有读者守卫，返回守卫释放时要清除的位。
+    ///
+    /// 同一CPU上只有最外层的守卫记录，内层守卫返回0。CPU未知或编号超出位数时不记录。
+    pub fn track_reader(&self, index: usize, cpu: Option<usize>) -> usize {
+        let bit = match cpu {
+            Some(cpu) if cpu < usize::BITS as usize => 1 << cpu,
+            _ => return 0,
+        };
+        if self.reader_cpus[index].fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+            bit
+        } else {
+            0
+        }
+    }
+
+    /// 清除[`Inner::track_reader`]记录的位
+    pub fn untrack_reader(&self, index: usize, bit: usize) {
+        if bit != 0 {
+            self.reader_cpus[index].fetch_and(!bit, Ordering::Relaxed);
+        }
+    }
+
+    /// `index`位置上记录的读者是否只在`cpu`上。
+    ///
+    /// 这只是写者决定是否继续等待的依据，不能据此释放旧版本：还没有记录、或者没有记录CPU的读者不在其中。
+    pub fn only_reader_cpu(&self, index: usize, cpu: Option<usize>) -> bool {
+        match cpu {
+            Some(cpu) if cpu < usize::BITS as usize => self.reader_cpus[index].load(Ordering::Relaxed) == 1 << cpu,
+            _ => false,
+        }
+    }
+
+    /// 报告`cpu`经过了一个静止状态：它不再持有在此之前拿到的静止状态模式的读者引用。
+    ///
+    /// `cpu`上还有没有释放的读者守卫时不报告，返回`false`。只访问这个CPU自己的缓存行。调用者保证`cpu`在范围内。
+    pub fn quiescent_state(&self, cpu: usize) -> bool {
+        // 和start_grace_period配对：报告之后读到的一定是这个宽限期开始之前发布的版本或者更新的版本
+        let grace_period = self.grace_period.load(Ordering::SeqCst);
+        // 和enter_quiescent配对：这里没有看到的读者在这之后才读取当前版本，读到的是这个宽限期开始之前发布的版本
+        // 或者更新的版本
+        if self.quiescent[cpu].readers.load(Ordering::SeqCst) > 0 {
+            return false;
+        }
+        // 报告之前对旧版本的访问先于写者的回收
+        self.quiescent[cpu].reported.store(grace_period, Ordering::SeqCst);
+        true
+    }
+
+    /// 在`cpu`上登记一个静止状态模式的读者守卫，之后才能读取当前版本。调用者保证`cpu`在范围内
+    pub fn enter_quiescent(&self, cpu: usize) {
+        self.quiescent[cpu].readers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// 撤销[`Inner::enter_quiescent`]的登记，之后不能再访问登记期间读到的版本
+    pub fn exit_quiescent(&self, cpu: usize) {
+        self.quiescent[cpu].readers.fetch_sub(1, Ordering::Release);
+    }
+
+    /// `cpu`上还没有释放的静止状态模式的读者守卫数。调用者保证`cpu`在范围内
+    pub fn quiescent_readers(&self, cpu: usize) -> usize {
+        self.quiescent[cpu].readers.load(Ordering::Relaxed)
+    }
+
+    /// 发布新版本之后开始一个宽限期，返回它的编号。所有CPU都报告了这个编号之后，宽限期结束
+    pub fn start_grace_period(&self) -> usize {
+        self.grace_period.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// 编号为`grace_period`的宽限期是否已经结束，即所有CPU在它开始之后都报告过静止状态
+    pub fn grace_period_over(&self, grace_period: usize) -> bool {
+        self.quiescent
+            .iter()
+            .all(|cpu| cpu.reported.load(Ordering::SeqCst) >= grace_period)
+    }
+
+    /// 把`value`放进一个节点，优先复用`spare`中的节点
+    fn node(&self, value: T) -> *mut Node<T> {
+        let node = self.spare.swap(null_mut(), Ordering::Acquire);
+        if node.is_null() {
+            Box::into_raw(Box::new(Node {
+                value: MaybeUninit::new(value),
+                next: null_mut(),
+            }))
+        } else {
+            unsafe { (*node).value.write(value) };
+            node
+        }
+    }
+
+    /// 预先分配一个空闲节点，返回是否分配了
+    pub fn reserve(&self) -> bool {
+        if !self.spare.load(Ordering::Relaxed).is_null() {
+            return false;
+        }
+        let node = Box::into_raw(Box::new(Node {
+            value: MaybeUninit::uninit(),
+            next: null_mut(),
+        }));
+        self.stash(node)
+    }
+
+    /// 把值已经被释放的节点留作`spare`，已经有了就释放它。返回是否留下了
+    fn stash(&self, node: *mut Node<T>) -> bool {
+        match self
+            .spare
+            .compare_exchange(null_mut(), node, Ordering::Release, Ordering::Relaxed)
+        {
+            Ok(_) => true,
+            Err(_) => {
+                // 值已经被释放，MaybeUninit不会再释放一次
+                drop(unsafe { Box::from_raw(node) });
+                false
+            }
+        }
+    }
+
+    /// 释放一串旧版本，回收一个节点留作下一个版本使用。返回释放的个数
+    fn free_retired(&self, mut retired: *mut Node<T>) -> usize {
+        let mut freed = 0;
+        while !retired.is_null() {
+            let node = retired;
+            unsafe {
+                retired = (*node).next;
+                (*node).value.assume_init_drop();
+            }
+            self.stash(node);
+            freed += 1;
+        }
+        freed
+    }
+
+    /// 把被替换下来的旧版本挂到`retired`上，等宽限期结束后释放。可以和其他退休、清理并发执行
+    fn retire(&self, node: *mut Node<T>) {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        self.retired.push(node);
+        // 先压栈再记录编号：清理先清空编号再取出链表，漏掉的只会是已经被取出的版本的编号
+        self.oldest_retired.fetch_min(self.version.get(), Ordering::SeqCst);
+    }
+
+    /// 在`index`位置上登记的读者和写者总数
+    pub fn borrowers(&self, index: usize) -> usize {
+        self.borrow_count[index]
+            .iter()
+            .map(|count| count.0.get())
+            .sum()
+    }
+}
+
+/// 根据栈地址选择分片：不同线程（CPU）的栈位于不同的页，散列后大多落在不同分片上。
+/// 同一线程每次选到的分片不必相同，因为守卫会记住自己登记的分片。
+#[inline(always)]
+fn current_shard() -> usize {
+    let marker = 0u8;
+    let page = (&marker as *const u8 as usize) >> 12;
+    let hash = page.wrapping_mul(0x9E37_79B9_7F4A_7C15_u64 as usize);
+    hash >> (usize::BITS - BORROW_COUNT_SHARDS.trailing_zeros())
 }
 
 impl<T> ops::Deref for ArcRcu<T> {
     type Target = T;
     fn deref(&self) -> &T {
-        // let aleady_borrowed = self.have_borrowed.get();
-        // if !aleady_borrowed {
-        //     self.inner.borrow_count.fetch_add(1, Ordering::Relaxed);
-        //     self.have_borrowed.set(true); // indicate we have borrowed this once.
-        // }
-        let next = self.inner.list.next.load(Ordering::Acquire);
-        if next.is_null() {
-            unsafe { &*self.inner.list.value.get() }
-        } else {
-            unsafe { &*(*next).value.get() }
-        }
+        unsafe { (*self.inner.current.load(Ordering::SeqCst)).value.assume_init_ref() }
     }
 }
 impl<T> borrow::Borrow<T> for ArcRcu<T> {
@@ -86,109 +368,330 @@ impl<T> borrow::Borrow<T> for ArcRcu<T> {
         self
     }
 }
-impl<T> Drop for List<T> {
+/// 最后一个句柄释放`Inner`时，会释放当前版本、所有尚未清理的旧版本和空闲节点。
+impl<T> Drop for Inner<T> {
     fn drop(&mut self) {
-        let next = self.next.load(Ordering::Acquire);
-        if !next.is_null() {
-            let _free_this = unsafe { Box::from_raw(next) };
+        let current = *self.current.get_mut();
+        unsafe { (*current).value.assume_init_drop() };
+        drop(unsafe { Box::from_raw(current) });
+        let retired = *self.retired.head.get_mut();
+        self.free_retired(retired);
+        let spare = *self.spare.get_mut();
+        if !spare.is_null() {
+            drop(unsafe { Box::from_raw(spare) });
         }
     }
 }
 
-impl<'a, T: Clone> ArcRcu<T> {
+impl<'a, T> ArcRcu<T> {
     pub fn new(x: T) -> Self {
+        Self::new_quiescent(x, 0)
+    }
+    /// 创建一个静止状态模式的[ArcRcu]，写者回收旧版本之前还要等待`cpus`个CPU各报告一次静止状态。
+    /// `cpus`为0时和[`ArcRcu::new`]相同
+    pub fn new_quiescent(x: T, cpus: usize) -> Self {
         ArcRcu {
             // have_borrowed: Cell::new(false),
             inner: Arc::new(Inner {
-                borrow_count: [AtomicUsize::new(0), AtomicUsize::new(0)],
+                borrow_count: [
+                    core::array::from_fn(|_| ShardCount(AtomicCounter::new(0))),
+                    core::array::from_fn(|_| ShardCount(AtomicCounter::new(0))),
+                ],
                 current_borrow_count_index: AtomicUsize::new(0),
+                reader_cpus: [AtomicUsize::new(0), AtomicUsize::new(0)],
+                quiescent: (0..cpus)
+                    .map(|_| QuiescentCpu {
+                        reported: AtomicUsize::new(0),
+                        readers: AtomicUsize::new(0),
+                    })
+                    .collect(),
+                grace_period: AtomicUsize::new(0),
                 am_writing: AtomicBool::new(false),
-                list: List {
-                    value: UnsafeCell::new(x),
-                    next: AtomicPtr::new(null_mut()),
-                },
+                version: AtomicCounter::new(0),
+                #[cfg(debug_assertions)]
+                writer_cpu: AtomicUsize::new(usize::MAX),
+                current: AtomicPtr::new(Box::into_raw(Box::new(Node {
+                    value: MaybeUninit::new(x),
+                    next: null_mut(),
+                }))),
+                retired: RetiredStack::new(),
+                pending: AtomicUsize::new(0),
+                oldest_retired: AtomicUsize::new(usize::MAX),
+                spare: AtomicPtr::new(null_mut()),
+                observers: Observers::new(),
             }),
         }
     }
-    pub fn try_update(&'a self) -> Option<Guard<'a, T>> {
-        if self.inner.am_writing.swap(true, Ordering::Relaxed) {
-            None
+    pub fn try_update(&'a self) -> Option<Guard<'a, T>>
+    where
+        T: Clone,
+    {
+        self.try_update_with(T::clone).ok()
+    }
+    /// 和[`ArcRcu::try_update`]相同，但新版本由`f`根据当前版本构造，而不是克隆当前版本。
+    /// 没有拿到写者锁时不会调用`f`，而是把它原样返回。
+    pub fn try_update_with<F: FnOnce(&T) -> T>(&'a self, f: F) -> Result<Guard<'a, T>, F> {
+        if self.inner.am_writing.swap(true, Ordering::Acquire) {
+            Err(f)
         } else {
-            Some(Guard {
-                list: Some(List {
-                    value: UnsafeCell::new((*(*self)).clone()),
-                    next: AtomicPtr::new(self.inner.list.next.load(Ordering::Acquire)),
-                }),
+            // 先构造Guard再调用f：如果f中panic，Guard被释放时会放开写者锁
+            let mut guard = Guard {
+                node: null_mut(),
                 rc_guts: &self.inner,
-            })
-        }
-    }
-    pub fn clean(&self) {
-        // let aleady_borrowed = self.have_borrowed.get();
-        // if aleady_borrowed {
-        //     self.inner.borrow_count.fetch_sub(1, Ordering::Relaxed);
-        //     self.have_borrowed.set(false); // indicate we have no longer borrowed this.
-        // }
-        // let borrow_count = self.inner.borrow_count.load(Ordering::Relaxed);
-        let next = self.inner.list.next.load(Ordering::Acquire);
-        // std::println!("clean?");
-        // if borrow_count == 0 && next != null_mut() {
-        if !next.is_null() {
-            // std::println!("clean.");
-            unsafe {
-                // make a copy of the old datum that we will need to free
-                let buffer: UnsafeCell<Option<T>> = UnsafeCell::new(None);
-                ptr::copy_nonoverlapping(self.inner.list.value.get(), buffer.get() as *mut T, 1);
-                // std::println!("clean 1");
-                // now copy the "good" value to the main spot
-                ptr::copy_nonoverlapping((*next).value.get(), self.inner.list.value.get(), 1);
-                // std::println!("clean 2");
-                // Now we can set the pointer to null which activates
-                // the copy we just made.
-                let _to_be_freed =
-                    Box::from_raw(self.inner.list.next.swap(null_mut(), Ordering::Release));
-                // std::println!("{:?}", _to_be_freed);
-                ptr::copy_nonoverlapping(buffer.get() as *mut T, (*next).value.get(), 1);
-                // std::println!("clean 3");
-                let buffer_copy: UnsafeCell<Option<T>> = UnsafeCell::new(None);
-                ptr::copy_nonoverlapping(buffer_copy.get(), buffer.get(), 1);
-                // std::println!("clean 4");
-                // std::println!("{:?}", _to_be_freed);
-            }
+            };
+            // 只有写者会替换当前版本，因此f运行期间它不会被释放
+            let value = f(self);
+            guard.node = self.inner.node(value);
+            Ok(guard)
+        }
+    }
+    /// 宽限期结束后释放所有被替换下来的旧版本，返回是否释放了。
+    ///
+    /// 旧版本被替换之后，写者会把读者切换到另一个引用计数位置，因此还可能引用旧版本的只有登记在切换前位置上的读者。
+    /// 这些读者还没有全部离开时，宽限期还没有结束，什么也不释放并返回`false`，调用者稍后重试即可。
+    /// 写者在上一批旧版本被释放之前不能再次切换位置，否则旧读者所在的位置又会变成当前位置。
+    /// 通过[`ops::Deref`]直接得到的引用没有登记，不受保护。
+    ///
+    /// 调用者必须持有写者锁，并且链表上的每个旧版本都是在最近一次切换位置之前退休的，这样它们的读者都登记在
+    /// 切换前的位置上。[`crate::RcuLock`]的写者在发布之前和切换位置之后调用，都满足这一点。没有写者锁时，
+    /// 一个写者可能在这里读取位置之后退休新的旧版本并切换位置：这个旧版本的读者登记在这里认为仍然有效的位置上，
+    /// 却会被一起释放。debug模式下会检查是否持有写者锁。
+    pub fn clean(&self) -> bool {
+        debug_assert!(
+            self.inner.am_writing.load(Ordering::Relaxed),
+            "ArcRcu::clean called without the writer lock"
+        );
+        let old = self.inner.current_borrow_count_index.load(Ordering::SeqCst) ^ 1;
+        if self.inner.borrowers(old) > 0 {
+            return false;
         }
+        self.inner.oldest_retired.store(usize::MAX, Ordering::SeqCst);
+        let freed = self.inner.free_retired(self.inner.retired.take_all());
+        self.inner.pending.fetch_sub(freed, Ordering::Release);
+        true
+    }
+    /// 被替换下来、还没有被[`ArcRcu::clean`]释放的旧版本数
+    pub fn pending(&self) -> usize {
+        self.inner.pending.load(Ordering::Acquire)
+    }
+    /// 最旧的尚未被[`ArcRcu::clean`]释放的旧版本落后当前版本多少个版本，没有旧版本时为0
+    pub fn lag(&self) -> usize {
+        match self.inner.oldest_retired.load(Ordering::SeqCst) {
+            usize::MAX => 0,
+            oldest => self.inner.version.get().saturating_sub(oldest),
+        }
+    }
+    /// 没有其他句柄、也没有等待回收的旧版本时，返回当前版本的可变引用，否则返回`None`。
+    ///
+    /// 读者守卫要么借用这个句柄，要么持有另一个句柄，因此`&mut self`唯一时没有读者。
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        let inner = Arc::get_mut(&mut self.inner)?;
+        if !inner.retired.head.get_mut().is_null() {
+            return None;
+        }
+        Some(unsafe { (**inner.current.get_mut()).value.assume_init_mut() })
     }
 }
 
-pub struct Guard<'a, T: Clone> {
-    list: Option<List<T>>,
+pub struct Guard<'a, T> {
+    /// 写者修改的新版本，发布之后为空
+    node: *mut Node<T>,
     rc_guts: &'a Inner<T>,
 }
-impl<'a, T: Clone> ops::Deref for Guard<'a, T> {
+impl<'a, T> ops::Deref for Guard<'a, T> {
     type Target = T;
     fn deref(&self) -> &T {
-        if let Some(ref list) = self.list {
-            unsafe { &*list.value.get() }
-        } else {
-            unreachable!()
-        }
+        assert!(!self.node.is_null());
+        unsafe { (*self.node).value.assume_init_ref() }
     }
 }
-impl<'a, T: Clone> ops::DerefMut for Guard<'a, T> {
+impl<'a, T> ops::DerefMut for Guard<'a, T> {
     fn deref_mut(&mut self) -> &mut T {
-        if let Some(ref list) = self.list {
-            unsafe { &mut *list.value.get() }
-        } else {
-            unreachable!()
+        assert!(!self.node.is_null());
+        unsafe { (*self.node).value.assume_init_mut() }
+    }
+}
+impl<'a, T> Guard<'a, T> {
+    /// 发布新版本，但仍然持有写者锁，直到Guard被释放。
+    /// 发布之后就不能再通过这个Guard访问数据了。
+    /// 旧版本被挂到待释放链表上，等宽限期结束后由[`ArcRcu::clean`]释放。
+    pub fn publish(&mut self) {
+        let node = core::mem::replace(&mut self.node, null_mut());
+        if !node.is_null() {
+            let old = self.rc_guts.current.swap(node, Ordering::SeqCst);
+            // 和Inner::enter中的fence配对
+            fence(Ordering::SeqCst);
+            // 读者只访问value，不会访问next
+            self.rc_guts.retire(old);
+            self.rc_guts.version.inc();
         }
     }
 }
-impl<'a, T: Clone> Drop for Guard<'a, T> {
+
+impl<'a, T> Drop for Guard<'a, T> {
     fn drop(&mut self) {
-        let list = self.list.take();
-        self.rc_guts
-            .list
-            .next
-            .store(Box::into_raw(Box::new(list.unwrap())), Ordering::Release);
-        // self.rc_guts.am_writing.store(false, Ordering::Relaxed);
+        self.publish();
+        self.rc_guts.am_writing.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::ArcRcu;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
+
+    /// 像写者一样拿到写者锁再清理
+    fn clean<T>(x: &ArcRcu<T>) -> bool {
+        while x.inner.am_writing.swap(true, Ordering::Acquire) {
+            std::thread::yield_now();
+        }
+        let cleaned = x.clean();
+        x.inner.am_writing.store(false, Ordering::Release);
+        cleaned
+    }
+
+    /// 记录每个值被释放的次数
+    static DROPS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    struct Tracked(usize);
+    impl Tracked {
+        fn new() -> Self {
+            let mut drops = DROPS.lock().unwrap();
+            drops.push(0);
+            Tracked(drops.len() - 1)
+        }
+    }
+    impl Clone for Tracked {
+        fn clone(&self) -> Self {
+            Tracked::new()
+        }
+    }
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            DROPS.lock().unwrap()[self.0] += 1;
+        }
+    }
+
+    #[test]
+    fn test_every_version_dropped_once() {
+        let x = ArcRcu::new(Tracked::new());
+        let first = &*x;
+        for _ in 0..10 {
+            drop(x.try_update().unwrap());
+        }
+        // 旧版本在clean之前仍然完好
+        assert_eq!(first.0, 0);
+        assert!(DROPS.lock().unwrap().iter().all(|&n| n == 0));
+        assert!(clean(&x));
+        for _ in 0..5 {
+            drop(x.try_update().unwrap());
+        }
+        drop(x);
+        let drops = DROPS.lock().unwrap();
+        assert_eq!(drops.len(), 16);
+        assert!(drops.iter().all(|&n| n == 1), "{drops:?}");
+    }
+
+    #[test]
+    fn test_guard_drop_releases_writer() {
+        let x = ArcRcu::new(3);
+        let mut guard = x.try_update().unwrap();
+        *guard = 7;
+        assert!(x.try_update().is_none());
+        drop(guard);
+        assert_eq!(*x, 7);
+        let mut guard = x.try_update().unwrap();
+        *guard += 1;
+        drop(guard);
+        // 两个旧版本都还没有释放，最旧的落后两个版本
+        assert_eq!(x.lag(), 2);
+        assert!(clean(&x));
+        assert_eq!(x.lag(), 0);
+        assert_eq!(*x, 8);
+    }
+
+    #[test]
+    fn test_clean_waits_for_old_readers() {
+        let x = ArcRcu::new(Counted(0, Arc::new(Vec::from([AtomicUsize::new(0), AtomicUsize::new(0)]))));
+        let drops = x.1.clone();
+        // 读者在写者切换位置之前登记，可能引用旧版本
+        let (index, shard) = x.inner.enter();
+        let old = &*x;
+        let mut guard = x.try_update().unwrap();
+        guard.0 = 1;
+        drop(guard);
+        x.inner.current_borrow_count_index.fetch_xor(1, Ordering::AcqRel);
+        // 切换之后登记的读者不影响回收
+        let (new_index, new_shard) = x.inner.enter();
+        assert!(!clean(&x));
+        assert_eq!(x.pending(), 1);
+        assert_eq!(old.0, 0);
+        assert_eq!(drops[0].load(Ordering::Relaxed), 0);
+        x.inner.exit(index, shard);
+        assert!(clean(&x));
+        assert_eq!(x.pending(), 0);
+        assert_eq!(drops[0].load(Ordering::Relaxed), 1);
+        x.inner.exit(new_index, new_shard);
+        assert_eq!(x.0, 1);
+    }
+
+    /// 记录自己被释放的次数
+    #[derive(Clone)]
+    struct Counted(usize, Arc<Vec<AtomicUsize>>);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.1[self.0].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_retire_and_clean() {
+        const THREADS: usize = 4;
+        const PER_THREAD: usize = 500;
+        let total = THREADS * PER_THREAD;
+        let drops = Arc::new((0..=total).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+        let x = ArcRcu::new(Counted(total, drops.clone()));
+        let done = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for t in 0..THREADS {
+                let (x, drops, done) = (&x, &drops, &done);
+                s.spawn(move || {
+                    // 不经过写者锁，直接退休新建的节点
+                    for i in 0..PER_THREAD {
+                        let node = x.inner.node(Counted(t * PER_THREAD + i, drops.clone()));
+                        x.inner.retire(node);
+                    }
+                    done.fetch_add(1, Ordering::Release);
+                });
+            }
+            s.spawn(|| {
+                while done.load(Ordering::Acquire) < THREADS {
+                    clean(&x);
+                    std::thread::yield_now();
+                }
+            });
+        });
+        assert!(clean(&x));
+        assert_eq!(x.pending(), 0);
+        // 每个退休的节点恰好被释放一次，当前版本还没有被释放
+        for (i, count) in drops.iter().enumerate() {
+            let expected = if i == total { 0 } else { 1 };
+            assert_eq!(count.load(Ordering::Relaxed), expected, "value {i}");
+        }
+        drop(x);
+        assert_eq!(drops[total].load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "without the writer lock")]
+    fn test_clean_without_writer_lock_panics() {
+        let x = ArcRcu::new(0);
+        drop(x.try_update().unwrap());
+        x.clean();
     }
 }
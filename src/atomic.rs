@@ -0,0 +1,58 @@
+//! Small atomic helpers shared by the locks in this crate.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A counter with ordering-correct increments and decrements.
+///
+/// [`AtomicCounter::inc`] and [`AtomicCounter::dec`] use `AcqRel`, so everything done before a decrement
+/// happens-before anything done after an [`AtomicCounter::get`] (which uses `Acquire`) that observes the
+/// decremented value. This is what reference and reader counts need: once a waiter sees the count drop to
+/// zero, all the work of the former holders is visible to it.
+///
+/// # Example
+///
+/// ```
+/// use kernel_sync::atomic::AtomicCounter;
+///
+/// static READERS: AtomicCounter = AtomicCounter::new(0);
+///
+/// READERS.inc();
+/// assert_eq!(READERS.get(), 1);
+/// READERS.dec();
+/// assert_eq!(READERS.get(), 0);
+/// ```
+#[derive(Default)]
+pub struct AtomicCounter(AtomicUsize);
+
+impl AtomicCounter {
+    /// Creates a new counter starting at `value`.
+    #[inline(always)]
+    pub const fn new(value: usize) -> Self {
+        AtomicCounter(AtomicUsize::new(value))
+    }
+
+    /// Increments the counter, returning the previous value.
+    #[inline(always)]
+    pub fn inc(&self) -> usize {
+        self.0.fetch_add(1, Ordering::AcqRel)
+    }
+
+    /// Decrements the counter, returning the previous value.
+    #[inline(always)]
+    pub fn dec(&self) -> usize {
+        self.0.fetch_sub(1, Ordering::AcqRel)
+    }
+
+    /// Returns the current value.
+    #[inline(always)]
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+impl fmt::Debug for AtomicCounter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.get(), f)
+    }
+}
@@ -0,0 +1,105 @@
+//! A barrier that makes a fixed number of CPUs wait for each other, e.g. for all harts to reach the same point
+//! during SMP boot.
+//!
+//! The CPUs that arrive early spin on a generation counter that the last one to arrive bumps, calling
+//! [`LockAction::wait`] in between, like [`Condvar`](crate::condvar::Condvar) waiters do. Bumping the generation
+//! opens the barrier for the current round and starts the next one, so a [`Barrier`] can be reused right away.
+use crate::LockAction;
+use core::{
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A barrier that releases its waiters once `n` of them have called [`Barrier::wait`].
+///
+/// Everything a CPU did before its call to [`Barrier::wait`] is visible to every CPU of the same round after theirs
+/// returns.
+///
+/// The same `n` CPUs must take part in every round: a CPU that is not one of them and calls [`Barrier::wait`]
+/// while a round is being released may be counted in the wrong round.
+///
+/// # Example
+///
+/// ```
+/// use kernel_sync::Barrier;
+/// use std::sync::Arc;
+///
+/// let barrier = Arc::new(Barrier::new(4));
+/// let harts: Vec<_> = (0..4)
+///     .map(|_| {
+///         let barrier = barrier.clone();
+///         std::thread::spawn(move || barrier.wait().is_leader())
+///     })
+///     .collect();
+/// let leaders = harts.into_iter().map(|hart| hart.join().unwrap()).filter(|&leader| leader).count();
+/// assert_eq!(leaders, 1);
+/// ```
+pub struct Barrier<L: LockAction> {
+    _marker: PhantomData<L>,
+    /// How many CPUs have arrived in the current round.
+    arrived: AtomicUsize,
+    /// Bumped by the last CPU of every round, which releases the others.
+    generation: AtomicUsize,
+    n: usize,
+}
+
+// `L` is only used for its hooks
+unsafe impl<L: LockAction> Send for Barrier<L> {}
+unsafe impl<L: LockAction> Sync for Barrier<L> {}
+
+/// Returned by [`Barrier::wait`], tells whether the caller was the leader of its round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Returns `true` for exactly one CPU of every round: the last one to arrive.
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl<L: LockAction> Barrier<L> {
+    /// Creates a barrier for `n` CPUs. Like `std::sync::Barrier`, a barrier for 0 CPUs behaves like one for 1,
+    /// whose [`Barrier::wait`] never waits.
+    pub const fn new(n: usize) -> Self {
+        Barrier {
+            _marker: PhantomData,
+            arrived: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+            n: if n == 0 { 1 } else { n },
+        }
+    }
+
+    /// Waits until `n` CPUs, the caller included, have called this in the current round.
+    ///
+    /// The last CPU to arrive doesn't wait. It becomes the leader of the round, resets the barrier for the next one
+    /// and releases the others.
+    pub fn wait(&self) -> BarrierWaitResult {
+        // The generation can't change before we arrive, as the round isn't complete without us.
+        let generation = self.generation.load(Ordering::Relaxed);
+        if self.arrived.fetch_add(1, Ordering::AcqRel) + 1 == self.n {
+            // The reset is published by the generation bump, so no CPU of the next round can arrive before it.
+            self.arrived.store(0, Ordering::Relaxed);
+            self.generation.fetch_add(1, Ordering::Release);
+            L::notify();
+            BarrierWaitResult(true)
+        } else {
+            while self.generation.load(Ordering::Acquire) == generation {
+                L::wait();
+            }
+            BarrierWaitResult(false)
+        }
+    }
+
+    /// Returns how many CPUs the barrier waits for.
+    pub fn n(&self) -> usize {
+        self.n
+    }
+}
+
+impl<L: LockAction> fmt::Debug for Barrier<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Barrier").field("n", &self.n).finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,99 @@
+//! A `Cell`-like wrapper for `Copy` data shared between CPUs.
+//!
+//! [`LockCell`] hides a [`SpinMutex`] behind `get`/`set`/`update`, for small shared state such as counters and
+//! flags where holding a guard would only add noise. Every call locks, copies and unlocks, so no guard can be
+//! leaked or held across other locks.
+use crate::spin::SpinMutex;
+use crate::LockAction;
+use core::fmt;
+
+/// A `Copy` value behind a [`SpinMutex`], accessed by value.
+///
+/// # Example
+///
+/// ```
+/// use kernel_sync::{cell::LockCell, EmptyLockAction};
+///
+/// static TICKS: LockCell<u64, EmptyLockAction> = LockCell::new(0);
+///
+/// TICKS.set(10);
+/// assert_eq!(TICKS.update(|ticks| ticks + 1), 11);
+/// assert_eq!(TICKS.get(), 11);
+/// ```
+pub struct LockCell<T: Copy, L: LockAction> {
+    lock: SpinMutex<T, L>,
+}
+
+impl<T: Copy, L: LockAction> LockCell<T, L> {
+    /// Creates a new [`LockCell`] containing `value`.
+    #[inline(always)]
+    pub const fn new(value: T) -> Self {
+        LockCell {
+            lock: SpinMutex::new(value),
+        }
+    }
+
+    /// Returns a copy of the value.
+    #[inline(always)]
+    pub fn get(&self) -> T {
+        *self.lock.lock()
+    }
+
+    /// Stores `value`.
+    #[inline(always)]
+    pub fn set(&self, value: T) {
+        *self.lock.lock() = value;
+    }
+
+    /// Stores `value` and returns the previous value.
+    #[inline(always)]
+    pub fn replace(&self, value: T) -> T {
+        core::mem::replace(&mut *self.lock.lock(), value)
+    }
+
+    /// Replaces the value with `f` applied to it and returns the new value.
+    ///
+    /// The lock is held while `f` runs, so no other update can slip in between reading and storing. `f` should
+    /// therefore be short and must not access this cell.
+    #[inline(always)]
+    pub fn update(&self, f: impl FnOnce(T) -> T) -> T {
+        let mut guard = self.lock.lock();
+        *guard = f(*guard);
+        *guard
+    }
+
+    /// Returns a mutable reference to the value.
+    ///
+    /// Since this call borrows the [`LockCell`] mutably, no locking needs to take place.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.lock.get_mut()
+    }
+
+    /// Consumes this [`LockCell`] and returns the value.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.lock.into_inner()
+    }
+}
+
+impl<T: Copy + fmt::Debug, L: LockAction> fmt::Debug for LockCell<T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.lock.try_lock() {
+            Some(guard) => f.debug_struct("LockCell").field("value", &*guard).finish(),
+            None => write!(f, "LockCell {{ <locked> }}"),
+        }
+    }
+}
+
+impl<T: Copy + Default, L: LockAction> Default for LockCell<T, L> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Copy, L: LockAction> From<T> for LockCell<T, L> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
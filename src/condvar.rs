@@ -0,0 +1,117 @@
+//! A condition variable for [`SpinMutex`].
+//!
+//! Without a scheduler to put a waiter to sleep, [`Condvar::wait`] spins on a generation counter that every
+//! notification bumps, calling [`LockAction::wait`] in between, so a port that stops the CPU there until
+//! [`LockAction::notify`] gets the same power savings as with its locks.
+use crate::{poison::PoisonPolicy, spin::SpinMutexGuard, LockAction};
+use core::{
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A condition variable, to wait for a condition on the data of a [`SpinMutex`] that another CPU makes true.
+///
+/// Like with any condition variable, a waiter may wake up even though the condition doesn't hold, so it checks the
+/// condition again in a loop, or uses [`Condvar::wait_while`]. A [`Condvar`] should only be used with one mutex
+/// at a time.
+///
+/// # Example
+///
+/// ```
+/// use kernel_sync::{Condvar, SpinMutex};
+/// use std::sync::Arc;
+///
+/// let pair = Arc::new((SpinMutex::new(false), Condvar::new()));
+/// let pair2 = pair.clone();
+/// std::thread::spawn(move || {
+///     let (ready, condvar) = &*pair2;
+///     *ready.lock() = true;
+///     condvar.notify_one();
+/// });
+///
+/// let (ready, condvar) = &*pair;
+/// let mut guard = ready.lock();
+/// while !*guard {
+///     guard = condvar.wait(guard);
+/// }
+/// ```
+///
+/// [`SpinMutex`]: crate::spin::SpinMutex
+pub struct Condvar<L: LockAction> {
+    _marker: PhantomData<L>,
+    /// Bumped by every notification.
+    generation: AtomicUsize,
+}
+
+// `L` is only used for its hooks
+unsafe impl<L: LockAction> Send for Condvar<L> {}
+unsafe impl<L: LockAction> Sync for Condvar<L> {}
+
+impl<L: LockAction> Condvar<L> {
+    /// Creates a new [`Condvar`] that no one waits on.
+    pub const fn new() -> Self {
+        Condvar {
+            _marker: PhantomData,
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Releases the mutex of `guard`, waits until this [`Condvar`] is notified and locks the mutex again.
+    ///
+    /// A notification sent after the caller last checked its condition, with the mutex held, is never missed, even
+    /// if it comes before this call has released the mutex.
+    pub fn wait<'a, T: ?Sized, P: PoisonPolicy>(
+        &self,
+        mut guard: SpinMutexGuard<'a, T, L, P>,
+    ) -> SpinMutexGuard<'a, T, L, P> {
+        // Sample the generation while still holding the mutex. A notifier that changed the condition under the
+        // mutex bumps it after this load, so the loop below can't miss that notification.
+        let generation = self.generation.load(Ordering::Relaxed);
+        guard.unlocked(|| {
+            while self.generation.load(Ordering::Acquire) == generation {
+                L::wait();
+            }
+        });
+        guard
+    }
+
+    /// Waits on this [`Condvar`] as long as `condition` returns `true` for the data of `guard`, see
+    /// [`Condvar::wait`].
+    pub fn wait_while<'a, T: ?Sized, P: PoisonPolicy>(
+        &self,
+        mut guard: SpinMutexGuard<'a, T, L, P>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> SpinMutexGuard<'a, T, L, P> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes up a waiter of this [`Condvar`].
+    ///
+    /// Waiters spin on the same counter, so this actually wakes all of them, which is allowed since any wakeup
+    /// may be spurious. Use it where one waiter is enough, in case a future version wakes only one.
+    pub fn notify_one(&self) {
+        self.notify_all();
+    }
+
+    /// Wakes up all waiters of this [`Condvar`].
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        L::notify();
+    }
+}
+
+impl<L: LockAction> Default for Condvar<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L: LockAction> fmt::Debug for Condvar<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Condvar").finish_non_exhaustive()
+    }
+}
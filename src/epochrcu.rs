@@ -0,0 +1,416 @@
+//! 基于全局epoch的RCU锁。接口和RcuLock相同，但回收旧版本的方式不同，多个锁可以共享同一个epoch域。
+
+use crate::{spin::SpinMutex, EmptyLockAction, LockAction, LockActionSendMarker};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+/// 一个epoch域中读者槽位的个数。
+///
+/// 这不是读者数的上限：`L::current_cpu`能给出CPU时，读者登记在自己CPU的槽位上，同一CPU上嵌套的读者共用它；
+/// 否则读者占用一个空闲槽位，没有空闲槽位时和其他读者共用一个。共用槽位只会让epoch更晚前进，读者从不等待。
+pub const EPOCH_SLOTS: usize = 64;
+
+/// 槽位的低位记录登记在上面的读者数，为0时槽位空闲；高位记录其中最早的读者登记时的epoch
+const COUNT_BITS: u32 = 16;
+const COUNT_MASK: usize = (1 << COUNT_BITS) - 1;
+/// 槽位中能记录的epoch的位
+const EPOCH_MASK: usize = usize::MAX >> COUNT_BITS;
+
+/// 一个等待回收的旧版本
+struct Retired {
+    /// 旧版本被替换下来时的全局epoch
+    epoch: usize,
+    ptr: *mut (),
+    drop: unsafe fn(*mut ()),
+}
+
+// Retired只在回收时被释放一次。只有`T: Send + 'static`的值会被登记，见[`EpochRcuLock::with_domain`]，
+// 因此由哪个线程、在锁被释放之后多久释放它都没有关系
+unsafe impl Send for Retired {}
+
+unsafe fn drop_box<T>(ptr: *mut ()) {
+    drop(Box::from_raw(ptr as *mut T));
+}
+
+/// epoch域：一个全局epoch计数、读者的槽位，以及等待回收的旧版本。
+///
+/// 读者进入时在一个槽位里登记当前的epoch（pin），离开时清空槽位。只有当所有登记的读者都处在当前epoch时，
+/// epoch才能前进。一个在epoch `e`被替换下来的旧版本，等全局epoch到达`e + 2`时，就不会再有读者引用它了，可以释放。
+///
+/// 和RcuLock的宽限期不同，写者不需要等待读者，回收被推迟到之后的写者（或[`EpochDomain::collect`]）进行，
+/// 这样多个互不相关的锁共享一个域时，开销不会随锁的数量增长。
+pub struct EpochDomain {
+    epoch: AtomicUsize,
+    slots: [AtomicUsize; EPOCH_SLOTS],
+    retired: SpinMutex<Vec<Retired>, EmptyLockAction>,
+}
+
+/// [`EpochRcuLock::new`]使用的默认epoch域
+pub static DEFAULT_DOMAIN: EpochDomain = EpochDomain::new();
+
+impl EpochDomain {
+    pub const fn new() -> Self {
+        EpochDomain {
+            epoch: AtomicUsize::new(0),
+            slots: [const { AtomicUsize::new(0) }; EPOCH_SLOTS],
+            retired: SpinMutex::new(Vec::new()),
+        }
+    }
+
+    /// 当前的全局epoch
+    pub fn epoch(&self) -> usize {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// 在当前CPU的槽位，或者一个空闲槽位上登记当前epoch，返回槽位下标
+    fn pin<L: LockAction>(&self) -> usize {
+        let home = L::current_cpu().map(|cpu| cpu % EPOCH_SLOTS);
+        loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let free = home.or_else(|| {
+                self.slots
+                    .iter()
+                    .position(|slot| slot.load(Ordering::Relaxed) & COUNT_MASK == 0)
+            });
+            // 没有空闲槽位，或者登记的读者数已满时，和其他读者共用一个槽位
+            for index in free.into_iter().chain(0..EPOCH_SLOTS) {
+                if self.join(index, epoch) {
+                    return index;
+                }
+            }
+            L::relax();
+        }
+    }
+
+    /// 在槽位上增加一个读者。槽位空闲时登记`epoch`，否则保留上面更早的epoch：
+    /// 读者登记之后才读取数据，读到的版本不会早于`epoch`被替换下来，更早的登记只会更保守地阻止epoch前进。
+    /// 槽位上的读者数已满时返回false
+    fn join(&self, index: usize, epoch: usize) -> bool {
+        let slot = &self.slots[index];
+        let mut value = slot.load(Ordering::Relaxed);
+        loop {
+            let new = match value & COUNT_MASK {
+                0 => ((epoch & EPOCH_MASK) << COUNT_BITS) | 1,
+                COUNT_MASK => return false,
+                _ => value + 1,
+            };
+            match slot.compare_exchange_weak(value, new, Ordering::SeqCst, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(actual) => value = actual,
+            }
+        }
+    }
+
+    fn unpin(&self, index: usize) {
+        self.slots[index].fetch_sub(1, Ordering::Release);
+    }
+
+    /// 尝试让epoch前进一步。只有所有登记的读者都处在当前epoch时才会成功。
+    fn try_advance(&self) -> usize {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        let all_current = self.slots.iter().all(|slot| {
+            let value = slot.load(Ordering::SeqCst);
+            value & COUNT_MASK == 0 || value >> COUNT_BITS == epoch & EPOCH_MASK
+        });
+        if all_current {
+            // 失败说明别人已经让epoch前进了
+            let _ = self.epoch.compare_exchange(
+                epoch,
+                epoch.wrapping_add(1),
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            );
+        }
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// 登记一个被替换下来的旧版本，等之后回收。它可能在任意线程上、在它所属的锁被释放之后才被释放
+    fn retire<T: Send + 'static>(&self, ptr: *mut T) {
+        let retired = Retired {
+            epoch: self.epoch.load(Ordering::SeqCst),
+            ptr: ptr as *mut (),
+            drop: drop_box::<T>,
+        };
+        self.retired.lock().push(retired);
+    }
+
+    /// 尝试让epoch前进，并释放所有已经不会再被读者引用的旧版本。返回仍在等待回收的旧版本数。
+    ///
+    /// 写者释放写者锁时会自动调用。如果一段时间内没有写者，可以手动调用来尽早回收内存。
+    pub fn collect(&self) -> usize {
+        let epoch = self.try_advance();
+        let mut retired = self.retired.lock();
+        let (free, keep): (Vec<_>, Vec<_>) = retired
+            .drain(..)
+            .partition(|item| epoch.wrapping_sub(item.epoch) >= 2);
+        *retired = keep;
+        let pending = retired.len();
+        // 在锁外释放，T的析构可能比较慢
+        drop(retired);
+        for item in free {
+            unsafe { (item.drop)(item.ptr) };
+        }
+        pending
+    }
+}
+
+impl Default for EpochDomain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for EpochDomain {
+    fn drop(&mut self) {
+        // 有&mut self说明已经没有读者了
+        for item in self.retired.get_mut().drain(..) {
+            unsafe { (item.drop)(item.ptr) };
+        }
+    }
+}
+
+/// 使用epoch回收的RCU锁，接口和[`crate::rculock::RcuLock`]相同。
+///
+/// 读者只需登记epoch，不会克隆`T`，也不会分配内存。写者克隆当前版本进行修改，释放写者锁时发布新版本，
+/// 并把旧版本交给epoch域，推迟到没有读者引用它之后再释放。
+///
+/// epoch域由多个锁共享，旧版本可能在任意线程上、在锁被释放之后才被释放，因此`T`必须是`Send + 'static`的：
+///
+/// ```compile_fail
+/// let lock = kernel_sync::EpochRcuLock::new(std::rc::Rc::new(0));
+/// ```
+///
+/// ```compile_fail
+/// let name = String::from("a");
+/// let lock = kernel_sync::EpochRcuLock::new(&name);
+/// ```
+pub struct EpochRcuLock<T: Clone + Send + 'static, L: LockAction> {
+    phantom: PhantomData<L>,
+    domain: &'static EpochDomain,
+    data: AtomicPtr<T>,
+    am_writing: AtomicBool,
+    /// 每发布一个新版本加一
+    version: AtomicUsize,
+    /// 持有写者锁的CPU，没有时为`usize::MAX`。只在debug模式下用于检测递归写。
+    #[cfg(debug_assertions)]
+    writer_cpu: AtomicUsize,
+}
+
+unsafe impl<T: Clone + Send + Sync + 'static, L: LockAction> Send for EpochRcuLock<T, L> {}
+unsafe impl<T: Clone + Send + Sync + 'static, L: LockAction> Sync for EpochRcuLock<T, L> {}
+
+// 只有L允许时，守卫才能在别的线程上释放
+unsafe impl<T: Sync, L: LockActionSendMarker> Send for EpochRcuLockReadGuard<'_, T, L> {}
+unsafe impl<T: Sync, L: LockAction> Sync for EpochRcuLockReadGuard<'_, T, L> {}
+unsafe impl<T: Clone + Send + Sync + 'static, L: LockActionSendMarker> Send for EpochRcuLockWriteGuard<'_, T, L> {}
+unsafe impl<T: Clone + Send + Sync + 'static, L: LockAction> Sync for EpochRcuLockWriteGuard<'_, T, L> {}
+
+impl<T: Clone + Send + Debug + 'static, L: LockAction> Debug for EpochRcuLock<T, L> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EpochRcuLock")
+            .field("data", &*self.read())
+            .finish()
+    }
+}
+
+impl<T: Clone + Send + 'static, L: LockAction> EpochRcuLock<T, L> {
+    /// 在[`DEFAULT_DOMAIN`]中创建锁
+    pub fn new(data: T) -> Self {
+        Self::with_domain(data, &DEFAULT_DOMAIN)
+    }
+
+    /// 在给定的epoch域中创建锁
+    pub fn with_domain(data: T, domain: &'static EpochDomain) -> Self {
+        EpochRcuLock {
+            phantom: PhantomData,
+            domain,
+            data: AtomicPtr::new(Box::into_raw(Box::new(data))),
+            am_writing: AtomicBool::new(false),
+            version: AtomicUsize::new(0),
+            #[cfg(debug_assertions)]
+            writer_cpu: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    /// 获取读者锁。
+    pub fn read(&self) -> EpochRcuLockReadGuard<'_, T, L> {
+        let token = L::before_lock();
+        self.read_guard(token)
+    }
+
+    /// 尝试获取读者锁。只有在写者正在更新数据时才会失败。
+    pub fn try_read(&self) -> Option<EpochRcuLockReadGuard<'_, T, L>> {
+        let token = L::before_lock();
+        if self.am_writing.load(Ordering::Acquire) {
+            L::after_lock(token);
+            None
+        } else {
+            Some(self.read_guard(token))
+        }
+    }
+
+    /// 返回当前数据的版本号。每个写者发布新版本后，版本号加一。
+    pub fn version(&self) -> usize {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// 以类似seqlock的乐观方式读取：对当前版本调用`f`，如果期间有写者发布了新版本，就重新调用`f`，
+    /// 直到`f`在一个没有被并发更新的版本上完成为止。
+    ///
+    /// `f`可能被调用多次，因此必须没有副作用，并且可以安全地重试。
+    pub fn read_consistent<R, F: Fn(&T) -> R>(&self, f: F) -> R {
+        loop {
+            let version = self.version();
+            let guard = self.read();
+            let result = f(&guard);
+            drop(guard);
+            if self.version() == version {
+                return result;
+            }
+        }
+    }
+
+    /// 获取写者锁。同一时刻只能有一个写者。
+    ///
+    /// 在已经持有写者锁的情况下再次调用`write`会永远自旋。debug模式下，如果`L::current_cpu`能给出当前CPU，
+    /// 会检测到这种情况并panic。
+    pub fn write(&self) -> EpochRcuLockWriteGuard<'_, T, L> {
+        let token = L::before_lock();
+        while self.am_writing.swap(true, Ordering::Acquire) {
+            #[cfg(debug_assertions)]
+            if let Some(cpu) = L::current_cpu() {
+                if self.writer_cpu.load(Ordering::Relaxed) == cpu {
+                    panic!("EpochRcuLock::write called recursively on CPU {cpu} while it already holds the write lock");
+                }
+            }
+            L::relax();
+        }
+        self.write_guard(token)
+    }
+
+    pub fn try_write(&self) -> Option<EpochRcuLockWriteGuard<'_, T, L>> {
+        let token = L::before_lock();
+        if self.am_writing.swap(true, Ordering::Acquire) {
+            L::after_lock(token);
+            None
+        } else {
+            Some(self.write_guard(token))
+        }
+    }
+
+    /// 发布新值`new`，并返回被替换掉的旧值。
+    pub fn replace(&self, new: T) -> T {
+        let mut guard = self.write();
+        let old = core::mem::replace(&mut *guard, new);
+        drop(guard);
+        old
+    }
+
+    /// 登记epoch并构造读者的守卫，调用者已经执行了`L::before_lock`，`token`是它的返回值
+    fn read_guard(&self, token: L::Guard) -> EpochRcuLockReadGuard<'_, T, L> {
+        let slot = self.domain.pin::<L>();
+        // 登记之后读到的版本，在读者离开之前都不会被释放
+        let data = unsafe { &*self.data.load(Ordering::SeqCst) };
+        L::on_hold_start();
+        EpochRcuLockReadGuard {
+            phantom: PhantomData,
+            domain: self.domain,
+            slot,
+            token,
+            data,
+        }
+    }
+
+    /// 拿到写者锁之后，克隆当前版本并构造写者的守卫
+    fn write_guard(&self, token: L::Guard) -> EpochRcuLockWriteGuard<'_, T, L> {
+        #[cfg(debug_assertions)]
+        if let Some(cpu) = L::current_cpu() {
+            self.writer_cpu.store(cpu, Ordering::Relaxed);
+        }
+        // 只有写者会替换数据，因此当前版本不会被释放
+        let data = unsafe { (*self.data.load(Ordering::Acquire)).clone() };
+        L::on_hold_start();
+        EpochRcuLockWriteGuard {
+            phantom: PhantomData,
+            lock: self,
+            token,
+            data: Some(data),
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static, L: LockAction> Drop for EpochRcuLock<T, L> {
+    fn drop(&mut self) {
+        // 有&mut self说明已经没有读者了，当前版本可以直接释放
+        drop(unsafe { Box::from_raw(*self.data.get_mut()) });
+    }
+}
+
+pub struct EpochRcuLockReadGuard<'a, T, L: LockAction> {
+    /// 指针使守卫默认不是Send，见下面的impl
+    phantom: PhantomData<(L, *const ())>,
+    domain: &'a EpochDomain,
+    slot: usize,
+    /// `L::before_lock`返回的令牌，释放时交给`L::after_lock`
+    token: L::Guard,
+    data: &'a T,
+}
+
+impl<'a, T, L: LockAction> Deref for EpochRcuLockReadGuard<'a, T, L> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T, L: LockAction> Drop for EpochRcuLockReadGuard<'a, T, L> {
+    fn drop(&mut self) {
+        L::on_hold_end();
+        self.domain.unpin(self.slot);
+        L::after_lock(core::mem::take(&mut self.token));
+    }
+}
+
+pub struct EpochRcuLockWriteGuard<'a, T: Clone + Send + 'static, L: LockAction> {
+    phantom: PhantomData<*const ()>,
+    lock: &'a EpochRcuLock<T, L>,
+    /// `L::before_lock`返回的令牌，释放时交给`L::after_lock`
+    token: L::Guard,
+    /// 写者修改的新版本，释放写者锁时发布
+    data: Option<T>,
+}
+
+impl<'a, T: Clone + Send + 'static, L: LockAction> Deref for EpochRcuLockWriteGuard<'a, T, L> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: Clone + Send + 'static, L: LockAction> DerefMut for EpochRcuLockWriteGuard<'a, T, L> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.data.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: Clone + Send + 'static, L: LockAction> Drop for EpochRcuLockWriteGuard<'a, T, L> {
+    fn drop(&mut self) {
+        L::on_hold_end();
+        // 发布新版本，旧版本交给epoch域推迟回收
+        let new = Box::into_raw(Box::new(self.data.take().unwrap()));
+        let old = self.lock.data.swap(new, Ordering::SeqCst);
+        self.lock.domain.retire(old);
+        self.lock.version.fetch_add(1, Ordering::Release);
+        #[cfg(debug_assertions)]
+        self.lock.writer_cpu.store(usize::MAX, Ordering::Relaxed);
+        self.lock.am_writing.store(false, Ordering::Release);
+        self.lock.domain.collect();
+        L::after_lock(core::mem::take(&mut self.token));
+    }
+}
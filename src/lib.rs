@@ -1,35 +1,448 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+pub mod atomic;
+pub mod barrier;
+pub mod cell;
+pub mod condvar;
+#[cfg(feature = "lockapi")]
+pub mod lockapi;
+pub mod mcs;
+pub mod once;
+pub mod poison;
+pub mod reentrant;
 pub mod rwlock;
 
 mod arcrcu;
 pub mod rculock;
+pub mod epochrcu;
+pub mod seqlock;
 pub mod ticket;
 pub mod spin;
+pub mod striped;
+pub mod smp;
 
+use core::sync::atomic::{AtomicPtr, Ordering};
 
+/// Asserts in debug builds that a lock is not held, e.g. in teardown or re-initialization code that relies on it.
+///
+/// Works with any lock that has an `is_locked` method: [`spin::SpinMutex`], [`ticket::TicketMutex`] and
+/// [`rwlock::RwLock`], for which readers count as holding the lock. Like `debug_assert!`, nothing is checked in
+/// release builds. An optional message with format arguments can follow the lock.
+///
+/// ```
+/// let lock = kernel_sync::SpinMutex::new(0);
+/// kernel_sync::assert_unlocked!(lock);
+/// let guard = lock.lock();
+/// drop(guard);
+/// kernel_sync::assert_unlocked!(lock, "lock leaked during {}", "init");
+/// ```
+#[macro_export]
+macro_rules! assert_unlocked {
+    ($lock:expr $(,)?) => {
+        debug_assert!(
+            !$lock.is_locked(),
+            "assert_unlocked!({}) failed: the lock is held",
+            stringify!($lock)
+        )
+    };
+    ($lock:expr, $($arg:tt)+) => {
+        debug_assert!(!$lock.is_locked(), $($arg)+)
+    };
+}
+
+/// The [`LockAction`] used by the top-level type aliases.
+///
+/// This is [`EmptyLockAction`] unless the `critical-section` feature is enabled, in which case it is
+/// `CriticalSectionLockAction`, or the `action-yield` feature is, in which case it is [`YieldLockAction`].
+#[cfg(not(any(feature = "action-yield", feature = "critical-section")))]
+pub type DefaultLockAction = EmptyLockAction;
+/// The [`LockAction`] used by the top-level type aliases.
+///
+/// This is [`EmptyLockAction`] unless the `critical-section` feature is enabled, in which case it is
+/// `CriticalSectionLockAction`, or the `action-yield` feature is, in which case it is [`YieldLockAction`].
+#[cfg(all(feature = "action-yield", not(feature = "critical-section")))]
+pub type DefaultLockAction = YieldLockAction;
+/// The [`LockAction`] used by the top-level type aliases.
+///
+/// This is [`EmptyLockAction`] unless the `critical-section` feature is enabled, in which case it is
+/// [`CriticalSectionLockAction`], or the `action-yield` feature is, in which case it is [`YieldLockAction`].
+#[cfg(feature = "critical-section")]
+pub type DefaultLockAction = CriticalSectionLockAction;
 
-pub type TicketMutex<T> = ticket::TicketMutex<T,EmptyLockAction>;
-pub type TicketMutexGuard<'a, T> = ticket::TicketMutexGuard<'a, T,EmptyLockAction>;
-pub type SpinMutex<T> = spin::SpinMutex<T,EmptyLockAction>;
-pub type SpinMutexGuard<'a, T> = spin::SpinMutexGuard<'a, T,EmptyLockAction>;
-pub type RwLock<T> = rwlock::RwLock<T,EmptyLockAction>;
-pub type RwLockReadGuard<'a, T> = rwlock::RwLockReadGuard<'a, T,EmptyLockAction>;
-pub type RwLockWriteGuard<'a, T> = rwlock::RwLockWriteGuard<'a, T,EmptyLockAction>;
-pub type RwLockUpgradableGuard<'a, T> = rwlock::RwLockUpgradableGuard<'a, T,EmptyLockAction>;
-pub type RcuLock<T> = rculock::RcuLock<T, EmptyLockAction>;
-pub type RcuLockReadGuard<'a, T> = rculock::RcuLockReadGuard<'a, T, EmptyLockAction>;
-pub type RcuLockWriteGuard<'a, T> = rculock::RcuLockWriteGuard<'a, T, EmptyLockAction>;
+pub type TicketMutex<T> = ticket::TicketMutex<T,DefaultLockAction>;
+pub type TicketMutexGuard<'a, T> = ticket::TicketMutexGuard<'a, T,DefaultLockAction>;
+/// The general-purpose mutex, for when there is no reason to pick a specific lock.
+///
+/// This is a [`TicketMutex`]: waiters are served in the order they arrived, so no CPU can be starved under
+/// contention, at the cost of a slightly slower uncontended path than [`SpinMutex`]. Like the other top-level
+/// aliases it uses [`DefaultLockAction`].
+///
+/// ```
+/// let lock = kernel_sync::Mutex::new(0);
+/// *lock.lock() += 1;
+/// let guard: kernel_sync::MutexGuard<_> = lock.lock();
+/// assert_eq!(*guard, 1);
+/// ```
+pub type Mutex<T> = TicketMutex<T>;
+pub type MappedTicketMutexGuard<'a, T> = ticket::MappedTicketMutexGuard<'a, T, DefaultLockAction>;
+pub type MutexGuard<'a, T> = TicketMutexGuard<'a, T>;
+pub type McsMutex<T> = mcs::McsMutex<T, DefaultLockAction>;
+pub type McsMutexGuard<'a, T> = mcs::McsMutexGuard<'a, T, DefaultLockAction>;
+pub type SpinMutex<T> = spin::SpinMutex<T,DefaultLockAction>;
+pub type SpinMutexGuard<'a, T> = spin::SpinMutexGuard<'a, T,DefaultLockAction>;
+pub type SpinRefGuard<'a, T> = spin::SpinRefGuard<'a, T,DefaultLockAction>;
+pub type MappedSpinMutexGuard<'a, T> = spin::MappedSpinMutexGuard<'a, T, DefaultLockAction>;
+pub type Condvar = condvar::Condvar<DefaultLockAction>;
+pub type Barrier = barrier::Barrier<DefaultLockAction>;
+pub type Once<T> = once::Once<T, DefaultLockAction>;
+pub type Lazy<T, F = fn() -> T> = once::Lazy<T, DefaultLockAction, F>;
+pub type LockCell<T> = cell::LockCell<T, DefaultLockAction>;
+pub type SeqLock<T> = seqlock::SeqLock<T, DefaultLockAction>;
+pub type RwLock<T> = rwlock::RwLock<T,DefaultLockAction>;
+/// A [`rwlock::RwLock`] that keeps new readers out while a writer waits.
+pub type FairRwLock<T> = rwlock::RwLock<T, DefaultLockAction, true>;
+pub type RwLockReadGuard<'a, T> = rwlock::RwLockReadGuard<'a, T,DefaultLockAction>;
+pub type RwLockWriteGuard<'a, T> = rwlock::RwLockWriteGuard<'a, T,DefaultLockAction>;
+pub type RwLockUpgradableGuard<'a, T> = rwlock::RwLockUpgradableGuard<'a, T,DefaultLockAction>;
+pub type RcuLock<T> = rculock::RcuLock<T, DefaultLockAction>;
+pub type RcuLockReadGuard<'a, T> = rculock::RcuLockReadGuard<'a, T, DefaultLockAction>;
+pub type RcuLockOwnedReadGuard<T> = rculock::RcuLockOwnedReadGuard<T, DefaultLockAction>;
+pub type RcuLockQuiescentReadGuard<'a, T> = rculock::RcuLockQuiescentReadGuard<'a, T, DefaultLockAction>;
+pub type RcuLockWriteGuard<'a, T> = rculock::RcuLockWriteGuard<'a, T, DefaultLockAction>;
+pub type EpochRcuLock<T> = epochrcu::EpochRcuLock<T, DefaultLockAction>;
+pub type EpochRcuLockReadGuard<'a, T> = epochrcu::EpochRcuLockReadGuard<'a, T, DefaultLockAction>;
+pub type EpochRcuLockWriteGuard<'a, T> = epochrcu::EpochRcuLockWriteGuard<'a, T, DefaultLockAction>;
 pub struct EmptyLockAction;
-impl LockAction for EmptyLockAction {}
+impl LockAction for EmptyLockAction {
+    type Guard = ();
+}
+impl LockActionSendMarker for EmptyLockAction {}
 
+/// A lock action that gives up the CPU while waiting, by calling the function passed to
+/// [`register_yield_now`]. Before anything is registered it behaves like [`EmptyLockAction`].
+pub struct YieldLockAction;
+impl LockAction for YieldLockAction {
+    type Guard = ();
+    fn relax() {
+        let f = YIELD_NOW.load(Ordering::Acquire);
+        if f.is_null() {
+            Self::on_spin();
+        } else {
+            // Safety: only `register_yield_now` stores into `YIELD_NOW`, and it always stores a `fn()`.
+            let f: fn() = unsafe { core::mem::transmute(f) };
+            f();
+        }
+    }
+}
 
+impl LockActionSendMarker for YieldLockAction {}
 
-/// A trait for lock action
-pub trait LockAction {
-    fn before_lock() {}
-    fn after_lock() {}
+static YIELD_NOW: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Register the function [`YieldLockAction`] calls while waiting for a lock, e.g. the scheduler's `yield_now`.
+pub fn register_yield_now(f: fn()) {
+    YIELD_NOW.store(f as *mut (), Ordering::Release);
+}
+
+/// A lock action that holds locks inside a critical section of the
+/// [`critical-section`](https://crates.io/crates/critical-section) crate, for platforms that already provide an
+/// implementation of it, e.g. one that disables interrupts.
+///
+/// The critical section is entered in [`LockAction::before_lock`] and left in [`LockAction::after_lock`]. Nested
+/// locks share the outermost critical section. Like any action that disables interrupts, its guards are not `Send`.
+///
+/// On hosted platforms, e.g. with the `std` implementation of `critical-section`, the critical section is a single
+/// global lock: while one thread holds any lock, every other thread waits in `before_lock`, so a thread must never
+/// wait for another one while it holds a lock.
+#[cfg(feature = "critical-section")]
+pub struct CriticalSectionLockAction;
+
+#[cfg(feature = "critical-section")]
+impl LockAction for CriticalSectionLockAction {
+    type Guard = ();
+    fn before_lock() {
+        // Safety: released in `after_lock`, which every lock calls exactly once per `before_lock`, in LIFO order.
+        let restore = unsafe { critical_section::acquire() };
+        // Safety: we are inside a critical section, so no other CPU can access the state.
+        let state = unsafe { &mut *CRITICAL_SECTION.0.get() };
+        if state.depth == 0 {
+            state.restore = restore;
+        } else {
+            // Safety: a nested critical section, released right away; the outermost one stays entered.
+            unsafe { critical_section::release(restore) };
+        }
+        state.depth += 1;
+    }
+    fn after_lock(_guard: ()) {
+        // Safety: `before_lock` entered the critical section, so no other CPU can access the state.
+        let state = unsafe { &mut *CRITICAL_SECTION.0.get() };
+        state.depth -= 1;
+        if state.depth == 0 {
+            // Safety: `restore` was returned by the outermost `acquire`, and every inner one has been released.
+            unsafe { critical_section::release(state.restore) };
+        }
+    }
 }
 
+/// The restore state of the outermost critical section entered by [`CriticalSectionLockAction`], and how many
+/// locks are inside it.
+#[cfg(feature = "critical-section")]
+struct CriticalSectionState {
+    restore: critical_section::RestoreState,
+    depth: usize,
+}
+
+/// Only accessed inside a critical section.
+#[cfg(feature = "critical-section")]
+struct CriticalSectionCell(core::cell::UnsafeCell<CriticalSectionState>);
+
+// Safety: see `CriticalSectionCell`.
+#[cfg(feature = "critical-section")]
+unsafe impl Sync for CriticalSectionCell {}
+
+#[cfg(feature = "critical-section")]
+static CRITICAL_SECTION: CriticalSectionCell = CriticalSectionCell(core::cell::UnsafeCell::new(CriticalSectionState {
+    restore: critical_section::RestoreState::invalid(),
+    depth: 0,
+}));
+
+/// Implemented by lock actions whose guards may be released on another thread than the one that locked.
+///
+/// A guard is only `Send` when its action implements this trait. Actions that pin the holder to its CPU, e.g.
+/// by disabling interrupts in [`LockAction::before_lock`] and restoring them in [`LockAction::after_lock`], must
+/// not implement it, so that a guard can't restore the interrupt state of the wrong CPU. A guard sent to another
+/// thread carries its [`LockAction::Guard`] token along, so the token has to be `Send`.
+///
+/// ```
+/// use kernel_sync::{spin::SpinMutex, EmptyLockAction};
+///
+/// fn assert_send<S: Send>(_: S) {}
+/// let lock = SpinMutex::<_, EmptyLockAction>::new(0);
+/// assert_send(lock.lock());
+/// ```
+///
+/// Every guard type of an action without the marker is `!Send`:
+///
+/// ```compile_fail
+/// # struct IrqAction;
+/// # impl kernel_sync::LockAction for IrqAction { type Guard = (); }
+/// # fn assert_send<S: Send>(_: S) {}
+/// let lock = kernel_sync::spin::SpinMutex::<_, IrqAction>::new(0);
+/// assert_send(lock.lock());
+/// ```
+///
+/// ```compile_fail
+/// # struct IrqAction;
+/// # impl kernel_sync::LockAction for IrqAction { type Guard = (); }
+/// # fn assert_send<S: Send>(_: S) {}
+/// let lock = kernel_sync::ticket::TicketMutex::<_, IrqAction>::new(0);
+/// assert_send(lock.lock());
+/// ```
+///
+/// ```compile_fail
+/// # struct IrqAction;
+/// # impl kernel_sync::LockAction for IrqAction { type Guard = (); }
+/// # fn assert_send<S: Send>(_: S) {}
+/// let lock = kernel_sync::rwlock::RwLock::<_, IrqAction>::new(0);
+/// assert_send(lock.read());
+/// ```
+///
+/// ```compile_fail
+/// # struct IrqAction;
+/// # impl kernel_sync::LockAction for IrqAction { type Guard = (); }
+/// # fn assert_send<S: Send>(_: S) {}
+/// let lock = kernel_sync::rwlock::RwLock::<_, IrqAction>::new(0);
+/// assert_send(lock.write());
+/// ```
+///
+/// ```compile_fail
+/// # struct IrqAction;
+/// # impl kernel_sync::LockAction for IrqAction { type Guard = (); }
+/// # fn assert_send<S: Send>(_: S) {}
+/// let lock = kernel_sync::rwlock::RwLock::<_, IrqAction>::new(0);
+/// assert_send(lock.upgradeable_read());
+/// ```
+///
+/// ```compile_fail
+/// # struct IrqAction;
+/// # impl kernel_sync::LockAction for IrqAction { type Guard = (); }
+/// # fn assert_send<S: Send>(_: S) {}
+/// let lock = kernel_sync::rculock::RcuLock::<_, IrqAction>::new(0);
+/// assert_send(lock.read());
+/// ```
+///
+/// ```compile_fail
+/// # struct IrqAction;
+/// # impl kernel_sync::LockAction for IrqAction { type Guard = (); }
+/// # fn assert_send<S: Send>(_: S) {}
+/// let lock = kernel_sync::rculock::RcuLock::<_, IrqAction>::new(0);
+/// assert_send(lock.write());
+/// ```
+///
+/// ```compile_fail
+/// # struct IrqAction;
+/// # impl kernel_sync::LockAction for IrqAction { type Guard = (); }
+/// # fn assert_send<S: Send>(_: S) {}
+/// let lock = kernel_sync::epochrcu::EpochRcuLock::<_, IrqAction>::new(0);
+/// assert_send(lock.read());
+/// ```
+///
+/// ```compile_fail
+/// # struct IrqAction;
+/// # impl kernel_sync::LockAction for IrqAction { type Guard = (); }
+/// # fn assert_send<S: Send>(_: S) {}
+/// let lock = kernel_sync::epochrcu::EpochRcuLock::<_, IrqAction>::new(0);
+/// assert_send(lock.write());
+/// ```
+pub trait LockActionSendMarker: LockAction<Guard: Send> {}
 
+/// A monotonic clock for timed lock operations such as [`ticket::TicketMutex::try_lock_for`], e.g. the `time`
+/// CSR on RISC-V.
+pub trait TimeSource {
+    /// How many ticks [`TimeSource::now`] advances per second.
+    const TICKS_PER_SEC: u64;
+    /// Returns the current time in ticks. It must never go backwards.
+    fn now() -> u64;
+    /// Converts a duration in microseconds to ticks, rounding down.
+    fn ticks_from_micros(micros: u64) -> u64 {
+        (micros as u128 * Self::TICKS_PER_SEC as u128 / 1_000_000) as u64
+    }
+}
+
+/// A trait for lock action
+///
+/// [`LockAction::before_lock`] returns a [`LockAction::Guard`] token, which the lock keeps in its guard and hands
+/// to the matching [`LockAction::after_lock`], e.g. whether interrupts were enabled before they were disabled.
+/// `examples/riscv.rs` saves and restores `sstatus.SIE` this way. Restoring the saved state is only right if the
+/// guards are released in reverse order of locking, which scoped guards are; an action that must cope with any
+/// order keeps a nesting depth per CPU instead, like `CriticalSectionLockAction`, and uses `()` as its token.
+///
+/// When an [`rwlock::RwLock`] guard turns into one of the other kind while the lock stays held, e.g. with
+/// [`rwlock::RwLockWriteGuard::downgrade`], its token is handed to [`LockAction::convert_to_read`] or
+/// [`LockAction::convert_to_write`] for the new guard. An upgradeable guard counts as a write hold.
+pub trait LockAction {
+    /// The state handed from a `before_*` hook to the matching `after_*` hook, `()` if there is none.
+    ///
+    /// The default value is used where a token is lost, e.g. by [`spin::SpinMutex::force_unlock`] for a forgotten
+    /// guard, so it should be the conservative state, e.g. "interrupts were disabled".
+    type Guard: Default;
+    /// How many times [`spin::SpinMutex::lock_optimistic`] retries, relaxing in between, before falling back to
+    /// the regular contended path.
+    const OPTIMISTIC_SPINS: usize = 4;
+    /// How many times [`rwlock::RwLock::read`] polls the lock word, without calling [`LockAction::relax`], when it
+    /// finds a writer holding the lock. Writes are usually short, so this saves the relax for the common case.
+    const OPTIMISTIC_READ_SPINS: usize = 32;
+    /// The ordering of the read-modify-writes that take a read, upgradeable or write hold of an
+    /// [`rwlock::RwLock`] using this action, e.g. `SeqCst` while chasing a suspected ordering bug. Must be
+    /// `Acquire`, `AcqRel` or `SeqCst`, see [`rwlock::RwLock#orderings`]. A single lock picks its own with
+    /// [`rwlock::RwLock::new_with_ordering`].
+    const RWLOCK_ACQUIRE: Ordering = Ordering::Acquire;
+    /// The ordering of the read-modify-writes that give a hold of an [`rwlock::RwLock`] up. Must be `Release`,
+    /// `AcqRel` or `SeqCst`.
+    const RWLOCK_RELEASE: Ordering = Ordering::Release;
+    /// Called before a lock is acquired, and before every attempt that may fail, e.g. `try_lock`.
+    fn before_lock() -> Self::Guard {
+        Self::Guard::default()
+    }
+    /// Called with the token of the matching [`LockAction::before_lock`] after a lock is released, or after an
+    /// attempt to acquire it failed.
+    fn after_lock(_guard: Self::Guard) {}
+    /// Called before a [`rwlock::RwLock`] is locked for reading.
+    fn before_read() -> Self::Guard {
+        Self::before_lock()
+    }
+    /// Called after a [`rwlock::RwLock`] read lock is released.
+    fn after_read(guard: Self::Guard) {
+        Self::after_lock(guard)
+    }
+    /// Called before a [`rwlock::RwLock`] is locked for writing or for an upgradeable read.
+    fn before_write() -> Self::Guard {
+        Self::before_lock()
+    }
+    /// Called after a [`rwlock::RwLock`] write or upgradeable lock is released.
+    fn after_write(guard: Self::Guard) {
+        Self::after_lock(guard)
+    }
+    /// Called with the token of a [`rwlock::RwLock`] write or upgradeable hold that becomes a read hold, before the
+    /// write hold ends. Returns the token for the read hold.
+    ///
+    /// The default ends the write hold with [`LockAction::after_write`] and starts the read hold with
+    /// [`LockAction::before_read`]. An action whose read and write hooks do the same, e.g. disable interrupts, can
+    /// return the token unchanged instead, so that interrupts stay disabled in between.
+    fn convert_to_read(guard: Self::Guard) -> Self::Guard {
+        Self::after_write(guard);
+        Self::before_read()
+    }
+    /// Called with the token of a [`rwlock::RwLock`] read hold that becomes a write hold, once the write hold is
+    /// taken. Returns the token for the write hold. The default is [`LockAction::after_read`] followed by
+    /// [`LockAction::before_write`], see [`LockAction::convert_to_read`].
+    fn convert_to_write(guard: Self::Guard) -> Self::Guard {
+        Self::after_read(guard);
+        Self::before_write()
+    }
+    /// Whether a holder stays on its CPU, and keeps it, until it releases the lock, e.g. because the action
+    /// disables interrupts and preemption.
+    ///
+    /// Only then does a CPU that waits for a lock it already holds itself wait forever, so the debug check of
+    /// [`rwlock::RwLock::write`] for a read guard held on the same CPU only panics for such actions. Otherwise
+    /// that guard may belong to a preempted task, or have been sent to another CPU, and the wait ends once it is
+    /// dropped. An action implementing [`LockActionSendMarker`] lets guards move, so it must leave this `false`.
+    const PINS_HOLDER: bool = false;
+    /// Returns the id of the CPU (hart) the caller runs on, or `None` if it is not known.
+    ///
+    /// Used for diagnostics such as detecting a CPU that waits for a lock it already holds.
+    fn current_cpu() -> Option<usize> {
+        None
+    }
+    /// Returns the NUMA node of the CPU the caller runs on. Without a topology every CPU is on node 0.
+    ///
+    /// See [`smp`] for how a kernel wires this and [`LockAction::current_cpu`] up.
+    fn current_node() -> usize {
+        0
+    }
+    /// Called on every iteration of a lock's wait loop.
+    fn relax() {
+        Self::on_spin()
+    }
+    /// Called every time a waiter busy-waits for one iteration: by the default [`LockAction::relax`], and
+    /// directly where a lock polls without relaxing, such as the optimistic spin of [`rwlock::RwLock::read`].
+    ///
+    /// Override it to count contention, e.g. in a perf counter, without changing how waiters relax.
+    fn on_spin() {
+        core::hint::spin_loop();
+    }
+    /// Called while a [`spin::SpinMutex`] or [`ticket::TicketMutex`] waits for the lock to be released, instead of
+    /// [`LockAction::relax`], and while a [`condvar::Condvar`] waits for a notification.
+    ///
+    /// A power-constrained port can stop the CPU here until another CPU signals an event, e.g. with `wfe` on ARM
+    /// or `wfi` on RISC-V, and send that event from [`LockAction::notify`]. Returning early is fine: the lock
+    /// checks its state again afterwards.
+    fn wait() {
+        Self::relax()
+    }
+    /// Called right after a [`spin::SpinMutex`] or [`ticket::TicketMutex`] is released or a [`condvar::Condvar`]
+    /// is notified, to wake the CPUs stopped in [`LockAction::wait`], e.g. with `sev` on ARM.
+    fn notify() {}
+    /// Called instead of [`LockAction::relax`] while a [`ticket::TicketMutex::lock`] waits for `ticket` to be
+    /// served. `lock` identifies the lock.
+    ///
+    /// An implementation may put the caller to sleep until [`LockAction::unpark`] is called with the same `lock`
+    /// and `ticket`. That call may come before `park` does, in which case `park` must return right away, and
+    /// `park` may also return spuriously: the lock checks its ticket again afterwards.
+    fn park(_lock: usize, _ticket: usize) {
+        Self::wait()
+    }
+    /// Called when `ticket` of the [`ticket::TicketMutex`] identified by `lock` is served, to wake the one waiter
+    /// parked on it.
+    fn unpark(_lock: usize, _ticket: usize) {}
+    /// Called right after a lock has been acquired, when the caller starts holding it.
+    ///
+    /// Unlike [`LockAction::before_lock`] this only runs once the lock is actually taken, so together with
+    /// [`LockAction::on_hold_end`] it brackets exactly the time the lock is held.
+    fn on_hold_start() {}
+    /// Called right before a held lock is released.
+    fn on_hold_end() {}
+}
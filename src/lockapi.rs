@@ -0,0 +1,160 @@
+//! Sending [`lock_api`] guards to other CPUs.
+//!
+//! The raw lock impls of [`SpinMutex`], [`TicketMutex`] and [`RwLock`] use [`lock_api::GuardNoSend`], so a
+//! `lock_api` guard stays on the CPU that locked, just like the native guards of an action that doesn't
+//! implement [`LockActionSendMarker`]:
+//!
+//! ```compile_fail
+//! # struct IrqAction;
+//! # impl kernel_sync::LockAction for IrqAction { type Guard = (); }
+//! # fn assert_send<S: Send>(_: S) {}
+//! let mutex = lock_api::Mutex::<kernel_sync::spin::SpinMutex<(), IrqAction>, _>::new(0);
+//! assert_send(mutex.lock());
+//! ```
+//!
+//! Wrap the raw lock in [`SendGuards`] to get guards that are `Send`, which only compiles for such actions:
+//!
+//! ```
+//! use kernel_sync::{lockapi::SendGuards, spin::SpinMutex, EmptyLockAction};
+//!
+//! type Mutex<T> = lock_api::Mutex<SendGuards<SpinMutex<(), EmptyLockAction>>, T>;
+//!
+//! let mutex = Mutex::new(0);
+//! let mut guard = mutex.lock();
+//! std::thread::scope(|s| {
+//!     s.spawn(move || *guard += 1);
+//! });
+//! assert_eq!(*mutex.lock(), 1);
+//! ```
+//!
+//! ```compile_fail
+//! # struct IrqAction;
+//! # impl kernel_sync::LockAction for IrqAction { type Guard = (); }
+//! use kernel_sync::{lockapi::SendGuards, spin::SpinMutex};
+//!
+//! let mutex = lock_api::Mutex::<SendGuards<SpinMutex<(), IrqAction>>, _>::new(0);
+//! ```
+use crate::{rwlock::RwLock, spin::SpinMutex, ticket::TicketMutex, LockAction, LockActionSendMarker};
+
+/// A raw lock of this crate, and the [`LockAction`] it runs.
+pub trait RawLockAction: private::Sealed {
+    type Action: LockAction;
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+impl<L: LockAction> private::Sealed for SpinMutex<(), L> {}
+impl<L: LockAction> RawLockAction for SpinMutex<(), L> {
+    type Action = L;
+}
+
+impl<L: LockAction> private::Sealed for TicketMutex<(), L> {}
+impl<L: LockAction> RawLockAction for TicketMutex<(), L> {
+    type Action = L;
+}
+
+impl<L: LockAction, const FAIR: bool> private::Sealed for RwLock<(), L, FAIR> {}
+impl<L: LockAction, const FAIR: bool> RawLockAction for RwLock<(), L, FAIR> {
+    type Action = L;
+}
+
+/// A raw lock whose [`lock_api`] guards are `Send`, for actions implementing [`LockActionSendMarker`].
+pub struct SendGuards<R>(R);
+
+unsafe impl<R: lock_api::RawMutex + RawLockAction> lock_api::RawMutex for SendGuards<R>
+where
+    R::Action: LockActionSendMarker,
+{
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = SendGuards(R::INIT);
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        self.0.lock()
+    }
+
+    fn try_lock(&self) -> bool {
+        self.0.try_lock()
+    }
+
+    unsafe fn unlock(&self) {
+        self.0.unlock()
+    }
+
+    fn is_locked(&self) -> bool {
+        self.0.is_locked()
+    }
+}
+
+unsafe impl<R: lock_api::RawRwLock + RawLockAction> lock_api::RawRwLock for SendGuards<R>
+where
+    R::Action: LockActionSendMarker,
+{
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = SendGuards(R::INIT);
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock_shared(&self) {
+        self.0.lock_shared()
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.0.try_lock_shared()
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.0.unlock_shared()
+    }
+
+    fn lock_exclusive(&self) {
+        self.0.lock_exclusive()
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.0.try_lock_exclusive()
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.0.unlock_exclusive()
+    }
+
+    fn is_locked(&self) -> bool {
+        self.0.is_locked()
+    }
+}
+
+unsafe impl<R: lock_api::RawRwLockUpgrade + RawLockAction> lock_api::RawRwLockUpgrade for SendGuards<R>
+where
+    R::Action: LockActionSendMarker,
+{
+    fn lock_upgradable(&self) {
+        self.0.lock_upgradable()
+    }
+
+    fn try_lock_upgradable(&self) -> bool {
+        self.0.try_lock_upgradable()
+    }
+
+    unsafe fn unlock_upgradable(&self) {
+        self.0.unlock_upgradable()
+    }
+
+    unsafe fn upgrade(&self) {
+        self.0.upgrade()
+    }
+
+    unsafe fn try_upgrade(&self) -> bool {
+        self.0.try_upgrade()
+    }
+}
+
+unsafe impl<R: lock_api::RawRwLockDowngrade + RawLockAction> lock_api::RawRwLockDowngrade for SendGuards<R>
+where
+    R::Action: LockActionSendMarker,
+{
+    unsafe fn downgrade(&self) {
+        self.0.downgrade()
+    }
+}
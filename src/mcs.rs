@@ -0,0 +1,349 @@
+//! An [MCS](https://dl.acm.org/doi/10.1145/103727.103729) queue lock.
+//!
+//! Like with a [`TicketMutex`](crate::ticket::TicketMutex), waiters are served in the order they arrived. But
+//! instead of all of them polling the same counter, every waiter brings its own queue node, usually on its
+//! stack, and spins on a flag in that node. The holder hands the lock over by clearing the flag of the next node
+//! only, so a release touches one remote cache line however many CPUs wait.
+use crate::{LockAction, LockActionSendMarker};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+/// A queue lock providing mutually exclusive access to data, where every waiter spins on its own [`McsNode`].
+///
+/// [`McsMutex::with_lock`] keeps the node and the guard on its own stack frame, so the node stays in place until
+/// the lock is handed over. [`McsMutex::lock`] and [`McsMutex::try_lock`] return the guard instead, which keeps
+/// the node borrowed, so a guard can't outlive its node:
+///
+/// ```compile_fail
+/// use kernel_sync::mcs::McsNode;
+///
+/// let lock = kernel_sync::McsMutex::new(0);
+/// let guard = {
+///     let mut node = McsNode::new();
+///     unsafe { lock.lock(&mut node) }
+/// };
+/// ```
+///
+/// The borrow ends when the guard is leaked with [`core::mem::forget`] though, while the lock still links to the
+/// node, and the next CPU to queue would write to it after it is gone. Lifetimes can't rule that out, so both are
+/// `unsafe` and the caller promises to drop the guard:
+///
+/// ```compile_fail,E0133
+/// use kernel_sync::mcs::McsNode;
+///
+/// let lock = kernel_sync::McsMutex::new(0);
+/// let mut node = McsNode::new();
+/// core::mem::forget(lock.lock(&mut node));
+/// ```
+///
+/// # Example
+///
+/// ```
+/// use kernel_sync::{mcs::McsNode, McsMutex};
+///
+/// static COUNTER: McsMutex<u32> = McsMutex::new(0);
+///
+/// COUNTER.with_lock(|counter| *counter += 1);
+/// let mut node = McsNode::new();
+/// // Safety: the guard is dropped at the end of the statement
+/// *unsafe { COUNTER.lock(&mut node) } += 1;
+/// assert_eq!(COUNTER.with_lock(|counter| *counter), 2);
+/// ```
+pub struct McsMutex<T: ?Sized, L: LockAction> {
+    /// The node of the last CPU in the queue, which may be the holder, or null when the lock is free.
+    tail: AtomicPtr<McsNode>,
+    _marker: PhantomData<L>,
+    data: UnsafeCell<T>,
+}
+
+/// The queue node of one CPU waiting for or holding an [`McsMutex`].
+///
+/// A node can be used for one lock at a time and reused once the guard is dropped.
+pub struct McsNode {
+    /// The node of the CPU queued right behind this one, linked in by that CPU.
+    next: AtomicPtr<McsNode>,
+    /// Set while the CPU owning the node waits for its predecessor to hand the lock over.
+    waiting: AtomicBool,
+}
+
+/// A guard that provides mutable data access.
+///
+/// When the guard is dropped, the lock is handed over to the next node in the queue.
+pub struct McsMutexGuard<'a, T: ?Sized + 'a, L: LockAction> {
+    lock: &'a McsMutex<T, L>,
+    node: &'a McsNode,
+    /// The token of `L::before_lock`, handed to `L::after_lock` when the lock is handed over.
+    token: L::Guard,
+    /// The pointer keeps the guard `!Send` unless `L` allows it, see the impls below.
+    _marker: PhantomData<(L, *const ())>,
+}
+
+unsafe impl<T: ?Sized + Send, L: LockAction> Sync for McsMutex<T, L> {}
+unsafe impl<T: ?Sized + Send, L: LockAction> Send for McsMutex<T, L> {}
+unsafe impl<T: ?Sized + Sync, L: LockAction> Sync for McsMutexGuard<'_, T, L> {}
+unsafe impl<T: ?Sized + Send, L: LockActionSendMarker> Send for McsMutexGuard<'_, T, L> {}
+
+impl McsNode {
+    /// Creates a node that is not in any queue.
+    pub const fn new() -> Self {
+        McsNode {
+            next: AtomicPtr::new(ptr::null_mut()),
+            waiting: AtomicBool::new(false),
+        }
+    }
+
+    #[inline(always)]
+    fn as_ptr(&self) -> *mut McsNode {
+        self as *const McsNode as *mut McsNode
+    }
+}
+
+impl Default for McsNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for McsNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("McsNode").finish_non_exhaustive()
+    }
+}
+
+impl<T, L: LockAction> McsMutex<T, L> {
+    /// Creates a new [`McsMutex`] wrapping the supplied data.
+    #[inline(always)]
+    pub const fn new(data: T) -> Self {
+        McsMutex {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            _marker: PhantomData,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this [`McsMutex`] and unwraps the underlying data.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized, L: LockAction> McsMutex<T, L> {
+    /// Locks the [`McsMutex`], queueing `node` behind the CPUs that are already waiting, and returns a guard that
+    /// permits access to the inner data.
+    ///
+    /// While waiting, the caller only polls `node`, calling `L::wait` in between. The guard keeps `node`
+    /// borrowed until it is dropped, as the next CPU in the queue links itself into it.
+    ///
+    /// # Safety
+    ///
+    /// The guard must be dropped, not leaked, e.g. with [`core::mem::forget`]: the lock keeps pointing to `node`
+    /// until the guard hands it over. Use [`McsMutex::with_lock`] to avoid the obligation.
+    ///
+    /// ```
+    /// use kernel_sync::mcs::McsNode;
+    ///
+    /// let lock = kernel_sync::McsMutex::new(0);
+    /// let mut node = McsNode::new();
+    /// // Safety: the guards are dropped at the end of each statement
+    /// *unsafe { lock.lock(&mut node) } += 1;
+    /// // The node can be reused once the guard is gone
+    /// assert_eq!(*unsafe { lock.lock(&mut node) }, 1);
+    /// ```
+    #[inline]
+    pub unsafe fn lock<'a>(&'a self, node: &'a mut McsNode) -> McsMutexGuard<'a, T, L> {
+        let token = L::before_lock();
+        let node: &'a McsNode = node;
+        node.next.store(ptr::null_mut(), Ordering::Relaxed);
+        node.waiting.store(true, Ordering::Relaxed);
+        // AcqRel: we see the data as the previous holder left it if it already released the lock, and the CPU that
+        // queues behind us sees our node initialized.
+        let prev = self.tail.swap(node.as_ptr(), Ordering::AcqRel);
+        if !prev.is_null() {
+            // Safety: the predecessor doesn't release its node before it has handed the lock over to us, which it
+            // can only do once it finds us linked in here.
+            unsafe { (*prev).next.store(node.as_ptr(), Ordering::Release) };
+            while node.waiting.load(Ordering::Acquire) {
+                L::wait();
+            }
+        }
+        L::on_hold_start();
+        McsMutexGuard {
+            lock: self,
+            node,
+            token,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Tries to lock the [`McsMutex`] with `node` without waiting. Returns `None` if it is held or other CPUs are
+    /// queued for it.
+    ///
+    /// # Safety
+    ///
+    /// As for [`McsMutex::lock`], a returned guard must be dropped, not leaked.
+    ///
+    /// ```
+    /// use kernel_sync::mcs::McsNode;
+    ///
+    /// let lock = kernel_sync::McsMutex::new(0);
+    /// let (mut a, mut b) = (McsNode::new(), McsNode::new());
+    /// // Safety: every guard is dropped
+    /// unsafe {
+    ///     let guard = lock.try_lock(&mut a).unwrap();
+    ///     assert!(lock.try_lock(&mut b).is_none());
+    ///     drop(guard);
+    ///     assert!(lock.try_lock(&mut b).is_some());
+    /// }
+    /// ```
+    #[inline]
+    pub unsafe fn try_lock<'a>(&'a self, node: &'a mut McsNode) -> Option<McsMutexGuard<'a, T, L>> {
+        let token = L::before_lock();
+        let node: &'a McsNode = node;
+        node.next.store(ptr::null_mut(), Ordering::Relaxed);
+        node.waiting.store(false, Ordering::Relaxed);
+        if self
+            .tail
+            .compare_exchange(ptr::null_mut(), node.as_ptr(), Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            L::on_hold_start();
+            Some(McsMutexGuard {
+                lock: self,
+                node,
+                token,
+                _marker: PhantomData,
+            })
+        } else {
+            L::after_lock(token);
+            None
+        }
+    }
+
+    /// Locks the [`McsMutex`] with a node on the caller's stack, runs `f` on the data and unlocks it again.
+    ///
+    /// ```
+    /// let lock = kernel_sync::McsMutex::new(vec![1]);
+    /// lock.with_lock(|data| data.push(2));
+    /// assert_eq!(lock.with_lock(|data| data.len()), 2);
+    /// ```
+    #[inline]
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut node = McsNode::new();
+        // Safety: the guard is dropped before this function returns, even if `f` panics
+        let mut guard = unsafe { self.lock(&mut node) };
+        f(&mut guard)
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the [`McsMutex`] mutably, and a mutable reference is guaranteed to be exclusive in
+    /// Rust, no actual locking needs to take place -- the mutable borrow statically guarantees no locks exist.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Returns `true` if the lock is currently held or CPUs are queued for it.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Ordering::Relaxed).is_null()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for McsMutex<T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut node = McsNode::new();
+        // Safety: the guard is dropped at the end of the match
+        let result = match unsafe { self.try_lock(&mut node) } {
+            Some(guard) => write!(f, "McsMutex {{ data: ")
+                .and_then(|()| (*guard).fmt(f))
+                .and_then(|()| write!(f, " }}")),
+            None => write!(f, "McsMutex {{ <locked> }}"),
+        };
+        result
+    }
+}
+
+impl<T: Default, L: LockAction> Default for McsMutex<T, L> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T, L: LockAction> From<T> for McsMutex<T, L> {
+    fn from(data: T) -> Self {
+        Self::new(data)
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> Drop for McsMutexGuard<'a, T, L> {
+    /// Hands the lock over to the next node in the queue, or frees it if the queue is empty.
+    fn drop(&mut self) {
+        L::on_hold_end();
+        let node = self.node.as_ptr();
+        let mut next = self.node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // Nobody seems to be queued: free the lock, unless a CPU has swapped itself into the tail meanwhile
+            if self
+                .lock
+                .tail
+                .compare_exchange(node, ptr::null_mut(), Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                L::after_lock(core::mem::take(&mut self.token));
+                return;
+            }
+            // It has, but not linked itself into our node yet
+            loop {
+                next = self.node.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                L::relax();
+            }
+        }
+        // Safety: the next CPU waits for this store before it may release its node. We don't touch it afterwards.
+        unsafe { (*next).waiting.store(false, Ordering::Release) };
+        L::notify();
+        L::after_lock(core::mem::take(&mut self.token));
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> Deref for McsMutexGuard<'a, T, L> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: We hold the lock
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> DerefMut for McsMutexGuard<'a, T, L> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: We hold the lock
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for McsMutexGuard<'a, T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display, L: LockAction> fmt::Display for McsMutexGuard<'a, T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,284 @@
+//! One-time initialization, e.g. of a device or a global table.
+//!
+//! [`Once`] runs an initializer exactly once, even if several CPUs ask for the value at the same time, and hands
+//! out shared references to the result from then on. CPUs that come while the initializer runs spin until it
+//! finishes. [`Lazy`] wraps a [`Once`] together with its initializer, for statics that are built on first use.
+use crate::LockAction;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ops::Deref,
+    sync::atomic::{AtomicU8, Ordering},
+};
+#[cfg(debug_assertions)]
+use core::sync::atomic::AtomicUsize;
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+const POISONED: u8 = 3;
+
+/// A value that is initialized once, by the first call to [`Once::call_once`].
+///
+/// The initializer runs between `L::before_lock` and `L::after_lock`, like the critical section of a lock, and
+/// so does the wait for it on other CPUs. If it panics, the [`Once`] is poisoned: the value will never be
+/// available and later calls to [`Once::call_once`] panic instead of waiting forever.
+///
+/// # Example
+///
+/// ```
+/// use kernel_sync::Once;
+///
+/// static DEVICE: Once<u32> = Once::new();
+///
+/// fn device() -> &'static u32 {
+///     DEVICE.call_once(|| 0x1000_0000)
+/// }
+///
+/// assert_eq!(DEVICE.get(), None);
+/// assert_eq!(*device(), 0x1000_0000);
+/// assert!(DEVICE.is_completed());
+/// ```
+pub struct Once<T, L: LockAction> {
+    _marker: PhantomData<L>,
+    state: AtomicU8,
+    /// The CPU running the initializer, or `usize::MAX`. Only tracked in debug builds to detect an initializer
+    /// that calls [`Once::call_once`] on its own [`Once`].
+    #[cfg(debug_assertions)]
+    init_cpu: AtomicUsize,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync, L: LockAction> Sync for Once<T, L> {}
+unsafe impl<T: Send, L: LockAction> Send for Once<T, L> {}
+
+impl<T, L: LockAction> Once<T, L> {
+    /// Creates a new [`Once`] whose value is not initialized yet.
+    #[inline(always)]
+    pub const fn new() -> Self {
+        Once {
+            _marker: PhantomData,
+            state: AtomicU8::new(INCOMPLETE),
+            #[cfg(debug_assertions)]
+            init_cpu: AtomicUsize::new(usize::MAX),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the value, initializing it with `f` if no one has done so yet.
+    ///
+    /// Only one caller runs its `f`; the others wait until it is done and return the same value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the [`Once`] is poisoned, because an initializer panicked. In debug builds, if `L::current_cpu`
+    /// knows the current CPU, it also panics if `f` calls `call_once` on the same [`Once`], which would wait for
+    /// itself forever.
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        let mut finish = Finish::<L> {
+            state: &self.state,
+            running: false,
+            token: L::before_lock(),
+            _marker: PhantomData,
+        };
+        loop {
+            match self
+                .state
+                .compare_exchange_weak(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    finish.running = true;
+                    self.track_init_cpu();
+                    let value = f();
+                    // Safety: only the caller that moved the state to `RUNNING` writes the value
+                    unsafe { (*self.data.get()).write(value) };
+                    #[cfg(debug_assertions)]
+                    self.init_cpu.store(usize::MAX, Ordering::Relaxed);
+                    finish.running = false;
+                    self.state.store(COMPLETE, Ordering::Release);
+                    L::notify();
+                    break;
+                }
+                Err(RUNNING) => {
+                    self.check_self_deadlock();
+                    while self.state.load(Ordering::Acquire) == RUNNING {
+                        L::wait();
+                    }
+                }
+                Err(COMPLETE) => break,
+                Err(POISONED) => panic!("Once instance has previously been poisoned"),
+                // Spurious failure
+                Err(_) => {}
+            }
+        }
+        drop(finish);
+        // Safety: the state is `COMPLETE`
+        unsafe { (*self.data.get()).assume_init_ref() }
+    }
+
+    /// Returns the value if it has been initialized.
+    #[inline(always)]
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            // Safety: the value was written before the state became `COMPLETE`, and is never written again
+            Some(unsafe { (*self.data.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if the value has been initialized.
+    #[inline(always)]
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Returns `true` if an initializer panicked, so that the value will never be initialized.
+    #[inline(always)]
+    pub fn is_poisoned(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == POISONED
+    }
+
+    /// Record the current CPU as running the initializer, in debug builds.
+    #[inline(always)]
+    fn track_init_cpu(&self) {
+        #[cfg(debug_assertions)]
+        if let Some(cpu) = L::current_cpu() {
+            self.init_cpu.store(cpu, Ordering::Relaxed);
+        }
+    }
+
+    /// Panic if the current CPU is the one running the initializer, in debug builds.
+    #[inline(always)]
+    fn check_self_deadlock(&self) {
+        #[cfg(debug_assertions)]
+        if let Some(cpu) = L::current_cpu() {
+            if self.init_cpu.load(Ordering::Relaxed) == cpu {
+                panic!("Once::call_once called on CPU {cpu} from its own initializer");
+            }
+        }
+    }
+}
+
+/// Ends [`Once::call_once`] after `L::before_lock`, also when it panics: runs `L::after_lock` and, if the
+/// initializer was running, poisons the [`Once`].
+struct Finish<'a, L: LockAction> {
+    state: &'a AtomicU8,
+    running: bool,
+    token: L::Guard,
+    _marker: PhantomData<L>,
+}
+
+impl<L: LockAction> Drop for Finish<'_, L> {
+    fn drop(&mut self) {
+        if self.running {
+            self.state.store(POISONED, Ordering::Release);
+        }
+        L::after_lock(core::mem::take(&mut self.token));
+    }
+}
+
+impl<T, L: LockAction> Drop for Once<T, L> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            // Safety: the value was initialized and is not used anymore
+            unsafe { self.data.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+impl<T, L: LockAction> Default for Once<T, L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: fmt::Debug, L: LockAction> fmt::Debug for Once<T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_struct("Once").field("data", value).finish(),
+            None => write!(f, "Once {{ <uninitialized> }}"),
+        }
+    }
+}
+
+/// A value that is initialized by `F` on first access, e.g. a global table that is expensive to build.
+///
+/// The initializer runs through a [`Once`], so exactly once even if several CPUs access the value at the same
+/// time, and a panicking initializer poisons the value for good.
+///
+/// # Example
+///
+/// ```
+/// use kernel_sync::Lazy;
+///
+/// static SQUARES: Lazy<Vec<u32>> = Lazy::new(|| (0..16).map(|i| i * i).collect());
+///
+/// assert_eq!(SQUARES[3], 9);
+/// ```
+pub struct Lazy<T, L: LockAction, F = fn() -> T> {
+    once: Once<T, L>,
+    /// The initializer, until [`Once::call_once`] takes it
+    init: UnsafeCell<Option<F>>,
+}
+
+// `init` is only accessed by the one caller that runs the initializer, which may be on any CPU
+unsafe impl<T: Send + Sync, L: LockAction, F: Send> Sync for Lazy<T, L, F> {}
+
+impl<T, L: LockAction, F: FnOnce() -> T> Lazy<T, L, F> {
+    /// Creates a new [`Lazy`] that initializes its value with `f`.
+    #[inline(always)]
+    pub const fn new(f: F) -> Self {
+        Lazy {
+            once: Once::new(),
+            init: UnsafeCell::new(Some(f)),
+        }
+    }
+
+    /// Returns the value, initializing it if no one has done so yet. The same as going through `Deref`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the initializer panicked, now or before.
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            // Safety: only the caller running the initializer gets here, and it only does so once
+            let init = unsafe { (*this.init.get()).take() };
+            init.expect("Lazy initializer taken twice")()
+        })
+    }
+
+    /// Returns the value if it has been initialized, without initializing it.
+    #[inline(always)]
+    pub fn get(this: &Self) -> Option<&T> {
+        this.once.get()
+    }
+}
+
+impl<T, L: LockAction, F: FnOnce() -> T> Deref for Lazy<T, L, F> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+impl<T: Default, L: LockAction> Default for Lazy<T, L> {
+    fn default() -> Self {
+        Self::new(T::default)
+    }
+}
+
+impl<T: fmt::Debug, L: LockAction, F> fmt::Debug for Lazy<T, L, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.once.get() {
+            Some(value) => f.debug_struct("Lazy").field("data", value).finish(),
+            None => write!(f, "Lazy {{ <uninitialized> }}"),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Opt-in lock poisoning.
+//!
+//! By default a guard dropped while unwinding from a panic releases its lock like any other guard, and the next
+//! owner gets no hint that the data may have been left half-updated. [`SpinMutex`] and [`RwLock`] take a
+//! [`PoisonPolicy`] as their last type parameter to change that: with [`Poison`], dropping a guard that grants
+//! write access during a panic marks the lock as poisoned, and every later `lock`, `read` or `write` returns a
+//! [`PoisonError`] that still carries the guard.
+//!
+//! Telling whether a guard is dropped during a panic needs `std::thread::panicking`, so [`Poison`] and the error
+//! types, which are the ones of `std::sync`, are only available with the `std` feature. The default, [`NoPoison`],
+//! keeps the infallible API.
+//!
+//! ```
+//! # #[cfg(feature = "std")] {
+//! use kernel_sync::{poison::Poison, spin::SpinMutex, EmptyLockAction};
+//!
+//! let lock = std::sync::Arc::new(SpinMutex::<_, EmptyLockAction, Poison>::new(0));
+//! let cloned = lock.clone();
+//! let _ = std::thread::spawn(move || {
+//!     let _guard = cloned.lock().unwrap();
+//!     panic!("failed halfway");
+//! })
+//! .join();
+//!
+//! assert!(lock.is_poisoned());
+//! let guard = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+//! assert_eq!(*guard, 0);
+//! # }
+//! ```
+//!
+//! [`SpinMutex`]: crate::spin::SpinMutex
+//! [`RwLock`]: crate::rwlock::RwLock
+#[cfg(feature = "std")]
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+pub use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+
+/// Whether a lock records that a guard was dropped during a panic, see the [module documentation](self).
+pub trait PoisonPolicy {
+    /// `true` if a guard dropped during a panic poisons the lock.
+    const POISON: bool;
+    /// Where a lock keeps its poison state: nothing for [`NoPoison`], so that it doesn't grow the lock.
+    #[doc(hidden)]
+    type Flag: private::Flag;
+}
+
+/// Never poison the lock. The locking methods return their guard directly.
+pub struct NoPoison;
+
+impl PoisonPolicy for NoPoison {
+    const POISON: bool = false;
+    type Flag = ();
+}
+
+/// Poison the lock when a guard that grants write access is dropped during a panic.
+#[cfg(feature = "std")]
+pub struct Poison;
+
+#[cfg(feature = "std")]
+impl PoisonPolicy for Poison {
+    const POISON: bool = true;
+    type Flag = AtomicBool;
+}
+
+mod private {
+    /// The storage of a poison state.
+    pub trait Flag {
+        const NEW: Self;
+        fn set(&self);
+        fn get(&self) -> bool;
+        fn clear(&self);
+    }
+
+    impl Flag for () {
+        const NEW: Self = ();
+        fn set(&self) {}
+        fn get(&self) -> bool {
+            false
+        }
+        fn clear(&self) {}
+    }
+
+    #[cfg(feature = "std")]
+    impl Flag for super::AtomicBool {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const NEW: Self = super::AtomicBool::new(false);
+        fn set(&self) {
+            self.store(true, super::Ordering::Relaxed);
+        }
+        fn get(&self) -> bool {
+            self.load(super::Ordering::Relaxed)
+        }
+        fn clear(&self) {
+            self.store(false, super::Ordering::Relaxed);
+        }
+    }
+}
+
+use private::Flag;
+
+/// The poison state of a lock with the policy `P`. Zero-sized unless `P` poisons.
+pub(crate) struct PoisonFlag<P: PoisonPolicy>(P::Flag);
+
+impl<P: PoisonPolicy> PoisonFlag<P> {
+    pub(crate) const fn new() -> Self {
+        PoisonFlag(P::Flag::NEW)
+    }
+
+    /// Called by a guard with write access before it releases the lock: poison it if `P` asks for it and the
+    /// current thread is panicking.
+    #[inline(always)]
+    pub(crate) fn on_release(&self) {
+        #[cfg(feature = "std")]
+        if P::POISON && std::thread::panicking() {
+            self.0.set();
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    pub(crate) fn get(&self) -> bool {
+        self.0.get()
+    }
+
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    pub(crate) fn clear(&self) {
+        self.0.clear();
+    }
+
+    /// Wrap a guard of the lock in an error if the lock is poisoned.
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    pub(crate) fn guard<G>(&self, guard: G) -> LockResult<G> {
+        if self.get() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
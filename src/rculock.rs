@@ -2,10 +2,10 @@
 
 use crate::{
     arcrcu::{ArcRcu, Guard},
-    LockAction,
+    LockAction, LockActionSendMarker,
 };
+use alloc::boxed::Box;
 use core::fmt::Debug;
-use core::mem::swap;
 use core::sync::atomic::Ordering;
 use core::{
     marker::PhantomData,
@@ -18,22 +18,39 @@ use core::{
 /// 使用长度为2的数组来记录引用计数。初始时，在位置0记录引用计数。写者每更新一次数据，就将记录引用计数的位置在0和1之间切换一次。
 /// 这样，更新后的读者就不会影响到这个写者的宽限期（grace peroid）了，其只需等待写者之前的读者完成，然后释放旧版本的数据即可。
 /// 最好在L中实现关中断，这样可以避免将某些更新后的读者划到写者的宽限期。
-
-pub struct RcuLock<T: Clone, L: LockAction> {
+///
+/// 用[`RcuLock::new_quiescent`]创建的锁还支持静止状态（quiescent state）模式的读者，即Linux经典RCU的做法：
+/// [`RcuLock::read_quiescent`]不写入任何共享的原子变量，每个CPU定期调用[`RcuLock::quiescent_state`]报告
+/// 自己不再持有之前的读者引用，写者回收旧版本之前等待所有CPU各报告一次。
+///
+/// 只有克隆当前版本的写入方法（[`RcuLock::write`]、[`RcuLock::try_write`]等）要求`T: Clone`。只读取、
+/// 或者用[`RcuLock::update_fold`]构造全新版本的用法不需要`T`可以克隆。
+pub struct RcuLock<T, L: LockAction> {
     phantom: PhantomData<L>,
     rcu: ArcRcu<T>,
 }
 
-impl<T: Clone + Debug, L: LockAction> Debug for RcuLock<T, L> {
+/// 打印当前版本的数据。读者从不等待写者，因此即使当前线程正持有写者锁也不会阻塞。
+impl<T: Debug, L: LockAction> Debug for RcuLock<T, L> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("RcuLock").field("rcu", &self.rcu).finish()
+        f.debug_struct("RcuLock").field("data", &&*self.read()).finish()
     }
 }
 
-unsafe impl<T: Clone + Send + Sync, L: LockAction> Send for RcuLock<T, L> {}
-unsafe impl<T: Clone + Send + Sync, L: LockAction> Sync for RcuLock<T, L> {}
+unsafe impl<T: Send + Sync, L: LockAction> Send for RcuLock<T, L> {}
+unsafe impl<T: Send + Sync, L: LockAction> Sync for RcuLock<T, L> {}
+
+// 只有L允许时，守卫才能在别的线程上释放
+unsafe impl<T: Send + Sync, L: LockActionSendMarker> Send for RcuLockReadGuard<'_, T, L> {}
+unsafe impl<T: Send + Sync, L: LockAction> Sync for RcuLockReadGuard<'_, T, L> {}
+unsafe impl<T: Send + Sync, L: LockActionSendMarker> Send for RcuLockOwnedReadGuard<T, L> {}
+unsafe impl<T: Send + Sync, L: LockAction> Sync for RcuLockOwnedReadGuard<T, L> {}
+// 静止状态由守卫所在的CPU报告，因此守卫永远不能移到别的CPU上
+unsafe impl<T: Send + Sync, L: LockAction> Sync for RcuLockQuiescentReadGuard<'_, T, L> {}
+unsafe impl<T: Send + Sync, L: LockActionSendMarker> Send for RcuLockWriteGuard<'_, T, L> {}
+unsafe impl<T: Send + Sync, L: LockAction> Sync for RcuLockWriteGuard<'_, T, L> {}
 
-impl<T: Clone, L: LockAction> Clone for RcuLock<T, L> {
+impl<T, L: LockAction> Clone for RcuLock<T, L> {
     fn clone(&self) -> Self {
         Self {
             phantom: PhantomData,
@@ -42,7 +59,7 @@ impl<T: Clone, L: LockAction> Clone for RcuLock<T, L> {
     }
 }
 
-impl<T: Clone, L: LockAction> RcuLock<T, L> {
+impl<T, L: LockAction> RcuLock<T, L> {
     pub fn new(data: T) -> Self {
         RcuLock {
             phantom: PhantomData,
@@ -50,87 +67,577 @@ impl<T: Clone, L: LockAction> RcuLock<T, L> {
         }
     }
 
-    pub fn read(&self) -> RcuLockReadGuard<T, L> {
-        L::before_lock();
-        let index = self
-            .rcu
-            .inner
-            .current_borrow_count_index
-            .load(Ordering::Acquire);
-        self.rcu.inner.borrow_count[index].fetch_add(1, Ordering::AcqRel);
-        // let count = self.rcu.inner.borrow_count[index].load(Ordering::Acquire);
-        // std::println!("read, index = {index}, count = {} -> {count}", count - 1);
-        RcuLockReadGuard {
+    /// 创建一个静止状态模式的锁，CPU编号为`0..cpus`。
+    ///
+    /// 除了[`RcuLock::new`]的所有功能，还可以通过[`RcuLock::read_quiescent`]读取。写者发布新版本之后，
+    /// 要等每个CPU都调用过一次[`RcuLock::quiescent_state`]才回收旧版本，因此空闲的CPU也必须定期报告，
+    /// 例如在时钟中断或进入空闲循环之前，否则写者会一直等待。
+    ///
+    /// 读者按`L::current_cpu`登记在自己所在的CPU上，因此`L::current_cpu`必须能给出`0..cpus`中的CPU。写者替自己所在的CPU
+    /// 报告静止状态；`L::current_cpu`不能给出当前CPU时，写者要等待每个CPU自己报告，因此只能在不属于这些CPU的线程上写入。
+    ///
+    /// ```
+    /// use kernel_sync::{rculock::RcuLock, LockAction};
+    ///
+    /// struct Cpu0;
+    /// impl LockAction for Cpu0 {
+    ///     type Guard = ();
+    ///     fn current_cpu() -> Option<usize> {
+    ///         Some(0)
+    ///     }
+    /// }
+    ///
+    /// let lock = RcuLock::<_, Cpu0>::new_quiescent(0, 1);
+    /// assert_eq!(*lock.read_quiescent(), 0);
+    /// // The guard above is gone, so the writer reports the quiescent state of CPU 0 itself
+    /// *lock.write() = 1;
+    /// assert_eq!(*lock.read_quiescent(), 1);
+    /// ```
+    pub fn new_quiescent(data: T, cpus: usize) -> Self {
+        RcuLock {
             phantom: PhantomData,
-            data: &*(self.rcu),
-            rcu: &self.rcu,
+            rcu: ArcRcu::new_quiescent(data, cpus),
+        }
+    }
+
+    /// 获取读者锁。读者直接借用当前版本的数据，不会克隆`T`，也不会分配内存。
+    pub fn read(&self) -> RcuLockReadGuard<'_, T, L> {
+        let token = L::before_lock();
+        self.read_guard(token)
+    }
+
+    /// 获取读者锁，返回的守卫持有锁的一个句柄，而不是借用`self`。
+    ///
+    /// 守卫的生命周期和`self`无关，可以是`'static`的，可以存放在长期存在的结构中。即使所有其他句柄都被释放了，
+    /// 守卫也能继续读取它登记时的版本。
+    ///
+    /// ```
+    /// let lock = kernel_sync::RcuLock::new(1);
+    /// let guard: kernel_sync::RcuLockOwnedReadGuard<i32> = lock.read_owned();
+    /// drop(lock);
+    /// assert_eq!(*guard, 1);
+    /// ```
+    pub fn read_owned(&self) -> RcuLockOwnedReadGuard<T, L> {
+        let token = L::before_lock();
+        L::on_hold_start();
+        let rcu = self.rcu.clone();
+        let (index, shard) = rcu.inner.enter();
+        RcuLockOwnedReadGuard {
+            phantom: PhantomData,
+            data: &*rcu as *const T,
+            reader_cpu: rcu.inner.track_reader(index, L::current_cpu()),
+            rcu,
             borrow_count_index: index,
+            borrow_count_shard: shard,
+            token,
+        }
+    }
+
+    /// 借用当前版本，和[`RcuLock::read`]相同。
+    ///
+    /// `RcuLock`没有实现`Deref<Target = T>`：`deref`返回的`&T`没有守卫登记引用计数，写者不会等待它，
+    /// 宽限期结束后引用的旧版本就会被释放。守卫在存活期间登记引用计数，因此`&*lock.current()`是安全的写法。
+    ///
+    /// ```compile_fail
+    /// let lock = kernel_sync::RcuLock::new(0);
+    /// let value: &i32 = &*lock;
+    /// ```
+    ///
+    /// ```
+    /// let lock = kernel_sync::RcuLock::new(0);
+    /// assert_eq!(*lock.current(), 0);
+    /// *lock.write() = 1;
+    /// assert_eq!(*lock.current(), 1);
+    /// ```
+    pub fn current(&self) -> RcuLockReadGuard<'_, T, L> {
+        self.read()
+    }
+
+    /// 获取静止状态模式的读者锁，只能用于[`RcuLock::new_quiescent`]创建的锁。
+    ///
+    /// 和[`RcuLock::read`]不同，读者不登记引用计数，不写入其他CPU会写入的内存，只在`L::current_cpu`给出的CPU自己的
+    /// 缓存行上记录守卫的个数。守卫的存活期间就是读者临界区：记录不为0时，这个CPU的静止状态不会被报告，
+    /// [`RcuLock::quiescent_state`]返回`false`，因此守卫保护的版本不会被回收。
+    ///
+    /// 持有守卫时，当前CPU不能写入这个锁：写者要等待当前CPU报告静止状态，而守卫在写者返回之前不会释放，
+    /// 因此写者会panic，而不是永远等待。在`L`中关中断或关抢占，就能保证守卫存活期间当前CPU不会切换到别的任务，
+    /// 否则同一个CPU上的其他任务也不能写入。
+    ///
+    /// # Panics
+    ///
+    /// 锁不是用[`RcuLock::new_quiescent`]创建的、创建时的CPU数为0，或者`L::current_cpu`没有给出锁的某个CPU时panic。
+    pub fn read_quiescent(&self) -> RcuLockQuiescentReadGuard<'_, T, L> {
+        let cpus = self.rcu.inner.quiescent.len();
+        assert!(cpus > 0, "RcuLock::read_quiescent called on a lock created without quiescent-state mode");
+        let token = L::before_lock();
+        let Some(cpu) = L::current_cpu().filter(|&cpu| cpu < cpus) else {
+            L::after_lock(token);
+            panic!("RcuLock::read_quiescent needs L::current_cpu to name one of the lock's {cpus} CPUs");
+        };
+        L::on_hold_start();
+        // 先登记再读取当前版本，见Inner::quiescent_state
+        self.rcu.inner.enter_quiescent(cpu);
+        RcuLockQuiescentReadGuard {
+            phantom: PhantomData,
+            data: &*self.rcu,
+            rcu: &self.rcu,
+            cpu,
+            token,
         }
     }
 
-    pub fn write(&self) -> RcuLockWriteGuard<T, L> {
-        L::before_lock();
+    /// 报告`cpu`经过了一个静止状态：它不再持有这之前通过[`RcuLock::read_quiescent`]拿到的守卫，返回是否报告了。
+    ///
+    /// `cpu`上还有没有释放的守卫时不报告，返回`false`，例如时钟中断打断了读者临界区时；之后再报告即可。
+    /// 应该在`cpu`上调用。它只访问这个CPU自己的缓存行，可以放在时钟中断、任务切换等经常执行的路径上。
+    ///
+    /// # Panics
+    ///
+    /// `cpu`不小于创建时的CPU数时panic。
+    pub fn quiescent_state(&self, cpu: usize) -> bool {
+        let cpus = self.rcu.inner.quiescent.len();
+        assert!(cpu < cpus, "CPU {cpu} reported a quiescent state, but the lock tracks {cpus} CPUs");
+        self.rcu.inner.quiescent_state(cpu)
+    }
+
+    /// 尝试获取读者锁。只有在写者正在更新数据时才会失败。
+    pub fn try_read(&self) -> Option<RcuLockReadGuard<'_, T, L>> {
+        Self::try_guard(
+            || (!self.rcu.inner.am_writing.load(Ordering::Acquire)).then_some(()),
+            |(), token| self.read_guard(token),
+        )
+    }
+
+    /// 返回当前数据的版本号。每个写者发布新版本后，版本号加一。
+    pub fn version(&self) -> usize {
+        self.rcu.inner.version.get()
+    }
+
+    /// 以类似seqlock的乐观方式读取：对当前版本调用`f`，如果期间有写者发布了新版本，就重新调用`f`，
+    /// 直到`f`在一个没有被并发更新的版本上完成为止。
+    ///
+    /// `f`可能被调用多次，因此必须没有副作用，并且可以安全地重试。
+    pub fn read_consistent<R, F: Fn(&T) -> R>(&self, f: F) -> R {
         loop {
-            match self.rcu.try_update() {
-                Some(guard) => {
-                    let index = self
-                        .rcu
-                        .inner
-                        .current_borrow_count_index
-                        .load(Ordering::Acquire);
-                    self.rcu.inner.borrow_count[index].fetch_add(1, Ordering::AcqRel);
-                    // let count = self.rcu.inner.borrow_count[index].load(Ordering::Acquire);
-                    // std::println!("write, index = {index}, count = {} -> {count}", count - 1);
-                    return RcuLockWriteGuard {
-                        phantom: PhantomData,
-                        data: Some(guard),
-                        rcu: &self.rcu,
-                        borrow_count_index: index,
-                    };
-                }
-                None => {
-                    core::hint::spin_loop();
+            let version = self.version();
+            let guard = self.read();
+            let result = f(&guard);
+            drop(guard);
+            if self.version() == version {
+                return result;
+            }
+        }
+    }
+
+    /// 获取写者锁。同一时刻只能有一个写者。
+    ///
+    /// 在已经持有写者锁的情况下再次调用`write`会永远自旋。debug模式下，如果`L::current_cpu`能给出当前CPU，
+    /// 会检测到这种情况并panic。
+    ///
+    /// 当前CPU可以在持有读者守卫时写入，例如通过锁的另一个句柄：如果`L::current_cpu`能给出当前CPU，
+    /// 写者释放时不会等待自己CPU上的读者，被替换的旧版本留给下一个写者回收。下一个写者会先等待这些读者离开，
+    /// 因此在这之前当前CPU不能再写一次。静止状态模式的读者守卫除外，见[`RcuLock::read_quiescent`]。
+    ///
+    /// 写者修改的是当前版本的克隆，因此要求`T: Clone`：
+    ///
+    /// ```compile_fail
+    /// struct Config(u32);
+    ///
+    /// let lock = kernel_sync::RcuLock::new(Config(0));
+    /// lock.write().0 = 1;
+    /// ```
+    pub fn write(&self) -> RcuLockWriteGuard<'_, T, L>
+    where
+        T: Clone,
+    {
+        self.write_with(T::clone)
+    }
+
+    /// 用`f`根据当前版本构造新版本并发布。
+    ///
+    /// 和通过[`RcuLock::write`]修改当前版本的克隆不同，`f`只拿到当前版本的引用，返回一个全新的值，
+    /// 不需要克隆。`f`在持有写者锁时运行，因此不会有其他写者的更新丢失。
+    ///
+    /// ```
+    /// let lock = kernel_sync::RcuLock::new(vec![1, 2]);
+    /// lock.update_fold(|old| old.iter().map(|x| x * 10).collect());
+    /// assert_eq!(*lock.read(), [10, 20]);
+    /// ```
+    pub fn update_fold<F: FnOnce(&T) -> T>(&self, f: F) {
+        drop(self.write_with(f));
+    }
+
+    /// 在当前版本的克隆上运行`f`并发布，返回`f`的结果。
+    ///
+    /// 返回时新版本已经发布、旧版本已经回收，因此之后的读取一定能看到`f`的修改。适合在更新中分配的编号之类的值，
+    /// 不需要再读一次。
+    ///
+    /// ```
+    /// let lock = kernel_sync::RcuLock::new(vec![1, 2]);
+    /// let id = lock.update_with(|ids| {
+    ///     ids.push(3);
+    ///     ids.len() - 1
+    /// });
+    /// assert_eq!(lock.read()[id], 3);
+    /// ```
+    pub fn update_with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R
+    where
+        T: Clone,
+    {
+        let mut guard = self.write();
+        let result = f(&mut guard);
+        drop(guard);
+        result
+    }
+
+    /// 获取写者锁，新版本由`f`根据当前版本构造
+    fn write_with<F: FnOnce(&T) -> T>(&self, mut f: F) -> RcuLockWriteGuard<'_, T, L> {
+        self.check_quiescent_writer();
+        let token = L::before_lock();
+        loop {
+            match self.rcu.try_update_with(f) {
+                Ok(guard) => return self.write_guard(guard, token),
+                Err(back) => {
+                    f = back;
+                    #[cfg(debug_assertions)]
+                    if let Some(cpu) = L::current_cpu() {
+                        if self.rcu.inner.writer_cpu.load(Ordering::Relaxed) == cpu {
+                            panic!("RcuLock::write called recursively on CPU {cpu} while it already holds the write lock");
+                        }
+                    }
+                    L::relax();
                 }
             }
         }
     }
 
-    pub fn try_write(&self) -> Option<RcuLockWriteGuard<T, L>> {
-        L::before_lock();
-        match self.rcu.try_update() {
-            Some(guard) => {
-                let index = self
-                    .rcu
-                    .inner
-                    .current_borrow_count_index
-                    .load(Ordering::Acquire);
-                self.rcu.inner.borrow_count[index].fetch_add(1, Ordering::AcqRel);
-                // let count = self.rcu.inner.borrow_count[index].load(Ordering::Acquire);
-                // std::println!("try_write, index = {index}, count = {} -> {count}", count - 1);
-                Some(RcuLockWriteGuard {
-                    phantom: PhantomData,
-                    data: Some(guard),
-                    rcu: &self.rcu,
-                    borrow_count_index: index,
-                })
+    /// 发布新值`new`，并返回被替换掉的旧值。
+    ///
+    /// 旧值在写者的宽限期结束、旧版本被回收之前就已经取出，因此调用者拿到的总是被替换前的完整数据。
+    pub fn replace(&self, new: T) -> T
+    where
+        T: Clone,
+    {
+        let mut guard = self.write();
+        let old = core::mem::replace(&mut *guard, new);
+        drop(guard);
+        old
+    }
+
+    /// 如果`new`和当前版本不同，就发布`new`，返回是否发布了新版本。
+    ///
+    /// 相同时不获取写者锁，也不会克隆、分配或等待宽限期，适合经常被设置成相同值的配置。比较基于读到的当前版本，
+    /// 和其他写者并发时，结果相当于在比较之后立即写入。
+    pub fn update_if_changed(&self, new: T) -> bool
+    where
+        T: Clone + PartialEq,
+    {
+        if *self.read() == new {
+            return false;
+        }
+        *self.write() = new;
+        true
+    }
+
+    /// 等待调用时已经存在的所有读者离开，类似Linux的`synchronize_rcu`。之后开始的读者不会被等待。
+    ///
+    /// 不发布新版本，也不修改数据，只像写者一样切换引用计数的位置，再等待切换前位置上的读者离开；
+    /// 静止状态模式下还要等待每个CPU报告一次静止状态。适合在自己替换了读者能看到的某个指针之后，
+    /// 释放旧指针引用的外部资源。
+    ///
+    /// 等待期间持有写者锁，因此其他写者要等它返回，[`RcuLock::try_read`]也会失败。当前CPU持有这个锁的读者守卫时
+    /// 调用会永远等待；debug模式下，如果`L::current_cpu`能给出当前CPU，会检测到这种情况并panic。
+    ///
+    /// ```
+    /// let lock = kernel_sync::RcuLock::new(0);
+    /// let reader = lock.read();
+    /// drop(reader);
+    /// // Returns at once: the only reader is gone
+    /// lock.synchronize();
+    /// assert_eq!(*lock.read(), 0);
+    /// ```
+    pub fn synchronize(&self) {
+        self.check_quiescent_writer();
+        let token = L::before_lock();
+        let inner = &self.rcu.inner;
+        while inner.am_writing.swap(true, Ordering::Acquire) {
+            L::relax();
+        }
+        // 和写者发布之前一样，先等上一个写者留下的旧版本的读者离开，两批读者才不会落在同一个位置上
+        while self.rcu.pending() > 0 && !self.rcu.clean() {
+            L::relax();
+        }
+        // 调用之前登记的读者都在切换前的位置上，之后登记的读者都在另一个位置上
+        let index = inner.current_borrow_count_index.fetch_xor(1, Ordering::SeqCst);
+        #[cfg(debug_assertions)]
+        if inner.only_reader_cpu(index, L::current_cpu()) {
+            inner.am_writing.store(false, Ordering::Release);
+            L::after_lock(token);
+            panic!("RcuLock::synchronize called on a CPU that holds a read guard of the same lock");
+        }
+        while inner.borrowers(index) > 0 {
+            L::relax();
+        }
+        Self::wait_quiescent(&self.rcu);
+        inner.am_writing.store(false, Ordering::Release);
+        L::after_lock(token);
+    }
+
+    /// 预先分配下一个版本使用的内存，返回是否分配了。
+    ///
+    /// 写者发布的新版本需要一块内存。每次写者回收旧版本时，会留下它的内存给下一个写者使用，因此只有第一次写入
+    /// （或者回收的内存被其他写者抢先用掉时）才需要分配。在初始化时调用，就能保证之后的写者不在写入路径上分配内存，
+    /// 适合分配器受限的内核。`T::clone`自身的分配不在此列。
+    ///
+    /// ```
+    /// let lock = kernel_sync::RcuLock::new(0);
+    /// assert!(lock.reserve());
+    /// assert!(!lock.reserve());
+    /// *lock.write() = 1;
+    /// ```
+    pub fn reserve(&self) -> bool {
+        self.rcu.inner.reserve()
+    }
+
+    /// 不经过写者直接修改当前版本，例如在单线程的初始化过程中。
+    ///
+    /// 只有这是锁唯一的句柄、并且没有等待回收的旧版本时才返回`Some`：这时不可能有读者，也不需要宽限期。
+    /// 锁被克隆过、克隆还存活时返回`None`，应该改用[`RcuLock::write`]。
+    ///
+    /// ```
+    /// let mut lock = kernel_sync::RcuLock::new(vec![1]);
+    /// lock.get_mut().unwrap().push(2);
+    /// assert_eq!(*lock.read(), [1, 2]);
+    /// ```
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.rcu.get_mut()
+    }
+
+    /// 已经被替换下来、还在等待宽限期结束的旧版本数。
+    ///
+    /// 写者在释放写者锁之前会等待宽限期并回收旧版本，因此只有在某个写者正在等待读者离开，
+    /// 或者写者持有读者守卫、把旧版本留给了下一个写者时（见[`RcuLock::write`]），这个值才不为0。
+    /// 关闭流程可以轮询它直到为0，再释放旧版本可能引用的外部资源。
+    pub fn pending_reclaims(&self) -> usize {
+        self.rcu.pending()
+    }
+
+    /// 最旧的尚未回收的版本落后当前版本多少个版本，没有待回收的版本时为0。写入频繁的子系统可以据此限制写入速度。
+    ///
+    /// 它由旧版本退休时的版本号得出。写者在发布之前会回收上一个写者留下的版本，释放写者锁之前会等待回收，
+    /// 所以通过这个锁写入时它至多为1：为1说明有写者正在等待长时间持有的读者离开，或者上一个写者把旧版本留给了下一个写者。
+    ///
+    /// ```
+    /// let lock = kernel_sync::RcuLock::new(0);
+    /// *lock.write() = 1;
+    /// assert_eq!(lock.reclamation_lag(), 0);
+    /// ```
+    pub fn reclamation_lag(&self) -> usize {
+        self.rcu.lag()
+    }
+
+    /// 登记一个观察者，之后每个写者发布新版本时都会用新版本调用它，例如在配置改变后重新设置硬件。
+    ///
+    /// 观察者由这个锁的所有句柄共享，可以登记多个，按登记顺序调用。写者在释放写者锁、回收旧版本之后，
+    /// 像读者一样借用当前版本来调用观察者，因此观察者可以读取这个锁，其他写者也不必等待观察者。
+    /// 如果在此之间又有写者发布了新版本，观察者看到的是更新的版本。
+    ///
+    /// 观察者不能写入这个锁：写者要等待包括观察者在内的所有读者离开，会永远等待。观察者也不能登记新的观察者。
+    /// 观察者持有的这个锁的句柄会形成引用环，锁永远不会被释放。
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let lock = kernel_sync::RcuLock::new(1);
+    /// let seen = Arc::new(AtomicUsize::new(0));
+    /// let observer = seen.clone();
+    /// lock.on_update(move |value| observer.store(*value, Ordering::Relaxed));
+    /// *lock.write() = 2;
+    /// assert_eq!(seen.load(Ordering::Relaxed), 2);
+    /// ```
+    pub fn on_update<F: Fn(&T) + Send + Sync + 'static>(&self, callback: F) {
+        self.rcu.inner.observers.lock().push(Box::new(callback));
+    }
+
+    pub fn try_write(&self) -> Option<RcuLockWriteGuard<'_, T, L>>
+    where
+        T: Clone,
+    {
+        self.check_quiescent_writer();
+        Self::try_guard(|| self.rcu.try_update(), |guard, token| self.write_guard(guard, token))
+    }
+}
+
+impl<T, L: LockAction> RcuLock<T, L> {
+    /// 静止状态模式下，当前CPU持有这个锁的读者守卫时panic：写者不能替当前CPU报告静止状态，会永远等待自己
+    fn check_quiescent_writer(&self) {
+        let inner = &self.rcu.inner;
+        if let Some(cpu) = L::current_cpu().filter(|&cpu| cpu < inner.quiescent.len()) {
+            if inner.quiescent_readers(cpu) > 0 {
+                panic!("RcuLock writer on CPU {cpu} would wait for a quiescent read guard held on the same CPU");
             }
+        }
+    }
+
+    /// 静止状态模式下，开始一个宽限期并等待它结束，否则什么也不做。
+    ///
+    /// 调用者所在的CPU没有读者守卫时替它报告静止状态，有时等待它自己报告。
+    fn wait_quiescent(rcu: &ArcRcu<T>) {
+        if rcu.inner.quiescent.is_empty() {
+            return;
+        }
+        let grace_period = rcu.inner.start_grace_period();
+        if let Some(cpu) = L::current_cpu().filter(|&cpu| cpu < rcu.inner.quiescent.len()) {
+            rcu.inner.quiescent_state(cpu);
+        }
+        while !rcu.inner.grace_period_over(grace_period) {
+            L::relax();
+        }
+    }
+
+    /// `try_read`和`try_write`共用的流程：调用`L::before_lock`之后尝试`attempt`，失败时调用`L::after_lock`并返回`None`，
+    /// 成功时才用`register`登记引用计数、构造守卫，令牌交给守卫，由守卫负责之后的`L::after_lock`
+    fn try_guard<A, G>(attempt: impl FnOnce() -> Option<A>, register: impl FnOnce(A, L::Guard) -> G) -> Option<G> {
+        let token = L::before_lock();
+        match attempt() {
+            Some(acquired) => Some(register(acquired, token)),
             None => {
-                L::after_lock();
+                L::after_lock(token);
                 None
             }
         }
     }
+
+    /// 登记引用计数并构造读者的守卫
+    fn read_guard(&self, token: L::Guard) -> RcuLockReadGuard<'_, T, L> {
+        RcuLockReadGuard::new(&self.rcu, token)
+    }
+
+    /// 拿到写者锁之后，登记引用计数并构造写者的守卫
+    fn write_guard<'a>(&'a self, guard: Guard<'a, T>, token: L::Guard) -> RcuLockWriteGuard<'a, T, L> {
+        #[cfg(debug_assertions)]
+        if let Some(cpu) = L::current_cpu() {
+            self.rcu.inner.writer_cpu.store(cpu, Ordering::Relaxed);
+        }
+        L::on_hold_start();
+        let (index, shard) = self.rcu.inner.enter();
+        RcuLockWriteGuard {
+            phantom: PhantomData,
+            data: Some(guard),
+            rcu: &self.rcu,
+            borrow_count_index: index,
+            borrow_count_shard: shard,
+            token,
+        }
+    }
 }
 
 /// 对读取RCU获得的结构的封装，目前这层封装是为了调用R的方法，以及维护引用计数
-pub struct RcuLockReadGuard<'a, T: Clone, L: LockAction> {
-    phantom: PhantomData<L>,
+///
+/// 每个Guard被释放时撤销一次登记。通过`ptr::read`之类的方式复制出来的Guard会被多释放一次，让引用计数下溢，
+/// 写者将永远等待宽限期结束；debug模式下这会panic，release模式下不检查。
+pub struct RcuLockReadGuard<'a, T, L: LockAction> {
+    /// 指针使守卫默认不是Send，见下面的impl
+    phantom: PhantomData<(L, *const ())>,
     data: &'a T,
+    /// 登记时记录的CPU位，见[`crate::arcrcu::Inner::track_reader`]
+    reader_cpu: usize,
     rcu: &'a ArcRcu<T>,
     borrow_count_index: usize,
+    borrow_count_shard: usize,
+    /// `L::before_lock`返回的令牌，释放时交给`L::after_lock`
+    token: L::Guard,
+}
+
+impl<'a, T, L: LockAction> RcuLockReadGuard<'a, T, L> {
+    /// 登记引用计数并构造`rcu`的读者守卫，调用者已经执行了`L::before_lock`，`token`是它的返回值
+    ///
+    /// 引用计数只在最后、紧接着构造守卫时才登记，这样登记的计数一定有守卫负责撤销。
+    fn new(rcu: &'a ArcRcu<T>, token: L::Guard) -> Self {
+        L::on_hold_start();
+        let (index, shard) = rcu.inner.enter();
+        RcuLockReadGuard {
+            phantom: PhantomData,
+            data: &**rcu,
+            reader_cpu: rcu.inner.track_reader(index, L::current_cpu()),
+            rcu,
+            borrow_count_index: index,
+            borrow_count_shard: shard,
+            token,
+        }
+    }
+}
+
+impl<'a, T, L: LockAction> Deref for RcuLockReadGuard<'a, T, L> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T, L: LockAction> Drop for RcuLockReadGuard<'a, T, L> {
+    fn drop(&mut self) {
+        L::on_hold_end();
+        self.rcu.inner.untrack_reader(self.borrow_count_index, self.reader_cpu);
+        self.rcu
+            .inner
+            .exit(self.borrow_count_index, self.borrow_count_shard);
+        L::after_lock(core::mem::take(&mut self.token));
+    }
+}
+
+/// [`RcuLock::read_owned`]返回的读者守卫，持有锁的一个句柄，因此不借用锁
+pub struct RcuLockOwnedReadGuard<T, L: LockAction> {
+    /// 指针使守卫默认不是Send，见上面的impl
+    phantom: PhantomData<(L, *const ())>,
+    /// 登记时的当前版本，在守卫释放前不会被回收
+    data: *const T,
+    /// 登记时记录的CPU位，见[`crate::arcrcu::Inner::track_reader`]
+    reader_cpu: usize,
+    rcu: ArcRcu<T>,
+    borrow_count_index: usize,
+    borrow_count_shard: usize,
+    /// `L::before_lock`返回的令牌，释放时交给`L::after_lock`
+    token: L::Guard,
+}
+
+impl<T, L: LockAction> Deref for RcuLockOwnedReadGuard<T, L> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // 守卫登记的引用计数阻止写者回收这个版本，句柄使Inner保持存活
+        unsafe { &*self.data }
+    }
+}
+
+impl<T, L: LockAction> Drop for RcuLockOwnedReadGuard<T, L> {
+    fn drop(&mut self) {
+        L::on_hold_end();
+        self.rcu.inner.untrack_reader(self.borrow_count_index, self.reader_cpu);
+        self.rcu
+            .inner
+            .exit(self.borrow_count_index, self.borrow_count_shard);
+        L::after_lock(core::mem::take(&mut self.token));
+    }
+}
+
+/// [`RcuLock::read_quiescent`]返回的读者守卫，不登记引用计数，只在拿到它的CPU自己的缓存行上记录。
+///
+/// 守卫借用的版本在它释放、拿到它的CPU报告下一个静止状态之前不会被回收，因此守卫不是`Send`的。
+pub struct RcuLockQuiescentReadGuard<'a, T, L: LockAction> {
+    /// 指针使守卫不是Send
+    phantom: PhantomData<(L, *const ())>,
+    data: &'a T,
+    rcu: &'a ArcRcu<T>,
+    /// 登记守卫的CPU
+    cpu: usize,
+    /// `L::before_lock`返回的令牌，释放时交给`L::after_lock`
+    token: L::Guard,
 }
 
-impl<'a, T: Clone, L: LockAction> Deref for RcuLockReadGuard<'a, T, L> {
+impl<'a, T, L: LockAction> Deref for RcuLockQuiescentReadGuard<'a, T, L> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -138,24 +645,26 @@ impl<'a, T: Clone, L: LockAction> Deref for RcuLockReadGuard<'a, T, L> {
     }
 }
 
-impl<'a, T: Clone, L: LockAction> Drop for RcuLockReadGuard<'a, T, L> {
+impl<'a, T, L: LockAction> Drop for RcuLockQuiescentReadGuard<'a, T, L> {
     fn drop(&mut self) {
-        self.rcu.inner.borrow_count[self.borrow_count_index].fetch_sub(1, Ordering::AcqRel);
-        // let count = self.rcu.inner.borrow_count[self.borrow_count_index].load(Ordering::Acquire);
-        // std::println!("read drop, index = {}, count = {} -> {count}", self.borrow_count_index, count + 1);
-        L::after_lock();
+        L::on_hold_end();
+        self.rcu.inner.exit_quiescent(self.cpu);
+        L::after_lock(core::mem::take(&mut self.token));
     }
 }
 
-pub struct RcuLockWriteGuard<'a, T: Clone, L: LockAction> {
-    phantom: PhantomData<L>,
+pub struct RcuLockWriteGuard<'a, T, L: LockAction> {
+    phantom: PhantomData<(L, *const ())>,
     data: Option<Guard<'a, T>>,
     /// 这个Guard所属的RCU
     rcu: &'a ArcRcu<T>,
     borrow_count_index: usize,
+    borrow_count_shard: usize,
+    /// `L::before_lock`返回的令牌，释放时交给`L::after_lock`
+    token: L::Guard,
 }
 
-impl<'a, T: Clone, L: LockAction> Deref for RcuLockWriteGuard<'a, T, L> {
+impl<'a, T, L: LockAction> Deref for RcuLockWriteGuard<'a, T, L> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -168,7 +677,7 @@ impl<'a, T: Clone, L: LockAction> Deref for RcuLockWriteGuard<'a, T, L> {
     }
 }
 
-impl<'a, T: Clone, L: LockAction> DerefMut for RcuLockWriteGuard<'a, T, L> {
+impl<'a, T, L: LockAction> DerefMut for RcuLockWriteGuard<'a, T, L> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         match &mut self.data {
             Some(guard) => &mut *guard,
@@ -179,12 +688,17 @@ impl<'a, T: Clone, L: LockAction> DerefMut for RcuLockWriteGuard<'a, T, L> {
     }
 }
 
-impl<'a, T: Clone, L: LockAction> Drop for RcuLockWriteGuard<'a, T, L> {
+impl<'a, T, L: LockAction> Drop for RcuLockWriteGuard<'a, T, L> {
     fn drop(&mut self) {
-        // 需要提前释放guard，这样才能使更改生效
-        let mut guard: Option<Guard<T>> = None;
-        swap(&mut guard, &mut (self.data));
-        drop(guard.unwrap());
+        L::on_hold_end();
+        // 上一个写者可能留下了没有回收的旧版本，它们的读者在另一个引用计数位置上。发布新版本、切换位置之前，
+        // 这些读者必须全部离开，否则两批读者会落在同一个位置上，回收时就分不清了
+        while self.rcu.pending() > 0 && !self.rcu.clean() {
+            L::relax();
+        }
+        // 需要提前发布新版本，这样才能使更改生效
+        let mut guard = self.data.take().unwrap();
+        guard.publish();
         // 将current_borrow_count_index在0和1间切换
         // 这样，更新数据后的读取就不会影响到这个引用计数了
         self.rcu
@@ -192,17 +706,188 @@ impl<'a, T: Clone, L: LockAction> Drop for RcuLockWriteGuard<'a, T, L> {
             .current_borrow_count_index
             .fetch_xor(1, Ordering::AcqRel);
         // 下降引用计数
-        self.rcu.inner.borrow_count[self.borrow_count_index].fetch_sub(1, Ordering::AcqRel);
-        // let count = self.rcu.inner.borrow_count[self.borrow_count_index].load(Ordering::Acquire);
-        // std::println!("write drop, index = {}, count = {} -> {count}", self.borrow_count_index, count + 1);
-        // 等待在此之前的所有读者执行完毕
-        while self.rcu.inner.borrow_count[self.borrow_count_index].load(Ordering::Acquire) > 0 {
-            core::hint::spin_loop();
-        }
-        // 清理之前的版本
-        self.rcu.clean();
-        // 释放写者锁
-        self.rcu.inner.am_writing.store(false, Ordering::Relaxed);
-        L::after_lock();
+        self.rcu
+            .inner
+            .exit(self.borrow_count_index, self.borrow_count_shard);
+        // 静止状态模式下，还要等待每个CPU在发布之后报告一次静止状态
+        RcuLock::<T, L>::wait_quiescent(self.rcu);
+        let cpu = L::current_cpu();
+        // 等待在此之前的所有读者执行完毕，然后清理之前的版本。
+        // 剩下的读者都在当前CPU上时，它们是写者自己在写入之前拿到的读者守卫，在写者返回之前不会离开：
+        // 不再等待，旧版本留给下一个写者回收
+        while !self.rcu.clean() {
+            if self.rcu.inner.only_reader_cpu(self.borrow_count_index, cpu) {
+                break;
+            }
+            L::relax();
+        }
+        #[cfg(debug_assertions)]
+        self.rcu.inner.writer_cpu.store(usize::MAX, Ordering::Relaxed);
+        // 释放guard的同时释放写者锁
+        drop(guard);
+        L::after_lock(core::mem::take(&mut self.token));
+        // 在写者锁之外通知观察者，这样观察者可以读取这个锁，其他写者也不用等待观察者
+        let observers = self.rcu.inner.observers.lock();
+        if !observers.is_empty() {
+            let current = RcuLockReadGuard::<T, L>::new(self.rcu, L::before_lock());
+            for observer in observers.iter() {
+                observer(&current);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::RcuLock;
+    use crate::EmptyLockAction;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    fn borrowers<T>(lock: &RcuLock<T, EmptyLockAction>) -> usize {
+        lock.rcu.inner.borrowers(0) + lock.rcu.inner.borrowers(1)
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "borrow count underflow")]
+    fn test_double_drop_read_guard_panics() {
+        let lock = RcuLock::<_, EmptyLockAction>::new(0);
+        let guard = lock.read();
+        let copy = unsafe { core::ptr::read(&guard) };
+        drop(guard);
+        drop(copy);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_borrow_count_underflow_restored() {
+        let lock = RcuLock::<_, EmptyLockAction>::new(0);
+        let guard = lock.read();
+        let copy = unsafe { core::ptr::read(&guard) };
+        drop(guard);
+        assert!(catch_unwind(AssertUnwindSafe(|| drop(copy))).is_err());
+        assert_eq!(borrowers(&lock), 0);
+        // Writers don't wait for the phantom reader
+        *lock.write() = 1;
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn test_try_write_balances_borrow_count() {
+        let lock = RcuLock::<_, EmptyLockAction>::new(0);
+        for i in 0..5000 {
+            let mut guard = lock.try_write().unwrap();
+            assert!(lock.try_write().is_none());
+            *guard = i;
+            drop(guard);
+            assert!(lock.try_write().is_some());
+            assert_eq!(borrowers(&lock), 0);
+        }
+        assert_eq!(*lock.read(), 4999);
+    }
+
+    #[test]
+    fn test_panicking_clone_releases_writer() {
+        struct Fragile(bool);
+        impl Clone for Fragile {
+            fn clone(&self) -> Self {
+                assert!(!self.0, "clone failed");
+                Fragile(false)
+            }
+        }
+
+        let lock = RcuLock::<_, EmptyLockAction>::new(Fragile(true));
+        assert!(catch_unwind(AssertUnwindSafe(|| lock.try_write())).is_err());
+        assert_eq!(borrowers(&lock), 0);
+        // The failed writer neither kept the write lock nor published anything
+        assert_eq!(lock.version(), 0);
+        assert!(catch_unwind(AssertUnwindSafe(|| lock.write())).is_err());
+        assert_eq!(borrowers(&lock), 0);
+        assert!(lock.read().0);
+    }
+
+    #[test]
+    fn test_reclamation_lag() {
+        use std::time::Duration;
+
+        let lock = RcuLock::<_, EmptyLockAction>::new(0);
+        *lock.write() = 1;
+        assert_eq!(lock.reclamation_lag(), 0);
+
+        // A pinned reader keeps the writer from reclaiming the version it replaced
+        let reader = lock.read();
+        let writer = {
+            let lock = lock.clone();
+            std::thread::spawn(move || *lock.write() = 2)
+        };
+        while lock.reclamation_lag() == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(lock.reclamation_lag(), 1);
+        assert_eq!(lock.pending_reclaims(), 1);
+        assert_eq!(*reader, 1);
+        assert!(!writer.is_finished());
+
+        drop(reader);
+        writer.join().unwrap();
+        assert_eq!(lock.reclamation_lag(), 0);
+        assert_eq!(*lock.read(), 2);
+    }
+
+    #[test]
+    fn test_quiescent_read_leaves_borrow_count_alone() {
+        use crate::LockAction;
+        use core::sync::atomic::Ordering;
+
+        struct Cpu0;
+        impl LockAction for Cpu0 {
+            type Guard = ();
+            fn current_cpu() -> Option<usize> {
+                Some(0)
+            }
+        }
+
+        let lock = RcuLock::<_, Cpu0>::new_quiescent(0, 1);
+        let guard = lock.read_quiescent();
+        assert_eq!(lock.rcu.inner.borrowers(0) + lock.rcu.inner.borrowers(1), 0);
+        assert_eq!(lock.rcu.inner.reader_cpus[0].load(Ordering::Relaxed), 0);
+        assert_eq!(*guard, 0);
+        drop(guard);
+        // The writer reports for its own CPU, the only one
+        *lock.write() = 1;
+        assert_eq!(lock.rcu.inner.grace_period.load(Ordering::Relaxed), 1);
+        assert_eq!(lock.pending_reclaims(), 0);
+        assert_eq!(*lock.read_quiescent(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "without quiescent-state mode")]
+    fn test_quiescent_read_needs_quiescent_mode() {
+        let lock = RcuLock::<_, EmptyLockAction>::new(0);
+        lock.read_quiescent();
+    }
+
+    #[test]
+    fn test_on_update() {
+        use std::sync::{Arc, Mutex};
+        use std::vec::Vec;
+
+        let lock = RcuLock::<_, EmptyLockAction>::new(0);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        for id in 0..2 {
+            let seen = seen.clone();
+            lock.clone().on_update(move |value| seen.lock().unwrap().push((id, *value)));
+        }
+
+        *lock.write() = 1;
+        assert_eq!(*seen.lock().unwrap(), [(0, 1), (1, 1)]);
+        *lock.try_write().unwrap() = 2;
+        lock.update_fold(|value| value + 1);
+        // Nothing is published, so nobody is notified
+        assert!(!lock.update_if_changed(3));
+        assert_eq!(*seen.lock().unwrap(), [(0, 1), (1, 1), (0, 2), (1, 2), (0, 3), (1, 3)]);
+        assert_eq!(borrowers(&lock), 0);
     }
 }
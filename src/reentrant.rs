@@ -0,0 +1,261 @@
+//! A spinning mutex that the CPU holding it can lock again.
+//!
+//! A [`SpinMutex`](crate::spin::SpinMutex) that is locked again by the CPU holding it spins forever. This is
+//! sometimes hard to avoid, e.g. for a console lock taken both by `println!` and by the panic handler it may
+//! call. [`ReentrantSpinMutex`] remembers which CPU holds it, so that CPU can lock it again, and only releases
+//! it when the last of its guards is dropped.
+use crate::{smp::CpuId, LockAction};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::Deref,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The owner of a [`ReentrantSpinMutex`] that is not locked.
+const UNLOCKED: usize = usize::MAX;
+
+/// A spinning mutex that the CPU holding it, as identified by `I`, can lock again.
+///
+/// Since the same CPU may hold several guards at once, a guard only derefs to `&T`. The data can be changed
+/// through interior mutability, or through [`ReentrantSpinMutexGuard::with_mut`] on the only guard.
+///
+/// # Example
+///
+/// ```
+/// use kernel_sync::{reentrant::ReentrantSpinMutex, smp::CpuId, EmptyLockAction};
+///
+/// struct Hart;
+/// // Safety: this example runs on a single hart.
+/// unsafe impl CpuId for Hart {
+///     fn current() -> usize {
+///         0
+///     }
+/// }
+///
+/// let lock = ReentrantSpinMutex::<_, EmptyLockAction, Hart>::new(0);
+/// let mut outer = lock.lock();
+/// assert_eq!(*lock.lock(), 0);
+/// outer.with_mut(|data| *data += 1);
+/// assert_eq!(*outer, 1);
+/// ```
+pub struct ReentrantSpinMutex<T: ?Sized, L: LockAction, I: CpuId> {
+    _marker: PhantomData<(L, I)>,
+    /// The CPU holding the lock, or [`UNLOCKED`].
+    owner: AtomicUsize,
+    /// How many guards the owner holds, or 0 while it has mutable access. Only accessed by the owner.
+    count: UnsafeCell<usize>,
+    data: UnsafeCell<T>,
+}
+
+/// A guard that provides shared data access to a [`ReentrantSpinMutex`].
+///
+/// When the last guard of the CPU holding the lock falls out of scope, the lock is released. A guard is never
+/// `Send`, since the lock belongs to the CPU that took it.
+pub struct ReentrantSpinMutexGuard<'a, T: ?Sized + 'a, L: LockAction, I: CpuId> {
+    lock: &'a ReentrantSpinMutex<T, L, I>,
+    /// The token of `L::before_lock`, handed to `L::after_lock` when the guard is dropped.
+    token: L::Guard,
+    _not_send: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Send, L: LockAction, I: CpuId> Sync for ReentrantSpinMutex<T, L, I> {}
+unsafe impl<T: ?Sized + Send, L: LockAction, I: CpuId> Send for ReentrantSpinMutex<T, L, I> {}
+unsafe impl<T: ?Sized + Sync, L: LockAction, I: CpuId> Sync for ReentrantSpinMutexGuard<'_, T, L, I> {}
+
+impl<T, L: LockAction, I: CpuId> ReentrantSpinMutex<T, L, I> {
+    /// Creates a new [`ReentrantSpinMutex`] wrapping the supplied data.
+    #[inline(always)]
+    pub const fn new(data: T) -> Self {
+        ReentrantSpinMutex {
+            _marker: PhantomData,
+            owner: AtomicUsize::new(UNLOCKED),
+            count: UnsafeCell::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this [`ReentrantSpinMutex`] and unwraps the underlying data.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized, L: LockAction, I: CpuId> ReentrantSpinMutex<T, L, I> {
+    /// Locks the [`ReentrantSpinMutex`], spinning until it is available unless the current CPU already holds it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current CPU holds the lock inside [`ReentrantSpinMutexGuard::with_mut`].
+    #[inline]
+    pub fn lock(&self) -> ReentrantSpinMutexGuard<'_, T, L, I> {
+        let mut token = L::before_lock();
+        // Read the id after `before_lock`, which keeps the caller on this CPU until `after_lock`
+        let cpu = I::current();
+        if !self.lock_again(cpu, &mut token) {
+            while self
+                .owner
+                .compare_exchange_weak(UNLOCKED, cpu, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                while self.owner.load(Ordering::Relaxed) != UNLOCKED {
+                    L::relax();
+                }
+            }
+            // Safety: the lock is now held by this CPU
+            unsafe { *self.count.get() = 1 };
+        }
+        ReentrantSpinMutexGuard {
+            lock: self,
+            token,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Tries to lock the [`ReentrantSpinMutex`], which succeeds if it is unlocked or held by the current CPU.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current CPU holds the lock inside [`ReentrantSpinMutexGuard::with_mut`].
+    #[inline]
+    pub fn try_lock(&self) -> Option<ReentrantSpinMutexGuard<'_, T, L, I>> {
+        let mut token = L::before_lock();
+        let cpu = I::current();
+        if !self.lock_again(cpu, &mut token) {
+            if self
+                .owner
+                .compare_exchange(UNLOCKED, cpu, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                L::after_lock(token);
+                return None;
+            }
+            // Safety: the lock is now held by this CPU
+            unsafe { *self.count.get() = 1 };
+        }
+        Some(ReentrantSpinMutexGuard {
+            lock: self,
+            token,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Counts one more guard and returns `true` if `cpu` already holds the lock. `token` is the one of the
+    /// caller's `L::before_lock`, handed back before panicking.
+    #[inline(always)]
+    fn lock_again(&self, cpu: usize, token: &mut L::Guard) -> bool {
+        // Only `cpu` itself can have stored its id, and it is still running, so it holds the lock
+        if self.owner.load(Ordering::Relaxed) != cpu {
+            return false;
+        }
+        // Safety: this CPU holds the lock
+        let count = unsafe { &mut *self.count.get() };
+        if *count == 0 {
+            L::after_lock(core::mem::take(token));
+            panic!("ReentrantSpinMutex locked again on CPU {cpu} while its data is borrowed mutably");
+        }
+        *count = count.checked_add(1).expect("ReentrantSpinMutex locked too many times");
+        true
+    }
+
+    /// Returns `true` if some CPU holds the lock.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) != UNLOCKED
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the [`ReentrantSpinMutex`] mutably, no actual locking needs to take place.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction, I: CpuId> ReentrantSpinMutexGuard<'a, T, L, I> {
+    /// Calls `f` with mutable access to the data.
+    ///
+    /// A guard can't implement `DerefMut`, since a reference it returned could outlive a guard that the same CPU
+    /// takes later. Instead, the lock can't be taken again while `f` runs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current CPU holds other guards of the lock, i.e. this is not the only one, and if `f` locks
+    /// the lock again.
+    pub fn with_mut<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> R {
+        struct Restore(*mut usize);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                // Safety: the guard `with_mut` was called on still holds the lock
+                unsafe { *self.0 = 1 };
+            }
+        }
+
+        let count = self.lock.count.get();
+        // Safety: this CPU holds the lock through `self`
+        unsafe {
+            assert_eq!(*count, 1, "ReentrantSpinMutexGuard::with_mut called while other guards of the lock are held");
+            *count = 0;
+        }
+        let _restore = Restore(count);
+        // Safety: `self` is the only guard, and no other one can be created until `_restore` is dropped
+        f(unsafe { &mut *self.lock.data.get() })
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction, I: CpuId> Drop for ReentrantSpinMutexGuard<'a, T, L, I> {
+    #[inline(always)]
+    fn drop(&mut self) {
+        // Safety: this CPU holds the lock through `self`
+        let count = unsafe { &mut *self.lock.count.get() };
+        *count -= 1;
+        if *count == 0 {
+            self.lock.owner.store(UNLOCKED, Ordering::Release);
+        }
+        L::after_lock(core::mem::take(&mut self.token));
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction, I: CpuId> Deref for ReentrantSpinMutexGuard<'a, T, L, I> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: the lock is held, and no guard hands out `&mut T` while another one exists
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, L: LockAction, I: CpuId> fmt::Debug for ReentrantSpinMutexGuard<'a, T, L, I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display, L: LockAction, I: CpuId> fmt::Display for ReentrantSpinMutexGuard<'a, T, L, I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized, L: LockAction, I: CpuId> fmt::Debug for ReentrantSpinMutex<T, L, I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Locking here could panic inside `with_mut`, so don't show the data
+        f.debug_struct("ReentrantSpinMutex")
+            .field("locked", &self.is_locked())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Default, L: LockAction, I: CpuId> Default for ReentrantSpinMutex<T, L, I> {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<T, L: LockAction, I: CpuId> From<T> for ReentrantSpinMutex<T, L, I> {
+    fn from(data: T) -> Self {
+        Self::new(data)
+    }
+}
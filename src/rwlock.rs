@@ -1,6 +1,16 @@
 //! A lock that provides data access to either one writer or many readers.
 
-use crate::{LockAction};
+#[cfg(feature = "std")]
+use crate::poison::{LockResult, Poison, TryLockError, TryLockResult};
+use crate::{
+    poison::{NoPoison, PoisonFlag, PoisonPolicy},
+    spin::SpinMutex,
+    ticket::TicketMutex,
+    LockAction, LockActionSendMarker,
+};
+use alloc::{boxed::Box, vec::Vec};
+#[cfg(any(test, feature = "optimistic-read"))]
+use core::sync::atomic::fence;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{
     cell::UnsafeCell,
@@ -14,7 +24,11 @@ use core::{
 ///
 /// This lock behaves in a similar manner to its namesake `std::sync::RwLock` but uses
 /// spinning for synchronisation instead. Unlike its namespace, this lock does not
-/// track lock poisoning.
+/// track lock poisoning: a guard dropped while unwinding from a panic releases the
+/// lock (and runs `L::after_read` or `L::after_write`) exactly as it would on a normal return.
+///
+/// Readers run [`LockAction::before_read`]/[`LockAction::after_read`] around their critical
+/// section, while writers and upgradeable readers run [`LockAction::before_write`]/[`LockAction::after_write`].
 ///
 /// This type of lock allows a number of readers or at most one writer at any
 /// point in time. The write portion of this lock typically allows modification
@@ -41,6 +55,20 @@ use core::{
 /// when there are existing readers. However if the lock is that highly contended and writes are
 /// crucial then this implementation may be a poor choice.
 ///
+/// Setting `FAIR` to `true` makes the lock fair to writers instead: while a writer waits in [`RwLock::write`],
+/// new readers are turned away, so the writer gets the lock as soon as the current readers leave. This costs
+/// readers throughput under write contention, and a thread that already holds a read guard of a fair lock must not
+/// [`RwLock::read`] it again, as it may end up waiting behind the writer that waits for it. The choice is made at
+/// compile time, the default lock is unfair.
+///
+/// ```
+/// use kernel_sync::{rwlock::RwLock, EmptyLockAction};
+///
+/// let lock = RwLock::<_, EmptyLockAction, true>::new(0);
+/// *lock.write() += 1;
+/// assert_eq!(*lock.read(), 1);
+/// ```
+///
 /// # Examples
 ///
 /// ```
@@ -63,13 +91,74 @@ use core::{
 ///     assert_eq!(*w, 6);
 /// } // write lock is dropped here
 /// ```
-pub struct RwLock<T: ?Sized, L:LockAction> {
-    phantom: PhantomData<L>,
+///
+/// # Orderings
+///
+/// The lock word is taken with `Acquire` and given up with `Release`. A single lock can pick others with
+/// [`RwLock::new_with_ordering`], e.g. `SeqCst` for both while chasing a suspected ordering bug, and an action can
+/// change them for all its locks with [`LockAction::RWLOCK_ACQUIRE`] and [`LockAction::RWLOCK_RELEASE`]. Either
+/// way they are constants, so the default lock pays nothing for the choice. The acquire ordering is used by every
+/// read-modify-write that takes a read, upgradeable or write hold, and the release ordering by every one that
+/// gives a hold up, including [`RwLock::force_read_decrement`] and [`RwLock::force_write_unlock`]. Failed compare-exchanges always use `Relaxed`, and the sequence number behind
+/// `RwLock::optimistic_read` keeps its own fences whatever is chosen.
+///
+/// Only the pairing matters for correctness: a reader must see everything the last writer did before releasing,
+/// and a writer everything readers did before they left, so the acquire ordering must include `Acquire` and the
+/// release ordering must include `Release`. `AcqRel` and `SeqCst` are accepted for both, they only add cost.
+/// Nothing can be relaxed below that, not even on a TSO machine such as x86: there `Acquire` and `Release` already
+/// compile to the same instructions as `Relaxed`, and a `Relaxed` lock would still let the compiler move data
+/// accesses out of the critical section.
+///
+/// ```
+/// use core::sync::atomic::Ordering;
+/// use kernel_sync::{rwlock::RwLock, LockAction};
+///
+/// struct SeqCstAction;
+/// impl LockAction for SeqCstAction {
+///     type Guard = ();
+///     const RWLOCK_ACQUIRE: Ordering = Ordering::SeqCst;
+///     const RWLOCK_RELEASE: Ordering = Ordering::SeqCst;
+/// }
+///
+/// static LOCK: RwLock<u32, SeqCstAction> = RwLock::new(0);
+///
+/// *LOCK.write() += 1;
+/// assert_eq!(*LOCK.read(), 1);
+/// ```
+///
+/// Any other ordering fails to compile once the lock is used:
+///
+/// ```compile_fail
+/// use core::sync::atomic::Ordering;
+/// use kernel_sync::{rwlock::RwLock, LockAction};
+///
+/// struct RelaxedAction;
+/// impl LockAction for RelaxedAction {
+///     type Guard = ();
+///     const RWLOCK_ACQUIRE: Ordering = Ordering::Relaxed;
+/// }
+///
+/// let lock = RwLock::<u32, RelaxedAction>::new(0);
+/// drop(lock.read());
+/// ```
+pub struct RwLock<T: ?Sized, L:LockAction, const FAIR: bool = false, P: PoisonPolicy = NoPoison> {
+    phantom: PhantomData<(L, P)>,
     lock: AtomicUsize,
+    #[cfg(any(test, feature = "stats"))]
+    max_write_wait: AtomicUsize,
+    /// Bit `cpu` is set while a read guard taken on that CPU is held, see [`RwLock::write`].
+    #[cfg(debug_assertions)]
+    read_cpus: AtomicUsize,
+    /// Odd while a writer holds the lock, bumped on every write acquire and release. See `RwLock::optimistic_read`.
+    #[cfg(any(test, feature = "optimistic-read"))]
+    seq: AtomicUsize,
+    poison: PoisonFlag<P>,
     data: UnsafeCell<T>,
 }
 
-const READER: usize = 1 << 2;
+const READER: usize = 1 << 3;
+/// Set by writers waiting on a fair [`RwLock`], to keep new readers out.
+const WRITER_WAITING: usize = 1 << 2;
 const UPGRADED: usize = 1 << 1;
 const WRITER: usize = 1;
 
@@ -77,18 +166,65 @@ const WRITER: usize = 1;
 ///
 /// When the guard falls out of scope it will decrement the read count,
 /// potentially releasing the lock.
-pub struct RwLockReadGuard<'a, T: 'a + ?Sized, L: LockAction> {
+///
+/// Every read guard hands out a shared `&T`, so a guard is only `Send` or `Sync` when `T: Sync`:
+///
+/// ```compile_fail
+/// fn assert_sync<S: Sync>(_: &S) {}
+///
+/// let lock = kernel_sync::RwLock::new(core::cell::Cell::new(0));
+/// assert_sync(&lock.read());
+/// ```
+///
+/// ```compile_fail
+/// fn assert_send<S: Send>(_: S) {}
+///
+/// let lock = kernel_sync::RwLock::new(core::cell::Cell::new(0));
+/// assert_send(lock.read());
+/// ```
+pub struct RwLockReadGuard<'a, T: 'a + ?Sized, L: LockAction, const FAIR: bool = false, P: PoisonPolicy = NoPoison> {
     phantom: PhantomData<L>,
     lock: &'a AtomicUsize,
+    // The lock the guard was taken from, for `try_into_write`; `None` once mapped to a part of the data
+    owner: Option<&'a RwLock<T, L, FAIR, P>>,
+    read_cpu: ReadCpu<'a>,
+    /// The token of `L::before_read`, handed to `L::after_read` when the guard is dropped.
+    token: L::Guard,
     data: *const T,
 }
 
+/// Records the CPU a read guard was taken on, so that [`RwLock::write`] can detect in debug builds that it
+/// would wait for a read guard held on its own CPU.
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy)]
+struct ReadCpu<'a> {
+    read_cpus: &'a AtomicUsize,
+    /// The bit this guard set in `read_cpus`, or 0 if it did not set one.
+    bit: usize,
+}
+
+#[cfg(not(debug_assertions))]
+#[derive(Clone, Copy)]
+struct ReadCpu<'a>(PhantomData<&'a ()>);
+
+impl ReadCpu<'_> {
+    #[inline(always)]
+    fn release(&self) {
+        #[cfg(debug_assertions)]
+        if self.bit != 0 {
+            self.read_cpus.fetch_and(!self.bit, Ordering::Relaxed);
+        }
+    }
+}
+
 /// A guard that provides mutable data access.
 ///
 /// When the guard falls out of scope it will release the lock.
-pub struct RwLockWriteGuard<'a, T: 'a + ?Sized, L: LockAction> {
+pub struct RwLockWriteGuard<'a, T: 'a + ?Sized, L: LockAction, const FAIR: bool = false, P: PoisonPolicy = NoPoison> {
     phantom: PhantomData<L>,
-    inner: &'a RwLock<T, L>,
+    inner: &'a RwLock<T, L, FAIR, P>,
+    /// The token of `L::before_write`, handed to `L::after_write` when the guard is dropped.
+    token: L::Guard,
     data: *mut T,
 }
 
@@ -99,26 +235,40 @@ pub struct RwLockWriteGuard<'a, T: 'a + ?Sized, L: LockAction> {
 /// when the lock is acquired.
 ///
 /// When the guard falls out of scope it will release the lock.
-pub struct RwLockUpgradableGuard<'a, T: 'a + ?Sized, L: LockAction> {
+pub struct RwLockUpgradableGuard<'a, T: 'a + ?Sized, L: LockAction, const FAIR: bool = false> {
     phantom: PhantomData<L>,
-    inner: &'a RwLock<T, L>,
+    inner: &'a RwLock<T, L, FAIR>,
+    /// The token of `L::before_write`, handed to `L::after_write` when the guard is dropped.
+    token: L::Guard,
     data: *const T,
 }
 
 // Same unsafe impls as `std::sync::RwLock`
-unsafe impl<T: ?Sized + Send, L:LockAction> Send for RwLock<T, L> {}
-unsafe impl<T: ?Sized + Send + Sync, L:LockAction> Sync for RwLock<T, L> {}
+unsafe impl<T: ?Sized + Send, L:LockAction, const FAIR: bool, P: PoisonPolicy> Send for RwLock<T, L, FAIR, P> {}
+unsafe impl<T: ?Sized + Send + Sync, L:LockAction, const FAIR: bool, P: PoisonPolicy> Sync for RwLock<T, L, FAIR, P> {}
 
-unsafe impl<T: ?Sized + Send + Sync, L: LockAction> Send for RwLockWriteGuard<'_, T, L> {}
-unsafe impl<T: ?Sized + Send + Sync, L: LockAction> Sync for RwLockWriteGuard<'_, T, L> {}
+unsafe impl<T: ?Sized + Send + Sync, L: LockActionSendMarker, const FAIR: bool, P: PoisonPolicy> Send
+    for RwLockWriteGuard<'_, T, L, FAIR, P>
+{
+}
+unsafe impl<T: ?Sized + Send + Sync, L: LockAction, const FAIR: bool, P: PoisonPolicy> Sync
+    for RwLockWriteGuard<'_, T, L, FAIR, P>
+{
+}
 
-unsafe impl<T: ?Sized + Sync, L: LockAction> Send for RwLockReadGuard<'_, T, L> {}
-unsafe impl<T: ?Sized + Sync, L: LockAction> Sync for RwLockReadGuard<'_, T, L> {}
+unsafe impl<T: ?Sized + Sync, L: LockActionSendMarker, const FAIR: bool, P: PoisonPolicy> Send
+    for RwLockReadGuard<'_, T, L, FAIR, P>
+{
+}
+unsafe impl<T: ?Sized + Sync, L: LockAction, const FAIR: bool, P: PoisonPolicy> Sync
+    for RwLockReadGuard<'_, T, L, FAIR, P>
+{
+}
 
-unsafe impl<T: ?Sized + Send + Sync, L: LockAction> Send for RwLockUpgradableGuard<'_, T, L> {}
-unsafe impl<T: ?Sized + Send + Sync, L: LockAction> Sync for RwLockUpgradableGuard<'_, T, L> {}
+unsafe impl<T: ?Sized + Send + Sync, L: LockActionSendMarker, const FAIR: bool> Send for RwLockUpgradableGuard<'_, T, L, FAIR> {}
+unsafe impl<T: ?Sized + Send + Sync, L: LockAction, const FAIR: bool> Sync for RwLockUpgradableGuard<'_, T, L, FAIR> {}
 
-impl<T, L:LockAction> RwLock<T, L> {
+impl<T, L:LockAction, const FAIR: bool, P: PoisonPolicy> RwLock<T, L, FAIR, P> {
     /// Creates a new spinlock wrapping the supplied data.
     ///
     /// May be used statically:
@@ -140,10 +290,47 @@ impl<T, L:LockAction> RwLock<T, L> {
         RwLock {
             phantom: PhantomData,
             lock: AtomicUsize::new(0),
+            #[cfg(any(test, feature = "stats"))]
+            max_write_wait: AtomicUsize::new(0),
+            #[cfg(debug_assertions)]
+            read_cpus: AtomicUsize::new(0),
+            #[cfg(any(test, feature = "optimistic-read"))]
+            seq: AtomicUsize::new(0),
+            poison: PoisonFlag::new(),
             data: UnsafeCell::new(data),
         }
     }
 
+    /// Creates a new lock whose lock word is taken and given up with the orderings of `O` instead of those of `L`,
+    /// see [`RwLock#orderings`].
+    ///
+    /// The orderings become part of the type, through the action [`Ordered<L, O>`], so other locks using `L` keep
+    /// theirs and this lock pays nothing at run time for the choice.
+    ///
+    /// ```
+    /// use kernel_sync::{rwlock::{Ordered, RwLock, SeqCstOrdering}, EmptyLockAction};
+    ///
+    /// static LOCK: RwLock<u32, Ordered<EmptyLockAction, SeqCstOrdering>> =
+    ///     RwLock::<_, EmptyLockAction>::new_with_ordering(0, SeqCstOrdering);
+    ///
+    /// *LOCK.write() += 1;
+    /// assert_eq!(*LOCK.read(), 1);
+    /// ```
+    #[inline]
+    pub const fn new_with_ordering<O: RwLockOrdering>(data: T, _ordering: O) -> RwLock<T, Ordered<L, O>, FAIR, P> {
+        RwLock::new(data)
+    }
+
+    /// Creates a new `RwLock` on the heap that is never freed, for locks allocated at boot that live as long as
+    /// the kernel. See [`SpinMutex::leak`].
+    #[inline]
+    pub fn leak(data: T) -> &'static Self
+    where
+        Self: 'static,
+    {
+        Box::leak(Box::new(Self::new(data)))
+    }
+
     /// Consumes this `RwLock`, returning the underlying data.
     #[inline]
     pub fn into_inner(self) -> T {
@@ -182,7 +369,7 @@ impl<T, L:LockAction> RwLock<T, L> {
     }
 }
 
-impl<T: ?Sized, L: LockAction> RwLock<T, L> {
+impl<T: ?Sized, L: LockAction, const FAIR: bool> RwLock<T, L, FAIR> {
     /// Locks this rwlock with shared read access, blocking the current thread
     /// until it can be acquired.
     ///
@@ -204,16 +391,13 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
     ///     // The lock is dropped
     /// }
     /// ```
+    ///
+    /// While a writer holds the lock, the lock word is first polled up to `L::OPTIMISTIC_READ_SPINS` times in
+    /// the hope that the write finishes soon. The spinning reader still doesn't get in ahead of a waiting writer
+    /// of a fair lock.
     #[inline]
-    pub fn read(&self) -> RwLockReadGuard<T, L> {
-        loop {
-            match self.try_read() {
-                Some(guard) => return guard,
-                None => {
-                    core::hint::spin_loop();
-                }
-            }
-        }
+    pub fn read(&self) -> RwLockReadGuard<'_, T, L, FAIR> {
+        self.read_internal()
     }
 
     /// Lock this rwlock with exclusive write access, blocking the current
@@ -225,6 +409,10 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
     /// Returns an RAII guard which will drop the write access of this rwlock
     /// when dropped.
     ///
+    /// Calling this while the current CPU holds a read guard of the same lock spins forever. In debug builds,
+    /// if `L::current_cpu` knows the current CPU and [`LockAction::PINS_HOLDER`] is set, this is detected and
+    /// panics instead.
+    ///
     /// ```
     /// let mylock = kernel_sync::RwLock::new(0);
     /// {
@@ -235,39 +423,201 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
     /// }
     /// ```
     #[inline]
-    pub fn write(&self) -> RwLockWriteGuard<T, L> {
+    pub fn write(&self) -> RwLockWriteGuard<'_, T, L, FAIR> {
+        self.write_internal()
+    }
+
+    /// Return the longest wait, in spin iterations, that any call to [`RwLock::write`] has observed
+    /// before acquiring the lock.
+    ///
+    /// Only available with the `stats` feature (and in this crate's own tests). It is meant to let test
+    /// suites check that writers are not starved under read-heavy load.
+    #[cfg(any(test, feature = "stats"))]
+    pub fn max_write_wait(&self) -> usize {
+        self.max_write_wait.load(Ordering::Relaxed)
+    }
+
+    /// Locks this rwlock with shared read access and maps the guard to a component of the data.
+    ///
+    /// This is shorthand for [`RwLockReadGuard::map`] applied to [`RwLock::read`]. The read lock stays held
+    /// until the returned guard is dropped.
+    ///
+    /// ```
+    /// let mylock = kernel_sync::RwLock::new((1, 2));
+    /// let second = mylock.read_map(|data| &data.1);
+    /// assert_eq!(*second, 2);
+    /// assert!(mylock.try_write().is_none());
+    /// ```
+    #[inline]
+    pub fn read_map<U: ?Sized, F: FnOnce(&T) -> &U>(&self, f: F) -> RwLockReadGuard<'_, U, L, FAIR> {
+        RwLockReadGuard::map(self.read(), f)
+    }
+
+    /// Copy the data out without taking the lock, seqlock style.
+    ///
+    /// The data is copied and then checked against a sequence number that writers bump when they take and release
+    /// the lock. If a writer held the lock meanwhile, the copy is thrown away and taken again, so the result is
+    /// never torn. Readers don't touch the lock word, which makes this cheaper than [`RwLock::read`] for small
+    /// `Copy` data that is read far more often than it is written. A steady stream of writers can keep it retrying.
+    ///
+    /// Only available with the `optimistic-read` feature, which adds the sequence number to every `RwLock` and two
+    /// stores to every write hold. Like [`crate::seqlock::SeqLock::read`], the copy is a volatile read that may race
+    /// with a writer, here one writing through its guard with plain stores. The Rust memory model has no blessed
+    /// way to do this yet, so it relies on the same exception every seqlock does: the racing read only yields bytes
+    /// that are thrown away, never a value that is used.
+    ///
+    /// ```
+    /// let mylock = kernel_sync::RwLock::new((1, 2));
+    /// *mylock.write() = (3, 4);
+    /// assert_eq!(mylock.optimistic_read(), (3, 4));
+    /// ```
+    #[cfg(any(test, feature = "optimistic-read"))]
+    #[inline]
+    pub fn optimistic_read(&self) -> T
+    where
+        T: Copy,
+    {
         loop {
-            match self.try_write_internal(false) {
-                Some(guard) => return guard,
-                None => {
-                    core::hint::spin_loop();
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 == 0 {
+                // Safety: the copy may race with a writer, in which case `seq` has changed by the time we check it
+                // below and the torn copy is never used. `T: Copy`, so dropping it is a no-op. This is the usual
+                // seqlock read, done volatile so it is not assumed to be stable.
+                let data = unsafe { core::ptr::read_volatile(self.data.get()) };
+                fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == seq {
+                    return data;
                 }
             }
+            L::relax();
+        }
+    }
+
+    /// Check the data under an upgradeable read, and upgrade to exclusive write access only if `pred` holds.
+    ///
+    /// No other writer or upgradeable reader can get in between evaluating `pred` and the upgrade, so the
+    /// returned guard sees the same value `pred` accepted. Returns `None`, releasing the lock, if `pred` fails.
+    ///
+    /// ```
+    /// let mylock = kernel_sync::RwLock::new(0);
+    ///
+    /// if let Some(mut data) = mylock.write_if(|data| *data == 0) {
+    ///     *data = 1;
+    /// }
+    /// assert!(mylock.write_if(|data| *data == 0).is_none());
+    /// assert_eq!(*mylock.read(), 1);
+    /// ```
+    #[inline]
+    pub fn write_if<F: Fn(&T) -> bool>(&self, pred: F) -> Option<RwLockWriteGuard<'_, T, L, FAIR>> {
+        let guard = self.upgradeable_read();
+        if pred(&guard) {
+            Some(guard.upgrade())
+        } else {
+            None
         }
     }
 
     /// Obtain a readable lock guard that can later be upgraded to a writable lock guard.
     /// Upgrades can be done through the [`RwLockUpgradableGuard::upgrade`](RwLockUpgradableGuard::upgrade) method.
     #[inline]
-    pub fn upgradeable_read(&self) -> RwLockUpgradableGuard<T, L> {
+    pub fn upgradeable_read(&self) -> RwLockUpgradableGuard<'_, T, L, FAIR> {
         loop {
             match self.try_upgradeable_read() {
                 Some(guard) => return guard,
                 None => {
-                    core::hint::spin_loop();
+                    L::relax();
                 }
             }
         }
     }
 }
 
-impl<T: ?Sized, L: LockAction> RwLock<T, L> {
+impl<T: ?Sized, L: LockAction, const FAIR: bool, P: PoisonPolicy> RwLock<T, L, FAIR, P> {
+    // Bits that make new readers back off.
+    const READ_BLOCKERS: usize = if FAIR {
+        WRITER | UPGRADED | WRITER_WAITING
+    } else {
+        WRITER | UPGRADED
+    };
+
+    // Called right after the WRITER bit is set: make `seq` odd before the writer touches the data.
+    #[inline(always)]
+    fn begin_write(&self) {
+        #[cfg(any(test, feature = "optimistic-read"))]
+        {
+            let seq = self.seq.load(Ordering::Relaxed);
+            self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+            fence(Ordering::Release);
+        }
+    }
+
+    // Called right before the WRITER bit is cleared: make `seq` even once the writer is done with the data.
+    #[inline(always)]
+    fn end_write(&self) {
+        #[cfg(any(test, feature = "optimistic-read"))]
+        {
+            let seq = self.seq.load(Ordering::Relaxed);
+            self.seq.store(seq.wrapping_add(1), Ordering::Release);
+        }
+    }
+
+    // The WRITER_WAITING bit a writer has to expect when taking the lock, so that it can take it over from
+    // waiting writers (itself included). Waiting writers that don't get the lock set the bit again.
+    #[inline(always)]
+    fn writer_waiting(&self) -> usize {
+        if FAIR {
+            self.lock.load(Ordering::Relaxed) & WRITER_WAITING
+        } else {
+            0
+        }
+    }
+
+    // Record the current CPU as holding a read guard. Only the outermost read guard on a CPU records it.
+    #[inline(always)]
+    fn track_reader(&self) -> ReadCpu<'_> {
+        #[cfg(debug_assertions)]
+        {
+            // A guard that can end up on another CPU, or whose task can be preempted, would make a writer on this
+            // CPU panic although the wait ends once the holder runs again
+            let bit = match L::current_cpu() {
+                Some(cpu) if L::PINS_HOLDER && cpu < usize::BITS as usize => 1 << cpu,
+                _ => 0,
+            };
+            let bit = if self.read_cpus.fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+                bit
+            } else {
+                0
+            };
+            ReadCpu {
+                read_cpus: &self.read_cpus,
+                bit,
+            }
+        }
+        #[cfg(not(debug_assertions))]
+        ReadCpu(PhantomData)
+    }
+
+    // A read guard that records no CPU, for releasing a read lock taken through `lock_api`.
+    #[cfg(feature = "lockapi")]
+    #[inline(always)]
+    fn untracked_reader(&self) -> ReadCpu<'_> {
+        #[cfg(debug_assertions)]
+        {
+            ReadCpu {
+                read_cpus: &self.read_cpus,
+                bit: 0,
+            }
+        }
+        #[cfg(not(debug_assertions))]
+        ReadCpu(PhantomData)
+    }
+
     // Acquire a read lock, returning the new lock value.
     fn acquire_reader(&self) -> usize {
         // An arbitrary cap that allows us to catch overflows long before they happen
         const MAX_READERS: usize = usize::MAX / READER / 2;
 
-        let value = self.lock.fetch_add(READER, Ordering::Acquire);
+        let value = self.lock.fetch_add(READER, acquire::<L>());
 
         if value > MAX_READERS * READER {
             self.lock.fetch_sub(READER, Ordering::Relaxed);
@@ -277,6 +627,115 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
         }
     }
 
+    #[inline(always)]
+    fn read_internal(&self) -> RwLockReadGuard<'_, T, L, FAIR, P> {
+        let mut spins = L::OPTIMISTIC_READ_SPINS;
+        loop {
+            match self.try_read_internal() {
+                Some(guard) => return guard,
+                None => {
+                    if spins > 0 && self.lock.load(Ordering::Relaxed) & WRITER != 0 {
+                        while spins > 0 && self.lock.load(Ordering::Relaxed) & WRITER != 0 {
+                            spins -= 1;
+                            L::on_spin();
+                        }
+                    } else {
+                        L::relax();
+                    }
+                }
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn write_internal(&self) -> RwLockWriteGuard<'_, T, L, FAIR, P> {
+        #[cfg(any(test, feature = "stats"))]
+        let mut spins = 0;
+        loop {
+            match self.try_write_internal(false) {
+                Some(guard) => {
+                    #[cfg(any(test, feature = "stats"))]
+                    self.max_write_wait.fetch_max(spins, Ordering::Relaxed);
+                    return guard;
+                }
+                None => {
+                    #[cfg(any(test, feature = "stats"))]
+                    {
+                        spins += 1;
+                    }
+                    // Check before turning readers away, so a caught panic leaves a fair lock readable
+                    #[cfg(debug_assertions)]
+                    if let Some(cpu) = L::current_cpu() {
+                        if cpu < usize::BITS as usize && self.read_cpus.load(Ordering::Relaxed) & (1 << cpu) != 0 {
+                            panic!(
+                                "RwLock::write called on CPU {cpu} while it holds a read guard of the same lock, \
+                                 which would deadlock; use upgradeable_read and upgrade instead"
+                            );
+                        }
+                    }
+                    if FAIR {
+                        self.lock.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+                    }
+                    L::relax();
+                }
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn try_read_internal(&self) -> Option<RwLockReadGuard<'_, T, L, FAIR, P>> {
+        let token = L::before_read();
+        let value = self.acquire_reader();
+
+        // We check the UPGRADED bit here so that new readers are prevented when an UPGRADED lock is held.
+        // This helps reduce writer starvation.
+        if value & Self::READ_BLOCKERS != 0 {
+            // Lock is taken, undo.
+            self.lock.fetch_sub(READER, release::<L>());
+            L::after_read(token);
+            None
+        } else {
+            L::on_hold_start();
+            Some(RwLockReadGuard {
+                phantom: Default::default(),
+                lock: &self.lock,
+                owner: Some(self),
+                read_cpu: self.track_reader(),
+                token,
+                data: unsafe { &*self.data.get() },
+            })
+        }
+    }
+
+    #[inline(always)]
+    fn try_write_internal(&self, strong: bool) -> Option<RwLockWriteGuard<'_, T, L, FAIR, P>> {
+        let token = L::before_write();
+        if compare_exchange(
+            &self.lock,
+            self.writer_waiting(),
+            WRITER,
+            acquire::<L>(),
+            Ordering::Relaxed,
+            strong,
+        )
+        .is_ok()
+        {
+            self.begin_write();
+            L::on_hold_start();
+            Some(RwLockWriteGuard {
+                phantom: PhantomData,
+                inner: self,
+                token,
+                data: unsafe { &mut *self.data.get() },
+            })
+        } else {
+            L::after_write(token);
+            None
+        }
+    }
+}
+
+impl<T: ?Sized, L: LockAction, const FAIR: bool> RwLock<T, L, FAIR> {
     /// Attempt to acquire this lock with shared read access.
     ///
     /// This function will never block and will return immediately if `read`
@@ -300,24 +759,8 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
     /// }
     /// ```
     #[inline]
-    pub fn try_read(&self) -> Option<RwLockReadGuard<T, L>> {
-        L::before_lock();
-        let value = self.acquire_reader();
-
-        // We check the UPGRADED bit here so that new readers are prevented when an UPGRADED lock is held.
-        // This helps reduce writer starvation.
-        if value & (WRITER | UPGRADED) != 0 {
-            // Lock is taken, undo.
-            self.lock.fetch_sub(READER, Ordering::Release);
-            L::after_lock();
-            None
-        } else {
-            Some(RwLockReadGuard {
-                phantom: Default::default(),
-                lock: &self.lock,
-                data: unsafe { &*self.data.get() },
-            })
-        }
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T, L, FAIR>> {
+        self.try_read_internal()
     }
 
     /// Return the number of readers that currently hold the lock (including upgradable readers).
@@ -328,7 +771,8 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
     /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
     pub fn reader_count(&self) -> usize {
         let state = self.lock.load(Ordering::Relaxed);
-        state / READER + (state & UPGRADED) / UPGRADED
+        // Like in `upgradable_held`, UPGRADED only means an upgradeable reader while no writer holds the lock
+        state / READER + usize::from(state & (WRITER | UPGRADED) == UPGRADED)
     }
 
     /// Return the number of writers that currently hold the lock.
@@ -343,6 +787,42 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
         (self.lock.load(Ordering::Relaxed) & WRITER) / WRITER
     }
 
+    /// Returns `true` if a writer currently holds the lock, e.g. to decide whether to yield instead of spinning
+    /// before calling [`RwLock::write`]. Writers that are only waiting for the lock don't count.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    #[inline(always)]
+    pub fn is_writer_active(&self) -> bool {
+        self.lock.load(Ordering::Relaxed) & WRITER != 0
+    }
+
+    /// Returns `true` if the lock is currently held by any reader, upgradeable reader or writer.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.lock.load(Ordering::Relaxed) & !WRITER_WAITING != 0
+    }
+
+    /// Return whether an upgradeable read guard is currently held.
+    ///
+    /// At most one upgradeable guard can exist at a time, so this is a single flag check.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    pub fn upgradable_held(&self) -> bool {
+        // A writer clears UPGRADED on release, so the bit only means something while no writer holds the lock.
+        self.lock.load(Ordering::Relaxed) & (WRITER | UPGRADED) == UPGRADED
+    }
+
     /// Force decrement the reader count.
     ///
     /// # Safety
@@ -350,12 +830,14 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
     /// This is *extremely* unsafe if there are outstanding `RwLockReadGuard`s
     /// live, or if called more times than `read` has been called, but can be
     /// useful in FFI contexts where the caller doesn't know how to deal with
-    /// RAII. The underlying atomic operation uses `Ordering::Release`.
+    /// RAII. The underlying atomic operation uses [`LockAction::RWLOCK_RELEASE`]. The token of the forgotten guard
+    /// is lost, so `L::after_read` gets the default one.
     #[inline]
     pub unsafe fn force_read_decrement(&self) {
         debug_assert!(self.lock.load(Ordering::Relaxed) & !WRITER > 0);
-        self.lock.fetch_sub(READER, Ordering::Release);
-        L::after_lock();
+        L::on_hold_end();
+        self.lock.fetch_sub(READER, release::<L>());
+        L::after_read(L::Guard::default());
     }
 
     /// Force unlock exclusive write access.
@@ -365,36 +847,15 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
     /// This is *extremely* unsafe if there are outstanding `RwLockWriteGuard`s
     /// live, or if called when there are current readers, but can be useful in
     /// FFI contexts where the caller doesn't know how to deal with RAII. The
-    /// underlying atomic operation uses `Ordering::Release`.
+    /// underlying atomic operation uses [`LockAction::RWLOCK_RELEASE`]. The token of the forgotten guard is lost,
+    /// so `L::after_write` gets the default one.
     #[inline]
     pub unsafe fn force_write_unlock(&self) {
-        debug_assert_eq!(self.lock.load(Ordering::Relaxed) & !(WRITER | UPGRADED), 0);
-        self.lock.fetch_and(!(WRITER | UPGRADED), Ordering::Release);
-        L::after_lock();
-    }
-
-    #[inline(always)]
-    fn try_write_internal(&self, strong: bool) -> Option<RwLockWriteGuard<T, L>> {
-        L::before_lock();
-        if compare_exchange(
-            &self.lock,
-            0,
-            WRITER,
-            Ordering::Acquire,
-            Ordering::Relaxed,
-            strong,
-        )
-        .is_ok()
-        {
-            Some(RwLockWriteGuard {
-                phantom: PhantomData,
-                inner: self,
-                data: unsafe { &mut *self.data.get() },
-            })
-        } else {
-            L::after_lock();
-            None
-        }
+        debug_assert_eq!(self.lock.load(Ordering::Relaxed) & !(WRITER | UPGRADED | WRITER_WAITING), 0);
+        L::on_hold_end();
+        self.end_write();
+        self.lock.fetch_and(!(WRITER | UPGRADED), release::<L>());
+        L::after_write(L::Guard::default());
     }
 
     /// Attempt to lock this rwlock with exclusive write access.
@@ -417,7 +878,7 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
     /// }
     /// ```
     #[inline]
-    pub fn try_write(&self) -> Option<RwLockWriteGuard<T, L>> {
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T, L, FAIR>> {
         self.try_write_internal(true)
     }
 
@@ -426,24 +887,33 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
     /// Unlike [`RwLock::try_write`], this function is allowed to spuriously fail even when acquiring exclusive write access
     /// would otherwise succeed, which can result in more efficient code on some platforms.
     #[inline]
-    pub fn try_write_weak(&self) -> Option<RwLockWriteGuard<T, L>> {
+    pub fn try_write_weak(&self) -> Option<RwLockWriteGuard<'_, T, L, FAIR>> {
         self.try_write_internal(false)
     }
 
     /// Tries to obtain an upgradeable lock guard.
+    ///
+    /// On a fair lock this fails while a writer is waiting, just like [`RwLock::try_read`].
     #[inline]
-    pub fn try_upgradeable_read(&self) -> Option<RwLockUpgradableGuard<T, L>> {
-        L::before_lock();
-        if self.lock.fetch_or(UPGRADED, Ordering::Acquire) & (WRITER | UPGRADED) == 0 {
+    pub fn try_upgradeable_read(&self) -> Option<RwLockUpgradableGuard<'_, T, L, FAIR>> {
+        let token = L::before_write();
+        let value = self.lock.fetch_or(UPGRADED, acquire::<L>());
+        if value & Self::READ_BLOCKERS == 0 {
+            L::on_hold_start();
             Some(RwLockUpgradableGuard {
                 phantom: PhantomData,
                 inner: self,
+                token,
                 data: unsafe { &*self.data.get() },
             })
         } else {
             // We can't unflip the UPGRADED bit back just yet as there is another upgradeable or write lock.
-            // When they unlock, they will clear the bit.
-            L::after_lock();
+            // When they unlock, they will clear the bit. If only a waiting writer turned us away, the bit is ours
+            // and must be cleared now, or the writer would never get in.
+            if value & (WRITER | UPGRADED) == 0 {
+                self.lock.fetch_and(!UPGRADED, release::<L>());
+            }
+            L::after_write(token);
             None
         }
     }
@@ -453,6 +923,10 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
     /// Since this call borrows the `RwLock` mutably, no actual locking needs to
     /// take place -- the mutable borrow statically guarantees no locks exist.
     ///
+    /// The lock state, including the debug tracking of reader CPUs and the `stats` counters, is left untouched.
+    /// Every guard cleaned up after itself when it was dropped, so the lock reports itself as unlocked; only a guard
+    /// leaked with [`core::mem::forget`] would keep it held.
+    ///
     /// # Examples
     ///
     /// ```
@@ -467,7 +941,107 @@ impl<T: ?Sized, L: LockAction> RwLock<T, L> {
     }
 }
 
-impl<T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for RwLock<T, L> {
+/// Write-lock every lock in `locks` without risking a deadlock against other callers.
+///
+/// The locks are acquired in order of their address, so any two threads calling this function on overlapping
+/// sets take the shared locks in the same order, whatever order they passed them in. The guards are returned in
+/// the order of `locks`. A lock that appears more than once is only locked once, and its guard takes the position
+/// of its first occurrence, so the result is shorter than `locks` in that case.
+///
+/// This only protects against callers that all go through this function (or otherwise lock by address); a thread
+/// that already holds one of the locks must not call it.
+///
+/// ```
+/// use kernel_sync::rwlock::{lock_all_sorted, RwLock};
+/// use kernel_sync::EmptyLockAction;
+///
+/// let (a, b) = (RwLock::<_, EmptyLockAction>::new(1), RwLock::<_, EmptyLockAction>::new(2));
+/// let mut guards = lock_all_sorted(&[&b, &a, &b]);
+/// assert_eq!(guards.len(), 2);
+/// *guards[0] += *guards[1];
+/// drop(guards);
+/// assert_eq!(*b.read(), 3);
+/// ```
+pub fn lock_all_sorted<'a, T: ?Sized, L: LockAction, const FAIR: bool>(
+    locks: &[&'a RwLock<T, L, FAIR>],
+) -> Vec<RwLockWriteGuard<'a, T, L, FAIR>> {
+    let addr = |lock: &RwLock<T, L, FAIR>| lock as *const RwLock<T, L, FAIR> as *const u8 as usize;
+    // Sorting by index as well puts the first occurrence of a duplicate first
+    let mut order: Vec<usize> = (0..locks.len()).collect();
+    order.sort_unstable_by_key(|&i| (addr(locks[i]), i));
+
+    let mut guards: Vec<Option<RwLockWriteGuard<'a, T, L, FAIR>>> = (0..locks.len()).map(|_| None).collect();
+    let mut last = None;
+    for i in order {
+        if last != Some(addr(locks[i])) {
+            last = Some(addr(locks[i]));
+            guards[i] = Some(locks[i].write());
+        }
+    }
+    guards.into_iter().flatten().collect()
+}
+
+/// The locking methods of an [`RwLock`] that is poisoned by a panic, see [`crate::poison`].
+///
+/// They are the ones of [`std::sync::RwLock`]: only a write guard dropped during a panic poisons the lock, but
+/// once it is poisoned, readers get an error as well. Upgradeable reads are not available on a poisoning lock, and
+/// [`RwLock::into_inner`] hands out the data even if it is poisoned.
+#[cfg(feature = "std")]
+impl<T: ?Sized, L: LockAction, const FAIR: bool> RwLock<T, L, FAIR, Poison> {
+    /// Locks this rwlock with shared read access like [`RwLock::read`], but returns an error holding the guard
+    /// if the lock is poisoned.
+    #[inline]
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T, L, FAIR, Poison>> {
+        self.poison.guard(self.read_internal())
+    }
+
+    /// Locks this rwlock with exclusive write access like [`RwLock::write`], but returns an error holding the
+    /// guard if the lock is poisoned.
+    #[inline]
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T, L, FAIR, Poison>> {
+        self.poison.guard(self.write_internal())
+    }
+
+    /// Attempts to acquire shared read access like [`RwLock::try_read`], but returns an error holding the guard
+    /// if the lock is poisoned.
+    #[inline]
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T, L, FAIR, Poison>> {
+        match self.try_read_internal() {
+            Some(guard) => Ok(self.poison.guard(guard)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Attempts to acquire exclusive write access like [`RwLock::try_write`], but returns an error holding the
+    /// guard if the lock is poisoned.
+    #[inline]
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T, L, FAIR, Poison>> {
+        match self.try_write_internal(true) {
+            Some(guard) => Ok(self.poison.guard(guard)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Returns `true` if a write guard of the lock was dropped during a panic since the poison was last cleared.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Clears the poison, once the data is known to be consistent again.
+    #[inline]
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    /// Returns `true` if the lock is held by readers or a writer, see [`RwLock::is_locked`].
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        self.lock.load(Ordering::Relaxed) & !WRITER_WAITING != 0
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, L: LockAction, const FAIR: bool> fmt::Debug for RwLock<T, L, FAIR> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.try_read() {
             Some(guard) => write!(f, "RwLock {{ data: ")
@@ -478,19 +1052,31 @@ impl<T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for RwLock<T, L> {
     }
 }
 
-impl<T: ?Sized + Default, L:LockAction> Default for RwLock<T, L> {
+impl<T: Default, L:LockAction, const FAIR: bool, P: PoisonPolicy> Default for RwLock<T, L, FAIR, P> {
     fn default() -> Self {
         Self::new(Default::default())
     }
 }
 
-impl<T, L:LockAction> From<T> for RwLock<T, L> {
+impl<T, L:LockAction, const FAIR: bool, P: PoisonPolicy> From<T> for RwLock<T, L, FAIR, P> {
     fn from(data: T) -> Self {
         Self::new(data)
     }
 }
 
-impl<'rwlock, T: ?Sized, L: LockAction> RwLockReadGuard<'rwlock, T, L> {
+impl<T, L:LockAction, const FAIR: bool> From<SpinMutex<T, L>> for RwLock<T, L, FAIR> {
+    fn from(lock: SpinMutex<T, L>) -> Self {
+        Self::new(lock.into_inner())
+    }
+}
+
+impl<T, L:LockAction, const FAIR: bool> From<TicketMutex<T, L>> for RwLock<T, L, FAIR> {
+    fn from(lock: TicketMutex<T, L>) -> Self {
+        Self::new(lock.into_inner())
+    }
+}
+
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool, P: PoisonPolicy> RwLockReadGuard<'rwlock, T, L, FAIR, P> {
     /// Leak the lock guard, yielding a reference to the underlying data.
     ///
     /// Note that this function will permanently lock the original lock for all but reading locks.
@@ -503,28 +1089,107 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockReadGuard<'rwlock, T, L> {
     /// assert_eq!(*data, 0);
     /// ```
     #[inline]
-    pub fn leak(this: Self) -> &'rwlock T {
-        L::after_lock();
-        let Self { data, .. } = this;
+    pub fn leak(mut this: Self) -> &'rwlock T {
+        L::after_read(mem::take(&mut this.token));
+        let data = this.data;
+        // Forget the guard so its destructor doesn't release the lock
+        mem::forget(this);
         unsafe { &*data }
     }
+
+    /// Make a new read guard for a component of the locked data.
+    ///
+    /// The read lock is handed over to the returned guard, so it stays held until that guard is dropped.
+    ///
+    /// ```
+    /// let mylock = kernel_sync::RwLock::new([1, 2, 3]);
+    ///
+    /// let element = kernel_sync::RwLockReadGuard::map(mylock.read(), |data| &data[1]);
+    /// assert_eq!(*element, 2);
+    /// ```
+    #[inline]
+    pub fn map<U: ?Sized, F: FnOnce(&T) -> &U>(mut this: Self, f: F) -> RwLockReadGuard<'rwlock, U, L, FAIR, P> {
+        let lock = this.lock;
+        let read_cpu = this.read_cpu;
+        let token = mem::take(&mut this.token);
+        // Safety: we hold a read lock, and the returned guard takes it over.
+        let data = f(unsafe { &*this.data }) as *const U;
+        mem::forget(this);
+        RwLockReadGuard {
+            phantom: PhantomData,
+            lock,
+            owner: None,
+            read_cpu,
+            token,
+            data,
+        }
+    }
+
+    /// Turns the read guard into a write guard if it is the only reader of its lock, or hands it back otherwise.
+    ///
+    /// Calling [`RwLock::write`] while holding a read guard waits for that very guard and never returns; this
+    /// never waits. It fails if there are other readers or an upgradeable guard, and always for a guard made with
+    /// [`map`](Self::map), which no longer covers the whole data. Since the read lock is never released in
+    /// between, the data is still what the read guard saw.
+    ///
+    /// On a fair lock a waiting writer doesn't prevent it: the new write guard goes first and clears
+    /// `WRITER_WAITING`, which the waiting writer sets again on its next attempt, so new readers stay turned away
+    /// until it got its turn.
+    ///
+    /// ```
+    /// let lock = kernel_sync::RwLock::new(0);
+    ///
+    /// let reader = lock.read();
+    /// let other = lock.read();
+    /// let reader = reader.try_into_write().unwrap_err();
+    /// drop(other);
+    /// let mut writer = reader.try_into_write().unwrap();
+    /// *writer += 1;
+    /// ```
+    #[inline]
+    pub fn try_into_write(mut self) -> Result<RwLockWriteGuard<'rwlock, T, L, FAIR, P>, Self> {
+        let Some(lock) = self.owner else {
+            return Err(self);
+        };
+        if lock
+            .lock
+            .compare_exchange(READER | lock.writer_waiting(), WRITER, acquire::<L>(), Ordering::Relaxed)
+            .is_ok()
+        {
+            lock.begin_write();
+            self.read_cpu.release();
+            let token = L::convert_to_write(mem::take(&mut self.token));
+            // The hold continues in the write guard, which ends it with `after_write`
+            mem::forget(self);
+            Ok(RwLockWriteGuard {
+                phantom: PhantomData,
+                inner: lock,
+                token,
+                data: unsafe { &mut *lock.data.get() },
+            })
+        } else {
+            Err(self)
+        }
+    }
 }
 
-impl<'rwlock, T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for RwLockReadGuard<'rwlock, T, L> {
+impl<'rwlock, T: ?Sized + fmt::Debug, L: LockAction, const FAIR: bool, P: PoisonPolicy> fmt::Debug
+    for RwLockReadGuard<'rwlock, T, L, FAIR, P>
+{
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(&**self, f)
     }
 }
 
-impl<'rwlock, T: ?Sized + fmt::Display, L: LockAction> fmt::Display
-    for RwLockReadGuard<'rwlock, T, L>
+impl<'rwlock, T: ?Sized + fmt::Display, L: LockAction, const FAIR: bool, P: PoisonPolicy> fmt::Display
+    for RwLockReadGuard<'rwlock, T, L, FAIR, P>
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(&**self, f)
     }
 }
 
-impl<'rwlock, T: ?Sized, L: LockAction> RwLockUpgradableGuard<'rwlock, T, L> {
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool> RwLockUpgradableGuard<'rwlock, T, L, FAIR> {
     /// Upgrades an upgradeable lock guard to a writable lock guard.
     ///
     /// ```
@@ -534,32 +1199,34 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockUpgradableGuard<'rwlock, T, L> {
     /// let writable = upgradeable.upgrade();
     /// ```
     #[inline]
-    pub fn upgrade(mut self) -> RwLockWriteGuard<'rwlock, T, L> {
+    pub fn upgrade(mut self) -> RwLockWriteGuard<'rwlock, T, L, FAIR> {
         loop {
             self = match self.try_upgrade_internal(false) {
                 Ok(guard) => return guard,
                 Err(e) => e,
             };
 
-            core::hint::spin_loop();
+            L::relax();
         }
     }
 }
 
-impl<'rwlock, T: ?Sized, L: LockAction> RwLockUpgradableGuard<'rwlock, T, L> {
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool> RwLockUpgradableGuard<'rwlock, T, L, FAIR> {
     #[inline(always)]
-    fn try_upgrade_internal(self, strong: bool) -> Result<RwLockWriteGuard<'rwlock, T, L>, Self> {
+    fn try_upgrade_internal(mut self, strong: bool) -> Result<RwLockWriteGuard<'rwlock, T, L, FAIR>, Self> {
         if compare_exchange(
             &self.inner.lock,
-            UPGRADED,
+            UPGRADED | self.inner.writer_waiting(),
             WRITER,
-            Ordering::Acquire,
+            acquire::<L>(),
             Ordering::Relaxed,
             strong,
         )
         .is_ok()
         {
             let inner = self.inner;
+            inner.begin_write();
+            let token = mem::take(&mut self.token);
 
             // Forget the old guard so its destructor doesn't run (before mutably aliasing data below)
             mem::forget(self);
@@ -568,6 +1235,7 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockUpgradableGuard<'rwlock, T, L> {
             Ok(RwLockWriteGuard {
                 phantom: PhantomData,
                 inner,
+                token,
                 data: unsafe { &mut *inner.data.get() },
             })
         } else {
@@ -577,6 +1245,10 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockUpgradableGuard<'rwlock, T, L> {
 
     /// Tries to upgrade an upgradeable lock guard to a writable lock guard.
     ///
+    /// Unlike [`RwLockUpgradableGuard::upgrade`] this never waits for the remaining readers, so it can't spin
+    /// forever in a context that keeps them from running, e.g. with interrupts disabled. On failure the guard is
+    /// handed back still holding the lock, so the caller can retry later or drop it.
+    ///
     /// ```
     /// let mylock = kernel_sync::RwLock::new(0);
     /// let upgradeable = mylock.upgradeable_read(); // Readable, but not yet writable
@@ -587,7 +1259,7 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockUpgradableGuard<'rwlock, T, L> {
     /// };
     /// ```
     #[inline]
-    pub fn try_upgrade(self) -> Result<RwLockWriteGuard<'rwlock, T, L>, Self> {
+    pub fn try_upgrade(self) -> Result<RwLockWriteGuard<'rwlock, T, L, FAIR>, Self> {
         self.try_upgrade_internal(true)
     }
 
@@ -596,7 +1268,7 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockUpgradableGuard<'rwlock, T, L> {
     /// Unlike [`RwLockUpgradableGuard::try_upgrade`], this function is allowed to spuriously fail even when upgrading
     /// would otherwise succeed, which can result in more efficient code on some platforms.
     #[inline]
-    pub fn try_upgrade_weak(self) -> Result<RwLockWriteGuard<'rwlock, T, L>, Self> {
+    pub fn try_upgrade_weak(self) -> Result<RwLockWriteGuard<'rwlock, T, L, FAIR>, Self> {
         self.try_upgrade_internal(false)
     }
 
@@ -614,18 +1286,25 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockUpgradableGuard<'rwlock, T, L> {
     /// assert!(mylock.try_read().is_some());
     /// assert_eq!(*readable, 1);
     /// ```
-    pub fn downgrade(self) -> RwLockReadGuard<'rwlock, T, L> {
+    pub fn downgrade(mut self) -> RwLockReadGuard<'rwlock, T, L, FAIR> {
         // Reserve the read guard for ourselves
         self.inner.acquire_reader();
 
         let inner = self.inner;
 
-        // Dropping self removes the UPGRADED bit
-        mem::drop(self);
+        // Convert the token while the lock is still held, the read guard is released with `after_read`
+        let token = L::convert_to_read(mem::take(&mut self.token));
+        L::on_hold_end();
+        inner.lock.fetch_sub(UPGRADED, release::<L>());
+        mem::forget(self);
+        L::on_hold_start();
 
         RwLockReadGuard {
             phantom: Default::default(),
             lock: &inner.lock,
+            owner: Some(inner),
+            read_cpu: inner.track_reader(),
+            token,
             data: unsafe { &*inner.data.get() },
         }
     }
@@ -642,30 +1321,32 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockUpgradableGuard<'rwlock, T, L> {
     /// assert_eq!(*data, 0);
     /// ```
     #[inline]
-    pub fn leak(this: Self) -> &'rwlock T {
-        L::after_lock();
-        let Self { data, .. } = this;
+    pub fn leak(mut this: Self) -> &'rwlock T {
+        L::after_write(mem::take(&mut this.token));
+        let data = this.data;
+        // Forget the guard so its destructor doesn't release the lock
+        mem::forget(this);
         unsafe { &*data }
     }
 }
 
-impl<'rwlock, T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug
-    for RwLockUpgradableGuard<'rwlock, T, L>
+impl<'rwlock, T: ?Sized + fmt::Debug, L: LockAction, const FAIR: bool> fmt::Debug
+    for RwLockUpgradableGuard<'rwlock, T, L, FAIR>
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(&**self, f)
     }
 }
 
-impl<'rwlock, T: ?Sized + fmt::Display, L: LockAction> fmt::Display
-    for RwLockUpgradableGuard<'rwlock, T, L>
+impl<'rwlock, T: ?Sized + fmt::Display, L: LockAction, const FAIR: bool> fmt::Display
+    for RwLockUpgradableGuard<'rwlock, T, L, FAIR>
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(&**self, f)
     }
 }
 
-impl<'rwlock, T: ?Sized, L: LockAction> RwLockWriteGuard<'rwlock, T, L> {
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool> RwLockWriteGuard<'rwlock, T, L, FAIR> {
     /// Downgrades the writable lock guard to a readable, shared lock guard. Cannot fail and is guaranteed not to spin.
     ///
     /// ```
@@ -674,25 +1355,87 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockWriteGuard<'rwlock, T, L> {
     /// let mut writable = mylock.write();
     /// *writable = 1;
     ///
-    /// let readable = writable.downgrade(); // This is guaranteed not to spin
-    /// # let readable_2 = mylock.try_read().unwrap();
-    /// assert_eq!(*readable, 1);
+    /// let readable = writable.downgrade(); // This is guaranteed not to spin
+    /// # let readable_2 = mylock.try_read().unwrap();
+    /// assert_eq!(*readable, 1);
+    /// ```
+    #[inline]
+    pub fn downgrade(mut self) -> RwLockReadGuard<'rwlock, T, L, FAIR> {
+        // Reserve the read guard for ourselves
+        self.inner.acquire_reader();
+
+        let inner = self.inner;
+
+        // Convert the token while the lock is still held, the read guard is released with `after_read`
+        let token = L::convert_to_read(mem::take(&mut self.token));
+        // Removes the WRITER and UPGRADED bits
+        self.release();
+        mem::forget(self);
+        L::on_hold_start();
+
+        RwLockReadGuard {
+            phantom: PhantomData,
+            lock: &inner.lock,
+            owner: Some(inner),
+            read_cpu: inner.track_reader(),
+            token,
+            data: unsafe { &*inner.data.get() },
+        }
+    }
+
+    /// Downgrades the writable lock guard to `n` read guards, e.g. to hand read access to several workers after an
+    /// exclusive update. Cannot fail and is guaranteed not to spin.
+    ///
+    /// All read holds are taken before the write hold is released, so no writer can get in between. The guards are
+    /// released independently; a writer can take the lock again once all of them are dropped. With `n == 0` this
+    /// just releases the lock.
+    ///
+    /// ```
+    /// let mylock = kernel_sync::RwLock::new(0);
+    ///
+    /// let mut writable = mylock.write();
+    /// *writable = 1;
+    ///
+    /// let mut readers = writable.split_shared(2);
+    /// assert!(readers.iter().all(|reader| **reader == 1));
+    /// readers.pop();
+    /// assert!(mylock.try_write().is_none());
+    /// readers.pop();
+    /// assert!(mylock.try_write().is_some());
     /// ```
-    #[inline]
-    pub fn downgrade(self) -> RwLockReadGuard<'rwlock, T, L> {
-        // Reserve the read guard for ourselves
-        self.inner.acquire_reader();
+    pub fn split_shared(mut self, n: usize) -> Vec<RwLockReadGuard<'rwlock, T, L, FAIR>> {
+        // Reserve the read guards while we still hold the write lock
+        for _ in 0..n {
+            self.inner.acquire_reader();
+        }
 
+        if n == 0 {
+            return Vec::new();
+        }
         let inner = self.inner;
 
-        // Dropping self removes the UPGRADED bit
-        mem::drop(self);
+        // Every read guard is released with `after_read`. Our token is converted while the lock is still held and
+        // goes to the last guard, which dropping the `Vec` releases last, the others start their own read holds.
+        let last = L::convert_to_read(mem::take(&mut self.token));
+        let mut tokens: Vec<L::Guard> = (1..n).map(|_| L::before_read()).collect();
+        tokens.push(last);
+        self.release();
+        mem::forget(self);
 
-        RwLockReadGuard {
-            phantom: PhantomData,
-            lock: &inner.lock,
-            data: unsafe { &*inner.data.get() },
-        }
+        tokens
+            .into_iter()
+            .map(|token| {
+                L::on_hold_start();
+                RwLockReadGuard {
+                    phantom: PhantomData,
+                    lock: &inner.lock,
+                    owner: Some(inner),
+                    read_cpu: inner.track_reader(),
+                    token,
+                    data: unsafe { &*inner.data.get() },
+                }
+            })
+            .collect()
     }
 
     /// Downgrades the writable lock guard to an upgradable, shared lock guard. Cannot fail and is guaranteed not to spin.
@@ -707,16 +1450,19 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockWriteGuard<'rwlock, T, L> {
     /// assert_eq!(*readable, 1);
     /// ```
     #[inline]
-    pub fn downgrade_to_upgradeable(self) -> RwLockUpgradableGuard<'rwlock, T, L> {
+    pub fn downgrade_to_upgradeable(mut self) -> RwLockUpgradableGuard<'rwlock, T, L, FAIR> {
         debug_assert_eq!(
             self.inner.lock.load(Ordering::Acquire) & (WRITER | UPGRADED),
             WRITER
         );
 
-        // Reserve the read guard for ourselves
-        self.inner.lock.store(UPGRADED, Ordering::Release);
+        // Reserve the read guard for ourselves. Readers that are backing off and waiting writers keep their bits.
+        self.inner.lock.fetch_or(UPGRADED, acquire::<L>());
+        self.inner.end_write();
+        self.inner.lock.fetch_and(!WRITER, release::<L>());
 
         let inner = self.inner;
+        let token = mem::take(&mut self.token);
 
         // Dropping self removes the UPGRADED bit
         mem::forget(self);
@@ -724,6 +1470,7 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockWriteGuard<'rwlock, T, L> {
         RwLockUpgradableGuard {
             phantom: PhantomData,
             inner,
+            token,
             data: unsafe { &*inner.data.get() },
         }
     }
@@ -741,31 +1488,33 @@ impl<'rwlock, T: ?Sized, L: LockAction> RwLockWriteGuard<'rwlock, T, L> {
     /// assert_eq!(*data, 1);
     /// ```
     #[inline]
-    pub fn leak(this: Self) -> &'rwlock mut T {
-        L::after_lock();
+    pub fn leak(mut this: Self) -> &'rwlock mut T {
+        L::after_write(mem::take(&mut this.token));
         let data = this.data as *mut _; // Keep it in pointer form temporarily to avoid double-aliasing
         core::mem::forget(this);
         unsafe { &mut *data }
     }
 }
 
-impl<'rwlock, T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug
-    for RwLockWriteGuard<'rwlock, T, L>
+impl<'rwlock, T: ?Sized + fmt::Debug, L: LockAction, const FAIR: bool, P: PoisonPolicy> fmt::Debug
+    for RwLockWriteGuard<'rwlock, T, L, FAIR, P>
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(&**self, f)
     }
 }
 
-impl<'rwlock, T: ?Sized + fmt::Display, L: LockAction> fmt::Display
-    for RwLockWriteGuard<'rwlock, T, L>
+impl<'rwlock, T: ?Sized + fmt::Display, L: LockAction, const FAIR: bool, P: PoisonPolicy> fmt::Display
+    for RwLockWriteGuard<'rwlock, T, L, FAIR, P>
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(&**self, f)
     }
 }
 
-impl<'rwlock, T: ?Sized, L: LockAction> Deref for RwLockReadGuard<'rwlock, T, L> {
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool, P: PoisonPolicy> Deref
+    for RwLockReadGuard<'rwlock, T, L, FAIR, P>
+{
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -774,7 +1523,7 @@ impl<'rwlock, T: ?Sized, L: LockAction> Deref for RwLockReadGuard<'rwlock, T, L>
     }
 }
 
-impl<'rwlock, T: ?Sized, L: LockAction> Deref for RwLockUpgradableGuard<'rwlock, T, L> {
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool> Deref for RwLockUpgradableGuard<'rwlock, T, L, FAIR> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -783,7 +1532,9 @@ impl<'rwlock, T: ?Sized, L: LockAction> Deref for RwLockUpgradableGuard<'rwlock,
     }
 }
 
-impl<'rwlock, T: ?Sized, L: LockAction> Deref for RwLockWriteGuard<'rwlock, T, L> {
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool, P: PoisonPolicy> Deref
+    for RwLockWriteGuard<'rwlock, T, L, FAIR, P>
+{
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -792,43 +1543,203 @@ impl<'rwlock, T: ?Sized, L: LockAction> Deref for RwLockWriteGuard<'rwlock, T, L
     }
 }
 
-impl<'rwlock, T: ?Sized, L: LockAction> DerefMut for RwLockWriteGuard<'rwlock, T, L> {
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool, P: PoisonPolicy> DerefMut
+    for RwLockWriteGuard<'rwlock, T, L, FAIR, P>
+{
     fn deref_mut(&mut self) -> &mut T {
         // Safety: We know statically that only we are referencing data
         unsafe { &mut *self.data }
     }
 }
 
-impl<'rwlock, T: ?Sized, L: LockAction> Drop for RwLockReadGuard<'rwlock, T, L> {
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool, P: PoisonPolicy> Drop
+    for RwLockReadGuard<'rwlock, T, L, FAIR, P>
+{
     fn drop(&mut self) {
-        debug_assert!(self.lock.load(Ordering::Relaxed) & !(WRITER | UPGRADED) > 0);
-        self.lock.fetch_sub(READER, Ordering::Release);
-        L::after_lock();
+        debug_assert!(self.lock.load(Ordering::Relaxed) & !(WRITER | UPGRADED | WRITER_WAITING) > 0);
+        L::on_hold_end();
+        self.read_cpu.release();
+        self.lock.fetch_sub(READER, release::<L>());
+        L::after_read(mem::take(&mut self.token));
     }
 }
 
-impl<'rwlock, T: ?Sized, L: LockAction> Drop for RwLockUpgradableGuard<'rwlock, T, L> {
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool> Drop for RwLockUpgradableGuard<'rwlock, T, L, FAIR> {
     fn drop(&mut self) {
         debug_assert_eq!(
             self.inner.lock.load(Ordering::Relaxed) & (WRITER | UPGRADED),
             UPGRADED
         );
-        self.inner.lock.fetch_sub(UPGRADED, Ordering::AcqRel);
-        L::after_lock();
+        L::on_hold_end();
+        self.inner.lock.fetch_sub(UPGRADED, release::<L>());
+        L::after_write(mem::take(&mut self.token));
     }
 }
 
-impl<'rwlock, T: ?Sized, L: LockAction> Drop for RwLockWriteGuard<'rwlock, T, L> {
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool, P: PoisonPolicy> Drop
+    for RwLockWriteGuard<'rwlock, T, L, FAIR, P>
+{
     fn drop(&mut self) {
+        self.release();
+        L::after_write(mem::take(&mut self.token));
+    }
+}
+
+impl<'rwlock, T: ?Sized, L: LockAction, const FAIR: bool, P: PoisonPolicy> RwLockWriteGuard<'rwlock, T, L, FAIR, P> {
+    /// Give the write hold up, everything of dropping the guard except for `L::after_write`.
+    #[inline(always)]
+    fn release(&self) {
         debug_assert_eq!(self.inner.lock.load(Ordering::Relaxed) & WRITER, WRITER);
+        self.inner.poison.on_release();
 
         // Writer is responsible for clearing both WRITER and UPGRADED bits.
         // The UPGRADED bit may be set if an upgradeable lock attempts an upgrade while this lock is held.
+        L::on_hold_end();
+        self.inner.end_write();
         self.inner
             .lock
-            .fetch_and(!(WRITER | UPGRADED), Ordering::Release);
-        L::after_lock();
+            .fetch_and(!(WRITER | UPGRADED), release::<L>());
+    }
+}
+
+/// The orderings of the lock word of an [`RwLock`], picked per lock with [`RwLock::new_with_ordering`].
+///
+/// Besides the ones here, an implementation can combine any acquire ordering with any release ordering, e.g.
+/// `SeqCst` only for taking the lock. The rules of [`RwLock#orderings`] are checked at compile time.
+pub trait RwLockOrdering: Copy {
+    /// The ordering of the read-modify-writes that take a hold. Must be `Acquire`, `AcqRel` or `SeqCst`.
+    const ACQUIRE: Ordering;
+    /// The ordering of the read-modify-writes that give a hold up. Must be `Release`, `AcqRel` or `SeqCst`.
+    const RELEASE: Ordering;
+}
+
+/// `Acquire` to take the lock and `Release` to give it up, the orderings of [`RwLock::new`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AcquireReleaseOrdering;
+
+impl RwLockOrdering for AcquireReleaseOrdering {
+    const ACQUIRE: Ordering = Ordering::Acquire;
+    const RELEASE: Ordering = Ordering::Release;
+}
+
+/// `AcqRel` both to take and to give up the lock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AcqRelOrdering;
+
+impl RwLockOrdering for AcqRelOrdering {
+    const ACQUIRE: Ordering = Ordering::AcqRel;
+    const RELEASE: Ordering = Ordering::AcqRel;
+}
+
+/// `SeqCst` both to take and to give up the lock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeqCstOrdering;
+
+impl RwLockOrdering for SeqCstOrdering {
+    const ACQUIRE: Ordering = Ordering::SeqCst;
+    const RELEASE: Ordering = Ordering::SeqCst;
+}
+
+/// The action of a lock made by [`RwLock::new_with_ordering`]: `L` with the orderings of `O`.
+///
+/// Every hook is the one of `L`.
+pub struct Ordered<L, O>(PhantomData<(L, O)>);
+
+impl<L: LockAction, O: RwLockOrdering> LockAction for Ordered<L, O> {
+    type Guard = L::Guard;
+    const OPTIMISTIC_SPINS: usize = L::OPTIMISTIC_SPINS;
+    const OPTIMISTIC_READ_SPINS: usize = L::OPTIMISTIC_READ_SPINS;
+    const RWLOCK_ACQUIRE: Ordering = O::ACQUIRE;
+    const RWLOCK_RELEASE: Ordering = O::RELEASE;
+    fn before_lock() -> Self::Guard {
+        L::before_lock()
+    }
+    fn after_lock(guard: Self::Guard) {
+        L::after_lock(guard)
+    }
+    fn before_read() -> Self::Guard {
+        L::before_read()
+    }
+    fn after_read(guard: Self::Guard) {
+        L::after_read(guard)
+    }
+    fn before_write() -> Self::Guard {
+        L::before_write()
     }
+    fn after_write(guard: Self::Guard) {
+        L::after_write(guard)
+    }
+    fn convert_to_read(guard: Self::Guard) -> Self::Guard {
+        L::convert_to_read(guard)
+    }
+    fn convert_to_write(guard: Self::Guard) -> Self::Guard {
+        L::convert_to_write(guard)
+    }
+    const PINS_HOLDER: bool = L::PINS_HOLDER;
+    fn current_cpu() -> Option<usize> {
+        L::current_cpu()
+    }
+    fn current_node() -> usize {
+        L::current_node()
+    }
+    fn relax() {
+        L::relax()
+    }
+    fn on_spin() {
+        L::on_spin()
+    }
+    fn wait() {
+        L::wait()
+    }
+    fn notify() {
+        L::notify()
+    }
+    fn park(lock: usize, ticket: usize) {
+        L::park(lock, ticket)
+    }
+    fn unpark(lock: usize, ticket: usize) {
+        L::unpark(lock, ticket)
+    }
+    fn on_hold_start() {
+        L::on_hold_start()
+    }
+    fn on_hold_end() {
+        L::on_hold_end()
+    }
+}
+
+impl<L: LockActionSendMarker, O: RwLockOrdering> LockActionSendMarker for Ordered<L, O> {}
+
+/// The orderings of the lock word, checked once for every action the lock is used with.
+struct Orderings<L>(PhantomData<L>);
+
+impl<L: LockAction> Orderings<L> {
+    const ACQUIRE: Ordering = {
+        assert!(
+            matches!(L::RWLOCK_ACQUIRE, Ordering::Acquire | Ordering::AcqRel | Ordering::SeqCst),
+            "LockAction::RWLOCK_ACQUIRE must be Acquire, AcqRel or SeqCst"
+        );
+        L::RWLOCK_ACQUIRE
+    };
+    const RELEASE: Ordering = {
+        assert!(
+            matches!(L::RWLOCK_RELEASE, Ordering::Release | Ordering::AcqRel | Ordering::SeqCst),
+            "LockAction::RWLOCK_RELEASE must be Release, AcqRel or SeqCst"
+        );
+        L::RWLOCK_RELEASE
+    };
+}
+
+/// The ordering of the read-modify-writes that take a hold of the lock word.
+#[inline(always)]
+fn acquire<L: LockAction>() -> Ordering {
+    Orderings::<L>::ACQUIRE
+}
+
+/// The ordering of the read-modify-writes that give a hold of the lock word up.
+#[inline(always)]
+fn release<L: LockAction>() -> Ordering {
+    Orderings::<L>::RELEASE
 }
 
 #[inline(always)]
@@ -848,22 +1759,29 @@ fn compare_exchange(
 }
 
 #[cfg(feature = "lockapi")]
-unsafe impl<L: LockAction> lock_api::RawRwLock for RwLock<(), L> {
+unsafe impl<L: LockAction<Guard = ()>, const FAIR: bool> lock_api::RawRwLock for RwLock<(), L, FAIR> {
     #[allow(clippy::declare_interior_mutable_const)]
     const INIT: Self = Self::new(());
 
-    type GuardMarker = lock_api::GuardSend;
+    type GuardMarker = lock_api::GuardNoSend;
 
     #[inline(always)]
     fn lock_shared(&self) {
-        // Prevent guard destructor running
-        core::mem::forget(self.read());
+        // Prevent guard destructor running. `unlock_shared` can't tell which CPU locked, so don't record it.
+        let guard = self.read();
+        guard.read_cpu.release();
+        core::mem::forget(guard);
     }
 
     #[inline(always)]
     fn try_lock_shared(&self) -> bool {
         // Prevent guard destructor running
-        self.try_read().map(core::mem::forget).is_some()
+        self.try_read()
+            .map(|guard| {
+                guard.read_cpu.release();
+                core::mem::forget(guard)
+            })
+            .is_some()
     }
 
     #[inline(always)]
@@ -871,6 +1789,9 @@ unsafe impl<L: LockAction> lock_api::RawRwLock for RwLock<(), L> {
         drop(RwLockReadGuard {
             phantom: PhantomData::<L>,
             lock: &self.lock,
+            owner: Some(self),
+            read_cpu: self.untracked_reader(),
+            token: (),
             data: &(),
         });
     }
@@ -891,6 +1812,7 @@ unsafe impl<L: LockAction> lock_api::RawRwLock for RwLock<(), L> {
     unsafe fn unlock_exclusive(&self) {
         drop(RwLockWriteGuard {
             inner: self,
+            token: (),
             data: &mut (),
             phantom: PhantomData,
         });
@@ -898,12 +1820,12 @@ unsafe impl<L: LockAction> lock_api::RawRwLock for RwLock<(), L> {
 
     #[inline(always)]
     fn is_locked(&self) -> bool {
-        self.lock.load(Ordering::Relaxed) != 0
+        self.lock.load(Ordering::Relaxed) & !WRITER_WAITING != 0
     }
 }
 
 #[cfg(feature = "lockapi")]
-unsafe impl<L: LockAction> lock_api::RawRwLockUpgrade for RwLock<(), L> {
+unsafe impl<L: LockAction<Guard = ()>, const FAIR: bool> lock_api::RawRwLockUpgrade for RwLock<(), L, FAIR> {
     #[inline(always)]
     fn lock_upgradable(&self) {
         // Prevent guard destructor running
@@ -922,6 +1844,7 @@ unsafe impl<L: LockAction> lock_api::RawRwLockUpgrade for RwLock<(), L> {
     unsafe fn unlock_upgradable(&self) {
         drop(RwLockUpgradableGuard {
             inner: self,
+            token: (),
             data: &(),
             phantom: PhantomData,
         });
@@ -931,6 +1854,7 @@ unsafe impl<L: LockAction> lock_api::RawRwLockUpgrade for RwLock<(), L> {
     unsafe fn upgrade(&self) {
         let tmp_guard = RwLockUpgradableGuard {
             inner: self,
+            token: (),
             data: &(),
             phantom: PhantomData,
         };
@@ -941,6 +1865,7 @@ unsafe impl<L: LockAction> lock_api::RawRwLockUpgrade for RwLock<(), L> {
     unsafe fn try_upgrade(&self) -> bool {
         let tmp_guard = RwLockUpgradableGuard {
             inner: self,
+            token: (),
             data: &(),
             phantom: PhantomData,
         };
@@ -952,14 +1877,18 @@ unsafe impl<L: LockAction> lock_api::RawRwLockUpgrade for RwLock<(), L> {
 }
 
 #[cfg(feature = "lockapi")]
-unsafe impl<L: LockAction> lock_api::RawRwLockDowngrade for RwLock<(), L> {
+unsafe impl<L: LockAction<Guard = ()>, const FAIR: bool> lock_api::RawRwLockDowngrade for RwLock<(), L, FAIR> {
     unsafe fn downgrade(&self) {
         let tmp_guard = RwLockWriteGuard {
             inner: self,
+            token: (),
             data: &mut (),
             phantom: PhantomData,
         };
-        core::mem::forget(tmp_guard.downgrade());
+        // `unlock_shared` can't tell which CPU locked, so don't record it
+        let guard = tmp_guard.downgrade();
+        guard.read_cpu.release();
+        core::mem::forget(guard);
     }
 }
 #[cfg(test)]
@@ -972,7 +1901,7 @@ mod tests {
     use std::sync::Arc;
     use std::thread;
 
-    type RwLock<T> = crate::RwLock<T>;
+    type RwLock<T> = super::RwLock<T, crate::EmptyLockAction>;
 
     #[derive(Eq, PartialEq, Debug)]
     struct NonCopy(i32);
@@ -1087,6 +2016,14 @@ mod tests {
         assert_eq!(m.into_inner(), NonCopy(10));
     }
 
+    #[test]
+    fn test_leak() {
+        let lock: &'static RwLock<usize> = RwLock::leak(0);
+        let writer = thread::spawn(move || *lock.write() += 1);
+        writer.join().unwrap();
+        assert_eq!(*lock.read(), 1);
+    }
+
     #[test]
     fn test_into_inner_drop() {
         struct Foo(Arc<AtomicUsize>);
@@ -1158,4 +2095,823 @@ mod tests {
 
         assert!(m.try_upgradeable_read().unwrap().try_upgrade().is_ok());
     }
+
+    #[test]
+    fn test_guards_released_on_panic() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use std::sync::atomic::AtomicIsize;
+
+        static HELD: AtomicIsize = AtomicIsize::new(0);
+        struct CountingAction;
+        impl crate::LockAction for CountingAction {
+            type Guard = ();
+            fn before_lock() {
+                HELD.fetch_add(1, Ordering::SeqCst);
+            }
+            fn after_lock(_guard: ()) {
+                HELD.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        let lock = crate::rwlock::RwLock::<i32, CountingAction>::new(0);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = lock.write();
+            *guard += 1;
+            panic!("panic while holding the write guard");
+        }));
+        assert!(result.is_err());
+        assert_eq!(HELD.load(Ordering::SeqCst), 0);
+        assert_eq!(*lock.try_write().unwrap(), 1);
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let _guard = lock.read();
+            panic!("panic while holding a read guard");
+        }));
+        assert!(result.is_err());
+        assert_eq!(HELD.load(Ordering::SeqCst), 0);
+        assert!(lock.try_write().is_some());
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let _guard = lock.upgradeable_read();
+            panic!("panic while holding an upgradeable guard");
+        }));
+        assert!(result.is_err());
+        assert_eq!(HELD.load(Ordering::SeqCst), 0);
+        assert!(lock.try_write().is_some());
+        assert_eq!(HELD.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_read_map() {
+        use std::collections::BTreeMap;
+
+        let mut map = BTreeMap::new();
+        map.insert("hart", 4);
+        map.insert("page_size", 4096);
+        let lock = RwLock::new(map);
+        {
+            let page_size = lock.read_map(|map| &map["page_size"]);
+            assert_eq!(*page_size, 4096);
+            assert!(lock.try_write().is_none());
+            assert_eq!(lock.reader_count(), 1);
+        }
+        assert_eq!(lock.reader_count(), 0);
+        lock.write().insert("hart", 8);
+        assert_eq!(*lock.read_map(|map| &map["hart"]), 8);
+    }
+
+    #[test]
+    fn test_read_write_hooks() {
+        use std::sync::atomic::AtomicIsize;
+        use std::sync::Mutex;
+
+        static READS: AtomicIsize = AtomicIsize::new(0);
+        static WRITES: AtomicIsize = AtomicIsize::new(0);
+        static NEXT_TOKEN: AtomicUsize = AtomicUsize::new(0);
+        // Every hook call with the token it returned or received
+        static HOOKS: Mutex<Vec<(&str, usize)>> = Mutex::new(Vec::new());
+        fn hook(name: &'static str, token: usize) -> usize {
+            HOOKS.lock().unwrap().push((name, token));
+            token
+        }
+        fn hooks() -> Vec<(&'static str, usize)> {
+            core::mem::take(&mut *HOOKS.lock().unwrap())
+        }
+        struct SplitAction;
+        impl crate::LockAction for SplitAction {
+            type Guard = usize;
+            fn before_read() -> usize {
+                READS.fetch_add(1, Ordering::SeqCst);
+                hook("before_read", NEXT_TOKEN.fetch_add(1, Ordering::SeqCst))
+            }
+            fn after_read(token: usize) {
+                READS.fetch_sub(1, Ordering::SeqCst);
+                hook("after_read", token);
+            }
+            fn before_write() -> usize {
+                WRITES.fetch_add(1, Ordering::SeqCst);
+                hook("before_write", NEXT_TOKEN.fetch_add(1, Ordering::SeqCst))
+            }
+            fn after_write(token: usize) {
+                WRITES.fetch_sub(1, Ordering::SeqCst);
+                hook("after_write", token);
+            }
+        }
+
+        let lock = crate::rwlock::RwLock::<i32, SplitAction>::new(0);
+        let r = lock.read();
+        assert_eq!(READS.load(Ordering::SeqCst), 1);
+        assert_eq!(WRITES.load(Ordering::SeqCst), 0);
+        drop(r);
+        assert_eq!(READS.load(Ordering::SeqCst), 0);
+        assert_eq!(hooks(), [("before_read", 0), ("after_read", 0)]);
+
+        let w = lock.write();
+        assert_eq!(READS.load(Ordering::SeqCst), 0);
+        assert_eq!(WRITES.load(Ordering::SeqCst), 1);
+        let r = w.downgrade();
+        assert_eq!(READS.load(Ordering::SeqCst), 1);
+        assert_eq!(WRITES.load(Ordering::SeqCst), 0);
+        drop(r);
+        // The write token goes back to `after_write`, the read guard gets one of `before_read`
+        assert_eq!(hooks(), [("before_write", 1), ("after_write", 1), ("before_read", 2), ("after_read", 2)]);
+
+        let u = lock.upgradeable_read();
+        assert_eq!(WRITES.load(Ordering::SeqCst), 1);
+        let w = u.upgrade();
+        assert_eq!(WRITES.load(Ordering::SeqCst), 1);
+        let u = w.downgrade_to_upgradeable();
+        let r = u.downgrade();
+        assert_eq!(READS.load(Ordering::SeqCst), 1);
+        assert_eq!(WRITES.load(Ordering::SeqCst), 0);
+        drop(r);
+        assert_eq!(READS.load(Ordering::SeqCst), 0);
+        assert_eq!(WRITES.load(Ordering::SeqCst), 0);
+        // Upgrading and downgrading to upgradeable stay write holds and keep the token
+        assert_eq!(hooks(), [("before_write", 3), ("after_write", 3), ("before_read", 4), ("after_read", 4)]);
+
+        let r = lock.read();
+        let other = lock.read();
+        let r = r.try_into_write().unwrap_err();
+        drop(other);
+        let w = r.try_into_write().unwrap();
+        assert_eq!(READS.load(Ordering::SeqCst), 0);
+        assert_eq!(WRITES.load(Ordering::SeqCst), 1);
+        drop(w);
+        assert_eq!(WRITES.load(Ordering::SeqCst), 0);
+        // The failed attempt calls no hook
+        assert_eq!(
+            hooks(),
+            [
+                ("before_read", 5),
+                ("before_read", 6),
+                ("after_read", 6),
+                ("after_read", 5),
+                ("before_write", 7),
+                ("after_write", 7),
+            ]
+        );
+
+        let readers = lock.write().split_shared(2);
+        assert_eq!(READS.load(Ordering::SeqCst), 2);
+        assert_eq!(WRITES.load(Ordering::SeqCst), 0);
+        drop(readers);
+        assert_eq!(READS.load(Ordering::SeqCst), 0);
+        // The read hold converted from the write hold is released last
+        assert_eq!(
+            hooks(),
+            [
+                ("before_write", 8),
+                ("after_write", 8),
+                ("before_read", 9),
+                ("before_read", 10),
+                ("after_read", 10),
+                ("after_read", 9),
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lockapi")]
+    fn test_lock_api_rwlock() {
+        struct CpuAction;
+        impl crate::LockAction for CpuAction {
+            type Guard = ();
+            fn current_cpu() -> Option<usize> {
+                Some(0)
+            }
+        }
+        type LockApiRwLock<T> = lock_api::RwLock<super::RwLock<(), CpuAction>, T>;
+
+        let lock = LockApiRwLock::new(0);
+        *lock.write() += 1;
+        {
+            let (a, b) = (lock.read(), lock.read());
+            assert_eq!(*a + *b, 2);
+            assert!(lock.try_write().is_none());
+        }
+        let mut mapped = lock_api::RwLockWriteGuard::map(lock.write(), |value| value);
+        *mapped += 1;
+        drop(mapped);
+
+        let upgradable = lock.upgradable_read();
+        assert!(lock.try_upgradable_read().is_none());
+        let mut writer = lock_api::RwLockUpgradableReadGuard::upgrade(upgradable);
+        *writer += 1;
+        let reader = lock_api::RwLockWriteGuard::downgrade(writer);
+        assert_eq!(*reader, 3);
+        assert!(lock.try_read().is_some());
+        drop(reader);
+        // No read CPU may stay recorded, or a later contended write on this CPU would report a deadlock
+        #[cfg(debug_assertions)]
+        assert_eq!(unsafe { lock.raw() }.read_cpus.load(Ordering::Relaxed), 0);
+        *lock.write() += 1;
+        assert!(!lock.is_locked());
+        assert_eq!(lock.into_inner(), 4);
+    }
+
+    #[test]
+    fn test_lock_all_sorted() {
+        let locks: Arc<Vec<RwLock<usize>>> = Arc::new((0..4).map(RwLock::new).collect());
+
+        // Duplicates are locked once, and the guards follow the input order
+        let guards = super::lock_all_sorted(&[&locks[2], &locks[0], &locks[2], &locks[1]]);
+        assert_eq!(guards.iter().map(|guard| **guard).collect::<Vec<_>>(), [2, 0, 1]);
+        assert!(locks[0].try_read().is_none());
+        assert!(locks[3].try_read().is_some());
+        drop(guards);
+        assert!(super::lock_all_sorted::<usize, crate::EmptyLockAction, false>(&[]).is_empty());
+
+        // Two threads locking overlapping sets in opposite orders must not deadlock
+        let (tx, rx) = channel();
+        for forward in [true, false] {
+            let (locks, tx) = (locks.clone(), tx.clone());
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    let mut set = [&locks[0], &locks[1], &locks[2], &locks[3], &locks[1]];
+                    if !forward {
+                        set.reverse();
+                    }
+                    for mut guard in super::lock_all_sorted(&set) {
+                        *guard += 1;
+                        thread::yield_now();
+                    }
+                }
+                tx.send(()).unwrap();
+            });
+        }
+        drop(tx);
+        for _ in 0..2 {
+            rx.recv_timeout(std::time::Duration::from_secs(60)).expect("lock_all_sorted deadlocked");
+        }
+        assert_eq!(locks.iter().map(|lock| *lock.read()).collect::<Vec<_>>(), [2000, 2001, 2002, 2003]);
+    }
+
+    #[test]
+    fn test_reader_count_and_writer_state() {
+        let m = RwLock::new(());
+        let readers: Vec<_> = (0..3).map(|_| m.read()).collect();
+        assert_eq!(m.reader_count(), 3);
+        assert!(!m.is_writer_active());
+        drop(readers);
+        assert_eq!(m.reader_count(), 0);
+
+        let w = m.write();
+        assert!(m.is_writer_active());
+        assert_eq!(m.reader_count(), 0);
+        // A failed attempt leaves the UPGRADED bit behind, which is no reader
+        assert!(m.try_upgradeable_read().is_none());
+        assert_eq!(m.reader_count(), 0);
+        drop(w);
+
+        let u = m.write().downgrade_to_upgradeable();
+        assert!(!m.is_writer_active());
+        assert_eq!(m.reader_count(), 1);
+        drop(u);
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn test_try_upgrade_keeps_hold_on_failure() {
+        let m = Arc::new(RwLock::new(0));
+        *m.upgradeable_read().try_upgrade().unwrap() += 1;
+        assert!(!m.is_locked());
+
+        let (taken_tx, taken_rx) = channel();
+        let (release_tx, release_rx) = channel::<()>();
+        let other = m.clone();
+        let reader = thread::spawn(move || {
+            let r = other.read();
+            taken_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+            drop(r);
+        });
+        taken_rx.recv().unwrap();
+
+        let u = m.upgradeable_read();
+        let u = u.try_upgrade().unwrap_err();
+        let u = u.try_upgrade_weak().unwrap_err();
+        // Still the upgradeable holder: no new readers or upgradeable guards, and the counts are unchanged
+        assert!(m.upgradable_held());
+        assert_eq!(m.reader_count(), 2);
+        assert!(m.try_read().is_none());
+        assert!(m.try_upgradeable_read().is_none());
+        assert_eq!(*u, 1);
+
+        release_tx.send(()).unwrap();
+        reader.join().unwrap();
+        let mut w = u.try_upgrade().unwrap();
+        *w += 1;
+        drop(w);
+        assert!(!m.is_locked());
+        assert_eq!(*m.read(), 2);
+    }
+
+    #[test]
+    fn test_downgrade_keeps_writers_out() {
+        let m = Arc::new(RwLock::new(0));
+        let mut w = m.write();
+        *w = 1;
+        let r = w.downgrade();
+        assert_eq!(m.reader_count(), 1);
+        assert_eq!(m.writer_count(), 0);
+
+        let other = m.clone();
+        thread::spawn(move || {
+            assert!(other.try_write().is_none());
+            let r = other.read();
+            assert_eq!(*r, 1);
+            assert_eq!(other.reader_count(), 2);
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(*r, 1);
+        drop(r);
+        assert_eq!(m.reader_count(), 0);
+        assert!(m.try_write().is_some());
+    }
+
+    #[test]
+    fn test_read_try_into_write() {
+        let m = RwLock::new(0);
+        let r = m.read();
+        let mut w = r.try_into_write().unwrap();
+        *w += 1;
+        assert!(m.try_read().is_none());
+        drop(w);
+        assert!(!m.is_locked());
+
+        let r = m.read();
+        let other = m.read();
+        let r = r.try_into_write().unwrap_err();
+        assert_eq!(*r, 1);
+        assert_eq!(m.reader_count(), 2);
+        drop(other);
+        let u = m.try_upgradeable_read().unwrap();
+        let r = r.try_into_write().unwrap_err();
+        drop(u);
+        let w = r.try_into_write().unwrap();
+        assert_eq!(m.writer_count(), 1);
+        drop(w);
+        assert!(!m.is_locked());
+        assert_eq!(*m.read(), 1);
+    }
+
+    #[test]
+    fn test_mapped_read_try_into_write() {
+        let m = RwLock::new((1, 2));
+        let r = super::RwLockReadGuard::map(m.read(), |data| &data.0);
+        let r = r.try_into_write().unwrap_err();
+        assert_eq!(*r, 1);
+        drop(r);
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn test_fair_read_try_into_write_with_waiting_writer() {
+        let lock = Arc::new(crate::rwlock::RwLock::<_, crate::EmptyLockAction, true>::new(0));
+        let reader = lock.read();
+        let lock2 = lock.clone();
+        let writer = thread::spawn(move || *lock2.write() += 10);
+        while lock.lock.load(Ordering::Relaxed) & super::WRITER_WAITING == 0 {
+            thread::yield_now();
+        }
+
+        // The upgrade goes ahead of the waiting writer, which marks itself waiting again
+        let mut w = reader.try_into_write().unwrap();
+        *w += 1;
+        while lock.lock.load(Ordering::Relaxed) & super::WRITER_WAITING == 0 {
+            thread::yield_now();
+        }
+        drop(w);
+
+        writer.join().unwrap();
+        assert_eq!(*lock.read(), 11);
+    }
+
+    #[test]
+    fn test_upgradable_held() {
+        let m = RwLock::new(());
+        assert!(!m.upgradable_held());
+        {
+            let _r = m.read();
+            let _u = m.upgradeable_read();
+            assert!(m.upgradable_held());
+        }
+        assert!(!m.upgradable_held());
+        {
+            let _w = m.write();
+            // A failed attempt sets the UPGRADED bit until the writer releases.
+            assert!(m.try_upgradeable_read().is_none());
+            assert!(!m.upgradable_held());
+        }
+        assert!(!m.upgradable_held());
+    }
+
+    #[test]
+    fn test_write_if() {
+        let m = RwLock::new(Some(1));
+        {
+            let w = m.write_if(|data| data.is_some());
+            assert!(w.is_some());
+            assert!(m.try_read().is_none());
+            *w.unwrap() = None;
+        }
+        assert!(m.write_if(|data| data.is_some()).is_none());
+        // A failed predicate leaves the lock free
+        assert!(m.try_write().is_some());
+        assert_eq!(*m.read(), None);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "upgradeable_read")]
+    fn test_write_while_reading_panics() {
+        struct SingleCpuAction;
+        impl crate::LockAction for SingleCpuAction {
+            type Guard = ();
+            const PINS_HOLDER: bool = true;
+            fn current_cpu() -> Option<usize> {
+                Some(3)
+            }
+        }
+
+        let m = crate::rwlock::RwLock::<_, SingleCpuAction>::new(0);
+        // Releasing every read guard, nested and mapped ones included, lets this CPU write again
+        let outer = m.read();
+        let inner = crate::rwlock::RwLockReadGuard::map(m.read(), |data| data);
+        drop(inner);
+        drop(outer);
+        *m.write() += 1;
+
+        let _reader = m.read();
+        let _writer = m.write();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_fair_write_while_reading_panics_readable() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        struct SingleCpuAction;
+        impl crate::LockAction for SingleCpuAction {
+            type Guard = ();
+            const PINS_HOLDER: bool = true;
+            fn current_cpu() -> Option<usize> {
+                Some(3)
+            }
+        }
+
+        let m = crate::rwlock::RwLock::<_, SingleCpuAction, true>::new(0);
+        let reader = m.read();
+        assert!(catch_unwind(AssertUnwindSafe(|| drop(m.write()))).is_err());
+        // The panicking writer must not leave new readers turned away
+        assert_eq!(*m.try_read().unwrap(), 0);
+        assert_eq!(*m.read(), 0);
+        drop(reader);
+        *m.write() += 1;
+        assert_eq!(*m.read(), 1);
+    }
+
+    #[test]
+    fn test_optimistic_read_not_torn() {
+        let lock = Arc::new(RwLock::new((0u64, 0u64)));
+        let done = Arc::new(AtomicUsize::new(0));
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let lock = lock.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    while done.load(Ordering::Relaxed) == 0 {
+                        let (a, b) = lock.optimistic_read();
+                        assert_eq!(a, b);
+                        assert!(a >= last);
+                        last = a;
+                    }
+                })
+            })
+            .collect();
+        for i in 1..=10000 {
+            let mut guard = lock.write();
+            guard.0 = i;
+            guard.1 = i;
+            if i % 100 == 0 {
+                // Every write path must keep the sequence number in step
+                let upgradeable = guard.downgrade_to_upgradeable();
+                drop(upgradeable.upgrade().downgrade());
+            }
+        }
+        done.store(1, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(lock.optimistic_read(), (10000, 10000));
+    }
+
+    #[test]
+    fn test_write_while_reading_elsewhere_waits() {
+        // Both threads report CPU 3, like a task that was preempted, or moved to another CPU, while it holds a read
+        // guard. The writer must wait for it instead of panicking.
+        struct SharedCpuAction;
+        impl crate::LockAction for SharedCpuAction {
+            type Guard = ();
+            fn current_cpu() -> Option<usize> {
+                Some(3)
+            }
+            fn relax() {
+                thread::yield_now();
+            }
+        }
+        impl crate::LockActionSendMarker for SharedCpuAction {}
+
+        let lock = Arc::new(crate::rwlock::RwLock::<_, SharedCpuAction>::new(0));
+        let reader = lock.read();
+        let lock2 = lock.clone();
+        let writer = thread::spawn(move || *lock2.write() += 1);
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!writer.is_finished());
+        // A guard that was sent to another thread doesn't count for this one either
+        let reader = thread::scope(|s| s.spawn(move || reader).join().unwrap());
+        assert_eq!(*reader, 0);
+        drop(reader);
+        writer.join().unwrap();
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn test_get_mut_leaves_lock_consistent() {
+        struct SingleCpuAction;
+        impl crate::LockAction for SingleCpuAction {
+            type Guard = ();
+            const PINS_HOLDER: bool = true;
+            fn current_cpu() -> Option<usize> {
+                Some(0)
+            }
+        }
+
+        let mut lock = crate::rwlock::RwLock::<_, SingleCpuAction>::new(0);
+        drop(lock.read());
+        drop(lock.upgradeable_read().upgrade().downgrade());
+        *lock.write() += 1;
+        let max_write_wait = lock.max_write_wait();
+        *lock.get_mut() += 1;
+        assert!(!lock.is_locked());
+        assert_eq!((lock.reader_count(), lock.writer_count()), (0, 0));
+        assert!(!lock.upgradable_held());
+        assert_eq!(lock.max_write_wait(), max_write_wait);
+        assert_eq!(lock.optimistic_read(), 2);
+        // No stale reader CPU either: writing on CPU 0 doesn't trip the debug check
+        *lock.write() += 1;
+        assert_eq!(lock.into_inner(), 3);
+    }
+
+    #[test]
+    fn test_split_shared() {
+        // Guards are only Send with an action that allows it
+        let lock = Arc::new(super::RwLock::<_, crate::EmptyLockAction>::new(0));
+        let mut guard = lock.write();
+        *guard = 1;
+        let mut readers = guard.split_shared(3);
+        assert_eq!(readers.len(), 3);
+        assert!(lock.try_upgradeable_read().is_some());
+
+        let (tx, rx) = channel();
+        let lock2 = lock.clone();
+        let writer = thread::spawn(move || {
+            *lock2.write() += 1;
+            tx.send(()).unwrap();
+        });
+        let last = readers.pop().unwrap();
+        // Read guards can be handed to other threads and dropped there
+        thread::scope(|s| {
+            s.spawn(move || drop(readers));
+        });
+        assert_eq!(*last, 1);
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+        drop(last);
+        rx.recv().unwrap();
+        writer.join().unwrap();
+        assert_eq!(*lock.read(), 2);
+        assert!(lock.write().split_shared(0).is_empty());
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn test_unfair_readers_pass_waiting_writer() {
+        let lock = Arc::new(RwLock::new(0));
+        let reader = lock.read();
+        let lock2 = lock.clone();
+        let writer = thread::spawn(move || *lock2.write() += 1);
+        thread::sleep(std::time::Duration::from_millis(50));
+        // The writer is waiting by now, but an unfair lock still lets new readers in
+        for _ in 0..100 {
+            assert_eq!(*lock.try_read().unwrap(), 0);
+        }
+        drop(reader);
+        writer.join().unwrap();
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn test_fair_writer_not_starved() {
+        fair_writer_not_starved::<crate::EmptyLockAction>();
+    }
+
+    /// Polls for a long time before relaxing while a writer holds the lock
+    struct EagerReadAction;
+    impl crate::LockAction for EagerReadAction {
+        type Guard = ();
+        const OPTIMISTIC_READ_SPINS: usize = 100_000;
+        fn relax() {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_optimistic_read_keeps_fairness() {
+        fair_writer_not_starved::<EagerReadAction>();
+    }
+
+    #[test]
+    fn test_optimistic_read_sees_finished_writes() {
+        let lock = Arc::new(crate::rwlock::RwLock::<_, EagerReadAction>::new((0, 0)));
+        let lock2 = lock.clone();
+        let writer = thread::spawn(move || {
+            for i in 1..=1000 {
+                let mut guard = lock2.write();
+                guard.0 = i;
+                guard.1 = 2 * i;
+            }
+        });
+        let mut last = 0;
+        while last < 1000 {
+            let guard = lock.read();
+            assert_eq!(guard.1, 2 * guard.0);
+            assert!(guard.0 >= last);
+            last = guard.0;
+        }
+        writer.join().unwrap();
+    }
+
+    fn fair_writer_not_starved<L: crate::LockAction + 'static>() {
+        let lock = Arc::new(crate::rwlock::RwLock::<_, L, true>::new(0));
+
+        // A waiting writer turns new readers away
+        let reader = lock.read();
+        let lock2 = lock.clone();
+        let writer = thread::spawn(move || *lock2.write() += 1);
+        while lock.try_read().is_some() {
+            thread::yield_now();
+        }
+        // ... and new upgradeable readers too, without keeping the writer out
+        assert!(lock.try_upgradeable_read().is_none());
+        drop(reader);
+        writer.join().unwrap();
+        *lock.write() -= 1;
+
+        let stop = Arc::new(AtomicUsize::new(0));
+        // Two readers that always overlap, so the lock is never free of readers
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let lock = lock.clone();
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    let mut guard = lock.read();
+                    while stop.load(Ordering::Relaxed) == 0 {
+                        thread::yield_now();
+                        let next = lock.try_read();
+                        drop(guard);
+                        guard = match next {
+                            Some(next) => next,
+                            None => lock.read(),
+                        };
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..10 {
+            *lock.write() += 1;
+        }
+        stop.store(1, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(*lock.read(), 10);
+    }
+
+    #[test]
+    fn test_max_write_wait() {
+        let lock = Arc::new(crate::rwlock::RwLock::<_, crate::EmptyLockAction>::new(0usize));
+        drop(lock.write());
+        assert_eq!(lock.max_write_wait(), 0);
+
+        // Keep the lock read-locked so the writer has to spin.
+        let reader = lock.read();
+        let lock2 = lock.clone();
+        let writer = thread::spawn(move || {
+            *lock2.write() += 1;
+        });
+        thread::sleep(std::time::Duration::from_millis(50));
+        drop(reader);
+        writer.join().unwrap();
+        assert!(lock.max_write_wait() > 0);
+
+        // A writer of the fair lock only waits for the readers already inside, however many more keep arriving.
+        // Every spin of the writer lets one of them leave and has a new one try to get in, so it waits for exactly
+        // as many spins as there were readers.
+        const READERS: usize = 4;
+        struct DrainAction;
+        impl crate::LockAction for DrainAction {
+            type Guard = ();
+            fn relax() {
+                assert!(FAIR_LOCK.try_read().is_none(), "a reader got past the waiting writer");
+                HELD.with(|held| drop(held.borrow_mut().pop()));
+            }
+        }
+        static FAIR_LOCK: crate::rwlock::RwLock<usize, DrainAction, true> = crate::rwlock::RwLock::new(0);
+        std::thread_local! {
+            static HELD: core::cell::RefCell<Vec<super::RwLockReadGuard<'static, usize, DrainAction, true>>> =
+                const { core::cell::RefCell::new(Vec::new()) };
+        }
+        HELD.with(|held| held.borrow_mut().extend((0..READERS).map(|_| FAIR_LOCK.read())));
+        *FAIR_LOCK.write() += 1;
+        assert_eq!(HELD.with(|held| held.borrow().len()), 0);
+        assert_eq!(*FAIR_LOCK.read(), 1);
+        assert_eq!(FAIR_LOCK.max_write_wait(), READERS);
+    }
+
+    #[test]
+    fn test_orderings_exclude() {
+        use super::{AcqRelOrdering, AcquireReleaseOrdering, SeqCstOrdering};
+
+        #[derive(Clone, Copy)]
+        struct SeqCstAcquire;
+        impl super::RwLockOrdering for SeqCstAcquire {
+            const ACQUIRE: Ordering = Ordering::SeqCst;
+            const RELEASE: Ordering = Ordering::Release;
+        }
+        type Lock<L> = crate::rwlock::RwLock<(usize, usize), L>;
+        mutual_exclusion_with(Lock::<crate::EmptyLockAction>::new((0, 0)));
+        mutual_exclusion_with(Lock::<crate::EmptyLockAction>::new_with_ordering((0, 0), AcquireReleaseOrdering));
+        mutual_exclusion_with(Lock::<crate::EmptyLockAction>::new_with_ordering((0, 0), AcqRelOrdering));
+        mutual_exclusion_with(Lock::<crate::EmptyLockAction>::new_with_ordering((0, 0), SeqCstOrdering));
+        mutual_exclusion_with(Lock::<crate::EmptyLockAction>::new_with_ordering((0, 0), SeqCstAcquire));
+    }
+
+    #[test]
+    fn test_new_with_ordering_is_per_lock() {
+        use super::{Ordered, SeqCstOrdering};
+        use crate::EmptyLockAction;
+
+        fn orderings<L: crate::LockAction>(_: &crate::rwlock::RwLock<u8, L>) -> (Ordering, Ordering) {
+            (super::acquire::<L>(), super::release::<L>())
+        }
+        let plain = crate::rwlock::RwLock::<u8, EmptyLockAction>::new(0);
+        let strict: crate::rwlock::RwLock<u8, Ordered<EmptyLockAction, SeqCstOrdering>> =
+            crate::rwlock::RwLock::<_, EmptyLockAction>::new_with_ordering(0, SeqCstOrdering);
+        assert_eq!(orderings(&plain), (Ordering::Acquire, Ordering::Release));
+        assert_eq!(orderings(&strict), (Ordering::SeqCst, Ordering::SeqCst));
+        assert_eq!(core::mem::size_of_val(&strict), core::mem::size_of_val(&plain));
+    }
+
+    // Readers and writers, some going through the upgradeable path, must never overlap with a writer. The writers
+    // update both halves of the pair one after the other, so a reader that gets in while one writes sees them differ.
+    fn mutual_exclusion_with<L: crate::LockAction + 'static>(lock: crate::rwlock::RwLock<(usize, usize), L>) {
+        let lock = Arc::new(lock);
+        let readers_in = Arc::new(AtomicUsize::new(0));
+        let writers_in = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..6)
+            .map(|i| {
+                let lock = lock.clone();
+                let readers_in = readers_in.clone();
+                let writers_in = writers_in.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        if i % 2 == 0 {
+                            let guard = lock.read();
+                            readers_in.fetch_add(1, Ordering::SeqCst);
+                            assert_eq!(writers_in.load(Ordering::SeqCst), 0);
+                            assert_eq!(guard.0, guard.1);
+                            readers_in.fetch_sub(1, Ordering::SeqCst);
+                        } else {
+                            let mut guard = if i == 1 { lock.upgradeable_read().upgrade() } else { lock.write() };
+                            assert_eq!(writers_in.fetch_add(1, Ordering::SeqCst), 0);
+                            assert_eq!(readers_in.load(Ordering::SeqCst), 0);
+                            guard.0 += 1;
+                            thread::yield_now();
+                            guard.1 += 1;
+                            writers_in.fetch_sub(1, Ordering::SeqCst);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.read(), (600, 600));
+    }
 }
@@ -0,0 +1,121 @@
+//! A sequence lock for small `Copy` data that is read far more often than it is written, e.g. the current time.
+//!
+//! Readers never write to shared memory: they copy the data and check a sequence number that writers make odd
+//! while they write, taking the copy again if a writer got in. Writers are serialized by a [`SpinMutex`] and
+//! never wait for readers, so readers can't starve them; a steady stream of writers can keep readers retrying
+//! instead.
+use crate::spin::SpinMutex;
+use crate::LockAction;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+/// A `Copy` value that readers copy out without taking a lock.
+///
+/// # Example
+///
+/// ```
+/// use kernel_sync::{seqlock::SeqLock, EmptyLockAction};
+///
+/// static CLOCK: SeqLock<(u64, u64), EmptyLockAction> = SeqLock::new((0, 0));
+///
+/// CLOCK.write((1, 500_000_000));
+/// let (secs, nanos) = CLOCK.read();
+/// assert_eq!(secs * 1_000_000_000 + nanos, 1_500_000_000);
+/// ```
+pub struct SeqLock<T: Copy, L: LockAction> {
+    /// Odd while a writer writes the data, bumped before and after every write.
+    seq: AtomicUsize,
+    writer: SpinMutex<(), L>,
+    data: UnsafeCell<T>,
+}
+
+// Readers copy the value out on any CPU, writers copy it in
+unsafe impl<T: Copy + Send, L: LockAction> Send for SeqLock<T, L> {}
+unsafe impl<T: Copy + Send, L: LockAction> Sync for SeqLock<T, L> {}
+
+impl<T: Copy, L: LockAction> SeqLock<T, L> {
+    /// Creates a new [`SeqLock`] containing `value`.
+    #[inline(always)]
+    pub const fn new(value: T) -> Self {
+        SeqLock {
+            seq: AtomicUsize::new(0),
+            writer: SpinMutex::new(()),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns a copy of the value, never one torn by a concurrent [`SeqLock::write`].
+    ///
+    /// Spins, calling `L::relax`, while a writer writes.
+    #[inline]
+    pub fn read(&self) -> T {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 == 0 {
+                // Safety: the copy may race with a writer, in which case `seq` has changed by the time we check it
+                // below and the torn copy is never used. `T: Copy`, so dropping it is a no-op.
+                let value = unsafe { core::ptr::read_volatile(self.data.get()) };
+                // Keep the copy before the second load of `seq`
+                fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == seq {
+                    return value;
+                }
+            }
+            L::relax();
+        }
+    }
+
+    /// Stores `value`. Writers wait for each other, but not for readers.
+    #[inline]
+    pub fn write(&self, value: T) {
+        let _writer = self.writer.lock();
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        // Keep the odd sequence number before the data, so a reader that sees any of the new data sees it too
+        fence(Ordering::Release);
+        // Safety: we hold the writer lock, and readers throw away what they copied meanwhile
+        unsafe { core::ptr::write_volatile(self.data.get(), value) };
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Returns how many times the value has been written.
+    #[inline]
+    pub fn writes(&self) -> usize {
+        self.seq.load(Ordering::Relaxed) / 2
+    }
+
+    /// Returns a mutable reference to the value.
+    ///
+    /// Since this call borrows the [`SeqLock`] mutably, no locking needs to take place.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Consumes this [`SeqLock`] and returns the value.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: Copy + fmt::Debug, L: LockAction> fmt::Debug for SeqLock<T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SeqLock").field("value", &self.read()).finish()
+    }
+}
+
+impl<T: Copy + Default, L: LockAction> Default for SeqLock<T, L> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Copy, L: LockAction> From<T> for SeqLock<T, L> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
@@ -0,0 +1,124 @@
+//! CPU-id dependent primitives.
+//!
+//! The locks only learn about the CPU topology through [`LockAction::current_cpu`] and
+//! [`LockAction::current_node`]. Both have single-core defaults: the CPU is unknown, which [`cpu_id`] treats as
+//! CPU 0, and every CPU is on node 0. A kernel wires them to its topology in its own lock action, typically by
+//! reading the hart id it keeps in `tp` or a per-CPU area:
+//!
+//! ```
+//! use kernel_sync::LockAction;
+//!
+//! # fn hart_id() -> usize { 0 }
+//! # fn node_of(hart: usize) -> usize { hart / 4 }
+//! pub struct KernelLockAction;
+//! impl LockAction for KernelLockAction {
+//!     type Guard = ();
+//!     fn current_cpu() -> Option<usize> {
+//!         Some(hart_id())
+//!     }
+//!     fn current_node() -> usize {
+//!         node_of(hart_id())
+//!     }
+//! }
+//! ```
+//!
+//! The ids may change as soon as the caller is preempted and migrated, so they are hints for picking per-CPU
+//! data, not proof of exclusive access: [`PerCpu`] only hands out shared references.
+use crate::LockAction;
+use core::marker::PhantomData;
+
+/// Returns the CPU the caller runs on according to `L`, or 0 if `L` doesn't know.
+#[inline(always)]
+pub fn cpu_id<L: LockAction>() -> usize {
+    L::current_cpu().unwrap_or(0)
+}
+
+/// Returns the NUMA node the caller runs on according to `L`.
+#[inline(always)]
+pub fn node_id<L: LockAction>() -> usize {
+    L::current_node()
+}
+
+/// One `T` per CPU, selected by [`cpu_id`].
+///
+/// Each CPU normally works on its own slot, so for example per-CPU counters don't bounce a cache line between
+/// CPUs. Since the caller may be migrated at any time, `T` still has to be safe to share, e.g. atomics or locks.
+///
+/// # Example
+///
+/// ```
+/// use core::sync::atomic::{AtomicUsize, Ordering};
+/// use kernel_sync::{smp::PerCpu, EmptyLockAction};
+///
+/// let counters = PerCpu::<AtomicUsize, EmptyLockAction, 4>::default();
+/// counters.get().fetch_add(1, Ordering::Relaxed);
+/// let total: usize = counters.iter().map(|c| c.load(Ordering::Relaxed)).sum();
+/// assert_eq!(total, 1);
+/// ```
+pub struct PerCpu<T, L: LockAction, const CPUS: usize> {
+    phantom: PhantomData<L>,
+    slots: [T; CPUS],
+}
+
+// `L` is only used to find the current CPU
+unsafe impl<T: Send, L: LockAction, const CPUS: usize> Send for PerCpu<T, L, CPUS> {}
+unsafe impl<T: Sync, L: LockAction, const CPUS: usize> Sync for PerCpu<T, L, CPUS> {}
+
+impl<T, L: LockAction, const CPUS: usize> PerCpu<T, L, CPUS> {
+    /// Creates a new [`PerCpu`] with one slot per element of `data`.
+    pub const fn new(data: [T; CPUS]) -> Self {
+        PerCpu {
+            phantom: PhantomData,
+            slots: data,
+        }
+    }
+
+    /// Returns the slot of the current CPU.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current CPU id is not below `CPUS`.
+    #[inline(always)]
+    pub fn get(&self) -> &T {
+        let cpu = cpu_id::<L>();
+        self.slots
+            .get(cpu)
+            .unwrap_or_else(|| panic!("CPU {cpu} out of range for PerCpu with {CPUS} slots"))
+    }
+
+    /// Returns the slot of `cpu`, or `None` if it is out of range.
+    #[inline(always)]
+    pub fn get_for(&self, cpu: usize) -> Option<&T> {
+        self.slots.get(cpu)
+    }
+
+    /// Returns the slots of all CPUs, in CPU order.
+    pub fn iter(&self) -> core::slice::Iter<'_, T> {
+        self.slots.iter()
+    }
+
+    /// Consumes this [`PerCpu`] and returns the slots.
+    pub fn into_inner(self) -> [T; CPUS] {
+        self.slots
+    }
+}
+
+impl<T: Default, L: LockAction, const CPUS: usize> Default for PerCpu<T, L, CPUS> {
+    fn default() -> Self {
+        Self::new(core::array::from_fn(|_| T::default()))
+    }
+}
+
+/// Identifies the CPU (or hart) the caller runs on, for locks whose correctness depends on it, such as
+/// [`ReentrantSpinMutex`](crate::reentrant::ReentrantSpinMutex).
+///
+/// # Safety
+///
+/// [`CpuId::current`] must never return the same id to two contexts that run at the same time, and never
+/// return `usize::MAX`. A context holding such a lock must not be migrated to another CPU, which the lock action
+/// usually guarantees by disabling interrupts or preemption. Unlike [`LockAction::current_cpu`], which is only
+/// a hint, a wrong id breaks mutual exclusion.
+pub unsafe trait CpuId {
+    /// Returns the id of the current CPU.
+    fn current() -> usize;
+}
@@ -2,40 +2,123 @@
 //!
 //! Waiting threads hammer an atomic variable until it becomes available. Best-case latency is low, but worst-case
 //! latency is theoretically infinite.
-use crate::LockAction;
+#[cfg(feature = "std")]
+use crate::poison::{LockResult, Poison, TryLockError, TryLockResult};
+use crate::{
+    poison::{NoPoison, PoisonFlag, PoisonPolicy},
+    ticket::TicketMutex,
+    LockAction, LockActionSendMarker, TimeSource,
+};
+use alloc::{boxed::Box, sync::Arc};
 use core::{
     cell::UnsafeCell,
     default::Default,
     fmt,
+    mem::{ManuallyDrop, MaybeUninit},
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
+#[cfg(debug_assertions)]
+use core::{panic::Location, sync::atomic::AtomicPtr};
 
 /// A [spin lock](https://en.m.wikipedia.org/wiki/Spinlock) providing mutually exclusive access to data.
 ///
-pub struct SpinMutex<T: ?Sized, L:LockAction> {
-    _marker: core::marker::PhantomData<L>,
+/// # Layout
+///
+/// A [`SpinMutex`] is `repr(C)`: the state of the lock comes first, ordered by decreasing alignment so it has no
+/// padding, and the data comes last, at the first offset after the state that is aligned for `T`. The whole lock
+/// is then rounded up to its alignment, which is that of `usize` or `T`, whichever is larger.
+///
+/// In release builds the state is a `usize` generation, a `u32` backoff limit and the `bool` lock flag, i.e. 13
+/// bytes on 64-bit targets: a lock around up to 3 bytes of data takes 16 bytes, and one around a `u64` 24.
+/// Debug builds add two words to track the owner, and `Poison` adds one byte; the lock
+/// action and [`NoPoison`] take no space.
+#[repr(C)]
+pub struct SpinMutex<T: ?Sized, L:LockAction, P: PoisonPolicy = NoPoison> {
+    generation: AtomicUsize,
+    /// The CPU holding the lock through a guard, or `usize::MAX`. Only tracked in debug builds to detect a CPU
+    /// waiting for a lock it already holds.
+    #[cfg(debug_assertions)]
+    owner_cpu: AtomicUsize,
+    /// Where the current guard was taken, or null.
+    #[cfg(debug_assertions)]
+    locked_at: AtomicPtr<Location<'static>>,
+    /// The most [`LockAction::on_spin`] iterations a waiter backs off for after a failed attempt, or 0 for none.
+    max_backoff: u32,
     locked: AtomicBool,
+    poison: PoisonFlag<P>,
+    _marker: core::marker::PhantomData<(L, P)>,
     data: UnsafeCell<T>,
 }
 
+// Keep the layout documented above
+#[cfg(target_pointer_width = "64")]
+const _: () = {
+    use core::mem::size_of;
+    let owner = if cfg!(debug_assertions) { 16 } else { 0 };
+    assert!(size_of::<SpinMutex<u8, crate::EmptyLockAction>>() == 16 + owner);
+    assert!(size_of::<SpinMutex<[u8; 3], crate::EmptyLockAction>>() == 16 + owner);
+    assert!(size_of::<SpinMutex<u64, crate::EmptyLockAction>>() == 24 + owner);
+};
+
 /// A guard that provides mutable data access.
 ///
 /// When the guard falls out of scope it will release the lock.
-pub struct SpinMutexGuard<'a, T: ?Sized + 'a, L: LockAction> {
-    lock: &'a AtomicBool,
+///
+/// The mutex makes a `T: Send` shareable, but the guard itself derefs to `&T`, so it is only `Sync` when `T: Sync`:
+///
+/// ```compile_fail
+/// fn assert_sync<S: Sync>(_: &S) {}
+///
+/// let lock = kernel_sync::SpinMutex::<_>::new(core::cell::Cell::new(0));
+/// assert_sync(&lock.lock());
+/// ```
+pub struct SpinMutexGuard<'a, T: ?Sized + 'a, L: LockAction, P: PoisonPolicy = NoPoison> {
+    lock: &'a SpinMutex<T, L, P>,
+    /// The token of `L::before_lock`, handed to `L::after_lock` when the lock is released.
+    token: L::Guard,
     _marker: core::marker::PhantomData<L>,
-    data: &'a mut T,
+    data: *mut T,
 }
 
-unsafe impl<T: ?Sized + Send, L:LockAction> Sync for SpinMutex<T, L> {}
-unsafe impl<T: ?Sized + Send, L:LockAction> Send for SpinMutex<T, L> {}
-unsafe impl<T: ?Sized + Sync, L: LockAction> Sync for SpinMutexGuard<'_, T, L> {}
-unsafe impl<T: ?Sized + Send, L: LockAction> Send for SpinMutexGuard<'_, T, L> {}
+/// A guard that keeps a [`SpinMutex`] locked but only provides shared data access.
+///
+/// Created by [`SpinMutexGuard::into_ref_guard`]. When the guard falls out of scope it will release the lock.
+pub struct SpinRefGuard<'a, T: ?Sized + 'a, L: LockAction> {
+    guard: SpinMutexGuard<'a, T, L>,
+}
 
-impl<T, L:LockAction> SpinMutex<T, L> {
+/// A guard for a component of the data of a [`SpinMutex`].
+///
+/// Created by [`SpinMutexGuard::map`]. When the guard falls out of scope it will release the lock it was mapped
+/// from, like the original guard would have. The two guards made by [`SpinMutexGuard::split_map`] release it when
+/// the second of them falls out of scope.
+pub struct MappedSpinMutexGuard<'a, T: ?Sized + 'a, L: LockAction> {
+    locked: &'a AtomicBool,
+    #[cfg(debug_assertions)]
+    owner_cpu: &'a AtomicUsize,
+    /// The token of `L::before_lock`, or the default for the halves of a [`SpinMutexGuard::split_map`].
+    token: L::Guard,
+    /// Shared by the two halves of a [`SpinMutexGuard::split_map`], which keep the token here so that the last
+    /// one to be dropped gets it; `None` for a guard that holds the lock alone.
+    split: Option<Arc<L::Guard>>,
+    _marker: core::marker::PhantomData<L>,
+    data: *mut T,
+}
+
+unsafe impl<T: ?Sized + Send, L:LockAction, P: PoisonPolicy> Sync for SpinMutex<T, L, P> {}
+unsafe impl<T: ?Sized + Send, L:LockAction, P: PoisonPolicy> Send for SpinMutex<T, L, P> {}
+unsafe impl<T: ?Sized + Sync, L: LockAction, P: PoisonPolicy> Sync for SpinMutexGuard<'_, T, L, P> {}
+unsafe impl<T: ?Sized + Send, L: LockActionSendMarker, P: PoisonPolicy> Send for SpinMutexGuard<'_, T, L, P> {}
+unsafe impl<T: ?Sized + Sync, L: LockAction> Sync for MappedSpinMutexGuard<'_, T, L> {}
+unsafe impl<T: ?Sized + Send, L: LockActionSendMarker> Send for MappedSpinMutexGuard<'_, T, L> {}
+
+impl<T, L:LockAction, P: PoisonPolicy> SpinMutex<T, L, P> {
     /// Creates a new [`SpinMutex`] wrapping the supplied data.
     ///
+    /// Like [`TicketMutex::new`], the lock action is inferred from the type the lock ends up in.
+    ///
     /// # Example
     ///
     /// ```
@@ -51,13 +134,60 @@ impl<T, L:LockAction> SpinMutex<T, L> {
     /// ```
     #[inline(always)]
     pub const fn new(data: T) -> Self {
+        Self::with_backoff(data, 0)
+    }
+
+    /// Creates a new [`SpinMutex`] whose waiters back off exponentially, for locks under heavy contention.
+    ///
+    /// After every failed attempt to take the lock, a waiter busy-waits for 1, 2, 4, ... iterations of
+    /// [`LockAction::on_spin`], capped at `max_backoff`, before it polls the lock again. This spreads out the
+    /// waiters that would otherwise all retry at once when the lock is released, and so bounce its cache line
+    /// between CPUs. The backoff starts over with every `lock` call and [`SpinMutex::try_lock`] never backs
+    /// off. A `max_backoff` of 0 is the same as [`SpinMutex::new`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kernel_sync::SpinMutex;
+    ///
+    /// static RUNQUEUE: SpinMutex<usize> = SpinMutex::with_backoff(0, 64);
+    ///
+    /// *RUNQUEUE.lock() += 1;
+    /// ```
+    #[inline(always)]
+    pub const fn with_backoff(data: T, max_backoff: u32) -> Self {
         SpinMutex {
             locked: AtomicBool::new(false),
+            generation: AtomicUsize::new(0),
+            max_backoff,
+            #[cfg(debug_assertions)]
+            owner_cpu: AtomicUsize::new(usize::MAX),
+            #[cfg(debug_assertions)]
+            locked_at: AtomicPtr::new(ptr::null_mut()),
+            poison: PoisonFlag::new(),
             data: UnsafeCell::new(data),
             _marker: core::marker::PhantomData,
         }
     }
 
+    /// Creates a new [`SpinMutex`] on the heap that is never freed, for locks allocated at boot that live as long
+    /// as the kernel, e.g. because their size is only known at run time.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let lock: &'static kernel_sync::SpinMutex<_> = kernel_sync::SpinMutex::leak(0);
+    /// std::thread::spawn(move || *lock.lock() += 1).join().unwrap();
+    /// assert_eq!(*lock.lock(), 1);
+    /// ```
+    #[inline]
+    pub fn leak(data: T) -> &'static Self
+    where
+        Self: 'static,
+    {
+        Box::leak(Box::new(Self::new(data)))
+    }
+
     /// Consumes this [`SpinMutex`] and unwraps the underlying data.
     ///
     /// # Example
@@ -97,6 +227,117 @@ impl<T, L:LockAction> SpinMutex<T, L> {
     pub fn as_mut_ptr(&self) -> *mut T {
         self.data.get()
     }
+
+    /// Creates an array of [`SpinMutex`]es, one wrapping each element of `init`.
+    ///
+    /// Unlike `init.map(SpinMutex::new)` this is a `const fn`, so it can build static tables of locks, e.g. one
+    /// per CPU, even when `T` is not `Copy`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kernel_sync::SpinMutex;
+    ///
+    /// static PER_CPU: [SpinMutex<u32>; 4] = SpinMutex::array([0, 1, 2, 3]);
+    ///
+    /// *PER_CPU[2].lock() += 1;
+    /// assert_eq!(*PER_CPU[2].lock(), 3);
+    /// ```
+    pub const fn array<const N: usize>(init: [T; N]) -> [Self; N] {
+        // The elements are moved out one by one below, so `init` itself must not be dropped.
+        let init = ManuallyDrop::new(init);
+        let src = &init as *const ManuallyDrop<[T; N]> as *const T;
+        let mut locks = [const { MaybeUninit::<Self>::uninit() }; N];
+        let mut i = 0;
+        while i < N {
+            // Safety: every element of `init` is read exactly once.
+            locks[i] = MaybeUninit::new(Self::new(unsafe { ptr::read(src.add(i)) }));
+            i += 1;
+        }
+        // Safety: all `N` locks have been initialized, and `MaybeUninit<Self>` has the same layout as `Self`.
+        unsafe { ptr::read(&locks as *const [MaybeUninit<Self>; N] as *const [Self; N]) }
+    }
+}
+
+impl<T: ?Sized, L: LockAction, P: PoisonPolicy> SpinMutex<T, L, P> {
+    /// Build the guard for a lock this thread has just taken.
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    fn guard(&self, token: L::Guard) -> SpinMutexGuard<'_, T, L, P> {
+        self.track_owner();
+        L::on_hold_start();
+        SpinMutexGuard {
+            lock: self,
+            token,
+            data: self.data.get(),
+            _marker: Default::default(),
+        }
+    }
+
+    /// Release a lock held by this thread, without running `L::after_lock`.
+    #[inline(always)]
+    fn release(&self) {
+        unlock::<L>(
+            &self.locked,
+            #[cfg(debug_assertions)]
+            &self.owner_cpu,
+        );
+    }
+
+    /// Record the current CPU and the caller's location as holding the lock, in debug builds.
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    fn track_owner(&self) {
+        #[cfg(debug_assertions)]
+        if let Some(cpu) = L::current_cpu() {
+            self.locked_at
+                .store(Location::caller() as *const _ as *mut _, Ordering::Relaxed);
+            self.owner_cpu.store(cpu, Ordering::Relaxed);
+        }
+    }
+
+    /// Panic if the current CPU is the one holding the lock, in debug builds.
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    fn check_self_deadlock(&self) {
+        #[cfg(debug_assertions)]
+        if let Some(cpu) = L::current_cpu() {
+            if self.owner_cpu.load(Ordering::Relaxed) == cpu {
+                let locked_at = self.locked_at.load(Ordering::Relaxed);
+                // Safety: only `track_owner` stores into `locked_at`, and always a `&'static Location`.
+                let locked_at = unsafe { &*locked_at };
+                panic!(
+                    "SpinMutex locked at {} on CPU {cpu}, which already holds it since {locked_at}",
+                    Location::caller()
+                );
+            }
+        }
+    }
+
+    /// Spin until the lock is taken, without running any [`LockAction`] hook.
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    fn acquire(&self) {
+        let mut backoff: u32 = 1;
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.check_self_deadlock();
+            if self.max_backoff != 0 {
+                for _ in 0..backoff {
+                    L::on_spin();
+                }
+                backoff = backoff.saturating_mul(2).min(self.max_backoff);
+            }
+            // Wait until the lock looks unlocked before retrying
+            while self.locked.load(Ordering::Relaxed) {
+                L::wait();
+            }
+        }
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 impl<T: ?Sized, L: LockAction> SpinMutex<T, L> {
@@ -114,25 +355,69 @@ impl<T: ?Sized, L: LockAction> SpinMutex<T, L> {
     ///     // The lock is implicitly dropped at the end of the scope
     /// }
     /// ```
+    ///
+    /// Calling this while the current CPU already holds the lock spins forever. In debug builds, if
+    /// `L::current_cpu` knows the current CPU, this is detected and panics instead, with both the location of
+    /// this call and the one where the lock was taken.
     #[inline(always)]
-    pub fn lock(&self) -> SpinMutexGuard<T, L> {
-        L::before_lock();
-        while self
-            .locked
-            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
-        {
-            // Wait until the lock looks unlocked before retrying
-            while self.is_locked() {
-                core::hint::spin_loop();
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn lock(&self) -> SpinMutexGuard<'_, T, L> {
+        let token = L::before_lock();
+        self.acquire();
+        self.guard(token)
+    }
+    /// Locks the [`SpinMutex`] like [`SpinMutex::lock`], optimised for very short contention.
+    ///
+    /// The lock is tried up to `L::OPTIMISTIC_SPINS` times with an `L::relax` in between before falling into the
+    /// regular contended path, which avoids its overhead when the holder is about to release the lock.
+    ///
+    /// ```
+    /// let lock = kernel_sync::SpinMutex::<_>::new(0);
+    /// *lock.lock_optimistic() += 1;
+    /// assert_eq!(*lock.lock(), 1);
+    /// ```
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn lock_optimistic(&self) -> SpinMutexGuard<'_, T, L> {
+        let token = L::before_lock();
+        let mut acquired = false;
+        for _ in 0..L::OPTIMISTIC_SPINS {
+            if self
+                .locked
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                self.generation.fetch_add(1, Ordering::Relaxed);
+                acquired = true;
+                break;
             }
+            L::relax();
         }
-        SpinMutexGuard {
-            lock: &self.locked,
-            data: unsafe { &mut *self.data.get() },
-            _marker: Default::default(),
+        if !acquired {
+            self.acquire();
         }
+        self.guard(token)
+    }
+
+    /// Locks the [`SpinMutex`], runs `f` on the data and returns both the guard and the result of `f`.
+    ///
+    /// Unlike locking, computing a value and dropping the guard, the lock is still held when this returns, so a
+    /// decision made by `f` stays valid for as long as the caller keeps the guard.
+    ///
+    /// ```
+    /// let lock = kernel_sync::SpinMutex::<_>::new(vec![1, 2, 3]);
+    /// let (mut guard, len) = lock.lock_map(|data| data.len());
+    /// assert!(lock.is_locked());
+    /// guard.push(len + 1);
+    /// ```
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn lock_map<R>(&self, f: impl FnOnce(&mut T) -> R) -> (SpinMutexGuard<'_, T, L>, R) {
+        let mut guard = self.lock();
+        let result = f(&mut guard);
+        (guard, result)
     }
+
     /// Try to lock this [`SpinMutex`], returning a lock guard if successful.
     ///
     /// # Example
@@ -148,30 +433,220 @@ impl<T: ?Sized, L: LockAction> SpinMutex<T, L> {
     /// assert!(maybe_guard2.is_none());
     /// ```
     #[inline(always)]
-    pub fn try_lock(&self) -> Option<SpinMutexGuard<T, L>> {
-        L::before_lock();
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T, L>> {
+        let token = L::before_lock();
         if self
             .locked
             .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
         {
-            Some(SpinMutexGuard {
-                lock: &self.locked,
-                data: unsafe { &mut *self.data.get() },
-                _marker: Default::default(),
-            })
+            self.generation.fetch_add(1, Ordering::Relaxed);
+            Some(self.guard(token))
+        } else {
+            L::after_lock(token);
+            None
+        }
+    }
+
+    /// Try to lock this [`SpinMutex`] from an interrupt handler, returning a lock guard if successful.
+    ///
+    /// A lock that is taken both in normal and in interrupt context on the same CPU can only be used safely with a
+    /// lock action that disables interrupts in [`LockAction::before_lock`] and restores them in
+    /// [`LockAction::after_lock`]. An interrupt then never arrives on a CPU while that CPU holds the lock, so when
+    /// this fails, the lock is held by another CPU and will be released without the handler's help: it never
+    /// deadlocks on its own CPU.
+    ///
+    /// Otherwise this is the same as [`SpinMutex::try_lock`]. In debug builds, and if [`LockAction::current_cpu`]
+    /// tells the CPUs apart, it additionally panics when the lock is held by the current CPU, as that means the
+    /// handler interrupted the holder: the lock action did not keep interrupts disabled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let lock = kernel_sync::SpinMutex::<_>::new(0);
+    ///
+    /// // In the interrupt handler, skip the work if another CPU has the lock
+    /// if let Some(mut guard) = lock.try_lock_irq() {
+    ///     *guard += 1;
+    /// }
+    /// assert_eq!(*lock.lock(), 1);
+    /// ```
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_lock_irq(&self) -> Option<SpinMutexGuard<'_, T, L>> {
+        let guard = self.try_lock();
+        #[cfg(debug_assertions)]
+        if guard.is_none() {
+            if let Some(cpu) = L::current_cpu() {
+                if self.owner_cpu.load(Ordering::Relaxed) == cpu {
+                    let locked_at = self.locked_at.load(Ordering::Relaxed);
+                    // Safety: only `track_owner` stores into `locked_at`, and always a `&'static Location`.
+                    let locked_at = unsafe { &*locked_at };
+                    panic!(
+                        "SpinMutex::try_lock_irq at {} on CPU {cpu}, which holds the lock since {locked_at}: \
+                         the lock action did not keep interrupts disabled",
+                        Location::caller()
+                    );
+                }
+            }
+        }
+        guard
+    }
+
+    /// Locks the [`SpinMutex`] like [`SpinMutex::lock`], but gives up and returns `None` if the lock is still held
+    /// by someone else after `ticks` of `C`, e.g. by a CPU that crashed while holding it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kernel_sync::TimeSource;
+    ///
+    /// struct Uptime;
+    /// impl TimeSource for Uptime {
+    ///     const TICKS_PER_SEC: u64 = 10_000_000;
+    ///     fn now() -> u64 {
+    ///         # static TICKS: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+    ///         # TICKS.fetch_add(1, core::sync::atomic::Ordering::Relaxed)
+    ///         // read the platform timer
+    ///     }
+    /// }
+    ///
+    /// let lock = kernel_sync::SpinMutex::<_>::new(0);
+    /// let guard = lock.lock();
+    /// assert!(lock.try_lock_for::<Uptime>(Uptime::ticks_from_micros(10)).is_none());
+    /// drop(guard);
+    /// assert!(lock.try_lock_for::<Uptime>(100).is_some());
+    /// ```
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_lock_for<C: TimeSource>(&self, ticks: u64) -> Option<SpinMutexGuard<'_, T, L>> {
+        self.try_lock_until::<C>(C::now().saturating_add(ticks))
+    }
+
+    /// Like [`SpinMutex::try_lock_for`], but gives up once `C::now()` reaches `deadline`.
+    ///
+    /// The lock is tried at least once even if `deadline` has already passed.
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_lock_until<C: TimeSource>(&self, deadline: u64) -> Option<SpinMutexGuard<'_, T, L>> {
+        let token = L::before_lock();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.is_locked() {
+                if C::now() >= deadline {
+                    L::after_lock(token);
+                    return None;
+                }
+                L::relax();
+            }
+        }
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        Some(self.guard(token))
+    }
+
+    /// Try to lock this [`SpinMutex`], returning a lock guard if successful.
+    ///
+    /// Unlike [`SpinMutex::try_lock`], this function is allowed to spuriously fail even when the mutex is unlocked,
+    /// which can result in more efficient code on some platforms. Use it in a loop that retries anyway.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let lock = kernel_sync::SpinMutex::<_>::new(42);
+    ///
+    /// let guard = loop {
+    ///     if let Some(guard) = lock.try_lock_weak() {
+    ///         break guard;
+    ///     }
+    /// };
+    /// assert!(lock.try_lock_weak().is_none());
+    /// ```
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_lock_weak(&self) -> Option<SpinMutexGuard<'_, T, L>> {
+        let token = L::before_lock();
+        if self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.generation.fetch_add(1, Ordering::Relaxed);
+            Some(self.guard(token))
         } else {
-            L::after_lock();
+            L::after_lock(token);
             None
         }
     }
 
+    /// Try to lock this [`SpinMutex`], and keep it locked only if `pred` accepts the data.
+    ///
+    /// `pred` runs with the lock held, so the returned guard sees the same value `pred` accepted. Returns `None`
+    /// if the lock is busy, or if `pred` fails, in which case the lock is released again.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let lock = kernel_sync::SpinMutex::<_>::new(0);
+    ///
+    /// assert!(lock.try_lock_if(|data| *data > 0).is_none());
+    /// assert!(!lock.is_locked());
+    ///
+    /// *lock.lock() = 1;
+    /// let guard = lock.try_lock_if(|data| *data > 0).unwrap();
+    /// assert_eq!(*guard, 1);
+    /// ```
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_lock_if<F: FnOnce(&T) -> bool>(&self, pred: F) -> Option<SpinMutexGuard<'_, T, L>> {
+        self.try_lock().filter(|guard| pred(guard))
+    }
+
+    /// Try to lock this [`SpinMutex`], returning the current lock generation if it is busy.
+    ///
+    /// The generation is bumped every time the lock is acquired, so a caller that failed can later compare it
+    /// against [`SpinMutex::generation`] to find out whether the holder has changed in the meantime.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let lock = kernel_sync::SpinMutex::<_>::new(42);
+    ///
+    /// let guard = lock.lock();
+    /// let generation = lock.try_lock_or_generation().unwrap_err();
+    /// drop(guard);
+    ///
+    /// let _guard = lock.lock();
+    /// assert_ne!(lock.try_lock_or_generation().unwrap_err(), generation);
+    /// ```
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_lock_or_generation(&self) -> Result<SpinMutexGuard<'_, T, L>, u64> {
+        self.try_lock()
+            .ok_or_else(|| self.generation.load(Ordering::Relaxed) as u64)
+    }
+
+    /// Returns the number of times this [`SpinMutex`] has been acquired, wrapping on overflow.
+    ///
+    /// Like [`SpinMutex::is_locked`], the result is only a snapshot and must not be used for synchronization.
+    #[inline(always)]
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed) as u64
+    }
+
     /// Returns a mutable reference to the underlying data.
     ///
     /// Since this call borrows the [`SpinMutex`] mutably, and a mutable reference is guaranteed to be exclusive in
     /// Rust, no actual locking needs to take place -- the mutable borrow statically guarantees no locks exist. As
     /// such, this is a 'zero-cost' operation.
     ///
+    /// The lock state is left untouched. Every guard released the lock when it was dropped, so [`SpinMutex::is_locked`]
+    /// reports `false` and the [`SpinMutex::generation`] is unchanged; only a guard leaked with
+    /// [`core::mem::forget`] would keep the lock held.
+    ///
     /// # Example
     ///
     /// ```
@@ -196,6 +671,52 @@ impl<T: ?Sized, L: LockAction> SpinMutex<T, L> {
         self.locked.load(Ordering::Relaxed)
     }
 
+    /// Lock this [`SpinMutex`] without running [`LockAction::before_lock`], and without creating a guard.
+    ///
+    /// This is meant for batches of locks taken under a single critical section that the caller sets up
+    /// itself, e.g. disabling interrupts once and then acquiring several locks.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be released with [`SpinMutex::raw_unlock`], and the caller is responsible for whatever
+    /// [`LockAction::before_lock`] and [`LockAction::after_lock`] would have done: if `L` disables interrupts,
+    /// the caller has to keep them disabled from before the first `raw_lock` until after the last `raw_unlock`.
+    /// The data may only be accessed through [`SpinMutex::as_mut_ptr`] while the lock is held.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let a = kernel_sync::SpinMutex::<_>::new(1);
+    /// let b = kernel_sync::SpinMutex::<_>::new(2);
+    ///
+    /// // ... disable interrupts once ...
+    /// unsafe {
+    ///     a.raw_lock();
+    ///     b.raw_lock();
+    ///     *a.as_mut_ptr() += *b.as_mut_ptr();
+    ///     b.raw_unlock();
+    ///     a.raw_unlock();
+    /// }
+    /// // ... restore interrupts ...
+    /// assert_eq!(*a.lock(), 3);
+    /// ```
+    #[inline(always)]
+    pub unsafe fn raw_lock(&self) {
+        self.acquire();
+        L::on_hold_start();
+    }
+
+    /// Unlock a [`SpinMutex`] locked with [`SpinMutex::raw_lock`], without running [`LockAction::after_lock`].
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the caller through [`SpinMutex::raw_lock`]. See there for the interrupt
+    /// state the caller has to manage.
+    #[inline(always)]
+    pub unsafe fn raw_unlock(&self) {
+        self.release();
+    }
+
     /// Force unlock this [`SpinMutex`].
     ///
     /// # Safety
@@ -203,74 +724,422 @@ impl<T: ?Sized, L: LockAction> SpinMutex<T, L> {
     /// This is *extremely* unsafe if the lock is not held by the current
     /// thread. However, this can be useful in some instances for exposing the
     /// lock to FFI that doesn't know how to deal with RAII.
+    ///
+    /// The token of the forgotten guard is lost, so `L::after_lock` gets the default one.
     #[inline(always)]
     pub unsafe fn force_unlock(&self) {
-        self.locked.store(false, Ordering::Release);
-        L::after_lock();
+        self.release();
+        L::after_lock(L::Guard::default());
+    }
+}
+
+/// The locking methods of a [`SpinMutex`] that is poisoned by a panic, see [`crate::poison`].
+///
+/// They are the ones of [`std::sync::Mutex`]. The other methods of a poisoning lock don't look at the poison flag:
+/// [`SpinMutex::into_inner`] and [`SpinMutex::get_mut`] hand out the data even if it is poisoned.
+#[cfg(feature = "std")]
+impl<T: ?Sized, L: LockAction> SpinMutex<T, L, Poison> {
+    /// Locks the [`SpinMutex`] like [`SpinMutex::lock`], but returns an error holding the guard if a guard of
+    /// the lock was dropped during a panic.
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn lock(&self) -> LockResult<SpinMutexGuard<'_, T, L, Poison>> {
+        let token = L::before_lock();
+        self.acquire();
+        self.poison.guard(self.guard(token))
+    }
+
+    /// Try to lock the [`SpinMutex`] like [`SpinMutex::try_lock`], but returns an error holding the guard if a
+    /// guard of the lock was dropped during a panic.
+    #[inline(always)]
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn try_lock(&self) -> TryLockResult<SpinMutexGuard<'_, T, L, Poison>> {
+        let token = L::before_lock();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.generation.fetch_add(1, Ordering::Relaxed);
+            Ok(self.poison.guard(self.guard(token))?)
+        } else {
+            L::after_lock(token);
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Returns `true` if a guard of the lock was dropped during a panic since the poison was last cleared.
+    #[inline(always)]
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Clears the poison, once the data is known to be consistent again.
+    #[inline(always)]
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    /// Returns `true` if the lock is currently held, see [`SpinMutex::is_locked`].
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
     }
 }
 
 impl<T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for SpinMutex<T, L> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.try_lock() {
-            Some(guard) => write!(f, "Mutex {{ data: ")
-                .and_then(|()| (*guard).fmt(f))
-                .and_then(|()| write!(f, "}}")),
-            None => write!(f, "Mutex {{ <locked> }}"),
-        }
+        // Read the lock state before `try_lock` changes it
+        #[cfg(feature = "debug-verbose")]
+        let generation = self.generation.load(Ordering::Relaxed);
+        let locked = match self.try_lock() {
+            Some(guard) => {
+                write!(f, "Mutex {{ data: ").and_then(|()| (*guard).fmt(f))?;
+                false
+            }
+            None => {
+                write!(f, "Mutex {{ <locked>")?;
+                true
+            }
+        };
+        #[cfg(feature = "debug-verbose")]
+        write!(f, ", generation: {generation}")?;
+        write!(f, "{}}}", if locked { " " } else { "" })
     }
 }
 
-impl<T: ?Sized + Default, L:LockAction> Default for SpinMutex<T, L> {
+impl<T: Default, L:LockAction, P: PoisonPolicy> Default for SpinMutex<T, L, P> {
     fn default() -> Self {
         SpinMutex::new(T::default())
     }
 }
 
-impl<T, L:LockAction> From<T> for SpinMutex<T, L> {
+impl<T, L:LockAction, P: PoisonPolicy> From<T> for SpinMutex<T, L, P> {
     fn from(data: T) -> Self {
         Self::new(data)
     }
 }
 
-impl<'a, T: ?Sized, L: LockAction> Drop for SpinMutexGuard<'a, T, L> {
+impl<T, L:LockAction> From<TicketMutex<T, L>> for SpinMutex<T, L> {
+    fn from(lock: TicketMutex<T, L>) -> Self {
+        Self::new(lock.into_inner())
+    }
+}
+
+/// Release the lock made of `locked` (and `owner_cpu`), without running `L::after_lock`.
+///
+/// Shared by [`SpinMutex`] and [`MappedSpinMutexGuard`], which no longer knows the type of the whole mutex.
+#[inline(always)]
+fn unlock<L: LockAction>(locked: &AtomicBool, #[cfg(debug_assertions)] owner_cpu: &AtomicUsize) {
+    L::on_hold_end();
+    #[cfg(debug_assertions)]
+    owner_cpu.store(usize::MAX, Ordering::Relaxed);
+    locked.store(false, Ordering::Release);
+    L::notify();
+}
+
+impl<'a, T: ?Sized, L: LockAction, P: PoisonPolicy> Drop for SpinMutexGuard<'a, T, L, P> {
     /// The dropping of the SpinMutexGuard will release the lock it was created from.
     fn drop(&mut self) {
-        self.lock.store(false, Ordering::Release);
-        L::after_lock();
+        self.lock.poison.on_release();
+        self.lock.release();
+        L::after_lock(core::mem::take(&mut self.token));
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction, P: PoisonPolicy> SpinMutexGuard<'a, T, L, P> {
+    /// Releases the lock, calls `f` and acquires the lock again, keeping this guard, with the same hooks as
+    /// dropping the guard and locking again. The lock is acquired again even if `f` panics.
+    pub(crate) fn unlocked<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        struct Relock<'b, T: ?Sized, L: LockAction, P: PoisonPolicy>(&'b SpinMutex<T, L, P>, &'b mut L::Guard);
+        impl<T: ?Sized, L: LockAction, P: PoisonPolicy> Drop for Relock<'_, T, L, P> {
+            fn drop(&mut self) {
+                *self.1 = L::before_lock();
+                self.0.acquire();
+                self.0.track_owner();
+                L::on_hold_start();
+            }
+        }
+
+        self.lock.release();
+        L::after_lock(core::mem::take(&mut self.token));
+        let _relock = Relock(self.lock, &mut self.token);
+        f()
     }
 }
 
-impl<'a, T: ?Sized, L: LockAction> Deref for SpinMutexGuard<'a, T, L> {
+impl<'a, T: ?Sized, L: LockAction> SpinMutexGuard<'a, T, L> {
+    /// Releases the lock and immediately acquires it again, keeping this guard.
+    ///
+    /// Unlike dropping the guard and calling [`SpinMutex::lock`], the caller's borrows stay as they are. Waiting
+    /// threads get a chance to take the lock in between, which is useful to let others in periodically during a
+    /// long loop. `L::after_lock` runs on release and `L::before_lock` before the lock is acquired again.
+    ///
+    /// ```
+    /// let lock = kernel_sync::SpinMutex::<_>::new(0);
+    /// let mut guard = lock.lock();
+    /// for _ in 0..10 {
+    ///     *guard += 1;
+    ///     guard.unlock_and_relock();
+    /// }
+    /// assert_eq!(*guard, 10);
+    /// ```
+    pub fn unlock_and_relock(&mut self) {
+        self.unlocked(L::relax);
+    }
+
+    /// Forgets the guard without unlocking and returns a raw pointer to the data.
+    ///
+    /// The lock stays held; it must later be released with [`SpinMutex::force_unlock`], which also runs
+    /// `L::after_lock`, with the default token. This is meant for handing the data to FFI code that doesn't know how to deal with RAII.
+    ///
+    /// ```
+    /// let lock = kernel_sync::SpinMutex::<_>::new(42);
+    ///
+    /// let ptr = kernel_sync::SpinMutexGuard::into_data_ptr(lock.lock());
+    /// unsafe {
+    ///     *ptr = 58;
+    ///     lock.force_unlock();
+    /// }
+    ///
+    /// assert_eq!(*lock.lock(), 58);
+    /// ```
+    #[inline(always)]
+    pub fn into_data_ptr(this: Self) -> *mut T {
+        let data = this.data;
+        core::mem::forget(this);
+        data
+    }
+
+    /// Returns a shared reference to the data, the same as going through `Deref`.
+    pub fn as_shared(&self) -> &T {
+        self
+    }
+
+    /// Turns the guard into one that keeps the lock held but only allows reading the data.
+    ///
+    /// Useful to pass a locked, read-only view to code that must not modify the data.
+    ///
+    /// ```
+    /// let lock = kernel_sync::SpinMutex::<_>::new(1);
+    /// let mut guard = lock.lock();
+    /// *guard += 1;
+    /// let guard = guard.into_ref_guard();
+    /// assert_eq!(*guard, 2);
+    /// assert!(lock.try_lock().is_none());
+    /// drop(guard);
+    /// assert!(lock.try_lock().is_some());
+    /// ```
+    pub fn into_ref_guard(self) -> SpinRefGuard<'a, T, L> {
+        SpinRefGuard { guard: self }
+    }
+
+    /// Make a new guard for a component of the locked data, such as a field.
+    ///
+    /// The lock is handed over to the returned guard, so it stays held until that guard is dropped, and dropping
+    /// it runs `L::after_lock` as dropping this guard would have. If `f` panics, this guard releases the lock.
+    ///
+    /// ```
+    /// use kernel_sync::spin::{MappedSpinMutexGuard, SpinMutexGuard};
+    ///
+    /// let lock = kernel_sync::SpinMutex::<_>::new((0, [1, 2]));
+    /// let mut second = SpinMutexGuard::map(lock.lock(), |(_, array)| array);
+    /// second[0] = 3;
+    /// let mut element = MappedSpinMutexGuard::map(second, |array| &mut array[1]);
+    /// *element += 1;
+    /// assert!(lock.is_locked());
+    /// drop(element);
+    /// assert_eq!(*lock.lock(), (0, [3, 3]));
+    /// ```
+    #[inline]
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(mut this: Self, f: F) -> MappedSpinMutexGuard<'a, U, L> {
+        let lock = this.lock;
+        // Safety: we hold the lock, and the returned guard takes it over.
+        let data = f(unsafe { &mut *this.data }) as *mut U;
+        let token = core::mem::take(&mut this.token);
+        core::mem::forget(this);
+        MappedSpinMutexGuard {
+            locked: &lock.locked,
+            #[cfg(debug_assertions)]
+            owner_cpu: &lock.owner_cpu,
+            token,
+            split: None,
+            _marker: Default::default(),
+            data,
+        }
+    }
+
+    /// Make two guards for disjoint components of the locked data, such as two fields, that can be used
+    /// independently while the lock stays held.
+    ///
+    /// The lock is released, running `L::after_lock` once, when the second of the two guards is dropped. The guards
+    /// share a reference count, so this allocates. If `f` panics, this guard releases the lock.
+    ///
+    /// ```
+    /// use kernel_sync::spin::SpinMutexGuard;
+    ///
+    /// let lock = kernel_sync::SpinMutex::<_>::new((vec![1], 0));
+    /// let (mut queue, mut stats) = SpinMutexGuard::split_map(lock.lock(), |(queue, stats)| (queue, stats));
+    /// let item = queue.pop();
+    /// *stats += 1;
+    /// drop(queue);
+    /// assert!(lock.is_locked());
+    /// drop(stats);
+    /// assert_eq!(item, Some(1));
+    /// assert_eq!(*lock.lock(), (vec![], 1));
+    /// ```
+    #[inline]
+    pub fn split_map<A: ?Sized, B: ?Sized, F: FnOnce(&mut T) -> (&mut A, &mut B)>(
+        mut this: Self,
+        f: F,
+    ) -> (MappedSpinMutexGuard<'a, A, L>, MappedSpinMutexGuard<'a, B, L>) {
+        let lock = this.lock;
+        // Safety: we hold the lock, and the returned guards take it over.
+        let (a, b) = f(unsafe { &mut *this.data });
+        let (a, b) = (a as *mut A, b as *mut B);
+        let split = Arc::new(core::mem::take(&mut this.token));
+        core::mem::forget(this);
+        (
+            MappedSpinMutexGuard {
+                locked: &lock.locked,
+                #[cfg(debug_assertions)]
+                owner_cpu: &lock.owner_cpu,
+                token: L::Guard::default(),
+                split: Some(split.clone()),
+                _marker: Default::default(),
+                data: a,
+            },
+            MappedSpinMutexGuard {
+                locked: &lock.locked,
+                #[cfg(debug_assertions)]
+                owner_cpu: &lock.owner_cpu,
+                token: L::Guard::default(),
+                split: Some(split),
+                _marker: Default::default(),
+                data: b,
+            },
+        )
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> MappedSpinMutexGuard<'a, T, L> {
+    /// Make a new guard for a component of the data of this one, see [`SpinMutexGuard::map`].
+    #[inline]
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(this: Self, f: F) -> MappedSpinMutexGuard<'a, U, L> {
+        // Safety: we hold the lock, and the returned guard takes it over.
+        let data = f(unsafe { &mut *this.data }) as *mut U;
+        let this = ManuallyDrop::new(this);
+        MappedSpinMutexGuard {
+            locked: this.locked,
+            #[cfg(debug_assertions)]
+            owner_cpu: this.owner_cpu,
+            // Safety: `this` is never dropped or used again, so the token and the share are only moved out once.
+            token: unsafe { ptr::read(&this.token) },
+            split: unsafe { ptr::read(&this.split) },
+            _marker: Default::default(),
+            data,
+        }
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> Drop for MappedSpinMutexGuard<'a, T, L> {
+    /// The dropping of the MappedSpinMutexGuard will release the lock it was mapped from.
+    fn drop(&mut self) {
+        // The other half of a split keeps holding the lock. The last half sees the writes of the other one.
+        let token = match self.split.take() {
+            Some(split) => match Arc::into_inner(split) {
+                Some(token) => token,
+                None => return,
+            },
+            None => core::mem::take(&mut self.token),
+        };
+        unlock::<L>(
+            self.locked,
+            #[cfg(debug_assertions)]
+            self.owner_cpu,
+        );
+        L::after_lock(token);
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> Deref for MappedSpinMutexGuard<'a, T, L> {
     type Target = T;
     fn deref(&self) -> &T {
-        self.data
+        // Safety: We hold the lock
+        unsafe { &*self.data }
     }
 }
 
-impl<'a, T: ?Sized, L: LockAction> DerefMut for SpinMutexGuard<'a, T, L> {
+impl<'a, T: ?Sized, L: LockAction> DerefMut for MappedSpinMutexGuard<'a, T, L> {
     fn deref_mut(&mut self) -> &mut T {
-        self.data
+        // Safety: We hold the lock
+        unsafe { &mut *self.data }
     }
 }
 
-impl<'a, T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for SpinMutexGuard<'a, T, L> {
+impl<'a, T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for MappedSpinMutexGuard<'a, T, L> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(&**self, f)
     }
 }
 
-impl<'a, T: ?Sized + fmt::Display, L: LockAction> fmt::Display for SpinMutexGuard<'a, T, L> {
+impl<'a, T: ?Sized + fmt::Display, L: LockAction> fmt::Display for MappedSpinMutexGuard<'a, T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> Deref for SpinRefGuard<'a, T, L> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for SpinRefGuard<'a, T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display, L: LockAction> fmt::Display for SpinRefGuard<'a, T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction, P: PoisonPolicy> Deref for SpinMutexGuard<'a, T, L, P> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: We hold the lock
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction, P: PoisonPolicy> DerefMut for SpinMutexGuard<'a, T, L, P> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: We hold the lock
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, L: LockAction, P: PoisonPolicy> fmt::Debug for SpinMutexGuard<'a, T, L, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display, L: LockAction, P: PoisonPolicy> fmt::Display for SpinMutexGuard<'a, T, L, P> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(&**self, f)
     }
 }
 
 #[cfg(feature = "lockapi")]
-unsafe impl<L: LockAction> lock_api::RawMutex for SpinMutex<(), L> {
+unsafe impl<L: LockAction<Guard = ()>> lock_api::RawMutex for SpinMutex<(), L> {
     #[allow(clippy::declare_interior_mutable_const)]
     const INIT: Self = Self::new(());
-    type GuardMarker = lock_api::GuardSend;
+    type GuardMarker = lock_api::GuardNoSend;
 
     fn lock(&self) {
         core::mem::forget(Self::lock(self))
@@ -289,3 +1158,57 @@ unsafe impl<L: LockAction> lock_api::RawMutex for SpinMutex<(), L> {
         Self::is_locked(self)
     }
 }
+
+/// A [`lock_api::RawMutex`] that also has a weak try-lock, see [`SpinMutex::try_lock_weak`].
+///
+/// `lock_api` has no weak try-lock of its own. Import this trait together with [`MutexWeakExt`] to use it through
+/// a [`lock_api::Mutex`].
+#[cfg(feature = "lockapi")]
+pub trait RawMutexWeak: lock_api::RawMutex {
+    /// Attempts to acquire this mutex, like [`lock_api::RawMutex::try_lock`], but may fail spuriously.
+    fn try_lock_weak(&self) -> bool;
+}
+
+#[cfg(feature = "lockapi")]
+impl<L: LockAction<Guard = ()>> RawMutexWeak for SpinMutex<(), L> {
+    fn try_lock_weak(&self) -> bool {
+        // Prevent guard destructor running
+        Self::try_lock_weak(self).map(core::mem::forget).is_some()
+    }
+}
+
+/// Adds `try_lock_weak` to a [`lock_api::Mutex`] whose raw mutex implements [`RawMutexWeak`].
+///
+/// ```
+/// use kernel_sync::spin::{MutexWeakExt, SpinMutex};
+///
+/// type Mutex<T> = lock_api::Mutex<SpinMutex<(), kernel_sync::EmptyLockAction>, T>;
+///
+/// let mutex = Mutex::new(0);
+/// let mut guard = loop {
+///     if let Some(guard) = mutex.try_lock_weak() {
+///         break guard;
+///     }
+/// };
+/// *guard += 1;
+/// assert!(mutex.try_lock_weak().is_none());
+/// ```
+#[cfg(feature = "lockapi")]
+pub trait MutexWeakExt<R: RawMutexWeak, T: ?Sized> {
+    /// Attempts to lock the mutex, like [`lock_api::Mutex::try_lock`], but may fail spuriously.
+    fn try_lock_weak(&self) -> Option<lock_api::MutexGuard<'_, R, T>>;
+}
+
+#[cfg(feature = "lockapi")]
+impl<R: RawMutexWeak, T: ?Sized> MutexWeakExt<R, T> for lock_api::Mutex<R, T> {
+    fn try_lock_weak(&self) -> Option<lock_api::MutexGuard<'_, R, T>> {
+        // Safety: the raw mutex is only locked here, and the guard created for that lock unlocks it again.
+        unsafe {
+            if self.raw().try_lock_weak() {
+                Some(self.make_guard_unchecked())
+            } else {
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! A lock split into independently locked stripes.
+//!
+//! Instead of guarding a whole table with one [`SpinMutex`], each stripe gets its own lock and keys are spread over
+//! the stripes by hash, so threads working on different keys rarely contend.
+use crate::spin::{SpinMutex, SpinMutexGuard};
+use crate::LockAction;
+
+/// `STRIPES` [`SpinMutex`]es selected by key hash.
+///
+/// # Example
+///
+/// ```
+/// use kernel_sync::{striped::StripedLock, EmptyLockAction};
+///
+/// let table = StripedLock::<u32, EmptyLockAction, 4>::new([0; 4]);
+/// let a = table.lock_for(1);
+/// let b = table.lock_for(2); // different stripe, doesn't block
+/// drop((a, b));
+///
+/// // Lock every stripe, in order, for a global operation
+/// let all: Vec<_> = table.iter().collect();
+/// let total: u32 = all.iter().map(|stripe| **stripe).sum();
+/// assert_eq!(total, 0);
+/// ```
+pub struct StripedLock<T, L: LockAction, const STRIPES: usize> {
+    stripes: [SpinMutex<T, L>; STRIPES],
+}
+
+impl<T, L: LockAction, const STRIPES: usize> StripedLock<T, L, STRIPES> {
+    /// Creates a new [`StripedLock`] with one stripe per element of `data`.
+    ///
+    /// There must be at least one stripe, otherwise no key would have a stripe to go to:
+    ///
+    /// ```compile_fail
+    /// use kernel_sync::{striped::StripedLock, EmptyLockAction};
+    ///
+    /// let table = StripedLock::<u32, EmptyLockAction, 0>::new([]);
+    /// ```
+    pub fn new(data: [T; STRIPES]) -> Self {
+        const { assert!(STRIPES > 0, "a StripedLock needs at least one stripe") };
+        StripedLock {
+            stripes: data.map(SpinMutex::new),
+        }
+    }
+
+    /// Returns the index of the stripe that guards `key_hash`.
+    #[inline(always)]
+    pub fn stripe_for(&self, key_hash: u64) -> usize {
+        (key_hash % STRIPES as u64) as usize
+    }
+
+    /// Locks the stripe that guards `key_hash`.
+    #[inline(always)]
+    pub fn lock_for(&self, key_hash: u64) -> SpinMutexGuard<'_, T, L> {
+        self.stripes[self.stripe_for(key_hash)].lock()
+    }
+
+    /// Tries to lock the stripe that guards `key_hash`, returning `None` if it is already locked.
+    #[inline(always)]
+    pub fn try_lock_for(&self, key_hash: u64) -> Option<SpinMutexGuard<'_, T, L>> {
+        self.stripes[self.stripe_for(key_hash)].try_lock()
+    }
+
+    /// Returns an iterator that locks the stripes one after another, in index order.
+    ///
+    /// Guards that are kept (for example by collecting the iterator) stay locked, so collecting locks the whole
+    /// table. Because every caller locks in the same order, this can't deadlock against another `iter`.
+    pub fn iter(&self) -> impl Iterator<Item = SpinMutexGuard<'_, T, L>> {
+        self.stripes.iter().map(|stripe| stripe.lock())
+    }
+
+    /// Consumes this [`StripedLock`] and returns the data of every stripe.
+    pub fn into_inner(self) -> [T; STRIPES] {
+        self.stripes.map(SpinMutex::into_inner)
+    }
+}
+
+impl<T: Default, L: LockAction, const STRIPES: usize> Default for StripedLock<T, L, STRIPES> {
+    fn default() -> Self {
+        Self::new(core::array::from_fn(|_| T::default()))
+    }
+}
@@ -5,13 +5,16 @@
 //! latency is infinitely better. Waiting threads simply need to wait for all threads that come before them in the
 //! queue to finish.
 //!
-use crate::{LockAction};
+use crate::{spin::SpinMutex, LockAction, LockActionSendMarker, TimeSource};
+use alloc::sync::Arc;
 use core::{
     cell::UnsafeCell,
     default::Default,
     fmt,
+    mem::ManuallyDrop,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicUsize, Ordering},
+    ptr,
+    sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering},
 };
 
 /// A spin-based [ticket lock](https://en.wikipedia.org/wiki/Ticket_lock) providing mutually exclusive access to data.
@@ -26,6 +29,9 @@ use core::{
 pub struct TicketMutex<T: ?Sized, L:LockAction> {
     next_ticket: AtomicUsize,
     next_serving: AtomicUsize,
+    /// Bit `ticket % usize::BITS` is set while `ticket` has been given up by [`TicketMutex::lock_or_abort`]
+    /// and still has to be skipped. Such tickets are only taken less than `usize::BITS` after `next_serving`.
+    abandoned: AtomicUsize,
     _marker: core::marker::PhantomData<L>,
     data: UnsafeCell<T>,
 }
@@ -35,17 +41,55 @@ pub struct TicketMutex<T: ?Sized, L:LockAction> {
 /// When the guard is dropped, the next ticket will be processed.
 pub struct TicketMutexGuard<'a, T: ?Sized + 'a, L: LockAction> {
     next_serving: &'a AtomicUsize,
+    /// `None` for a [`RelaxedTicketMutex`], which never abandons a ticket.
+    abandoned: Option<&'a AtomicUsize>,
     ticket: usize,
+    /// The token of `L::before_lock`, handed to `L::after_lock` when the next ticket is served.
+    token: L::Guard,
     data: &'a mut T,
-    _marker: core::marker::PhantomData<L>,
+    /// The pointer keeps the guard `!Send` unless `L` allows it, see the impls below.
+    _marker: core::marker::PhantomData<(L, *const ())>,
+}
+
+/// A guard for a component of the data of a [`TicketMutex`].
+///
+/// Created by [`TicketMutexGuard::map`]. When the guard is dropped, the next ticket will be processed, like for the
+/// original guard. The two guards made by [`TicketMutexGuard::split_map`] process it when the second of them is
+/// dropped.
+pub struct MappedTicketMutexGuard<'a, T: ?Sized + 'a, L: LockAction> {
+    next_serving: &'a AtomicUsize,
+    abandoned: Option<&'a AtomicUsize>,
+    ticket: usize,
+    /// The token of `L::before_lock`, or the default for the halves of a [`TicketMutexGuard::split_map`].
+    token: L::Guard,
+    /// Shared by the two halves of a [`TicketMutexGuard::split_map`], which keep the token here so that the last
+    /// one to be dropped gets it; `None` for a guard that holds the lock alone.
+    split: Option<Arc<L::Guard>>,
+    data: *mut T,
+    _marker: core::marker::PhantomData<(L, *const ())>,
 }
 
 unsafe impl<T: ?Sized + Send, L:LockAction> Sync for TicketMutex<T, L> {}
 unsafe impl<T: ?Sized + Send, L:LockAction> Send for TicketMutex<T, L> {}
+unsafe impl<T: ?Sized + Sync, L: LockAction> Sync for TicketMutexGuard<'_, T, L> {}
+unsafe impl<T: ?Sized + Send, L: LockActionSendMarker> Send for TicketMutexGuard<'_, T, L> {}
+unsafe impl<T: ?Sized + Sync, L: LockAction> Sync for MappedTicketMutexGuard<'_, T, L> {}
+unsafe impl<T: ?Sized + Send, L: LockActionSendMarker> Send for MappedTicketMutexGuard<'_, T, L> {}
 
 impl<T, L:LockAction> TicketMutex<T, L> {
     /// Creates a new [`TicketMutex`] wrapping the supplied data.
     ///
+    /// The lock action is inferred from the type the lock ends up in, so no turbofish is needed when a binding,
+    /// field or static spells it out:
+    ///
+    /// ```
+    /// use kernel_sync::{ticket::TicketMutex, EmptyLockAction};
+    ///
+    /// let lock: TicketMutex<i32, EmptyLockAction> = TicketMutex::new(0);
+    /// ```
+    ///
+    /// The top-level `kernel_sync::TicketMutex` alias always uses [`crate::DefaultLockAction`].
+    ///
     /// # Example
     ///
     /// ```
@@ -64,6 +108,7 @@ impl<T, L:LockAction> TicketMutex<T, L> {
         TicketMutex {
             next_ticket: AtomicUsize::new(0),
             next_serving: AtomicUsize::new(0),
+            abandoned: AtomicUsize::new(0),
             data: UnsafeCell::new(data),
             _marker: core::marker::PhantomData,
         }
@@ -125,15 +170,24 @@ impl<T: ?Sized, L: LockAction> TicketMutex<T, L> {
     /// }
     /// ```
     #[inline(always)]
-    pub fn lock(&self) -> TicketMutexGuard<T, L> {
-        L::before_lock();
+    pub fn lock(&self) -> TicketMutexGuard<'_, T, L> {
+        self.lock_with(Some(&self.abandoned))
+    }
+
+    /// Take a ticket and wait for it, handing `abandoned` to the guard.
+    #[inline(always)]
+    fn lock_with<'a>(&'a self, abandoned: Option<&'a AtomicUsize>) -> TicketMutexGuard<'a, T, L> {
+        let token = L::before_lock();
         let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
         while self.next_serving.load(Ordering::Acquire) != ticket {
-            core::hint::spin_loop();
+            L::park(wait_key(&self.next_serving), ticket);
         }
+        L::on_hold_start();
         TicketMutexGuard {
             next_serving: &self.next_serving,
+            abandoned,
             ticket,
+            token,
             // Safety
             // We know that we are the next ticket to be served,
             // so there's no other thread accessing the data.
@@ -144,6 +198,145 @@ impl<T: ?Sized, L: LockAction> TicketMutex<T, L> {
             _marker: Default::default(),
         }
     }
+    /// Locks the [`TicketMutex`] like [`TicketMutex::lock`], but gives up and returns `None` if `abort` becomes
+    /// `true` before this thread's ticket is served.
+    ///
+    /// The ticket taken by an aborted call is skipped when it comes up, so the threads queued behind it are
+    /// still served in order. No ticket is taken once `abort` is already set. While `usize::BITS` or more tickets
+    /// are outstanding, the call waits for the queue to shrink before taking one, so no two tickets that may be
+    /// abandoned at once are `usize::BITS` apart and share a bit of the abandon mask.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use core::sync::atomic::AtomicBool;
+    ///
+    /// let lock = kernel_sync::TicketMutex::new(0);
+    /// let abort = AtomicBool::new(true);
+    ///
+    /// let guard = lock.lock();
+    /// assert!(lock.lock_or_abort(&abort).is_none());
+    /// drop(guard);
+    ///
+    /// assert!(lock.try_lock().is_some());
+    /// ```
+    #[inline(always)]
+    pub fn lock_or_abort(&self, abort: &AtomicBool) -> Option<TicketMutexGuard<'_, T, L>> {
+        if abort.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.lock_or_give_up(|| abort.load(Ordering::Relaxed))
+    }
+
+    /// Locks the [`TicketMutex`] like [`TicketMutex::lock`], but gives up and returns `None` if this thread's
+    /// ticket isn't served within `ticks` of `C`.
+    ///
+    /// A waiter that times out leaves its ticket behind to be skipped, exactly like
+    /// [`TicketMutex::lock_or_abort`], so the threads queued behind it are still served in order.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use kernel_sync::TimeSource;
+    ///
+    /// struct Uptime;
+    /// impl TimeSource for Uptime {
+    ///     const TICKS_PER_SEC: u64 = 10_000_000;
+    ///     fn now() -> u64 {
+    ///         # static TICKS: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+    ///         # TICKS.fetch_add(1, core::sync::atomic::Ordering::Relaxed)
+    ///         // read the platform timer
+    ///     }
+    /// }
+    ///
+    /// let lock = kernel_sync::TicketMutex::new(0);
+    /// let guard = lock.lock();
+    /// assert!(lock.try_lock_for::<Uptime>(Uptime::ticks_from_micros(10)).is_none());
+    /// drop(guard);
+    /// assert!(lock.try_lock_for::<Uptime>(100).is_some());
+    /// ```
+    #[inline(always)]
+    pub fn try_lock_for<C: TimeSource>(&self, ticks: u64) -> Option<TicketMutexGuard<'_, T, L>> {
+        self.try_lock_until::<C>(C::now().saturating_add(ticks))
+    }
+
+    /// Like [`TicketMutex::try_lock_for`], but gives up once `C::now()` reaches `deadline`.
+    ///
+    /// The ticket is taken even if `deadline` has already passed, so an uncontended lock is still acquired.
+    #[inline(always)]
+    pub fn try_lock_until<C: TimeSource>(&self, deadline: u64) -> Option<TicketMutexGuard<'_, T, L>> {
+        self.lock_or_give_up(|| C::now() >= deadline)
+    }
+
+    /// Take a ticket and wait for it, abandoning it as soon as `give_up` returns `true`.
+    ///
+    /// The ticket is only taken while fewer than `usize::BITS` tickets are outstanding. Every abandoned ticket
+    /// is at least `next_serving`, until it is skipped, so two abandoned tickets are never `usize::BITS` apart.
+    #[inline(always)]
+    fn lock_or_give_up(&self, mut give_up: impl FnMut() -> bool) -> Option<TicketMutexGuard<'_, T, L>> {
+        let token = L::before_lock();
+        let ticket = loop {
+            // A stale `next_serving` only overestimates the length of the queue
+            let serving = self.next_serving.load(Ordering::Relaxed);
+            let ticket = self.next_ticket.load(Ordering::Relaxed);
+            if ticket.wrapping_sub(serving) < usize::BITS as usize {
+                if self
+                    .next_ticket
+                    .compare_exchange_weak(ticket, ticket.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    break ticket;
+                }
+                continue;
+            }
+            if give_up() {
+                L::after_lock(token);
+                return None;
+            }
+            L::relax();
+        };
+        while self.next_serving.load(Ordering::Acquire) != ticket {
+            if give_up() {
+                self.abandon(ticket);
+                L::after_lock(token);
+                return None;
+            }
+            L::relax();
+        }
+        L::on_hold_start();
+        Some(TicketMutexGuard {
+            next_serving: &self.next_serving,
+            abandoned: Some(&self.abandoned),
+            ticket,
+            token,
+            // Safety
+            // Same as `lock`: our ticket is being served.
+            data: unsafe { &mut *self.data.get() },
+            _marker: Default::default(),
+        })
+    }
+
+    /// Give up `ticket` without having been served.
+    fn abandon(&self, ticket: usize) {
+        // If nobody queued up behind us, simply hand the ticket back.
+        if self
+            .next_ticket
+            .compare_exchange(ticket.wrapping_add(1), ticket, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+        let bit = ticket_bit(ticket);
+        self.abandoned.fetch_or(bit, Ordering::SeqCst);
+        // If our ticket came up before the releaser could see the bit, whoever clears the bit first has to pass
+        // the lock on.
+        if self.next_serving.load(Ordering::SeqCst) == ticket
+            && self.abandoned.fetch_and(!bit, Ordering::SeqCst) & bit != 0
+        {
+            unpark_next::<L>(&self.next_serving, Some(&self.abandoned), ticket.wrapping_add(1));
+        }
+    }
+
     /// Try to lock this [`TicketMutex`], returning a lock guard if successful.
     ///
     /// # Example
@@ -158,9 +351,21 @@ impl<T: ?Sized, L: LockAction> TicketMutex<T, L> {
     /// let maybe_guard2 = lock.try_lock();
     /// assert!(maybe_guard2.is_none());
     /// ```
+    ///
+    /// # Lock actions
+    ///
+    /// `L::before_lock` runs exactly once per call, before the ticket is taken, no matter how often the
+    /// compare-and-swap inside has to retry. On failure `L::after_lock` runs before returning `None`; on success it
+    /// runs when the guard is dropped. Either way every `before_lock` is matched by exactly one `after_lock`.
+    #[inline(always)]
+    pub fn try_lock(&self) -> Option<TicketMutexGuard<'_, T, L>> {
+        self.try_lock_with(Some(&self.abandoned))
+    }
+
+    /// Take the ticket being served if it is the next one, handing `abandoned` to the guard.
     #[inline(always)]
-    pub fn try_lock(&self) -> Option<TicketMutexGuard<T, L>> {
-        L::before_lock();
+    fn try_lock_with<'a>(&'a self, abandoned: Option<&'a AtomicUsize>) -> Option<TicketMutexGuard<'a, T, L>> {
+        let token = L::before_lock();
         let ticket = self
             .next_ticket
             .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |ticket| {
@@ -171,9 +376,12 @@ impl<T: ?Sized, L: LockAction> TicketMutex<T, L> {
                 }
             });
         if let Ok(ticket) = ticket {
+            L::on_hold_start();
             Some(TicketMutexGuard {
                 next_serving: &self.next_serving,
+                abandoned,
                 ticket,
+                token,
                 // Safety
                 // We have a ticket that is equal to the next_serving ticket, so we know:
                 // - that no other thread can have the same ticket id as this thread
@@ -182,7 +390,7 @@ impl<T: ?Sized, L: LockAction> TicketMutex<T, L> {
                 _marker: Default::default(),
             })
         } else {
-            L::after_lock();
+            L::after_lock(token);
             None
         }
     }
@@ -192,6 +400,10 @@ impl<T: ?Sized, L: LockAction> TicketMutex<T, L> {
     /// Rust, no actual locking needs to take place -- the mutable borrow statically guarantees no locks exist. As
     /// such, this is a 'zero-cost' operation.
     ///
+    /// The lock state is left untouched. Every guard served the next ticket when it was dropped, so
+    /// [`TicketMutex::is_locked`] reports `false`; only a guard leaked with [`core::mem::forget`] would keep the
+    /// lock held.
+    ///
     /// # Example
     ///
     /// ```
@@ -225,45 +437,350 @@ impl<T: ?Sized, L: LockAction> TicketMutex<T, L> {
     /// This is *extremely* unsafe if the lock is not held by the current
     /// thread. However, this can be useful in some instances for exposing the
     /// lock to FFI that doesn't know how to deal with RAII.
+    ///
+    /// The token of the forgotten guard is lost, so `L::after_lock` gets the default one.
     #[inline(always)]
     pub unsafe fn force_unlock(&self) {
-        self.next_serving.fetch_add(1, Ordering::Release);
-        L::after_lock()
+        self.force_unlock_with(Some(&self.abandoned))
+    }
+
+    /// Serve the next ticket like [`TicketMutex::force_unlock`], skipping the tickets marked in `abandoned`.
+    #[inline(always)]
+    unsafe fn force_unlock_with(&self, abandoned: Option<&AtomicUsize>) {
+        L::on_hold_end();
+        let ticket = self.next_serving.load(Ordering::Relaxed);
+        unpark_next::<L>(&self.next_serving, abandoned, ticket.wrapping_add(1));
+        L::after_lock(L::Guard::default())
+    }
+}
+
+/// A [`TicketMutex`] that hands the lock over with a relaxed store of the next ticket after a single `Release`
+/// fence.
+///
+/// [`TicketMutex`] serves the next ticket with a `SeqCst` store, because [`TicketMutex::lock_or_abort`] and the
+/// timed locks may abandon a ticket just as it comes up, and the releaser and the waiter giving up must not both
+/// miss each other. This lock has no way to give up a ticket, so the handover only has to publish the critical
+/// section to the next owner, which is what the fence does. Whether that is cheaper than the `SeqCst` store
+/// depends on the architecture; `examples/bench.rs` measures both.
+///
+/// The guards are the ones of [`TicketMutex`].
+///
+/// # Example
+///
+/// ```
+/// use kernel_sync::{ticket::RelaxedTicketMutex, EmptyLockAction};
+///
+/// let lock: RelaxedTicketMutex<i32, EmptyLockAction> = RelaxedTicketMutex::new(0);
+/// *lock.lock() += 1;
+/// assert!(!lock.is_locked());
+/// assert_eq!(lock.into_inner(), 1);
+/// ```
+pub struct RelaxedTicketMutex<T: ?Sized, L: LockAction> {
+    inner: TicketMutex<T, L>,
+}
+
+impl<T, L: LockAction> RelaxedTicketMutex<T, L> {
+    /// Creates a new [`RelaxedTicketMutex`] wrapping the supplied data.
+    #[inline(always)]
+    pub const fn new(data: T) -> Self {
+        RelaxedTicketMutex { inner: TicketMutex::new(data) }
+    }
+
+    /// Consumes this [`RelaxedTicketMutex`] and unwraps the underlying data.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized, L: LockAction> RelaxedTicketMutex<T, L> {
+    /// Locks the [`RelaxedTicketMutex`] like [`TicketMutex::lock`].
+    #[inline(always)]
+    pub fn lock(&self) -> TicketMutexGuard<'_, T, L> {
+        self.inner.lock_with(None)
+    }
+
+    /// Tries to lock the [`RelaxedTicketMutex`] like [`TicketMutex::try_lock`].
+    #[inline(always)]
+    pub fn try_lock(&self) -> Option<TicketMutexGuard<'_, T, L>> {
+        self.inner.try_lock_with(None)
+    }
+
+    /// Returns a mutable reference to the underlying data, see [`TicketMutex::get_mut`].
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    /// Returns `true` if the lock is currently held, see [`TicketMutex::is_locked`].
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Force unlock this [`RelaxedTicketMutex`], by serving the next ticket.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`TicketMutex::force_unlock`]: the lock must be held by a guard that was leaked.
+    #[inline(always)]
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock_with(None)
     }
 }
 
 impl<'a, T: ?Sized, L: LockAction> Drop for TicketMutexGuard<'a, T, L> {
     /// The dropping of the TicketMutexGuard will release the lock it was created from.
     fn drop(&mut self) {
-        let new_ticket = self.ticket + 1;
-        self.next_serving.store(new_ticket, Ordering::Release);
-        L::after_lock()
+        unlock::<L>(self.next_serving, self.abandoned, self.ticket, core::mem::take(&mut self.token));
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> TicketMutexGuard<'a, T, L> {
+    /// Make a new guard for a component of the locked data, such as a field.
+    ///
+    /// The ticket is handed over to the returned guard, so the lock stays held until that guard is dropped, and
+    /// dropping it serves the next ticket and runs `L::after_lock` as dropping this guard would have. If `f` panics,
+    /// this guard releases the lock.
+    ///
+    /// ```
+    /// use kernel_sync::ticket::{MappedTicketMutexGuard, TicketMutexGuard};
+    ///
+    /// let lock = kernel_sync::TicketMutex::new((0, [1, 2]));
+    /// let mut second = TicketMutexGuard::map(lock.lock(), |(_, array)| array);
+    /// second[0] = 3;
+    /// let mut element = MappedTicketMutexGuard::map(second, |array| &mut array[1]);
+    /// *element += 1;
+    /// assert!(lock.is_locked());
+    /// drop(element);
+    /// assert_eq!(*lock.lock(), (0, [3, 3]));
+    /// ```
+    #[inline]
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(this: Self, f: F) -> MappedTicketMutexGuard<'a, U, L> {
+        let this = ManuallyDrop::new(this);
+        // The mapped guard takes over the ticket first, so it releases the lock if `f` panics
+        let guard = MappedTicketMutexGuard {
+            next_serving: this.next_serving,
+            abandoned: this.abandoned,
+            ticket: this.ticket,
+            // Safety: `this` is never dropped or used again, so the token and the data reference are only moved
+            // out once.
+            token: unsafe { ptr::read(&this.token) },
+            split: None,
+            data: unsafe { ptr::read(&this.data) } as *mut T,
+            _marker: Default::default(),
+        };
+        MappedTicketMutexGuard::map(guard, f)
+    }
+
+    /// Make two guards for disjoint components of the locked data, such as two fields, that can be used
+    /// independently while the lock stays held.
+    ///
+    /// The next ticket is served, and `L::after_lock` runs once, when the second of the two guards is dropped. The
+    /// guards share a reference count, so this allocates. If `f` panics, this guard releases the lock.
+    ///
+    /// ```
+    /// use kernel_sync::ticket::TicketMutexGuard;
+    ///
+    /// let lock = kernel_sync::TicketMutex::new((vec![1], 0));
+    /// let (mut queue, mut stats) = TicketMutexGuard::split_map(lock.lock(), |(queue, stats)| (queue, stats));
+    /// let item = queue.pop();
+    /// *stats += 1;
+    /// drop(stats);
+    /// assert!(lock.is_locked());
+    /// drop(queue);
+    /// assert_eq!(item, Some(1));
+    /// assert_eq!(*lock.lock(), (vec![], 1));
+    /// ```
+    #[inline]
+    pub fn split_map<A: ?Sized, B: ?Sized, F: FnOnce(&mut T) -> (&mut A, &mut B)>(
+        this: Self,
+        f: F,
+    ) -> (MappedTicketMutexGuard<'a, A, L>, MappedTicketMutexGuard<'a, B, L>) {
+        let mut guard = TicketMutexGuard::map(this, |data| data);
+        // Safety: we hold the lock, and the returned guards take it over.
+        let (a, b) = f(unsafe { &mut *guard.data });
+        let (a, b) = (a as *mut A, b as *mut B);
+        let split = Arc::new(core::mem::take(&mut guard.token));
+        let guard = ManuallyDrop::new(guard);
+        (
+            MappedTicketMutexGuard {
+                next_serving: guard.next_serving,
+                abandoned: guard.abandoned,
+                ticket: guard.ticket,
+                token: L::Guard::default(),
+                split: Some(split.clone()),
+                data: a,
+                _marker: Default::default(),
+            },
+            MappedTicketMutexGuard {
+                next_serving: guard.next_serving,
+                abandoned: guard.abandoned,
+                ticket: guard.ticket,
+                token: L::Guard::default(),
+                split: Some(split),
+                data: b,
+                _marker: Default::default(),
+            },
+        )
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> MappedTicketMutexGuard<'a, T, L> {
+    /// Make a new guard for a component of the data of this one, see [`TicketMutexGuard::map`].
+    #[inline]
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(this: Self, f: F) -> MappedTicketMutexGuard<'a, U, L> {
+        // Safety: we hold the lock, and the returned guard takes it over.
+        let data = f(unsafe { &mut *this.data }) as *mut U;
+        let this = ManuallyDrop::new(this);
+        MappedTicketMutexGuard {
+            next_serving: this.next_serving,
+            abandoned: this.abandoned,
+            ticket: this.ticket,
+            // Safety: `this` is never dropped or used again, so the token and the share are only moved out once.
+            token: unsafe { ptr::read(&this.token) },
+            split: unsafe { ptr::read(&this.split) },
+            data,
+            _marker: Default::default(),
+        }
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> Drop for MappedTicketMutexGuard<'a, T, L> {
+    /// The dropping of the MappedTicketMutexGuard will release the lock it was mapped from.
+    fn drop(&mut self) {
+        // The other half of a split keeps holding the lock. The last half sees the writes of the other one.
+        let token = match self.split.take() {
+            Some(split) => match Arc::into_inner(split) {
+                Some(token) => token,
+                None => return,
+            },
+            None => core::mem::take(&mut self.token),
+        };
+        unlock::<L>(self.next_serving, self.abandoned, self.ticket, token);
+    }
+}
+
+/// Give up `ticket`, which is being served, and run `L::after_lock` with `token`.
+#[inline(always)]
+fn unlock<L: LockAction>(next_serving: &AtomicUsize, abandoned: Option<&AtomicUsize>, ticket: usize, token: L::Guard) {
+    L::on_hold_end();
+    unpark_next::<L>(next_serving, abandoned, ticket.wrapping_add(1));
+    L::after_lock(token)
+}
+
+#[inline(always)]
+fn ticket_bit(ticket: usize) -> usize {
+    1 << (ticket % usize::BITS as usize)
+}
+
+/// The lock identity passed to [`LockAction::park`] and [`LockAction::unpark`].
+#[inline(always)]
+fn wait_key(next_serving: &AtomicUsize) -> usize {
+    next_serving as *const AtomicUsize as usize
+}
+
+/// Serve `ticket` and wake the waiter holding the ticket that ends up being served.
+///
+/// Only that one waiter is unparked, so waiters are woken strictly in ticket order. Waiters that don't park
+/// are woken with [`LockAction::notify`].
+#[inline(always)]
+fn unpark_next<L: LockAction>(next_serving: &AtomicUsize, abandoned: Option<&AtomicUsize>, ticket: usize) {
+    let served = serve(next_serving, abandoned, ticket);
+    L::unpark(wait_key(next_serving), served);
+    L::notify();
+}
+
+/// Serve `ticket`, skipping over any tickets that were abandoned by [`TicketMutex::lock_or_abort`].
+///
+/// Returns the ticket that is being served.
+#[inline(always)]
+fn serve(next_serving: &AtomicUsize, abandoned: Option<&AtomicUsize>, mut ticket: usize) -> usize {
+    let Some(abandoned) = abandoned else {
+        // Nothing can be abandoned, so the store only has to publish the critical section to the next owner. Its
+        // `Acquire` load of the ticket synchronizes with the fence.
+        fence(Ordering::Release);
+        next_serving.store(ticket, Ordering::Relaxed);
+        return ticket;
+    };
+    loop {
+        // Not just `Release`: `abandon` marks its ticket and then checks whether it is served, while this checks the
+        // mark after serving the ticket. Without a total order between the two sides, both could miss each other.
+        next_serving.store(ticket, Ordering::SeqCst);
+        let bit = ticket_bit(ticket);
+        if abandoned.load(Ordering::SeqCst) & bit == 0
+            || abandoned.fetch_and(!bit, Ordering::SeqCst) & bit == 0
+        {
+            return ticket;
+        }
+        ticket = ticket.wrapping_add(1);
     }
 }
 
 impl<T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for TicketMutex<T, L> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.try_lock() {
-            Some(guard) => write!(f, "Mutex {{ data: ")
-                .and_then(|()| (*guard).fmt(f))
-                .and_then(|()| write!(f, "}}")),
-            None => write!(f, "Mutex {{ <locked> }}"),
-        }
+        // Read the lock state before `try_lock` changes it
+        #[cfg(feature = "debug-verbose")]
+        let (next_ticket, next_serving) = (
+            self.next_ticket.load(Ordering::Relaxed),
+            self.next_serving.load(Ordering::Relaxed),
+        );
+        let locked = match self.try_lock() {
+            Some(guard) => {
+                write!(f, "Mutex {{ data: ").and_then(|()| (*guard).fmt(f))?;
+                false
+            }
+            None => {
+                write!(f, "Mutex {{ <locked>")?;
+                true
+            }
+        };
+        #[cfg(feature = "debug-verbose")]
+        write!(f, ", next_ticket: {next_ticket}, next_serving: {next_serving}")?;
+        write!(f, "{}}}", if locked { " " } else { "" })
     }
 }
 
-impl<T: ?Sized + Default, L:LockAction> Default for TicketMutex<T, L> {
+impl<T: Default, L:LockAction> Default for TicketMutex<T, L> {
     fn default() -> Self {
         TicketMutex::new(T::default())
     }
 }
 
+impl<T: Default, L: LockAction> Default for RelaxedTicketMutex<T, L> {
+    fn default() -> Self {
+        RelaxedTicketMutex::new(T::default())
+    }
+}
+
+impl<T, L: LockAction> From<T> for RelaxedTicketMutex<T, L> {
+    fn from(data: T) -> Self {
+        Self::new(data)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for RelaxedTicketMutex<T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "RelaxedTicketMutex {{ data: ").and_then(|()| (*guard).fmt(f))?,
+            None => write!(f, "RelaxedTicketMutex {{ <locked> ")?,
+        }
+        write!(f, "}}")
+    }
+}
+
 impl<T, L:LockAction> From<T> for TicketMutex<T, L> {
     fn from(data: T) -> Self {
         Self::new(data)
     }
 }
 
+impl<T, L:LockAction> From<SpinMutex<T, L>> for TicketMutex<T, L> {
+    fn from(lock: SpinMutex<T, L>) -> Self {
+        Self::new(lock.into_inner())
+    }
+}
+
 impl<'a, T: ?Sized + fmt::Display, L: LockAction> fmt::Display for TicketMutexGuard<'a, T, L> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(&**self, f)
@@ -276,6 +793,33 @@ impl<'a, T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for TicketMutexGuard<
     }
 }
 
+impl<'a, T: ?Sized + fmt::Display, L: LockAction> fmt::Display for MappedTicketMutexGuard<'a, T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, L: LockAction> fmt::Debug for MappedTicketMutexGuard<'a, T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> Deref for MappedTicketMutexGuard<'a, T, L> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: We hold the lock
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized, L: LockAction> DerefMut for MappedTicketMutexGuard<'a, T, L> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: We hold the lock
+        unsafe { &mut *self.data }
+    }
+}
+
 impl<'a, T: ?Sized, L: LockAction> Deref for TicketMutexGuard<'a, T, L> {
     type Target = T;
     fn deref(&self) -> &T {
@@ -289,10 +833,10 @@ impl<'a, T: ?Sized, L: LockAction> DerefMut for TicketMutexGuard<'a, T, L> {
     }
 }
 #[cfg(feature = "lockapi")]
-unsafe impl<L: LockAction> lock_api::RawMutex for TicketMutex<(), L> {
+unsafe impl<L: LockAction<Guard = ()>> lock_api::RawMutex for TicketMutex<(), L> {
     #[allow(clippy::declare_interior_mutable_const)]
     const INIT: Self = Self::new(());
-    type GuardMarker = lock_api::GuardSend;
+    type GuardMarker = lock_api::GuardNoSend;
 
     fn lock(&self) {
         core::mem::forget(Self::lock(self))
@@ -0,0 +1,42 @@
+mod common;
+use common::{RwLock, SpinMutex, TicketMutex};
+use kernel_sync::assert_unlocked;
+
+#[test]
+fn free_locks_pass() {
+    let spin = SpinMutex::new(0);
+    let ticket = TicketMutex::new(0);
+    let rwlock = RwLock::new(0);
+    drop((spin.lock(), ticket.lock(), rwlock.write()));
+    drop(rwlock.read());
+    assert_unlocked!(spin);
+    assert_unlocked!(ticket);
+    assert_unlocked!(rwlock, "rwlock still held");
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "assert_unlocked!(spin) failed")]
+fn held_spin_panics() {
+    let spin = SpinMutex::new(0);
+    let _guard = spin.lock();
+    assert_unlocked!(spin);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "ticket held by test")]
+fn held_ticket_panics() {
+    let ticket = TicketMutex::new(0);
+    let _guard = ticket.lock();
+    assert_unlocked!(ticket, "ticket held by {}", "test");
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "assert_unlocked!(rwlock) failed")]
+fn held_reader_panics() {
+    let rwlock = RwLock::new(0);
+    let _guard = rwlock.read();
+    assert_unlocked!(rwlock);
+}
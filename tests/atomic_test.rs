@@ -0,0 +1,30 @@
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec;
+use kernel_sync::atomic::AtomicCounter;
+
+#[test]
+fn inc_dec_balance_test() {
+    let counter = Arc::new(AtomicCounter::new(0));
+    let thread_cnt = 4;
+    let loop_cnt = 100000;
+    let mut threads = vec![];
+    for _ in 0..thread_cnt {
+        let counter = counter.clone();
+        threads.push(std::thread::spawn(move || {
+            for _ in 0..loop_cnt {
+                counter.inc();
+                counter.dec();
+            }
+            counter.inc();
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(counter.get(), thread_cnt);
+    for _ in 0..thread_cnt {
+        counter.dec();
+    }
+    assert_eq!(counter.get(), 0);
+}
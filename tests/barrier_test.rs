@@ -0,0 +1,49 @@
+mod common;
+use kernel_sync::barrier::Barrier;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use common::YieldAction;
+
+/// Every thread bumps the counter only after the barrier, and checks that all threads arrived before any bump of
+/// its round: a thread released early would see a count below the round's start.
+#[test]
+fn rendezvous_test() {
+    const THREADS: usize = 4;
+    const ROUNDS: usize = 50;
+    let barrier = Arc::new(Barrier::<YieldAction>::new(THREADS));
+    let arrived = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let barrier = barrier.clone();
+            let arrived = arrived.clone();
+            let counter = counter.clone();
+            std::thread::spawn(move || {
+                let mut leaders = 0;
+                for round in 0..ROUNDS {
+                    arrived.fetch_add(1, Ordering::Relaxed);
+                    if barrier.wait().is_leader() {
+                        leaders += 1;
+                    }
+                    assert!(arrived.load(Ordering::Relaxed) >= (round + 1) * THREADS);
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    // Nobody starts the next round before everyone bumped the counter for this one
+                    barrier.wait();
+                    assert!(counter.load(Ordering::Relaxed) >= (round + 1) * THREADS);
+                }
+                leaders
+            })
+        })
+        .collect();
+    let leaders: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+    assert_eq!(counter.load(Ordering::Relaxed), THREADS * ROUNDS);
+    assert_eq!(leaders, ROUNDS);
+}
+
+#[test]
+fn single_cpu_test() {
+    let barrier = Barrier::<YieldAction>::new(0);
+    assert_eq!(barrier.n(), 1);
+    assert!(barrier.wait().is_leader());
+    assert!(barrier.wait().is_leader());
+}
@@ -0,0 +1,41 @@
+extern crate alloc;
+mod common;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use kernel_sync::cell::LockCell;
+use common::YieldAction;
+
+#[test]
+fn get_set_replace_test() {
+    let cell = LockCell::<u32, YieldAction>::new(1);
+    assert_eq!(cell.get(), 1);
+    cell.set(2);
+    assert_eq!(cell.replace(3), 2);
+    assert_eq!(cell.update(|v| v * 2), 6);
+    assert_eq!(format!("{cell:?}"), "LockCell { value: 6 }");
+    let mut cell = cell;
+    *cell.get_mut() += 1;
+    assert_eq!(cell.into_inner(), 7);
+    assert_eq!(kernel_sync::LockCell::<u8>::default().get(), 0);
+}
+
+#[test]
+fn concurrent_update_test() {
+    let cell = Arc::new(LockCell::<usize, YieldAction>::new(0));
+    let thread_cnt = 4;
+    let loop_cnt = 10000;
+    let threads: Vec<_> = (0..thread_cnt)
+        .map(|_| {
+            let cell = cell.clone();
+            std::thread::spawn(move || {
+                for _ in 0..loop_cnt {
+                    cell.update(|v| v + 1);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(cell.get(), thread_cnt * loop_cnt);
+}
@@ -0,0 +1,45 @@
+//! Lock actions shared by the integration tests. Each test file only uses some of them.
+#![allow(dead_code)]
+
+use kernel_sync::{EmptyLockAction, LockAction};
+use std::cell::Cell;
+
+// The top-level aliases with `EmptyLockAction` instead of `DefaultLockAction`, which the features pick. With
+// `critical-section`, every lock of the top-level aliases holds one global critical section, and the tests that
+// make a thread wait for another one while holding a lock would deadlock.
+pub type SpinMutex<T> = kernel_sync::spin::SpinMutex<T, EmptyLockAction>;
+pub type TicketMutex<T> = kernel_sync::ticket::TicketMutex<T, EmptyLockAction>;
+pub type RelaxedTicketMutex<T> = kernel_sync::ticket::RelaxedTicketMutex<T, EmptyLockAction>;
+pub type RwLock<T> = kernel_sync::rwlock::RwLock<T, EmptyLockAction>;
+pub type RcuLock<T> = kernel_sync::rculock::RcuLock<T, EmptyLockAction>;
+pub type EpochRcuLock<T> = kernel_sync::epochrcu::EpochRcuLock<T, EmptyLockAction>;
+
+/// Yield instead of spinning so the tests also make progress on a single CPU.
+pub struct YieldAction;
+impl LockAction for YieldAction {
+    type Guard = ();
+    fn relax() {
+        std::thread::yield_now();
+    }
+}
+
+thread_local! {
+    static IRQ_OFF: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts nested interrupt-disable sections of the current thread, see [`irq_off`].
+pub struct IrqAction;
+impl LockAction for IrqAction {
+    type Guard = ();
+    fn before_lock() {
+        IRQ_OFF.with(|off| off.set(off.get() + 1));
+    }
+    fn after_lock(_guard: ()) {
+        IRQ_OFF.with(|off| off.set(off.get() - 1));
+    }
+}
+
+/// How many interrupt-disable sections of [`IrqAction`] the current thread is in.
+pub fn irq_off() -> usize {
+    IRQ_OFF.with(Cell::get)
+}
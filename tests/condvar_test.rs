@@ -0,0 +1,52 @@
+mod common;
+use kernel_sync::condvar::Condvar;
+use kernel_sync::spin::SpinMutex;
+use std::sync::Arc;
+use common::YieldAction;
+
+#[test]
+fn notify_test() {
+    let pair = Arc::new((SpinMutex::<_, YieldAction>::new(false), Condvar::<YieldAction>::new()));
+    let pair2 = pair.clone();
+    let consumer = std::thread::spawn(move || {
+        let (flag, condvar) = &*pair2;
+        let guard = condvar.wait_while(flag.lock(), |ready| !*ready);
+        assert!(*guard);
+    });
+
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    let (flag, condvar) = &*pair;
+    *flag.lock() = true;
+    condvar.notify_one();
+    consumer.join().unwrap();
+    assert!(!flag.is_locked());
+}
+
+/// The producer notifies right after each change, often before the consumer has released the mutex in `wait`.
+/// A lost wakeup would leave the consumer waiting forever.
+#[test]
+fn ping_pong_test() {
+    let pair = Arc::new((SpinMutex::<_, YieldAction>::new(0), Condvar::<YieldAction>::new()));
+    let pair2 = pair.clone();
+    let rounds = 1000;
+    let consumer = std::thread::spawn(move || {
+        let (turn, condvar) = &*pair2;
+        let mut guard = turn.lock();
+        for i in 0..rounds {
+            guard = condvar.wait_while(guard, |turn| *turn != 2 * i + 1);
+            *guard += 1;
+            condvar.notify_all();
+        }
+    });
+
+    let (turn, condvar) = &*pair;
+    let mut guard = turn.lock();
+    for i in 0..rounds {
+        guard = condvar.wait_while(guard, |turn| *turn != 2 * i);
+        *guard += 1;
+        condvar.notify_all();
+    }
+    drop(guard);
+    consumer.join().unwrap();
+    assert_eq!(*turn.lock(), 2 * rounds);
+}
@@ -0,0 +1,217 @@
+extern crate alloc;
+mod common;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel_sync::epochrcu::EpochDomain;
+use common::EpochRcuLock;
+
+#[test]
+fn basic_test() {
+    let x = Arc::new(EpochRcuLock::new(0));
+    let thread_cnt = 3;
+    let loop_cnt = 100000;
+    let mut threads = vec![];
+    for _ in 0..thread_cnt {
+        let x_clone = x.clone();
+        threads.push(std::thread::spawn(move || {
+            for _ in 0..loop_cnt {
+                let mut guard = x_clone.write();
+                *guard += 1;
+            }
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*(x.read()), thread_cnt * loop_cnt);
+}
+
+#[test]
+fn try_lock_test() {
+    let x = EpochRcuLock::new(0);
+    let lock_result0 = x.try_write();
+    assert!(lock_result0.is_some());
+
+    let lock_result1 = x.try_write();
+    assert!(lock_result1.is_none());
+
+    drop(lock_result0);
+
+    let lock_result2 = x.try_write();
+    assert!(lock_result2.is_some());
+}
+
+#[test]
+fn read_write_test() {
+    let x = EpochRcuLock::new(0);
+    let reader = x.read();
+    {
+        let mut writer = x.write();
+        *writer = 1;
+        // Readers keep seeing the published version while the writer works on its copy
+        assert_eq!(*x.read(), 0);
+    }
+    // An older reader still sees the version it pinned, newer readers see the update
+    assert_eq!(*reader, 0);
+    assert_eq!(*x.read(), 1);
+    *x.write() += 1;
+    assert_eq!(*reader, 0);
+    drop(reader);
+    assert_eq!(*x.read(), 2);
+}
+
+#[test]
+fn concurrent_read_write_test() {
+    let x = Arc::new(EpochRcuLock::new((0, 0)));
+    let loop_cnt = 10000;
+    let writer = {
+        let x = x.clone();
+        std::thread::spawn(move || {
+            for i in 1..=loop_cnt {
+                let mut guard = x.write();
+                guard.0 = i;
+                guard.1 = 2 * i;
+            }
+        })
+    };
+    let mut last = 0;
+    while last < loop_cnt {
+        let guard = x.read();
+        assert_eq!(guard.1, 2 * guard.0);
+        assert!(guard.0 >= last);
+        last = guard.0;
+    }
+    writer.join().unwrap();
+}
+
+#[test]
+fn reclaim_test() {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+    #[derive(Clone)]
+    struct Counted;
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let domain: &'static EpochDomain = Box::leak(Box::new(EpochDomain::new()));
+    let x = EpochRcuLock::with_domain(Counted, domain);
+    let y = EpochRcuLock::with_domain(Counted, domain);
+
+    let reader = x.read();
+    drop(x.write());
+    drop(y.write());
+    // The reader pins the epoch, so neither old version can be freed yet
+    assert_eq!(domain.collect(), 2);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+
+    drop(reader);
+    while domain.collect() > 0 {}
+    assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+
+    drop((x, y));
+    assert_eq!(DROPS.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn try_read_test() {
+    let x = EpochRcuLock::new(0);
+    assert_eq!(*x.try_read().unwrap(), 0);
+
+    let mut guard = x.try_write().unwrap();
+    *guard = 1;
+    assert!(x.try_read().is_none());
+    assert!(x.try_write().is_none());
+    // A blocking read still sees the old version
+    assert_eq!(*x.read(), 0);
+    drop(guard);
+
+    assert_eq!(*x.try_read().unwrap(), 1);
+    assert!(x.try_write().is_some());
+}
+
+#[test]
+fn read_consistent_test() {
+    let x = Arc::new(EpochRcuLock::new((0usize, 0usize)));
+    let loop_cnt = 100000;
+    let x_clone = x.clone();
+    let writer = std::thread::spawn(move || {
+        for i in 1..=loop_cnt {
+            let mut guard = x_clone.write();
+            guard.0 = i;
+            guard.1 = 2 * i;
+        }
+    });
+    let mut last = 0;
+    while last < loop_cnt {
+        let (a, b) = x.read_consistent(|v| (v.0, v.1));
+        assert_eq!(b, 2 * a);
+        assert!(a >= last);
+        last = a;
+    }
+    writer.join().unwrap();
+    assert_eq!(x.version(), loop_cnt);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "recursively")]
+fn recursive_write_test() {
+    struct SingleCpuAction;
+    impl kernel_sync::LockAction for SingleCpuAction {
+        type Guard = ();
+        fn current_cpu() -> Option<usize> {
+            Some(0)
+        }
+    }
+
+    let x = kernel_sync::epochrcu::EpochRcuLock::<_, SingleCpuAction>::new(0);
+    let _guard = x.write();
+    let _nested = x.write();
+}
+
+#[test]
+fn replace_test() {
+    let x = EpochRcuLock::new(alloc::string::String::from("a"));
+    assert_eq!(x.replace("b".into()), "a");
+    assert_eq!(x.replace("c".into()), "b");
+    assert_eq!(*x.read(), "c");
+    assert_eq!(x.version(), 2);
+}
+
+/// More read guards than there are slots, nested on one CPU and spread over several locks, neither wait nor
+/// let a writer free what they read.
+#[test]
+fn nested_readers_test() {
+    use kernel_sync::epochrcu::EPOCH_SLOTS;
+
+    struct SingleCpuAction;
+    impl kernel_sync::LockAction for SingleCpuAction {
+        type Guard = ();
+        fn current_cpu() -> Option<usize> {
+            Some(0)
+        }
+    }
+
+    let domain: &'static EpochDomain = Box::leak(Box::new(EpochDomain::new()));
+    let locks: Vec<_> = (0..3)
+        .map(|i| kernel_sync::epochrcu::EpochRcuLock::<_, SingleCpuAction>::with_domain(vec![i], domain))
+        .collect();
+    let guards: Vec<_> = (0..2 * EPOCH_SLOTS).map(|i| locks[i % 3].read()).collect();
+    // Readers without a CPU take free slots, and share one once none are left
+    let anonymous: Vec<_> = (0..2 * EPOCH_SLOTS).map(|_| EpochRcuLock::with_domain(0, domain)).collect();
+    let anonymous_guards: Vec<_> = anonymous.iter().map(|lock| lock.read()).collect();
+    for lock in &locks {
+        lock.write().push(10);
+    }
+    assert_eq!(domain.collect(), 3);
+    for (i, guard) in guards.iter().enumerate() {
+        assert_eq!(**guard, [i % 3]);
+    }
+    drop(guards);
+    drop(anonymous_guards);
+    while domain.collect() > 0 {}
+    assert_eq!(*locks[1].read(), [1, 10]);
+}
@@ -0,0 +1,330 @@
+use std::cell::Cell;
+
+use kernel_sync::{
+    rculock::RcuLock, rwlock::RwLock, spin::SpinMutex, ticket::TicketMutex, LockAction,
+};
+
+thread_local! {
+    static STARTS: Cell<usize> = const { Cell::new(0) };
+    static ENDS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts hold start and end per thread, checking that every end matches an earlier start.
+struct HoldAction;
+impl LockAction for HoldAction {
+    type Guard = ();
+    fn on_hold_start() {
+        STARTS.with(|starts| starts.set(starts.get() + 1));
+    }
+    fn on_hold_end() {
+        let starts = STARTS.with(Cell::get);
+        ENDS.with(|ends| {
+            assert!(ends.get() < starts, "on_hold_end without a matching on_hold_start");
+            ends.set(ends.get() + 1);
+        });
+    }
+}
+
+/// Run `f` and return how many holds it started and ended.
+fn holds(f: impl FnOnce()) -> (usize, usize) {
+    let starts = STARTS.with(Cell::get);
+    let ends = ENDS.with(Cell::get);
+    f();
+    (STARTS.with(Cell::get) - starts, ENDS.with(Cell::get) - ends)
+}
+
+#[test]
+fn spin_hold_hooks() {
+    let lock = SpinMutex::<_, HoldAction>::new(0);
+    assert_eq!(holds(|| *lock.lock() += 1), (1, 1));
+    assert_eq!(holds(|| *lock.lock_optimistic() += 1), (1, 1));
+    assert_eq!(
+        holds(|| {
+            let guard = lock.lock();
+            assert!(lock.try_lock().is_none());
+            drop(guard);
+        }),
+        (1, 1)
+    );
+    assert_eq!(holds(|| lock.lock().unlock_and_relock()), (2, 2));
+    assert_eq!(
+        holds(|| {
+            core::mem::forget(lock.lock());
+            unsafe { lock.force_unlock() };
+        }),
+        (1, 1)
+    );
+}
+
+#[test]
+fn ticket_hold_hooks() {
+    let lock = TicketMutex::<_, HoldAction>::new(0);
+    assert_eq!(holds(|| *lock.lock() += 1), (1, 1));
+    assert_eq!(
+        holds(|| {
+            let guard = lock.try_lock().unwrap();
+            assert!(lock.try_lock().is_none());
+            drop(guard);
+        }),
+        (1, 1)
+    );
+    assert_eq!(
+        holds(|| {
+            core::mem::forget(lock.lock());
+            unsafe { lock.force_unlock() };
+        }),
+        (1, 1)
+    );
+}
+
+#[test]
+fn rwlock_hold_hooks() {
+    let lock = RwLock::<_, HoldAction>::new(0);
+    assert_eq!(
+        holds(|| {
+            let a = lock.read();
+            let b = lock.read();
+            assert!(lock.try_write().is_none());
+            drop((a, b));
+        }),
+        (2, 2)
+    );
+    assert_eq!(holds(|| *lock.write() += 1), (1, 1));
+    assert_eq!(holds(|| *lock.upgradeable_read().upgrade() += 1), (1, 1));
+    assert_eq!(holds(|| drop(lock.write().downgrade())), (2, 2));
+    assert_eq!(holds(|| drop(lock.upgradeable_read().downgrade())), (2, 2));
+    assert_eq!(
+        holds(|| drop(lock.write().downgrade_to_upgradeable())),
+        (1, 1)
+    );
+    assert!(lock.write_if(|data| *data < 0).is_none());
+}
+
+#[test]
+fn rcu_hold_hooks() {
+    let lock = RcuLock::<_, HoldAction>::new(0);
+    assert_eq!(holds(|| assert_eq!(*lock.read(), 0)), (1, 1));
+    assert_eq!(holds(|| *lock.write() += 1), (1, 1));
+    assert_eq!(
+        holds(|| {
+            let guard = lock.write();
+            assert!(lock.try_write().is_none());
+            assert!(lock.try_read().is_none());
+            drop(guard);
+        }),
+        (1, 1)
+    );
+    assert_eq!(holds(|| assert_eq!(*lock.try_read().unwrap(), 1)), (1, 1));
+}
+
+#[test]
+fn on_spin_counts_contention() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    static SPINS: AtomicUsize = AtomicUsize::new(0);
+    struct SpinCountAction;
+    impl LockAction for SpinCountAction {
+        type Guard = ();
+        fn on_spin() {
+            SPINS.fetch_add(1, Ordering::Relaxed);
+            std::thread::yield_now();
+        }
+    }
+
+    let lock = Arc::new(SpinMutex::<_, SpinCountAction>::new(0));
+    *lock.lock() += 1;
+    assert_eq!(SPINS.load(Ordering::Relaxed), 0);
+
+    let guard = lock.lock();
+    let other = lock.clone();
+    let waiter = std::thread::spawn(move || *other.lock() += 1);
+    while SPINS.load(Ordering::Relaxed) == 0 {
+        std::thread::yield_now();
+    }
+    drop(guard);
+    waiter.join().unwrap();
+    assert_eq!(*lock.lock(), 2);
+}
+
+#[test]
+fn wait_and_notify_hooks() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    static WAITS: AtomicUsize = AtomicUsize::new(0);
+    static NOTIFIES: AtomicUsize = AtomicUsize::new(0);
+    /// Stands in for `wfe`/`sev`: yields while waiting and counts the events sent.
+    struct EventAction;
+    impl LockAction for EventAction {
+        type Guard = ();
+        fn wait() {
+            WAITS.fetch_add(1, Ordering::Relaxed);
+            std::thread::yield_now();
+        }
+        fn notify() {
+            NOTIFIES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let spin = Arc::new(SpinMutex::<_, EventAction>::new(0));
+    let ticket = Arc::new(TicketMutex::<_, EventAction>::new(0));
+    *spin.lock() += 1;
+    *ticket.lock() += 1;
+    assert_eq!(WAITS.load(Ordering::Relaxed), 0);
+    assert_eq!(NOTIFIES.load(Ordering::Relaxed), 2);
+    assert!(spin.try_lock().is_some());
+    unsafe {
+        core::mem::forget(ticket.lock());
+        ticket.force_unlock();
+    }
+    assert_eq!(NOTIFIES.load(Ordering::Relaxed), 4);
+
+    // Contended locks wait until the holder releases
+    let (spin_guard, ticket_guard) = (spin.lock(), ticket.lock());
+    let waiters = [
+        {
+            let spin = spin.clone();
+            std::thread::spawn(move || *spin.lock() += 1)
+        },
+        {
+            let ticket = ticket.clone();
+            std::thread::spawn(move || *ticket.lock() += 1)
+        },
+    ];
+    while WAITS.load(Ordering::Relaxed) < 2 {
+        std::thread::yield_now();
+    }
+    drop((spin_guard, ticket_guard));
+    for waiter in waiters {
+        waiter.join().unwrap();
+    }
+    assert_eq!((*spin.lock(), *ticket.lock()), (2, 2));
+}
+
+#[test]
+#[cfg(feature = "critical-section")]
+fn critical_section_action_nests() {
+    use kernel_sync::CriticalSectionLockAction;
+
+    let outer = SpinMutex::<_, CriticalSectionLockAction>::new(0);
+    let inner = TicketMutex::<_, CriticalSectionLockAction>::new(0);
+    {
+        let mut a = outer.lock();
+        let mut b = inner.lock();
+        assert!(outer.try_lock().is_none());
+        *a += 1;
+        *b += 1;
+    }
+    // Both critical sections were left, so another thread can take the locks
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            *outer.lock() += 1;
+            *inner.lock() += 1;
+        });
+    });
+    assert_eq!((*outer.lock(), *inner.lock()), (2, 2));
+}
+
+#[test]
+fn action_inferred_from_annotation() {
+    use kernel_sync::{cell::LockCell, epochrcu::EpochRcuLock};
+
+    struct MyAction;
+    impl LockAction for MyAction {
+        type Guard = ();
+    }
+
+    static STATIC: TicketMutex<i32, MyAction> = TicketMutex::new(0);
+    struct Device {
+        regs: SpinMutex<u32, MyAction>,
+        config: RwLock<u8, MyAction>,
+    }
+
+    let ticket: TicketMutex<i32, MyAction> = TicketMutex::new(1);
+    let device = Device {
+        regs: SpinMutex::new(2),
+        config: RwLock::new(3),
+    };
+    let rcu: RcuLock<i32, MyAction> = RcuLock::new(4);
+    let epoch: EpochRcuLock<i32, MyAction> = EpochRcuLock::new(5);
+    let cell: LockCell<i32, MyAction> = LockCell::new(6);
+    *STATIC.lock() += 7;
+    assert_eq!(*ticket.lock(), 1);
+    assert_eq!(*device.regs.lock(), 2);
+    assert_eq!(*device.config.read(), 3);
+    assert_eq!(*rcu.read(), 4);
+    assert_eq!(*epoch.read(), 5);
+    assert_eq!(cell.get(), 6);
+    assert_eq!(*STATIC.lock(), 7);
+}
+
+thread_local! {
+    static TOKENS: std::cell::RefCell<Vec<usize>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Hands out numbered tokens, checking that each comes back to `after_lock` in reverse order.
+struct TokenAction;
+impl LockAction for TokenAction {
+    type Guard = usize;
+    fn before_lock() -> usize {
+        TOKENS.with(|tokens| {
+            let mut tokens = tokens.borrow_mut();
+            let token = tokens.len() + 1;
+            tokens.push(token);
+            token
+        })
+    }
+    fn after_lock(token: usize) {
+        let last = TOKENS.with(|tokens| tokens.borrow_mut().pop());
+        assert_eq!(last, Some(token), "token released out of order");
+    }
+}
+
+fn outstanding_tokens() -> usize {
+    TOKENS.with(|tokens| tokens.borrow().len())
+}
+
+#[test]
+fn tokens_reach_after_lock() {
+    let spin = SpinMutex::<_, TokenAction>::new(0);
+    let ticket = TicketMutex::<_, TokenAction>::new(0);
+    {
+        let mut a = spin.lock();
+        let mut b = ticket.lock();
+        assert_eq!(outstanding_tokens(), 2);
+        assert!(spin.try_lock().is_none());
+        assert!(ticket.try_lock().is_none());
+        *a += 1;
+        *b += 1;
+        drop(b);
+        drop(a);
+    }
+    assert_eq!(outstanding_tokens(), 0);
+
+    let rwlock = RwLock::<_, TokenAction>::new(0);
+    {
+        let read = rwlock.write().downgrade();
+        assert!(rwlock.try_write().is_none());
+        let upgradeable = rwlock.upgradeable_read();
+        drop(upgradeable);
+        drop(read);
+    }
+    {
+        let write = rwlock.upgradeable_read().upgrade();
+        let upgradeable = write.downgrade_to_upgradeable();
+        assert!(rwlock.try_upgradeable_read().is_none());
+        drop(upgradeable.downgrade());
+    }
+    assert_eq!(outstanding_tokens(), 0);
+
+    let rcu = RcuLock::<_, TokenAction>::new(0);
+    {
+        let write = rcu.write();
+        assert!(rcu.try_write().is_none());
+        assert!(rcu.try_read().is_none());
+        drop(write);
+        assert_eq!(*rcu.read(), 0);
+    }
+    assert_eq!(outstanding_tokens(), 0);
+}
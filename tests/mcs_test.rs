@@ -0,0 +1,61 @@
+mod common;
+use kernel_sync::mcs::{McsMutex, McsNode};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use common::YieldAction;
+
+#[test]
+fn lock_test() {
+    let lock = McsMutex::<_, YieldAction>::new(0);
+    let mut node = McsNode::new();
+    // Safety: the guard is dropped below
+    let mut guard = unsafe { lock.lock(&mut node) };
+    *guard += 1;
+    assert_eq!(format!("{lock:?}"), "McsMutex { <locked> }");
+    drop(guard);
+    assert!(!lock.is_locked());
+    assert_eq!(format!("{lock:?}"), "McsMutex { data: 1 }");
+    let mut lock = lock;
+    *lock.get_mut() += 1;
+    assert_eq!(lock.into_inner(), 2);
+}
+
+/// Every thread brings its own node on its stack. Inside the lock, a thread checks that it is alone, and updates a
+/// counter with a separate load and store that would lose updates if two threads got in at once.
+#[test]
+fn contended_test() {
+    const THREADS: usize = 4;
+    const ITERATIONS: usize = 5000;
+    let lock = McsMutex::<_, YieldAction>::new(0usize);
+    let inside = AtomicUsize::new(0);
+    let locked = AtomicUsize::new(0);
+    std::thread::scope(|s| {
+        for t in 0..THREADS {
+            let (lock, inside, locked) = (&lock, &inside, &locked);
+            s.spawn(move || {
+                for i in 0..ITERATIONS {
+                    let mut node = McsNode::new();
+                    // Safety: the guard is dropped at the end of the iteration
+                    let mut guard = if (t + i) % 4 == 0 {
+                        match unsafe { lock.try_lock(&mut node) } {
+                            Some(guard) => guard,
+                            None => continue,
+                        }
+                    } else {
+                        unsafe { lock.lock(&mut node) }
+                    };
+                    assert_eq!(inside.fetch_add(1, Ordering::Relaxed), 0);
+                    let value = *guard;
+                    if i % 64 == 0 {
+                        // Let the others queue up behind us
+                        std::thread::yield_now();
+                    }
+                    *guard = value + 1;
+                    inside.fetch_sub(1, Ordering::Relaxed);
+                    locked.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+    assert_eq!(lock.into_inner(), locked.load(Ordering::Relaxed));
+    assert!(locked.load(Ordering::Relaxed) >= THREADS * ITERATIONS * 3 / 4);
+}
@@ -0,0 +1,220 @@
+//! Small scenarios for every lock type, sized so that they also run under Miri:
+//!
+//! ```text
+//! cargo +nightly miri test --test miri_test
+//! ```
+extern crate alloc;
+mod common;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use common::{EpochRcuLock, RcuLock, RelaxedTicketMutex, RwLock, SpinMutex, TicketMutex};
+
+const THREADS: usize = 3;
+const LOOPS: usize = 20;
+
+/// Run `f` on `THREADS` threads and wait for all of them.
+fn on_threads(f: impl Fn(usize) + Send + Sync + 'static) {
+    let f = Arc::new(f);
+    let threads: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let f = f.clone();
+            std::thread::spawn(move || f(i))
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn spin() {
+    let lock = Arc::new(SpinMutex::new(vec![0]));
+    lock.lock().push(1);
+    assert!(lock.try_lock().is_some());
+    let other = lock.clone();
+    on_threads(move |_| {
+        for _ in 0..LOOPS {
+            other.lock()[0] += 1;
+        }
+    });
+    assert_eq!(lock.lock()[0], THREADS * LOOPS);
+    let (guard, len) = lock.lock_map(|data| data.len());
+    assert_eq!(len, 2);
+    drop(guard);
+    let lock = Arc::try_unwrap(lock).unwrap();
+    assert_eq!(lock.into_inner(), vec![THREADS * LOOPS, 1]);
+}
+
+#[test]
+fn ticket() {
+    let lock = Arc::new(TicketMutex::new(String::new()));
+    let other = lock.clone();
+    on_threads(move |i| {
+        for _ in 0..LOOPS {
+            other.lock().push(char::from(b'a' + i as u8));
+        }
+    });
+    assert_eq!(lock.lock().len(), THREADS * LOOPS);
+    let guard = lock.try_lock().unwrap();
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+}
+
+/// The handover of the relaxed variant is a fence and a relaxed store, so run this with several seeds.
+#[test]
+fn relaxed_ticket() {
+    let lock = Arc::new(RelaxedTicketMutex::new(Vec::new()));
+    let other = lock.clone();
+    on_threads(move |i| {
+        for _ in 0..LOOPS {
+            let mut guard = other.lock();
+            guard.push(i);
+        }
+    });
+    assert_eq!(lock.lock().len(), THREADS * LOOPS);
+    let guard = lock.try_lock().unwrap();
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+}
+
+#[test]
+fn ticket_abort() {
+    let lock = Arc::new(TicketMutex::new(0));
+    let abort = Arc::new(core::sync::atomic::AtomicBool::new(false));
+    let other = lock.clone();
+    let flag = abort.clone();
+    on_threads(move |i| {
+        for n in 0..LOOPS {
+            if i == 0 {
+                *other.lock() += 1;
+                flag.store(n % 2 == 0, core::sync::atomic::Ordering::Relaxed);
+            } else if let Some(mut guard) = other.lock_or_abort(&flag) {
+                *guard += 1;
+            }
+        }
+    });
+    // Abandoned tickets must have been skipped, or this would wait forever
+    assert!(*lock.lock() >= LOOPS);
+}
+
+#[test]
+fn rwlock() {
+    let lock = Arc::new(RwLock::new(vec![0usize]));
+    let other = lock.clone();
+    on_threads(move |i| {
+        for _ in 0..LOOPS {
+            if i == 0 {
+                other.write()[0] += 1;
+            } else {
+                let a = other.read();
+                let b = other.read();
+                assert_eq!(a[0], b[0]);
+            }
+        }
+    });
+    let upgradeable = lock.upgradeable_read();
+    let mut writable = upgradeable.upgrade();
+    writable.push(1);
+    let readable = writable.downgrade();
+    assert_eq!(*readable, [LOOPS, 1]);
+    drop(readable);
+    let element = kernel_sync::rwlock::RwLockReadGuard::map(lock.read(), |data| &data[1]);
+    assert_eq!(*element, 1);
+}
+
+#[test]
+fn rcu() {
+    let lock = RcuLock::new(vec![0usize]);
+    let mut writer = lock.write();
+    writer.push(1);
+    // Readers see the old version while the writer works on its copy. The writer waits for them when it
+    // publishes, so they have to be gone by then.
+    let reader = lock.read();
+    assert_eq!(*reader, [0]);
+    drop(reader);
+    drop(writer);
+    let other = lock.clone();
+    on_threads(move |i| {
+        for _ in 0..LOOPS {
+            if i == 0 {
+                other.write()[0] += 1;
+            } else {
+                let guard = other.read();
+                assert_eq!(guard.len(), 2);
+            }
+        }
+    });
+    assert_eq!(*lock.read(), [LOOPS, 1]);
+    assert_eq!(lock.replace(vec![]), [LOOPS, 1]);
+}
+
+#[test]
+fn rcu_drops_every_version_once() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static LIVE: AtomicUsize = AtomicUsize::new(0);
+    struct Counted;
+    impl Clone for Counted {
+        fn clone(&self) -> Self {
+            LIVE.fetch_add(1, Ordering::Relaxed);
+            Counted
+        }
+    }
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            LIVE.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    LIVE.fetch_add(1, Ordering::Relaxed);
+    let lock = RcuLock::new(Counted);
+    let other = lock.clone();
+    on_threads(move |i| {
+        for _ in 0..LOOPS {
+            if i == 0 {
+                drop(other.write());
+            } else {
+                drop(other.read());
+            }
+        }
+    });
+    assert_eq!(LIVE.load(Ordering::Relaxed), 1);
+    drop(lock);
+    assert_eq!(LIVE.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn epoch_rcu() {
+    let lock = Arc::new(EpochRcuLock::new(vec![0usize]));
+    let reader = lock.read();
+    lock.write().push(1);
+    assert_eq!(*reader, [0]);
+    drop(reader);
+    let other = lock.clone();
+    on_threads(move |i| {
+        for _ in 0..LOOPS {
+            if i == 0 {
+                other.write()[0] += 1;
+            } else {
+                let guard = other.read();
+                assert_eq!(guard.len(), 2);
+            }
+        }
+    });
+    assert_eq!(*lock.read(), [LOOPS, 1]);
+}
+
+#[test]
+fn striped() {
+    let lock: Arc<kernel_sync::striped::StripedLock<usize, kernel_sync::DefaultLockAction, 4>> =
+        Arc::new(Default::default());
+    let other = lock.clone();
+    on_threads(move |i| {
+        for key in 0..LOOPS as u64 {
+            *other.lock_for(key + i as u64) += 1;
+        }
+    });
+    assert_eq!(lock.iter().map(|guard| *guard).sum::<usize>(), THREADS * LOOPS);
+}
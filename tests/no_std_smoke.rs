@@ -0,0 +1,147 @@
+//! Calls the core methods of every public type from a `#![no_std]` crate, so that code depending on `std`
+//! can't sneak into the smoke path. Run it without default features too:
+//! `cargo test --no-default-features --test no_std_smoke`.
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec;
+use kernel_sync::{
+    atomic::AtomicCounter, barrier::Barrier, cell::LockCell, condvar::Condvar, epochrcu::EpochRcuLock, mcs::{McsMutex, McsNode}, once::{Lazy, Once}, reentrant::ReentrantSpinMutex, rculock::RcuLock, seqlock::SeqLock,
+    rwlock::RwLock, smp::{CpuId, PerCpu}, spin::SpinMutex, striped::StripedLock, ticket::TicketMutex, EmptyLockAction,
+};
+
+#[test]
+fn spin_smoke() {
+    let lock = SpinMutex::<_, EmptyLockAction>::new(0);
+    *lock.lock() += 1;
+    *lock.lock_optimistic() += 1;
+    assert_eq!(*lock.try_lock().unwrap(), 2);
+    let guard = lock.lock();
+    assert!(lock.is_locked());
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert_eq!(lock.into_inner(), 2);
+}
+
+#[test]
+fn condvar_smoke() {
+    let lock = SpinMutex::<_, EmptyLockAction>::new(1);
+    let condvar = Condvar::<EmptyLockAction>::new();
+    condvar.notify_one();
+    let guard = condvar.wait_while(lock.lock(), |n| *n == 0);
+    assert_eq!(*guard, 1);
+    drop(guard);
+    condvar.notify_all();
+    assert!(!lock.is_locked());
+}
+
+#[test]
+fn reentrant_smoke() {
+    struct OneCpu;
+    // Safety: the test locks from a single thread
+    unsafe impl CpuId for OneCpu {
+        fn current() -> usize {
+            0
+        }
+    }
+
+    let lock = ReentrantSpinMutex::<_, EmptyLockAction, OneCpu>::new(0);
+    let mut outer = lock.lock();
+    assert_eq!(*lock.try_lock().unwrap(), 0);
+    outer.with_mut(|data| *data += 1);
+    drop(outer);
+    assert!(!lock.is_locked());
+    assert_eq!(lock.into_inner(), 1);
+}
+
+#[test]
+fn ticket_smoke() {
+    let lock = TicketMutex::<_, EmptyLockAction>::new(0);
+    *lock.lock() += 1;
+    let guard = lock.try_lock().unwrap();
+    assert!(lock.is_locked());
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert_eq!(lock.into_inner(), 1);
+}
+
+#[test]
+fn mcs_smoke() {
+    let lock = McsMutex::<_, EmptyLockAction>::new(0);
+    let (mut a, mut b) = (McsNode::new(), McsNode::new());
+    // Safety: every guard is dropped
+    unsafe {
+        *lock.lock(&mut a) += 1;
+        let guard = lock.try_lock(&mut a).unwrap();
+        assert!(lock.is_locked());
+        assert!(lock.try_lock(&mut b).is_none());
+        drop(guard);
+    }
+    assert_eq!(lock.with_lock(|n| *n), 1);
+    assert_eq!(lock.into_inner(), 1);
+}
+
+#[test]
+fn rwlock_smoke() {
+    let lock = RwLock::<_, EmptyLockAction>::new(0);
+    *lock.write() += 1;
+    let (a, b) = (lock.read(), lock.read());
+    assert_eq!(*a + *b, 2);
+    assert!(lock.try_write().is_none());
+    drop((a, b));
+    let upgradeable = lock.upgradeable_read();
+    *upgradeable.upgrade() += 1;
+    assert_eq!(lock.into_inner(), 2);
+}
+
+#[test]
+fn rcu_smoke() {
+    let lock = RcuLock::<_, EmptyLockAction>::new(vec![1]);
+    let mut writer = lock.write();
+    writer.push(2);
+    // Readers see the old version until the writer publishes
+    assert_eq!(lock.read().len(), 1);
+    drop(writer);
+    assert_eq!(lock.read().len(), 2);
+    assert_eq!(lock.pending_reclaims(), 0);
+
+    let lock = EpochRcuLock::<_, EmptyLockAction>::new(vec![1]);
+    lock.write().push(2);
+    assert_eq!(lock.read().len(), 2);
+}
+
+#[test]
+fn helpers_smoke() {
+    let counter = AtomicCounter::new(0);
+    counter.inc();
+    assert_eq!(counter.get(), 1);
+
+    let once = Once::<_, EmptyLockAction>::new();
+    assert_eq!(*once.call_once(|| 1), 1);
+    assert_eq!(once.get(), Some(&1));
+    let lazy = Lazy::<_, EmptyLockAction>::new(|| 2);
+    assert_eq!(*lazy, 2);
+
+    // A barrier for one CPU never waits
+    let barrier = Barrier::<EmptyLockAction>::new(1);
+    assert!(barrier.wait().is_leader());
+    assert!(barrier.wait().is_leader());
+
+    let cell = LockCell::<_, EmptyLockAction>::new(1);
+    assert_eq!(cell.update(|v| v + 1), 2);
+
+    let clock = SeqLock::<_, EmptyLockAction>::new((0u32, 0u32));
+    clock.write((1, 2));
+    assert_eq!(clock.read(), (1, 2));
+
+    let table = StripedLock::<u32, EmptyLockAction, 4>::new([0; 4]);
+    *table.lock_for(7) += 1;
+    assert_eq!(table.into_inner().iter().sum::<u32>(), 1);
+
+    // Without `current_cpu` everything runs on CPU 0
+    let per_cpu = PerCpu::<_, EmptyLockAction, 2>::new([AtomicCounter::new(0), AtomicCounter::new(0)]);
+    per_cpu.get().inc();
+    assert_eq!(per_cpu.iter().map(AtomicCounter::get).sum::<usize>(), 1);
+    assert_eq!(per_cpu.get_for(0).unwrap().get(), 1);
+}
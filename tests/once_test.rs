@@ -0,0 +1,127 @@
+mod common;
+use kernel_sync::once::{Lazy, Once};
+use kernel_sync::LockAction;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+use common::YieldAction;
+
+#[test]
+fn call_once_test() {
+    let once = Once::<_, YieldAction>::new();
+    assert_eq!(once.get(), None);
+    assert!(!once.is_completed());
+    assert_eq!(*once.call_once(|| 1), 1);
+    assert_eq!(*once.call_once(|| 2), 1);
+    assert_eq!(once.get(), Some(&1));
+    assert!(once.is_completed());
+}
+
+#[test]
+fn concurrent_call_once_test() {
+    let once = Arc::new(Once::<_, YieldAction>::new());
+    let calls = Arc::new(AtomicUsize::new(0));
+    let barrier = Arc::new(Barrier::new(8));
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            let (once, calls, barrier) = (once.clone(), calls.clone(), barrier.clone());
+            std::thread::spawn(move || {
+                barrier.wait();
+                *once.call_once(|| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    // Keep the others waiting for a while
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    i
+                })
+            })
+        })
+        .collect();
+    let values: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert!(values.iter().all(|&value| value == values[0]));
+    assert_eq!(once.get(), Some(&values[0]));
+}
+
+#[test]
+fn poison_test() {
+    let once = Once::<u32, YieldAction>::new();
+    assert!(catch_unwind(AssertUnwindSafe(|| once.call_once(|| panic!("init failed")))).is_err());
+    assert!(once.is_poisoned());
+    assert!(!once.is_completed());
+    assert_eq!(once.get(), None);
+    let err = catch_unwind(AssertUnwindSafe(|| *once.call_once(|| 1))).unwrap_err();
+    assert_eq!(*err.downcast_ref::<&str>().unwrap(), "Once instance has previously been poisoned");
+}
+
+#[test]
+fn hooks_balanced_test() {
+    static DEPTH: AtomicUsize = AtomicUsize::new(0);
+    struct CountAction;
+    impl LockAction for CountAction {
+        type Guard = ();
+        fn before_lock() {
+            DEPTH.fetch_add(1, Ordering::SeqCst);
+        }
+        fn after_lock(_guard: ()) {
+            DEPTH.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    let once = Once::<u32, CountAction>::new();
+    once.call_once(|| {
+        assert_eq!(DEPTH.load(Ordering::SeqCst), 1);
+        1
+    });
+    once.call_once(|| 2);
+    assert_eq!(DEPTH.load(Ordering::SeqCst), 0);
+
+    let once = Once::<u32, CountAction>::new();
+    assert!(catch_unwind(AssertUnwindSafe(|| once.call_once(|| panic!()))).is_err());
+    assert!(catch_unwind(AssertUnwindSafe(|| once.call_once(|| 1))).is_err());
+    assert_eq!(DEPTH.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "from its own initializer")]
+fn reentrant_call_once_test() {
+    struct CpuAction;
+    impl LockAction for CpuAction {
+        type Guard = ();
+        fn current_cpu() -> Option<usize> {
+            Some(0)
+        }
+    }
+
+    let once = Once::<u32, CpuAction>::new();
+    once.call_once(|| *once.call_once(|| 1));
+}
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+static TABLE: Lazy<Vec<u32>, YieldAction> = Lazy::new(|| {
+    CALLS.fetch_add(1, Ordering::SeqCst);
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    (0..100).collect()
+});
+
+#[test]
+fn lazy_static_test() {
+    assert_eq!(Lazy::get(&TABLE), None);
+    let threads: Vec<_> = (0..8)
+        .map(|i| std::thread::spawn(move || TABLE[i * 10]))
+        .collect();
+    for (i, t) in threads.into_iter().enumerate() {
+        assert_eq!(t.join().unwrap(), i as u32 * 10);
+    }
+    assert_eq!(TABLE.len(), 100);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    assert!(Lazy::get(&TABLE).is_some());
+}
+
+#[test]
+fn lazy_poison_test() {
+    let lazy = Lazy::<u32, YieldAction, _>::new(|| panic!("init failed"));
+    assert!(catch_unwind(AssertUnwindSafe(|| *lazy)).is_err());
+    assert_eq!(Lazy::get(&lazy), None);
+    assert!(catch_unwind(AssertUnwindSafe(|| *lazy)).is_err());
+}
@@ -0,0 +1,94 @@
+use kernel_sync::poison::{NoPoison, Poison, TryLockError};
+use kernel_sync::{rwlock::RwLock, spin::SpinMutex, EmptyLockAction};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+
+#[test]
+fn spin_poison_test() {
+    let lock = SpinMutex::<_, EmptyLockAction, Poison>::new(0);
+    assert!(catch_unwind(AssertUnwindSafe(|| {
+        let mut guard = lock.lock().unwrap();
+        *guard += 1;
+        panic!("failed while holding the lock");
+    }))
+    .is_err());
+    assert!(!lock.is_locked());
+    assert!(lock.is_poisoned());
+
+    // The guard is still handed out, with the changes made before the panic
+    let guard = lock.lock().unwrap_err().into_inner();
+    assert_eq!(*guard, 1);
+    match lock.try_lock() {
+        Err(TryLockError::WouldBlock) => {}
+        _ => panic!("the lock is held"),
+    }
+    drop(guard);
+    match lock.try_lock() {
+        Err(TryLockError::Poisoned(poisoned)) => assert_eq!(*poisoned.into_inner(), 1),
+        _ => panic!("the lock is poisoned"),
+    }
+
+    lock.clear_poison();
+    assert!(!lock.is_poisoned());
+    *lock.try_lock().unwrap() += 1;
+    assert_eq!(lock.into_inner(), 2);
+}
+
+#[test]
+fn spin_no_poison_test() {
+    let lock = SpinMutex::<_, EmptyLockAction, NoPoison>::new(0);
+    assert!(catch_unwind(AssertUnwindSafe(|| {
+        let mut guard = lock.lock();
+        *guard += 1;
+        panic!("failed while holding the lock");
+    }))
+    .is_err());
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test]
+fn rwlock_poison_test() {
+    let lock = Arc::new(RwLock::<_, EmptyLockAction, false, Poison>::new(0));
+
+    // Readers don't poison the lock
+    let cloned = lock.clone();
+    assert!(std::thread::spawn(move || {
+        let _guard = cloned.read().unwrap();
+        panic!("failed while reading");
+    })
+    .join()
+    .is_err());
+    assert!(!lock.is_poisoned());
+
+    let cloned = lock.clone();
+    assert!(std::thread::spawn(move || {
+        let mut guard = cloned.write().unwrap();
+        *guard += 1;
+        panic!("failed while writing");
+    })
+    .join()
+    .is_err());
+    assert!(!lock.is_locked());
+    assert!(lock.is_poisoned());
+
+    assert_eq!(*lock.read().unwrap_err().into_inner(), 1);
+    {
+        assert!(matches!(lock.try_read(), Err(TryLockError::Poisoned(_))));
+        let guard = lock.write().unwrap_err().into_inner();
+        match lock.try_write() {
+            Err(TryLockError::WouldBlock) => {}
+            _ => panic!("the lock is held"),
+        }
+        drop(guard);
+    }
+    match lock.try_write() {
+        Err(TryLockError::Poisoned(poisoned)) => *poisoned.into_inner() += 1,
+        _ => panic!("the lock is poisoned"),
+    }
+
+    lock.clear_poison();
+    assert_eq!(*lock.try_read().unwrap(), 2);
+    *lock.write().unwrap() += 1;
+    assert_eq!(*lock.read().unwrap(), 3);
+}
@@ -0,0 +1,49 @@
+//! Counts allocations, so it is kept in its own test binary with a single test.
+mod common;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use common::RcuLock;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAllocator;
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::SeqCst)
+}
+
+#[test]
+fn write_reuses_reclaimed_version() {
+    let lock = RcuLock::new([0u64; 4]);
+    assert!(lock.reserve());
+    let before = allocations();
+    for i in 1..=10 {
+        *lock.write() = [i; 4];
+        lock.update_fold(|old| old.map(|x| x + 1));
+        assert!(lock.try_write().is_some());
+        assert_eq!(lock.replace([i; 4]), [i + 1; 4]);
+    }
+    assert_eq!(allocations(), before);
+    assert_eq!(*lock.read(), [10; 4]);
+
+    // Without reserving, only the first write allocates
+    let lock = RcuLock::new(0u64);
+    let before = allocations();
+    for i in 1..=10 {
+        *lock.write() = i;
+    }
+    assert_eq!(allocations(), before + 1);
+}
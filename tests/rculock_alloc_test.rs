@@ -0,0 +1,69 @@
+extern crate alloc;
+mod common;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use common::RcuLock;
+
+struct CountingAllocator;
+
+// Counted per thread, so tests running in parallel don't see each other's allocations.
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    static LIVE_BYTES: Cell<isize> = const { Cell::new(0) };
+}
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|a| a.get())
+}
+
+fn live_bytes() -> isize {
+    LIVE_BYTES.with(|l| l.get())
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        let _ = LIVE_BYTES.try_with(|l| l.set(l.get() + layout.size() as isize));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = LIVE_BYTES.try_with(|l| l.set(l.get() - layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[test]
+fn read_does_not_allocate() {
+    let loop_cnt = 1000000;
+    let x = RcuLock::new(alloc::vec![1usize, 2, 3]);
+    // Publish a second version first, so the reads see a version a writer swapped in rather than the initial one.
+    x.write().push(4);
+
+    let before = allocations();
+    let mut sum = 0;
+    for _ in 0..loop_cnt {
+        sum += x.read()[3];
+    }
+    assert_eq!(allocations(), before);
+    assert_eq!(sum, 4 * loop_cnt);
+}
+
+#[test]
+fn drop_frees_all_versions() {
+    let before = live_bytes();
+    let x = RcuLock::new(alloc::vec![0usize; 16]);
+    let y = x.clone();
+    for i in 0..1000 {
+        x.write()[i % 16] += 1;
+    }
+    y.write().push(1);
+    assert_eq!(x.read().len(), 17);
+    drop(x);
+    drop(y);
+    assert_eq!(live_bytes(), before);
+}
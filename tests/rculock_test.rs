@@ -1,6 +1,7 @@
 extern crate alloc;
+mod common;
 use alloc::vec;
-use kernel_sync::RcuLock;
+use common::{RcuLock, YieldAction};
 
 #[test]
 fn basic_test() {
@@ -40,6 +41,23 @@ fn try_lock_test() {
     assert!(lock_result2.is_some());
 }
 
+#[test]
+fn try_read_test() {
+    let x = RcuLock::new(0);
+    assert_eq!(*x.try_read().unwrap(), 0);
+
+    let mut guard = x.try_write().unwrap();
+    *guard = 1;
+    assert!(x.try_read().is_none());
+    assert!(x.try_write().is_none());
+    // A blocking read still sees the old version
+    assert_eq!(*x.read(), 0);
+    drop(guard);
+
+    assert_eq!(*x.try_read().unwrap(), 1);
+    assert!(x.try_write().is_some());
+}
+
 #[test]
 fn read_write_test() {
     let x = RcuLock::new(0);
@@ -103,3 +121,569 @@ fn read_write_test() {
         thread.join().unwrap();
     }
 }
+
+#[test]
+fn read_consistent_test() {
+    let x = RcuLock::new((0usize, 0usize));
+    let loop_cnt = 100000;
+    let x_clone = x.clone();
+    let writer = std::thread::spawn(move || {
+        for i in 1..=loop_cnt {
+            let mut guard = x_clone.write();
+            guard.0 = i;
+            guard.1 = 2 * i;
+        }
+    });
+    let mut last = 0;
+    while last < loop_cnt {
+        let (a, b) = x.read_consistent(|v| (v.0, v.1));
+        assert_eq!(b, 2 * a);
+        assert!(a >= last);
+        last = a;
+    }
+    writer.join().unwrap();
+    assert_eq!(x.version(), loop_cnt);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "recursively")]
+fn recursive_write_test() {
+    struct SingleCpuAction;
+    impl kernel_sync::LockAction for SingleCpuAction {
+        type Guard = ();
+        fn current_cpu() -> Option<usize> {
+            Some(0)
+        }
+    }
+
+    let x = kernel_sync::rculock::RcuLock::<_, SingleCpuAction>::new(0);
+    let _guard = x.write();
+    let _nested = x.write();
+}
+
+#[test]
+fn replace_test() {
+    let x = RcuLock::new(alloc::string::String::from("a"));
+    assert_eq!(x.replace("b".into()), "a");
+    assert_eq!(x.replace("c".into()), "b");
+    assert_eq!(*x.read(), "c");
+    assert_eq!(x.version(), 2);
+}
+
+#[test]
+fn debug_test() {
+    let x = RcuLock::new(alloc::vec![1, 2]);
+    assert_eq!(std::format!("{:?}", x), "RcuLock { data: [1, 2] }");
+    let mut guard = x.write();
+    guard.push(3);
+    // Formatting while holding the write lock shows the published version and does not block
+    assert!(std::format!("{:?}", x).contains("[1, 2]"));
+    drop(guard);
+    assert!(std::format!("{:?}", x).contains("[1, 2, 3]"));
+}
+
+#[test]
+fn pending_reclaims_test() {
+    let x = RcuLock::new(0);
+    assert_eq!(x.pending_reclaims(), 0);
+    *x.write() = 1;
+    // An uncontended writer reclaims the old version before it releases the lock
+    assert_eq!(x.pending_reclaims(), 0);
+
+    let reader = x.read();
+    let x_clone = x.clone();
+    let writer = std::thread::spawn(move || *x_clone.write() = 2);
+    // The writer published, but can't free the old version while `reader` may still use it
+    while x.pending_reclaims() == 0 {
+        std::thread::yield_now();
+    }
+    assert_eq!(x.pending_reclaims(), 1);
+    assert_eq!(*reader, 1);
+    assert_eq!(*x.read(), 2);
+    drop(reader);
+    writer.join().unwrap();
+    assert_eq!(x.pending_reclaims(), 0);
+}
+
+#[test]
+fn update_if_changed_test() {
+    let x = RcuLock::new(alloc::string::String::from("a"));
+    for _ in 0..100 {
+        assert!(!x.update_if_changed("a".into()));
+    }
+    assert_eq!(x.version(), 0);
+    assert!(x.update_if_changed("b".into()));
+    assert_eq!(x.version(), 1);
+    assert_eq!(*x.read(), "b");
+}
+
+#[test]
+fn current_test() {
+    let x = RcuLock::new(alloc::vec![0]);
+    let value = x.current();
+    let x_clone = x.clone();
+    let writer = std::thread::spawn(move || x_clone.write().push(1));
+    // The writer publishes, but has to wait for `value` before it frees the old version
+    while x.version() == 0 {
+        std::thread::yield_now();
+    }
+    assert_eq!(*value, [0]);
+    assert_eq!(*x.current(), [0, 1]);
+    drop(value);
+    writer.join().unwrap();
+}
+
+#[test]
+fn update_fold_test() {
+    let x = RcuLock::new(0usize);
+    let thread_cnt = 3;
+    let loop_cnt = 10000;
+    let threads: alloc::vec::Vec<_> = (0..thread_cnt)
+        .map(|_| {
+            let x = x.clone();
+            std::thread::spawn(move || {
+                for _ in 0..loop_cnt {
+                    x.update_fold(|old| old + 1);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*x.read(), thread_cnt * loop_cnt);
+    assert_eq!(x.version(), thread_cnt * loop_cnt);
+}
+
+#[test]
+fn update_with_test() {
+    let x = RcuLock::new(0usize);
+    let new = x.update_with(|value| {
+        *value += 5;
+        *value
+    });
+    assert_eq!(new, 5);
+    assert_eq!(*x.read(), new);
+    assert_eq!(x.version(), 1);
+    assert_eq!(x.pending_reclaims(), 0);
+    // The write lock is released again
+    assert!(x.try_write().is_some());
+}
+
+#[test]
+fn read_owned_test() {
+    struct Cache {
+        snapshot: kernel_sync::rculock::RcuLockOwnedReadGuard<alloc::vec::Vec<usize>, kernel_sync::EmptyLockAction>,
+    }
+
+    let x = RcuLock::new(vec![1]);
+    let handle = x.clone();
+    let cache = Cache {
+        snapshot: handle.read_owned(),
+    };
+    drop(handle);
+    // The guard keeps its version alive while writers move on
+    let x_clone = x.clone();
+    let writer = std::thread::spawn(move || x_clone.write().push(2));
+    while x.version() == 0 {
+        std::thread::yield_now();
+    }
+    assert_eq!(*cache.snapshot, [1]);
+    assert_eq!(*x.read(), [1, 2]);
+    drop(cache);
+    writer.join().unwrap();
+
+    // Outliving every handle
+    let snapshot = x.read_owned();
+    drop(x);
+    assert_eq!(*snapshot, [1, 2]);
+}
+
+#[test]
+fn long_readers_keep_version_alive_test() {
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    const WRITERS: usize = 2;
+    const READERS: usize = 3;
+    const WRITES: usize = 200;
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    static DROPPED: [AtomicBool; 1 + WRITERS * WRITES] = [const { AtomicBool::new(false) }; 1 + WRITERS * WRITES];
+
+    /// A version that records when it is dropped, and complains when it is dropped twice.
+    struct Version {
+        id: usize,
+        value: usize,
+    }
+    impl Version {
+        fn new(value: usize) -> Self {
+            Version {
+                id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                value,
+            }
+        }
+    }
+    impl Clone for Version {
+        fn clone(&self) -> Self {
+            Version::new(self.value)
+        }
+    }
+    impl Drop for Version {
+        fn drop(&mut self) {
+            assert!(!DROPPED[self.id].swap(true, Ordering::SeqCst), "version {} dropped twice", self.id);
+        }
+    }
+
+    let lock = kernel_sync::rculock::RcuLock::<_, YieldAction>::new(Version::new(0));
+    let readers_started = AtomicUsize::new(0);
+    let writers_done = AtomicUsize::new(0);
+    std::thread::scope(|s| {
+        for _ in 0..WRITERS {
+            s.spawn(|| {
+                while readers_started.load(Ordering::Acquire) < READERS {
+                    std::thread::yield_now();
+                }
+                for _ in 0..WRITES {
+                    lock.write().value += 1;
+                    std::thread::yield_now();
+                }
+                writers_done.fetch_add(1, Ordering::Release);
+            });
+        }
+        for _ in 0..READERS {
+            s.spawn(|| {
+                readers_started.fetch_add(1, Ordering::Release);
+                while writers_done.load(Ordering::Acquire) < WRITERS {
+                    // Hold the version across several writes
+                    let guard = lock.read();
+                    let id = guard.id;
+                    for _ in 0..20 {
+                        std::thread::yield_now();
+                        assert!(!DROPPED[id].load(Ordering::SeqCst), "version {id} dropped while read");
+                        assert_eq!(guard.id, id);
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(lock.read().value, WRITERS * WRITES);
+    assert_eq!(lock.pending_reclaims(), 0);
+    let versions = NEXT_ID.load(Ordering::Relaxed);
+    assert_eq!(versions, 1 + WRITERS * WRITES);
+    drop(lock);
+    assert!(DROPPED.iter().all(|dropped| dropped.load(Ordering::SeqCst)));
+}
+
+/// Every thread is its own CPU.
+struct ThreadCpuAction;
+impl kernel_sync::LockAction for ThreadCpuAction {
+    type Guard = ();
+    fn current_cpu() -> Option<usize> {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+        std::thread_local!(static CPU: usize = NEXT_CPU.fetch_add(1, Ordering::Relaxed));
+        Some(CPU.with(|cpu| *cpu))
+    }
+    fn relax() {
+        std::thread::yield_now();
+    }
+}
+
+#[test]
+fn write_while_reading_test() {
+    let x = kernel_sync::rculock::RcuLock::<_, ThreadCpuAction>::new(vec![0]);
+    let reader = x.read();
+    let handle = x.clone();
+    // The writer doesn't wait for the read guard its own CPU holds
+    handle.write().push(1);
+    assert_eq!(*reader, [0]);
+    assert_eq!(*x.read(), [0, 1]);
+    // The old version outlives the write, for the next writer to reclaim
+    assert_eq!(x.pending_reclaims(), 1);
+    drop(reader);
+    handle.write().push(2);
+    assert_eq!(x.pending_reclaims(), 0);
+    assert_eq!(*x.read(), [0, 1, 2]);
+}
+
+#[test]
+fn write_while_reading_waits_for_other_cpus_test() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    let x = kernel_sync::rculock::RcuLock::<_, ThreadCpuAction>::new(0);
+    let other_reading = AtomicBool::new(false);
+    let release_other = AtomicBool::new(false);
+    let writer_done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let guard = x.read();
+            other_reading.store(true, Ordering::Release);
+            while !release_other.load(Ordering::Acquire) {
+                std::thread::yield_now();
+            }
+            // The writer is still waiting for this guard
+            assert!(!writer_done.load(Ordering::Acquire));
+            assert_eq!(*guard, 0);
+        });
+        while !other_reading.load(Ordering::Acquire) {
+            std::thread::yield_now();
+        }
+        let reader = x.read();
+        s.spawn(|| {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            release_other.store(true, Ordering::Release);
+        });
+        *x.write() = 1;
+        writer_done.store(true, Ordering::Release);
+        assert_eq!(*reader, 0);
+    });
+    assert_eq!(*x.read(), 1);
+}
+
+/// A version that counts its drops, so a reader can check the version it reads hasn't been reclaimed.
+#[derive(Clone)]
+struct Counted(usize, std::sync::Arc<Vec<core::sync::atomic::AtomicUsize>>);
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.1[self.0].fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+std::thread_local! {
+    static ASSIGNED_CPU: core::cell::Cell<Option<usize>> = const { core::cell::Cell::new(None) };
+}
+
+/// Yields while waiting, and runs on the CPU the current test thread was assigned with [`on_cpu`].
+struct AssignedCpuAction;
+impl kernel_sync::LockAction for AssignedCpuAction {
+    type Guard = ();
+    fn relax() {
+        std::thread::yield_now();
+    }
+    fn current_cpu() -> Option<usize> {
+        ASSIGNED_CPU.with(core::cell::Cell::get)
+    }
+}
+
+fn on_cpu(cpu: usize) {
+    ASSIGNED_CPU.with(|current| current.set(Some(cpu)));
+}
+
+#[test]
+fn quiescent_state_test() {
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let drops = Arc::new((0..2).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+    let x = kernel_sync::rculock::RcuLock::<_, AssignedCpuAction>::new_quiescent(Counted(0, drops.clone()), 2);
+    let stop = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        // CPU 0 never reads, but reports a quiescent state on every tick
+        s.spawn(|| {
+            on_cpu(0);
+            while !stop.load(Ordering::Relaxed) {
+                x.quiescent_state(0);
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+        on_cpu(1);
+        let guard = x.read_quiescent();
+        // The writer runs on none of the lock's CPUs
+        let writer = s.spawn(|| x.update_fold(|_| Counted(1, drops.clone())));
+        std::thread::sleep(Duration::from_millis(50));
+        // The writer has published, but waits for CPU 1, which can't report while it holds the guard
+        assert!(!writer.is_finished());
+        assert!(!x.quiescent_state(1));
+        assert_eq!(x.read_quiescent().0, 1);
+        assert_eq!(guard.0, 0);
+        assert_eq!(drops[0].load(Ordering::Relaxed), 0);
+        drop(guard);
+        assert!(x.quiescent_state(1));
+        writer.join().unwrap();
+        stop.store(true, Ordering::Relaxed);
+    });
+    assert_eq!(drops[0].load(Ordering::Relaxed), 1);
+    assert_eq!(x.pending_reclaims(), 0);
+}
+
+#[test]
+fn quiescent_readers_never_see_reclaimed_test() {
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const CPUS: usize = 3;
+    const WRITES: usize = 200;
+    let drops = Arc::new((0..=WRITES).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+    let x = kernel_sync::rculock::RcuLock::<_, AssignedCpuAction>::new_quiescent(Counted(0, drops.clone()), CPUS);
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        for cpu in 0..CPUS {
+            let (x, drops, done) = (&x, &drops, &done);
+            s.spawn(move || {
+                on_cpu(cpu);
+                while !done.load(Ordering::Relaxed) {
+                    for _ in 0..10 {
+                        let guard = x.read_quiescent();
+                        assert_eq!(drops[guard.0].load(Ordering::Relaxed), 0, "read a reclaimed version");
+                        std::thread::yield_now();
+                        assert_eq!(drops[guard.0].load(Ordering::Relaxed), 0, "version reclaimed under a reader");
+                    }
+                    x.quiescent_state(cpu);
+                }
+            });
+        }
+        for i in 1..=WRITES {
+            x.update_fold(|_| Counted(i, drops.clone()));
+            assert_eq!(x.pending_reclaims(), 0);
+        }
+        done.store(true, Ordering::Relaxed);
+    });
+    for (i, count) in drops.iter().enumerate() {
+        let expected = if i == WRITES { 0 } else { 1 };
+        assert_eq!(count.load(Ordering::Relaxed), expected, "version {i}");
+    }
+}
+
+#[test]
+fn quiescent_writer_on_reader_cpu_test() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::Arc;
+
+    let drops = Arc::new((0..2).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+    let x = kernel_sync::rculock::RcuLock::<_, AssignedCpuAction>::new_quiescent(Counted(0, drops.clone()), 1);
+    on_cpu(0);
+    let guard = x.read_quiescent();
+    // Neither the writer nor the CPU itself may report CPU 0 quiescent while the guard lives
+    assert!(catch_unwind(AssertUnwindSafe(|| x.update_fold(|_| Counted(1, drops.clone())))).is_err());
+    assert!(catch_unwind(AssertUnwindSafe(|| x.synchronize())).is_err());
+    assert!(!x.quiescent_state(0));
+    // Another thread on the same CPU is refused as well
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            on_cpu(0);
+            assert!(catch_unwind(AssertUnwindSafe(|| x.update_fold(|_| Counted(1, drops.clone())))).is_err());
+            assert!(!x.quiescent_state(0));
+        });
+    });
+    assert_eq!(guard.0, 0);
+    assert_eq!(drops[0].load(Ordering::Relaxed), 0);
+    assert_eq!(x.version(), 0);
+    drop(guard);
+
+    x.update_fold(|_| Counted(1, drops.clone()));
+    assert_eq!(drops[0].load(Ordering::Relaxed), 1);
+    assert_eq!(x.read_quiescent().0, 1);
+}
+
+#[test]
+fn get_mut_test() {
+    let mut x = RcuLock::new(vec![0]);
+    x.get_mut().unwrap().push(1);
+    assert_eq!(*x.read(), [0, 1]);
+    // Readers through a clone could still see the current version
+    let clone = x.clone();
+    assert!(x.get_mut().is_none());
+    clone.write().push(2);
+    drop(clone);
+    x.get_mut().unwrap().push(3);
+    assert_eq!(*x.read(), [0, 1, 2, 3]);
+}
+
+#[test]
+fn get_mut_with_pending_version_test() {
+    let mut x = kernel_sync::rculock::RcuLock::<_, ThreadCpuAction>::new(0);
+    let handle = x.clone();
+    {
+        // The writer leaves the version this CPU still reads for the next writer to reclaim
+        let reader = x.read();
+        *handle.write() = 1;
+        assert_eq!(*reader, 0);
+    }
+    drop(handle);
+    assert_eq!(x.pending_reclaims(), 1);
+    assert!(x.get_mut().is_none());
+    *x.write() = 2;
+    assert_eq!(x.get_mut(), Some(&mut 2));
+}
+
+#[test]
+fn synchronize_test() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    let x = RcuLock::new(0);
+    let old_reading = AtomicBool::new(false);
+    let old_released = AtomicBool::new(false);
+    let synchronizing = AtomicBool::new(false);
+    let new_reading = AtomicBool::new(false);
+    let synchronized = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        // A long reader that was there before the call
+        s.spawn(|| {
+            let guard = x.read();
+            old_reading.store(true, Ordering::Release);
+            std::thread::sleep(Duration::from_millis(100));
+            old_released.store(true, Ordering::Release);
+            drop(guard);
+        });
+        // A reader that starts after the call and stays until it returns
+        s.spawn(|| {
+            while !synchronizing.load(Ordering::Acquire) {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(20));
+            let guard = x.read();
+            new_reading.store(true, Ordering::Release);
+            while !synchronized.load(Ordering::Acquire) {
+                std::thread::yield_now();
+            }
+            drop(guard);
+        });
+        while !old_reading.load(Ordering::Acquire) {
+            std::thread::yield_now();
+        }
+        synchronizing.store(true, Ordering::Release);
+        x.synchronize();
+        assert!(old_released.load(Ordering::Acquire));
+        assert!(new_reading.load(Ordering::Acquire));
+        synchronized.store(true, Ordering::Release);
+    });
+    // Writers still work after the extra index flip
+    *x.write() = 1;
+    assert_eq!(*x.read(), 1);
+    assert_eq!(x.pending_reclaims(), 0);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "holds a read guard")]
+fn synchronize_while_reading_test() {
+    let x = kernel_sync::rculock::RcuLock::<_, ThreadCpuAction>::new(0);
+    let _reader = x.read();
+    x.synchronize();
+}
+
+#[test]
+fn non_clone_test() {
+    /// A configuration blob that is rebuilt from scratch instead of cloned
+    #[derive(Debug, PartialEq)]
+    struct Config {
+        entries: Vec<u32>,
+    }
+
+    let x = RcuLock::new(Config { entries: vec![1] });
+    assert_eq!(x.read().entries, [1]);
+    assert_eq!(x.read_owned().entries, [1]);
+    assert_eq!(format!("{x:?}"), "RcuLock { data: Config { entries: [1] } }");
+    x.update_fold(|old| Config {
+        entries: old.entries.iter().map(|entry| entry + 1).collect(),
+    });
+    // Cloning the lock only clones the handle
+    let handle = x.clone();
+    assert_eq!(*handle.current(), Config { entries: vec![2] });
+    x.synchronize();
+    drop(handle);
+    assert_eq!(x.read_consistent(|config| config.entries.len()), 1);
+}
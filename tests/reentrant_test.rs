@@ -0,0 +1,119 @@
+mod common;
+use kernel_sync::reentrant::ReentrantSpinMutex;
+use kernel_sync::smp::CpuId;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use common::YieldAction;
+
+std::thread_local! {
+    static CPU: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Each test thread plays one CPU, whose id it sets with [`on_cpu`].
+struct TestCpu;
+// Safety: every thread that locks sets a distinct id and never migrates.
+unsafe impl CpuId for TestCpu {
+    fn current() -> usize {
+        CPU.with(Cell::get)
+    }
+}
+
+fn on_cpu(id: usize) {
+    CPU.with(|cpu| cpu.set(id));
+}
+
+type Lock<T> = ReentrantSpinMutex<T, YieldAction, TestCpu>;
+
+#[test]
+fn recursion_test() {
+    on_cpu(0);
+    let lock = Lock::new(Cell::new(0));
+    let outer = lock.lock();
+    let inner = lock.lock();
+    inner.set(1);
+    assert!(lock.try_lock().is_some());
+    drop(inner);
+    assert!(lock.is_locked());
+    assert_eq!(outer.get(), 1);
+    drop(outer);
+    assert!(!lock.is_locked());
+}
+
+#[test]
+fn with_mut_test() {
+    on_cpu(0);
+    let lock = Lock::new(0);
+    let mut guard = lock.lock();
+    guard.with_mut(|data| *data += 1);
+    assert_eq!(*guard, 1);
+    drop(guard);
+    assert_eq!(lock.into_inner(), 1);
+}
+
+#[test]
+#[should_panic(expected = "other guards")]
+fn with_mut_nested_guard_test() {
+    on_cpu(0);
+    let lock = Lock::new(0);
+    let mut outer = lock.lock();
+    let _inner = lock.lock();
+    outer.with_mut(|data| *data += 1);
+}
+
+#[test]
+#[should_panic(expected = "borrowed mutably")]
+fn lock_inside_with_mut_test() {
+    on_cpu(0);
+    let lock = Lock::new(0);
+    lock.lock().with_mut(|_| drop(lock.lock()));
+}
+
+#[test]
+fn mutual_exclusion_test() {
+    let lock = Arc::new(Lock::new(0));
+    on_cpu(0);
+    let guard = lock.lock();
+
+    let cloned = lock.clone();
+    let locked = Arc::new(AtomicBool::new(false));
+    let locked2 = locked.clone();
+    let other = std::thread::spawn(move || {
+        on_cpu(1);
+        assert!(cloned.try_lock().is_none());
+        let mut guard = cloned.lock();
+        locked2.store(true, Ordering::SeqCst);
+        guard.with_mut(|data| *data += 1);
+    });
+
+    // CPU 0 can still lock again while CPU 1 waits
+    drop(lock.lock());
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    assert!(!locked.load(Ordering::SeqCst));
+    drop(guard);
+    other.join().unwrap();
+    assert!(locked.load(Ordering::SeqCst));
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test]
+fn contention_test() {
+    let lock = Arc::new(Lock::new(0));
+    let threads: Vec<_> = (0..4)
+        .map(|cpu| {
+            let lock = lock.clone();
+            std::thread::spawn(move || {
+                on_cpu(cpu);
+                for _ in 0..1000 {
+                    let mut outer = lock.lock();
+                    let value = *lock.lock();
+                    outer.with_mut(|data| *data = value + 1);
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(*lock.lock(), 4000);
+}
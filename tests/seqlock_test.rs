@@ -0,0 +1,65 @@
+mod common;
+use kernel_sync::seqlock::SeqLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use common::YieldAction;
+
+/// Both fields are always written together, so a reader that sees them differ got a torn copy.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Pair {
+    a: u64,
+    b: u64,
+}
+
+#[test]
+fn read_write_test() {
+    let lock = SeqLock::<Pair, YieldAction>::default();
+    assert_eq!(lock.read(), Pair::default());
+    lock.write(Pair { a: 1, b: 1 });
+    assert_eq!(lock.read(), Pair { a: 1, b: 1 });
+    assert_eq!(lock.writes(), 1);
+    assert_eq!(format!("{lock:?}"), "SeqLock { value: Pair { a: 1, b: 1 } }");
+    let mut lock = lock;
+    lock.get_mut().a = 2;
+    assert_eq!(lock.into_inner(), Pair { a: 2, b: 1 });
+}
+
+#[test]
+fn readers_never_torn_test() {
+    const WRITES: u64 = 20_000;
+    let lock = SeqLock::<Pair, YieldAction>::new(Pair::default());
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        let pair = lock.read();
+                        assert_eq!(pair.a, pair.b, "torn read");
+                    }
+                })
+            })
+            .collect();
+        // Two writers, serialized by the lock, that always write both fields together
+        let writers: Vec<_> = (0..2)
+            .map(|w| {
+                let lock = &lock;
+                s.spawn(move || {
+                    for i in 0..WRITES {
+                        let n = i * 2 + w;
+                        lock.write(Pair { a: n, b: n });
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    });
+    assert_eq!(lock.writes(), 2 * WRITES as usize);
+    let pair = lock.read();
+    assert_eq!(pair.a, pair.b);
+}
@@ -0,0 +1,79 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use kernel_sync::{smp::PerCpu, spin::SpinMutex, EmptyLockAction, LockAction};
+
+thread_local! {
+    static CPU: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Treats every test thread as a CPU, with the id it was given by `set_cpu`. CPUs 0 and 1 are node 0, the rest
+/// node 1.
+struct ThreadCpuAction;
+impl LockAction for ThreadCpuAction {
+    type Guard = ();
+    fn current_cpu() -> Option<usize> {
+        CPU.with(Cell::get)
+    }
+    fn current_node() -> usize {
+        kernel_sync::smp::cpu_id::<Self>() / 2
+    }
+}
+
+fn set_cpu(cpu: usize) {
+    CPU.with(|c| c.set(Some(cpu)));
+}
+
+#[test]
+fn per_cpu_counter_test() {
+    const CPUS: usize = 4;
+    let counters = Arc::new(PerCpu::<AtomicUsize, ThreadCpuAction, CPUS>::default());
+    let threads: Vec<_> = (0..CPUS)
+        .map(|cpu| {
+            let counters = counters.clone();
+            std::thread::spawn(move || {
+                set_cpu(cpu);
+                for _ in 0..=cpu {
+                    counters.get().fetch_add(1, Ordering::Relaxed);
+                }
+                assert_eq!(kernel_sync::smp::node_id::<ThreadCpuAction>(), cpu / 2);
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let counts: Vec<_> = counters.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+    assert_eq!(counts, [1, 2, 3, 4]);
+}
+
+#[test]
+fn per_cpu_lock_test() {
+    // Per-CPU locks: each CPU mostly takes its own, but any CPU may take another one
+    let queues = PerCpu::<SpinMutex<Vec<usize>, ThreadCpuAction>, ThreadCpuAction, 2>::default();
+    set_cpu(1);
+    queues.get().lock().push(1);
+    queues.get_for(0).unwrap().lock().push(0);
+    assert!(queues.get_for(2).is_none());
+    let queues: Vec<_> = queues.into_inner().map(SpinMutex::into_inner).into();
+    assert_eq!(queues, [[0], [1]]);
+}
+
+#[test]
+fn single_core_defaults_test() {
+    assert_eq!(kernel_sync::smp::cpu_id::<EmptyLockAction>(), 0);
+    assert_eq!(kernel_sync::smp::node_id::<EmptyLockAction>(), 0);
+    let counters = PerCpu::<AtomicUsize, EmptyLockAction, 1>::default();
+    counters.get().fetch_add(1, Ordering::Relaxed);
+    let [counter] = counters.into_inner();
+    assert_eq!(counter.into_inner(), 1);
+}
+
+#[test]
+#[should_panic(expected = "out of range")]
+fn cpu_out_of_range_test() {
+    let counters = PerCpu::<AtomicUsize, ThreadCpuAction, 2>::default();
+    set_cpu(2);
+    counters.get();
+}
@@ -1,7 +1,9 @@
 extern crate alloc;
+mod common;
 use alloc::sync::Arc;
 use alloc::vec;
-use kernel_sync::SpinMutex as SpinLock;
+use kernel_sync::LockAction;
+use common::{irq_off, IrqAction, SpinMutex as SpinLock, YieldAction};
 
 #[test]
 fn basic_test() {
@@ -38,3 +40,505 @@ fn try_lock_test() {
     let lock_result2 = x.try_lock();
     assert!(lock_result2.is_some());
 }
+
+#[test]
+fn try_lock_or_generation_test() {
+    let x = SpinLock::new(0);
+    let guard = x.lock();
+    let generation0 = x.try_lock_or_generation().unwrap_err();
+    drop(guard);
+
+    let guard = x.lock();
+    let generation1 = x.try_lock_or_generation().unwrap_err();
+    assert_ne!(generation0, generation1);
+    drop(guard);
+
+    assert!(x.try_lock_or_generation().is_ok());
+}
+
+#[test]
+fn conversion_test() {
+    let spin = SpinLock::new(vec![1, 2, 3]);
+    let ticket: common::TicketMutex<alloc::vec::Vec<i32>> = spin.into();
+    assert_eq!(*ticket.lock(), vec![1, 2, 3]);
+    let spin: SpinLock<alloc::vec::Vec<i32>> = ticket.into();
+    assert_eq!(*spin.lock(), vec![1, 2, 3]);
+    let rwlock: common::RwLock<alloc::vec::Vec<i32>> = spin.into();
+    assert_eq!(*rwlock.read(), vec![1, 2, 3]);
+}
+
+#[test]
+fn leak_test() {
+    let lock: &'static kernel_sync::spin::SpinMutex<_, YieldAction> = kernel_sync::spin::SpinMutex::leak(0);
+    let threads: alloc::vec::Vec<_> = (0..2)
+        .map(|_| {
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    *lock.lock() += 1;
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(*lock.lock(), 2000);
+}
+
+#[test]
+fn unlock_and_relock_test() {
+    let x = Arc::new(kernel_sync::spin::SpinMutex::<_, YieldAction>::new(0));
+    let mut guard = x.lock();
+    let x_clone = x.clone();
+    let competitor = std::thread::spawn(move || {
+        *x_clone.lock() += 1;
+    });
+    // The competitor can only get in during the window opened by `unlock_and_relock`.
+    while *guard == 0 {
+        guard.unlock_and_relock();
+    }
+    assert!(x.is_locked());
+    drop(guard);
+    competitor.join().unwrap();
+    assert_eq!(*x.lock(), 1);
+}
+
+#[test]
+fn lock_map_test() {
+    let x = SpinLock::new(vec![1, 2, 3]);
+    let (mut guard, sum) = x.lock_map(|data| data.iter().sum::<i32>());
+    assert_eq!(sum, 6);
+    assert!(x.try_lock().is_none());
+    guard.push(sum);
+    drop(guard);
+    assert_eq!(*x.lock(), vec![1, 2, 3, 6]);
+}
+
+#[test]
+fn lock_optimistic_test() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static RELAXES: AtomicUsize = AtomicUsize::new(0);
+    static LOCK: kernel_sync::spin::SpinMutex<i32, ReleasingAction> =
+        kernel_sync::spin::SpinMutex::new(0);
+
+    /// Releases `LOCK` on the second relax, i.e. still inside the optimistic retries.
+    struct ReleasingAction;
+    impl LockAction for ReleasingAction {
+        type Guard = ();
+        fn relax() {
+            if RELAXES.fetch_add(1, Ordering::SeqCst) == 1 {
+                unsafe { LOCK.force_unlock() };
+            }
+        }
+    }
+
+    *LOCK.lock_optimistic() += 1;
+    assert_eq!(RELAXES.load(Ordering::SeqCst), 0);
+
+    core::mem::forget(LOCK.lock());
+    *LOCK.lock_optimistic() += 1;
+    assert_eq!(RELAXES.load(Ordering::SeqCst), 2);
+    assert_eq!(*LOCK.lock(), 2);
+}
+
+#[test]
+fn into_data_ptr_test() {
+    let x = SpinLock::new(1);
+    let ptr = kernel_sync::spin::SpinMutexGuard::into_data_ptr(x.lock());
+    assert!(x.is_locked());
+    unsafe {
+        *ptr += 1;
+        x.force_unlock();
+    }
+    assert!(!x.is_locked());
+    assert_eq!(*x.lock(), 2);
+}
+
+#[test]
+fn array_test() {
+    static LOCKS: [kernel_sync::spin::SpinMutex<u32, YieldAction>; 4] =
+        kernel_sync::spin::SpinMutex::array([0, 1, 2, 3]);
+
+    *LOCKS[1].lock() += 10;
+    let values: Vec<u32> = LOCKS.iter().map(|lock| *lock.lock()).collect();
+    assert_eq!(values, vec![0, 11, 2, 3]);
+
+    // Each element is moved into its lock exactly once.
+    let shared = Arc::new(());
+    let locks = SpinLock::array([shared.clone(), shared.clone()]);
+    assert_eq!(Arc::strong_count(&shared), 3);
+    drop(locks);
+    assert_eq!(Arc::strong_count(&shared), 1);
+}
+
+#[test]
+fn raw_lock_test() {
+    let a = kernel_sync::spin::SpinMutex::<_, IrqAction>::new(1);
+    let b = kernel_sync::spin::SpinMutex::<_, IrqAction>::new(2);
+
+    IrqAction::before_lock();
+    unsafe {
+        a.raw_lock();
+        b.raw_lock();
+        assert!(a.is_locked() && b.is_locked());
+        assert!(a.try_lock().is_none() && b.try_lock().is_none());
+        *a.as_mut_ptr() += *b.as_mut_ptr();
+        b.raw_unlock();
+        a.raw_unlock();
+    }
+    assert_eq!(irq_off(), 1);
+    IrqAction::after_lock(());
+
+    assert!(!a.is_locked() && !b.is_locked());
+    assert_eq!(*a.lock(), 3);
+    assert_eq!(irq_off(), 0);
+}
+
+#[test]
+fn into_ref_guard_test() {
+    fn sum(data: &[usize]) -> usize {
+        data.iter().sum()
+    }
+
+    let lock = Arc::new(SpinLock::new(vec![1, 2]));
+    let mut guard = lock.lock();
+    guard.push(3);
+    assert_eq!(guard.as_shared().len(), 3);
+    let guard = guard.into_ref_guard();
+    assert_eq!(sum(&guard), 6);
+    // Still locked: another thread can't get in while the read-only guard is alive
+    let other = lock.clone();
+    assert!(std::thread::spawn(move || other.try_lock().is_none())
+        .join()
+        .unwrap());
+    assert!(lock.is_locked());
+    drop(guard);
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.lock(), [1, 2, 3]);
+}
+
+#[test]
+fn try_lock_if_test() {
+    let lock = SpinLock::new(vec![1]);
+    // Rejected: no guard, and the lock is free again
+    assert!(lock.try_lock_if(|data| data.len() > 1).is_none());
+    assert!(!lock.is_locked());
+
+    lock.lock().push(2);
+    let mut guard = lock.try_lock_if(|data| data.len() > 1).unwrap();
+    assert!(lock.is_locked());
+    // Busy: the predicate doesn't even run
+    assert!(lock.try_lock_if(|_| unreachable!()).is_none());
+    guard.pop();
+    drop(guard);
+    assert!(lock.try_lock_if(|data| data.len() > 1).is_none());
+    assert_eq!(*lock.lock(), [1]);
+}
+
+#[test]
+fn get_mut_leaves_lock_consistent_test() {
+    let mut lock = SpinLock::new(0);
+    *lock.lock() += 1;
+    assert!(lock.try_lock_if(|_| false).is_none());
+    let generation = lock.generation();
+    *lock.get_mut() += 1;
+    assert!(!lock.is_locked());
+    assert_eq!(lock.generation(), generation);
+    assert_eq!(*lock.try_lock().unwrap(), 2);
+
+    let mut ticket = common::TicketMutex::new(0);
+    drop(ticket.lock());
+    *ticket.get_mut() += 1;
+    assert!(!ticket.is_locked());
+    assert_eq!(*ticket.try_lock().unwrap(), 1);
+}
+
+#[test]
+#[cfg(feature = "stats")]
+fn get_mut_keeps_stats_test() {
+    let mut lock = common::RwLock::new(0);
+    *lock.write() += 1;
+    let max_write_wait = lock.max_write_wait();
+    *lock.get_mut() += 1;
+    assert!(!lock.is_locked());
+    assert_eq!(lock.max_write_wait(), max_write_wait);
+    assert_eq!(*lock.read(), 2);
+}
+
+#[test]
+#[cfg(feature = "lockapi")]
+fn lock_api_try_lock_weak_test() {
+    use kernel_sync::spin::{MutexWeakExt, RawMutexWeak};
+
+    type Mutex<T> = lock_api::Mutex<kernel_sync::spin::SpinMutex<(), kernel_sync::EmptyLockAction>, T>;
+
+    let mutex = Arc::new(Mutex::new(0));
+    let threads: alloc::vec::Vec<_> = (0..3)
+        .map(|_| {
+            let mutex = mutex.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    loop {
+                        if let Some(mut guard) = mutex.try_lock_weak() {
+                            *guard += 1;
+                            break;
+                        }
+                        std::thread::yield_now();
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*mutex.lock(), 3000);
+
+    let guard = mutex.lock();
+    assert!(mutex.try_lock_weak().is_none());
+    assert!(!RawMutexWeak::try_lock_weak(unsafe { mutex.raw() }));
+    drop(guard);
+    assert!(!mutex.is_locked());
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn relock_on_same_cpu_panics_with_locations_test() {
+    struct SingleCpuAction;
+    impl LockAction for SingleCpuAction {
+        type Guard = ();
+        fn current_cpu() -> Option<usize> {
+            Some(0)
+        }
+    }
+
+    let lock = kernel_sync::spin::SpinMutex::<_, SingleCpuAction>::new(0);
+    let first_line = line!() + 1;
+    let guard = lock.lock();
+    let second_line = line!() + 1;
+    let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| lock.lock())).unwrap_err();
+    let message = err.downcast_ref::<String>().unwrap();
+    assert!(message.contains(&format!("{}:{second_line}:", file!())), "{message}");
+    assert!(message.contains(&format!("{}:{first_line}:", file!())), "{message}");
+    drop(guard);
+    assert!(!lock.is_locked());
+    *lock.lock() += 1;
+}
+
+#[test]
+fn backoff_test() {
+    let lock = Arc::new(kernel_sync::spin::SpinMutex::<usize, YieldAction>::with_backoff(0, 64));
+    let thread_cnt = 8;
+    let loop_cnt = 10000;
+    let mut threads = vec![];
+    for _ in 0..thread_cnt {
+        let lock = lock.clone();
+        threads.push(std::thread::spawn(move || {
+            for _ in 0..loop_cnt {
+                *lock.lock() += 1;
+            }
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*lock.lock(), thread_cnt * loop_cnt);
+
+    // try_lock is still a single attempt
+    let guard = lock.lock();
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert!(lock.try_lock().is_some());
+}
+
+#[test]
+fn debug_test() {
+    let lock = SpinLock::new(1);
+    let guard = lock.lock();
+    #[cfg(not(feature = "debug-verbose"))]
+    assert_eq!(format!("{lock:?}"), "Mutex { <locked> }");
+    #[cfg(feature = "debug-verbose")]
+    assert_eq!(format!("{lock:?}"), "Mutex { <locked>, generation: 1 }");
+    drop(guard);
+    #[cfg(not(feature = "debug-verbose"))]
+    assert_eq!(format!("{lock:?}"), "Mutex { data: 1}");
+    #[cfg(feature = "debug-verbose")]
+    assert_eq!(format!("{lock:?}"), "Mutex { data: 1, generation: 1}");
+}
+
+#[test]
+fn try_lock_for_test() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use kernel_sync::TimeSource;
+
+    static TIME: AtomicU64 = AtomicU64::new(0);
+    static READS: AtomicU64 = AtomicU64::new(0);
+    /// A manual clock that counts how often it is read.
+    struct FakeClock;
+    impl TimeSource for FakeClock {
+        const TICKS_PER_SEC: u64 = 1000;
+        fn now() -> u64 {
+            READS.fetch_add(1, Ordering::SeqCst);
+            TIME.load(Ordering::SeqCst)
+        }
+    }
+
+    let lock = Arc::new(kernel_sync::spin::SpinMutex::<_, YieldAction>::new(0));
+    // A passed deadline still tries once
+    assert!(lock.try_lock_until::<FakeClock>(0).is_some());
+
+    let guard = lock.lock();
+    assert!(lock.try_lock_until::<FakeClock>(0).is_none());
+    let waiter = {
+        let lock = lock.clone();
+        std::thread::spawn(move || lock.try_lock_for::<FakeClock>(FakeClock::ticks_from_micros(10_000)).is_some())
+    };
+    // Let the waiter spin on the clock before it runs out
+    let reads = READS.load(Ordering::SeqCst);
+    while READS.load(Ordering::SeqCst) < reads + 3 {
+        std::thread::yield_now();
+    }
+    TIME.store(10, Ordering::SeqCst);
+    assert!(!waiter.join().unwrap());
+    assert!(lock.is_locked());
+    drop(guard);
+
+    let guard = lock.lock();
+    let waiter = {
+        let lock = lock.clone();
+        std::thread::spawn(move || lock.try_lock_for::<FakeClock>(10).map(|mut guard| *guard += 1).is_some())
+    };
+    let reads = READS.load(Ordering::SeqCst);
+    while READS.load(Ordering::SeqCst) < reads + 3 {
+        std::thread::yield_now();
+    }
+    drop(guard);
+    assert!(waiter.join().unwrap());
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test]
+fn map_test() {
+    use kernel_sync::spin::{MappedSpinMutexGuard, SpinMutexGuard};
+
+    let lock = kernel_sync::spin::SpinMutex::<_, IrqAction>::new((1, (2, String::from("a"))));
+    let mut inner = SpinMutexGuard::map(lock.lock(), |(_, inner)| inner);
+    inner.0 += 1;
+    let mut name = MappedSpinMutexGuard::map(inner, |(_, name)| name);
+    name.push('b');
+    assert_eq!(format!("{name:?}"), "\"ab\"");
+    assert!(lock.is_locked() && lock.try_lock().is_none());
+    assert_eq!(irq_off(), 1);
+    drop(name);
+    assert!(!lock.is_locked());
+    assert_eq!(irq_off(), 0);
+    assert_eq!(*lock.lock(), (1, (3, String::from("ab"))));
+
+    // A panicking projection still releases the lock
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        SpinMutexGuard::map(lock.lock(), |_| -> &mut u8 { panic!("projection failed") });
+    }));
+    assert!(result.is_err());
+    assert!(!lock.is_locked());
+    assert_eq!(irq_off(), 0);
+}
+
+#[test]
+fn split_map_test() {
+    use kernel_sync::spin::{MappedSpinMutexGuard, SpinMutexGuard};
+
+    let lock = kernel_sync::spin::SpinMutex::<_, IrqAction>::new((vec![1, 2], (0, String::from("a"))));
+    let (mut queue, stats) = SpinMutexGuard::split_map(lock.lock(), |(queue, stats)| (queue, stats));
+    // A half can be mapped further and still shares the lock with the other one
+    let mut name = MappedSpinMutexGuard::map(stats, |(_, name)| name);
+    queue.push(3);
+    name.push('b');
+    drop(queue);
+    assert!(lock.is_locked() && lock.try_lock().is_none());
+    assert_eq!(irq_off(), 1);
+    drop(name);
+    assert!(!lock.is_locked());
+    assert_eq!(irq_off(), 0);
+    assert_eq!(*lock.lock(), (vec![1, 2, 3], (0, String::from("ab"))));
+
+    // A panicking projection still releases the lock
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        SpinMutexGuard::split_map(lock.lock(), |_| -> (&mut u8, &mut u8) { panic!("projection failed") });
+    }));
+    assert!(result.is_err());
+    assert!(!lock.is_locked());
+    assert_eq!(irq_off(), 0);
+}
+
+/// A single CPU whose "interrupt" runs right away unless the lock action disabled interrupts.
+mod irq {
+    use kernel_sync::LockAction;
+    use std::cell::Cell;
+
+    thread_local! {
+        static IRQ_OFF: Cell<usize> = const { Cell::new(0) };
+        static PENDING: Cell<Option<fn()>> = const { Cell::new(None) };
+    }
+
+    /// Deliver `handler` now, or once interrupts are enabled again.
+    pub fn raise(handler: fn()) {
+        if IRQ_OFF.with(Cell::get) == 0 {
+            handler();
+        } else {
+            PENDING.with(|pending| pending.set(Some(handler)));
+        }
+    }
+
+    pub struct IrqOffAction;
+    impl LockAction for IrqOffAction {
+        type Guard = ();
+        fn before_lock() {
+            IRQ_OFF.with(|off| off.set(off.get() + 1));
+        }
+        fn after_lock(_guard: ()) {
+            if IRQ_OFF.with(|off| off.replace(off.get() - 1)) == 1 {
+                if let Some(handler) = PENDING.with(Cell::take) {
+                    handler();
+                }
+            }
+        }
+        fn current_cpu() -> Option<usize> {
+            Some(0)
+        }
+    }
+
+    /// Forgets to disable interrupts.
+    pub struct BrokenAction;
+    impl LockAction for BrokenAction {
+        type Guard = ();
+        fn current_cpu() -> Option<usize> {
+            Some(0)
+        }
+    }
+}
+
+#[test]
+fn try_lock_irq_test() {
+    static LOCK: kernel_sync::spin::SpinMutex<u32, irq::IrqOffAction> = kernel_sync::spin::SpinMutex::new(0);
+
+    let mut guard = LOCK.lock();
+    irq::raise(|| *LOCK.try_lock_irq().expect("the handler ran while the lock was held") += 1);
+    *guard += 1;
+    assert_eq!(*guard, 1);
+    drop(guard);
+    // The handler ran once interrupts were enabled again
+    assert_eq!(*LOCK.lock(), 2);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "did not keep interrupts disabled")]
+fn try_lock_irq_detects_enabled_irq_test() {
+    static LOCK: kernel_sync::spin::SpinMutex<u32, irq::BrokenAction> = kernel_sync::spin::SpinMutex::new(0);
+
+    let _guard = LOCK.lock();
+    irq::raise(|| {
+        let _ = LOCK.try_lock_irq();
+    });
+}
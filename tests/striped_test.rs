@@ -0,0 +1,45 @@
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use kernel_sync::{striped::StripedLock, EmptyLockAction};
+
+#[test]
+fn different_stripes_test() {
+    let table = StripedLock::<usize, EmptyLockAction, 8>::new([0; 8]);
+    assert_ne!(table.stripe_for(3), table.stripe_for(4));
+    let mut a = table.lock_for(3);
+    let mut b = table.lock_for(4);
+    *a += 1;
+    *b += 1;
+    drop((a, b));
+
+    let _a = table.lock_for(3);
+    assert!(table.try_lock_for(4).is_some());
+    // Same stripe as the held key
+    assert_eq!(table.stripe_for(3), table.stripe_for(11));
+    assert!(table.try_lock_for(11).is_none());
+}
+
+#[test]
+fn iter_locks_all_test() {
+    let table = Arc::new(StripedLock::<usize, EmptyLockAction, 4>::default());
+    let mut threads = Vec::new();
+    for t in 0..4u64 {
+        let table = table.clone();
+        threads.push(std::thread::spawn(move || {
+            for key in 0..1000 {
+                *table.lock_for(key * 4 + t) += 1;
+            }
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let guards: Vec<_> = table.iter().collect();
+    assert_eq!(guards.iter().map(|g| **g).sum::<usize>(), 4000);
+    drop(guards);
+    assert_eq!(
+        Arc::try_unwrap(table).ok().unwrap().into_inner(),
+        [1000; 4]
+    );
+}
@@ -0,0 +1,487 @@
+extern crate alloc;
+mod common;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use common::{irq_off, IrqAction, TicketMutex, YieldAction};
+use kernel_sync::LockAction;
+
+#[test]
+fn basic_test() {
+    let x = Arc::new(kernel_sync::ticket::TicketMutex::<_, YieldAction>::new(0));
+    let thread_cnt = 3;
+    let loop_cnt = 100000;
+    let mut threads = vec![];
+    for _ in 0..thread_cnt {
+        let x_clone = x.clone();
+        threads.push(std::thread::spawn(move || {
+            for _ in 0..loop_cnt {
+                let mut guard = x_clone.lock();
+                *guard += 1;
+            }
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*(x.lock()), thread_cnt * loop_cnt);
+}
+
+/// Every owner must see the data left by the previous one.
+#[test]
+fn handoff_test() {
+    let x = Arc::new(kernel_sync::ticket::TicketMutex::<_, YieldAction>::new(alloc::vec::Vec::new()));
+    let thread_cnt = 3;
+    let loop_cnt = 1000;
+    let mut threads = vec![];
+    for _ in 0..thread_cnt {
+        let x_clone = x.clone();
+        threads.push(std::thread::spawn(move || {
+            for _ in 0..loop_cnt {
+                let mut guard = x_clone.lock();
+                let len = guard.len();
+                assert_eq!(guard.last().map_or(0, |last| last + 1), len);
+                guard.push(len);
+            }
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert!(x.lock().iter().copied().eq(0..thread_cnt * loop_cnt));
+}
+
+/// The relaxed handover must keep both the count and the order of the owners' writes intact.
+#[test]
+fn relaxed_counter_test() {
+    let x = Arc::new(kernel_sync::ticket::RelaxedTicketMutex::<_, YieldAction>::new((0, alloc::vec::Vec::new())));
+    let thread_cnt = 3;
+    let loop_cnt = 10000;
+    let mut threads = vec![];
+    for _ in 0..thread_cnt {
+        let x_clone = x.clone();
+        threads.push(std::thread::spawn(move || {
+            for _ in 0..loop_cnt {
+                let mut guard = x_clone.lock();
+                let (count, log) = &mut *guard;
+                assert_eq!(*count, log.len());
+                log.push(*count);
+                *count += 1;
+            }
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert!(!x.is_locked());
+    let (count, log) = &*x.lock();
+    assert_eq!(*count, thread_cnt * loop_cnt);
+    assert!(log.iter().copied().eq(0..thread_cnt * loop_cnt));
+}
+
+#[test]
+fn lock_or_abort_test() {
+    let x = Arc::new(TicketMutex::new(0));
+    let abort = Arc::new(AtomicBool::new(false));
+    let guard = x.lock();
+
+    let x_clone = x.clone();
+    let abort_clone = abort.clone();
+    let aborted = std::thread::spawn(move || x_clone.lock_or_abort(&abort_clone).is_none());
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    // This waiter is queued behind the one that will abort.
+    let x_clone = x.clone();
+    let waiter = std::thread::spawn(move || {
+        *x_clone.lock() += 1;
+    });
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    abort.store(true, Ordering::Relaxed);
+    assert!(aborted.join().unwrap());
+    assert!(x.is_locked());
+
+    drop(guard);
+    waiter.join().unwrap();
+    assert_eq!(*x.lock(), 1);
+    assert!(x.try_lock().is_some());
+}
+
+#[test]
+fn lock_or_abort_race_test() {
+    let x = Arc::new(kernel_sync::ticket::TicketMutex::<_, YieldAction>::new(0));
+    let abort = Arc::new(AtomicBool::new(false));
+    let thread_cnt = 4;
+    let loop_cnt = 20000;
+    let mut threads = vec![];
+    for i in 0..thread_cnt {
+        let x_clone = x.clone();
+        let abort_clone = abort.clone();
+        threads.push(std::thread::spawn(move || {
+            let mut locked = 0;
+            for _ in 0..loop_cnt {
+                if i % 2 == 0 {
+                    if let Some(mut guard) = x_clone.lock_or_abort(&abort_clone) {
+                        *guard += 1;
+                        locked += 1;
+                    }
+                } else {
+                    *x_clone.lock() += 1;
+                    locked += 1;
+                }
+            }
+            locked
+        }));
+    }
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    abort.store(true, Ordering::Relaxed);
+    let locked: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+    assert_eq!(*x.lock(), locked);
+    assert!(x.try_lock().is_some());
+}
+
+#[test]
+fn conversion_test() {
+    let ticket = TicketMutex::new(42);
+    let rwlock: common::RwLock<i32> = ticket.into();
+    assert_eq!(*rwlock.read(), 42);
+}
+
+#[test]
+fn unpark_order_test() {
+    use std::collections::BTreeSet;
+    use std::sync::{Condvar, Mutex};
+
+    struct Slots {
+        /// Tickets that have been unparked but whose waiter has not woken up yet.
+        tokens: BTreeSet<usize>,
+        parked: BTreeSet<usize>,
+        woken: alloc::vec::Vec<usize>,
+    }
+    static SLOTS: Mutex<Slots> = Mutex::new(Slots {
+        tokens: BTreeSet::new(),
+        parked: BTreeSet::new(),
+        woken: alloc::vec::Vec::new(),
+    });
+    static CHANGED: Condvar = Condvar::new();
+
+    /// Gives every ticket its own wait slot and records the order in which tickets are unparked.
+    struct ParkingAction;
+    impl LockAction for ParkingAction {
+        type Guard = ();
+        fn park(_lock: usize, ticket: usize) {
+            let mut slots = SLOTS.lock().unwrap();
+            slots.parked.insert(ticket);
+            CHANGED.notify_all();
+            while !slots.tokens.remove(&ticket) {
+                slots = CHANGED.wait(slots).unwrap();
+            }
+        }
+        fn unpark(_lock: usize, ticket: usize) {
+            let mut slots = SLOTS.lock().unwrap();
+            slots.woken.push(ticket);
+            slots.tokens.insert(ticket);
+            CHANGED.notify_all();
+        }
+    }
+
+    let lock = Arc::new(kernel_sync::ticket::TicketMutex::<_, ParkingAction>::new(vec![]));
+    let guard = lock.lock();
+    let mut threads = vec![];
+    for i in 1..=4 {
+        let lock = lock.clone();
+        threads.push(std::thread::spawn(move || lock.lock().push(i)));
+        // Wait until thread `i` has taken ticket `i` and parked, so tickets follow spawn order.
+        let mut slots = SLOTS.lock().unwrap();
+        while !slots.parked.contains(&i) {
+            slots = CHANGED.wait(slots).unwrap();
+        }
+    }
+    drop(guard);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*lock.lock(), vec![1, 2, 3, 4]);
+    assert_eq!(SLOTS.lock().unwrap().woken[..5], [1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn try_lock_action_balance_test() {
+    use std::cell::Cell;
+
+    thread_local! {
+        static BEFORE: Cell<usize> = const { Cell::new(0) };
+        static AFTER: Cell<usize> = const { Cell::new(0) };
+    }
+
+    struct CountAction;
+    impl LockAction for CountAction {
+        type Guard = ();
+        fn before_lock() {
+            BEFORE.with(|n| n.set(n.get() + 1));
+        }
+        fn after_lock(_guard: ()) {
+            AFTER.with(|n| n.set(n.get() + 1));
+        }
+    }
+    let counts = || (BEFORE.with(Cell::get), AFTER.with(Cell::get));
+
+    let lock = kernel_sync::ticket::TicketMutex::<_, CountAction>::new(0);
+    for i in 0..10000 {
+        let guard = lock.try_lock().unwrap();
+        // Successful: one before, the after is left to the guard
+        assert_eq!(counts(), (2 * i + 1, 2 * i));
+        assert!(lock.try_lock().is_none());
+        // Failed: balanced before returning
+        assert_eq!(counts(), (2 * i + 2, 2 * i + 1));
+        drop(guard);
+        assert_eq!(counts(), (2 * i + 2, 2 * i + 2));
+    }
+}
+
+#[test]
+fn try_lock_for_skips_timed_out_waiter_test() {
+    use core::sync::atomic::AtomicU64;
+    use kernel_sync::TimeSource;
+
+    static TIME: AtomicU64 = AtomicU64::new(0);
+    static EARLY_READS: AtomicU64 = AtomicU64::new(0);
+    static LATE_READS: AtomicU64 = AtomicU64::new(0);
+
+    /// A manual clock. Every waiter reads it once for its deadline and then once per wait iteration, so a count
+    /// of at least two means the waiter holds a ticket.
+    struct EarlyClock;
+    impl TimeSource for EarlyClock {
+        const TICKS_PER_SEC: u64 = 1000;
+        fn now() -> u64 {
+            EARLY_READS.fetch_add(1, Ordering::SeqCst);
+            TIME.load(Ordering::SeqCst)
+        }
+    }
+    struct LateClock;
+    impl TimeSource for LateClock {
+        const TICKS_PER_SEC: u64 = 1000;
+        fn now() -> u64 {
+            LATE_READS.fetch_add(1, Ordering::SeqCst);
+            TIME.load(Ordering::SeqCst)
+        }
+    }
+    let wait_for = |reads: &AtomicU64| {
+        while reads.load(Ordering::SeqCst) < 2 {
+            std::thread::yield_now();
+        }
+    };
+
+    let lock = Arc::new(kernel_sync::ticket::TicketMutex::<_, YieldAction>::new(0));
+    let guard = lock.lock();
+
+    let other = lock.clone();
+    let middle = std::thread::spawn(move || other.try_lock_for::<EarlyClock>(10).map(|_| ()));
+    wait_for(&EARLY_READS);
+    let other = lock.clone();
+    let last = std::thread::spawn(move || {
+        *other.try_lock_for::<LateClock>(1000).unwrap() += 1;
+    });
+    wait_for(&LATE_READS);
+
+    // The middle waiter times out with the last one queued behind it
+    TIME.store(100, Ordering::SeqCst);
+    assert!(middle.join().unwrap().is_none());
+    assert!(!last.is_finished());
+
+    // Releasing skips the abandoned ticket and serves the last waiter
+    drop(guard);
+    last.join().unwrap();
+    assert_eq!(*lock.lock(), 1);
+    assert!(!lock.is_locked());
+}
+
+#[test]
+fn debug_test() {
+    let lock = TicketMutex::new(1);
+    let guard = lock.lock();
+    #[cfg(not(feature = "debug-verbose"))]
+    assert_eq!(format!("{lock:?}"), "Mutex { <locked> }");
+    #[cfg(feature = "debug-verbose")]
+    assert_eq!(format!("{lock:?}"), "Mutex { <locked>, next_ticket: 1, next_serving: 0 }");
+    drop(guard);
+    #[cfg(not(feature = "debug-verbose"))]
+    assert_eq!(format!("{lock:?}"), "Mutex { data: 1}");
+    #[cfg(feature = "debug-verbose")]
+    assert_eq!(format!("{lock:?}"), "Mutex { data: 1, next_ticket: 1, next_serving: 1}");
+}
+
+#[test]
+fn try_lock_until_keeps_queue_test() {
+    use core::sync::atomic::AtomicU64;
+    use kernel_sync::TimeSource;
+
+    static TIME: AtomicU64 = AtomicU64::new(0);
+    static READS: AtomicU64 = AtomicU64::new(0);
+    /// A manual clock, only read by a waiter once it holds a ticket.
+    struct FakeClock;
+    impl TimeSource for FakeClock {
+        const TICKS_PER_SEC: u64 = 1000;
+        fn now() -> u64 {
+            READS.fetch_add(1, Ordering::SeqCst);
+            TIME.load(Ordering::SeqCst)
+        }
+    }
+
+    let lock = Arc::new(kernel_sync::ticket::TicketMutex::<_, YieldAction>::new(0));
+    assert_eq!(FakeClock::ticks_from_micros(5_000), 5);
+    assert!(lock.try_lock_until::<FakeClock>(0).is_some());
+
+    let guard = lock.lock();
+    let timed = {
+        let lock = lock.clone();
+        std::thread::spawn(move || lock.try_lock_until::<FakeClock>(5).is_some())
+    };
+    // The timed waiter gives up while holding a ticket in the middle of the queue
+    while READS.load(Ordering::SeqCst) == 0 {
+        std::thread::yield_now();
+    }
+    TIME.store(5, Ordering::SeqCst);
+    assert!(!timed.join().unwrap());
+    let queued = {
+        let lock = lock.clone();
+        std::thread::spawn(move || *lock.lock() += 1)
+    };
+    drop(guard);
+    queued.join().unwrap();
+    assert_eq!(*lock.lock(), 1);
+    assert!(lock.try_lock_until::<FakeClock>(0).is_some());
+}
+
+/// Two timed waiters whose tickets would be `usize::BITS` apart share a bit of the abandon mask. The second one
+/// must wait for room in the queue instead, or the ticket of the queued waiter after it would be skipped.
+#[test]
+fn try_lock_until_long_queue_test() {
+    use core::sync::atomic::{AtomicU64, AtomicUsize};
+    use kernel_sync::TimeSource;
+    use std::cell::Cell;
+
+    static TIME: AtomicU64 = AtomicU64::new(0);
+    static READS: AtomicU64 = AtomicU64::new(0);
+    static QUEUED: AtomicUsize = AtomicUsize::new(0);
+    struct FakeClock;
+    impl TimeSource for FakeClock {
+        const TICKS_PER_SEC: u64 = 1000;
+        fn now() -> u64 {
+            READS.fetch_add(1, Ordering::SeqCst);
+            TIME.load(Ordering::SeqCst)
+        }
+    }
+    /// Counts the threads waiting in [`TicketMutex::lock`], which hold a ticket once they park.
+    struct QueueAction;
+    impl LockAction for QueueAction {
+        type Guard = ();
+        fn relax() {
+            std::thread::yield_now();
+        }
+        fn park(_lock: usize, _ticket: usize) {
+            thread_local!(static PARKED: Cell<bool> = const { Cell::new(false) });
+            if !PARKED.replace(true) {
+                QUEUED.fetch_add(1, Ordering::SeqCst);
+            }
+            std::thread::yield_now();
+        }
+    }
+    let timed = |lock: &Arc<kernel_sync::ticket::TicketMutex<usize, QueueAction>>| {
+        let lock = lock.clone();
+        let reads = READS.load(Ordering::SeqCst);
+        let thread = std::thread::spawn(move || lock.try_lock_until::<FakeClock>(10).is_some());
+        while READS.load(Ordering::SeqCst) < reads + 2 {
+            std::thread::yield_now();
+        }
+        thread
+    };
+    let queue = |lock: &Arc<kernel_sync::ticket::TicketMutex<usize, QueueAction>>, count| {
+        let waiting = QUEUED.load(Ordering::SeqCst) + count;
+        let threads: Vec<_> = (0..count)
+            .map(|_| {
+                let lock = lock.clone();
+                std::thread::spawn(move || *lock.lock() += 1)
+            })
+            .collect();
+        while QUEUED.load(Ordering::SeqCst) < waiting {
+            std::thread::yield_now();
+        }
+        threads
+    };
+
+    let bits = usize::BITS as usize;
+    let lock = Arc::new(kernel_sync::ticket::TicketMutex::<_, QueueAction>::new(0));
+    let guard = lock.lock();
+    // Tickets 1 and 2..=bits. The second timed waiter would get ticket `bits + 1`, so it has to wait for room
+    // while the two waiters after it take that ticket and the next one
+    let first = timed(&lock);
+    let mut waiters = queue(&lock, bits - 1);
+    let second = timed(&lock);
+    waiters.extend(queue(&lock, 2));
+    TIME.store(10, Ordering::SeqCst);
+    assert!(!first.join().unwrap());
+    assert!(!second.join().unwrap());
+
+    drop(guard);
+    for waiter in waiters {
+        waiter.join().unwrap();
+    }
+    assert_eq!(*lock.lock(), bits + 1);
+    assert!(!lock.is_locked());
+}
+
+#[test]
+fn map_test() {
+    use kernel_sync::ticket::{MappedTicketMutexGuard, TicketMutexGuard};
+
+    let lock = kernel_sync::ticket::TicketMutex::<_, IrqAction>::new((1, (2, String::from("a"))));
+    let mut inner = TicketMutexGuard::map(lock.lock(), |(_, inner)| inner);
+    inner.0 += 1;
+    let mut name = MappedTicketMutexGuard::map(inner, |(_, name)| name);
+    name.push('b');
+    assert_eq!(format!("{name:?}"), "\"ab\"");
+    assert!(lock.is_locked() && lock.try_lock().is_none());
+    assert_eq!(irq_off(), 1);
+    drop(name);
+    // The next ticket is served exactly once
+    assert!(!lock.is_locked());
+    assert_eq!(irq_off(), 0);
+    assert_eq!(*lock.lock(), (1, (3, String::from("ab"))));
+
+    // A panicking projection still releases the lock
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        TicketMutexGuard::map(lock.lock(), |_| -> &mut u8 { panic!("projection failed") });
+    }));
+    assert!(result.is_err());
+    assert!(!lock.is_locked());
+    assert_eq!(irq_off(), 0);
+    assert_eq!(*lock.lock(), (1, (3, String::from("ab"))));
+}
+
+#[test]
+fn split_map_test() {
+    use kernel_sync::ticket::{MappedTicketMutexGuard, TicketMutexGuard};
+
+    let lock = kernel_sync::ticket::TicketMutex::<_, IrqAction>::new((vec![1, 2], (0, String::from("a"))));
+    let (mut queue, stats) = TicketMutexGuard::split_map(lock.lock(), |(queue, stats)| (queue, stats));
+    // A half can be mapped further and still shares the lock with the other one
+    let mut name = MappedTicketMutexGuard::map(stats, |(_, name)| name);
+    queue.push(3);
+    name.push('b');
+    drop(queue);
+    assert!(lock.is_locked() && lock.try_lock().is_none());
+    assert_eq!(irq_off(), 1);
+    drop(name);
+    assert!(!lock.is_locked());
+    assert_eq!(irq_off(), 0);
+    assert_eq!(*lock.lock(), (vec![1, 2, 3], (0, String::from("ab"))));
+
+    // A panicking projection still releases the lock
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        TicketMutexGuard::split_map(lock.lock(), |_| -> (&mut u8, &mut u8) { panic!("projection failed") });
+    }));
+    assert!(result.is_err());
+    assert!(!lock.is_locked());
+    assert_eq!(irq_off(), 0);
+}